@@ -211,6 +211,12 @@ where
         self.writer.flush().await?;
         Ok(())
     }
+
+    /// Shut down the write side, signalling end of stream to the peer.
+    pub async fn shutdown(&mut self) -> Result<(), TunnelError> {
+        self.writer.shutdown().await?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
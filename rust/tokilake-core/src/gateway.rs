@@ -1,8 +1,29 @@
+//! Embeddable gateway: runs the worker control plane on any tunnel session.
+//!
+//! [`Gateway`] accepts the control stream of a [`TunnelSession`], drives the
+//! auth → register → heartbeat / models_sync state machine against an
+//! [`Authenticator`] and a [`WorkerRegistry`], binds the worker's channel in a
+//! [`SessionManager`] and releases everything when the worker goes away.
+//! Transports only need to produce a session; HTTP frameworks only need to
+//! authenticate the upgrade request and hand the tunnel over.
+
 use crate::{
-    error::TunnelError,
-    protocol::{RegisterResult, Token},
+    codec::ControlCodec,
+    error::{ErrorMessage, TunnelError},
+    protocol::{AckMessage, ControlMessage, RegisterResult, Token, control_type, transport},
+    session::{ChannelBindParams, GatewaySession, SessionManager},
+    tunnel::{TunnelSession, quic::QuicSession},
+};
+use std::{future::Future, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{Mutex, RwLock, mpsc},
 };
-use std::future::Future;
+
+/// Time allowed for a worker to open its control stream and register.
+pub const REGISTER_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time allowed between control messages once registered.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(45);
 
 /// Authenticator trait - authenticates tunnel worker tokens.
 pub trait Authenticator: Send + Sync + 'static {
@@ -45,3 +66,697 @@ pub trait WorkerRegistry: Send + Sync + 'static {
         worker_id: i32,
     ) -> impl Future<Output = Result<(), TunnelError>> + Send;
 }
+
+/// Connection metadata known before the tunnel is served.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectInfo {
+    /// Set when the worker already authenticated at the HTTP layer
+    /// (WebSocket). Without it the worker must send an `auth` message.
+    pub token:       Option<Token>,
+    pub token_key:   String,
+    pub remote_addr: String,
+    /// One of [`transport`]'s names.
+    pub transport:   String,
+}
+
+/// Extract the worker token from a connect request: `Authorization: Bearer`,
+/// then the `access_token` and `token` query parameters. `sk-` is stripped.
+pub fn extract_connect_token(
+    authorization: Option<&str>,
+    access_token: Option<&str>,
+    token: Option<&str>,
+) -> Result<String, TunnelError> {
+    let header = authorization.map(|value| {
+        let value = value.trim_start();
+        match value.get(..7) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("bearer ") => value[7..].trim(),
+            _ => value,
+        }
+    });
+    let key = [header, access_token, token]
+        .into_iter()
+        .flatten()
+        .map(normalize_token_key)
+        .find(|key| !key.is_empty())
+        .ok_or_else(|| TunnelError::auth_failed("missing authorization token"))?;
+    Ok(key.to_string())
+}
+
+fn normalize_token_key(raw: &str) -> &str {
+    let raw = raw.trim();
+    raw.strip_prefix("sk-").unwrap_or(raw).trim()
+}
+
+/// Builder for [`Gateway`].
+pub struct GatewayBuilder<A, R> {
+    auth:              A,
+    registry:          R,
+    register_timeout:  Duration,
+    heartbeat_timeout: Duration,
+}
+
+impl<A: Authenticator, R: WorkerRegistry> GatewayBuilder<A, R> {
+    pub fn register_timeout(mut self, timeout: Duration) -> Self {
+        self.register_timeout = timeout;
+        self
+    }
+
+    pub fn heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.heartbeat_timeout = timeout;
+        self
+    }
+
+    pub fn build(self) -> Gateway<A, R> {
+        Gateway {
+            auth:              Arc::new(self.auth),
+            registry:          Arc::new(self.registry),
+            register_timeout:  self.register_timeout,
+            heartbeat_timeout: self.heartbeat_timeout,
+        }
+    }
+}
+
+/// Worker-facing gateway. Cheap to clone.
+pub struct Gateway<A, R> {
+    auth:              Arc<A>,
+    registry:          Arc<R>,
+    register_timeout:  Duration,
+    heartbeat_timeout: Duration,
+}
+
+impl<A, R> Clone for Gateway<A, R> {
+    fn clone(&self) -> Self {
+        Self {
+            auth:              self.auth.clone(),
+            registry:          self.registry.clone(),
+            register_timeout:  self.register_timeout,
+            heartbeat_timeout: self.heartbeat_timeout,
+        }
+    }
+}
+
+/// Per-connection control-plane state.
+#[derive(Default)]
+struct ControlState {
+    authenticated: bool,
+    worker_id:     Option<i32>,
+}
+
+/// A control message the gateway refuses; written back as an `error` frame
+/// and ends the session.
+struct Rejection {
+    code:    &'static str,
+    message: String,
+}
+
+fn reject(code: &'static str, message: impl ToString) -> Rejection {
+    Rejection {
+        code,
+        message: message.to_string(),
+    }
+}
+
+impl<A: Authenticator, R: WorkerRegistry> Gateway<A, R> {
+    pub fn builder(auth: A, registry: R) -> GatewayBuilder<A, R> {
+        GatewayBuilder {
+            auth,
+            registry,
+            register_timeout: REGISTER_TIMEOUT,
+            heartbeat_timeout: HEARTBEAT_TIMEOUT,
+        }
+    }
+
+    pub fn new(auth: A, registry: R) -> Self {
+        Self::builder(auth, registry).build()
+    }
+
+    pub fn authenticator(&self) -> &A {
+        &self.auth
+    }
+
+    pub fn registry(&self) -> &R {
+        &self.registry
+    }
+
+    /// Authenticate a raw worker token (an optional `sk-` prefix is ignored).
+    pub async fn authenticate(&self, raw_token: &str) -> Result<(String, Token), TunnelError> {
+        let token_key = normalize_token_key(raw_token);
+        if token_key.is_empty() {
+            return Err(TunnelError::auth_failed("missing authorization token"));
+        }
+        self.auth.authenticate_token_key(token_key).await
+    }
+
+    /// Serve one worker connection until it disconnects, then unregister it.
+    pub async fn serve<T>(
+        &self,
+        manager: &SessionManager<T>,
+        tunnel: T,
+        info: ConnectInfo,
+    ) -> Result<(), TunnelError>
+    where
+        T: TunnelSession,
+        T::Stream: AsyncRead + AsyncWrite + Unpin,
+    {
+        let tunnel = Arc::new(Mutex::new(tunnel));
+        let session = manager.new_session(
+            info.token,
+            info.token_key,
+            info.remote_addr.clone(),
+            info.transport.clone(),
+        );
+        session.write().await.tunnel_session = Some(tunnel.clone());
+
+        let result = self.serve_session(manager, &session, &tunnel).await;
+        match &result {
+            Ok(()) => tracing::info!(
+                "worker disconnected: transport={} addr={}",
+                info.transport,
+                info.remote_addr
+            ),
+            Err(e) => tracing::warn!(
+                "worker session ended: transport={} addr={} error={}",
+                info.transport,
+                info.remote_addr,
+                e
+            ),
+        }
+
+        {
+            // `release` re-reads the indexed sessions, so only a read guard
+            // may be held here.
+            let s = session.read().await;
+            if let Some(worker_id) = s.worker_info.as_ref().map(|i| i.worker_id)
+                && let Err(e) = self.registry.cleanup_worker(worker_id).await
+            {
+                tracing::warn!("worker cleanup failed: id={} error={}", worker_id, e);
+            }
+            manager.release(&s).await;
+        }
+        {
+            let mut s = session.write().await;
+            s.control_tx = None;
+            s.tunnel_session = None;
+        }
+        let _ = tunnel.lock().await.close().await;
+        result
+    }
+
+    /// Accept QUIC connections on `endpoint` and serve each as a worker.
+    pub async fn serve_quic(
+        &self,
+        endpoint: quinn::Endpoint,
+        manager: Arc<SessionManager<QuicSession>>,
+    ) {
+        while let Some(incoming) = endpoint.accept().await {
+            let gateway = self.clone();
+            let manager = manager.clone();
+            tokio::spawn(async move {
+                let conn = match incoming.await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        tracing::warn!("QUIC accept error: {}", e);
+                        return;
+                    }
+                };
+                let info = ConnectInfo {
+                    token:       None,
+                    token_key:   String::new(),
+                    remote_addr: conn.remote_address().to_string(),
+                    transport:   transport::QUIC.to_string(),
+                };
+                tracing::info!("QUIC connection from {}", info.remote_addr);
+                let _ = gateway.serve(&manager, QuicSession::new(conn), info).await;
+            });
+        }
+    }
+
+    async fn serve_session<T>(
+        &self,
+        manager: &SessionManager<T>,
+        session: &Arc<RwLock<GatewaySession<T>>>,
+        tunnel: &Mutex<T>,
+    ) -> Result<(), TunnelError>
+    where
+        T: TunnelSession,
+        T::Stream: AsyncRead + AsyncWrite + Unpin,
+    {
+        let control = {
+            let mut tunnel = tunnel.lock().await;
+            tokio::time::timeout(self.register_timeout, tunnel.accept_stream())
+                .await
+                .map_err(|_| TunnelError::Timeout)??
+                .ok_or(TunnelError::StreamClosed)?
+        };
+        let (reader, writer) = tokio::io::split(control);
+        let mut codec = ControlCodec::new(reader, writer);
+
+        // Other tasks (e.g. request cancellation) write through this channel
+        // so the control stream keeps a single writer.
+        let (control_tx, mut control_rx) = mpsc::channel::<ControlMessage>(32);
+        let mut state = {
+            let mut s = session.write().await;
+            s.control_tx = Some(control_tx);
+            ControlState {
+                authenticated: s.authenticated,
+                worker_id:     None,
+            }
+        };
+
+        enum Event {
+            Inbound(Option<Box<ControlMessage>>),
+            Outbound(Box<ControlMessage>),
+            Timeout,
+        }
+
+        let mut deadline = tokio::time::Instant::now() + self.register_timeout;
+        loop {
+            let event = tokio::select! {
+                msg = codec.read_message() => Event::Inbound(msg?.map(Box::new)),
+                Some(msg) = control_rx.recv() => Event::Outbound(Box::new(msg)),
+                _ = tokio::time::sleep_until(deadline) => Event::Timeout,
+            };
+
+            let msg = match event {
+                Event::Inbound(Some(msg)) => *msg,
+                Event::Inbound(None) => return Ok(()),
+                Event::Outbound(msg) => {
+                    codec.write_message(&msg).await?;
+                    continue;
+                }
+                Event::Timeout => return Err(TunnelError::Timeout),
+            };
+
+            let request_id = msg.request_id.clone().unwrap_or_default();
+            match self.handle_message(manager, session, &mut state, msg).await {
+                Ok(Some(ack)) => {
+                    codec
+                        .write_message(&ControlMessage::ack(request_id, ack))
+                        .await?
+                }
+                Ok(None) => {}
+                Err(rejection) => {
+                    codec
+                        .write_message(&ControlMessage::error_msg(
+                            request_id,
+                            ErrorMessage::new(rejection.code, rejection.message.clone()),
+                        ))
+                        .await?;
+                    let message = format!("{}: {}", rejection.code, rejection.message);
+                    return Err(match rejection.code {
+                        "auth_failed" | "not_authenticated" => TunnelError::auth_failed(message),
+                        _ => TunnelError::protocol(message),
+                    });
+                }
+            }
+
+            let timeout = match state.worker_id {
+                Some(_) => self.heartbeat_timeout,
+                None => self.register_timeout,
+            };
+            deadline = tokio::time::Instant::now() + timeout;
+        }
+    }
+
+    async fn handle_message<T: TunnelSession>(
+        &self,
+        manager: &SessionManager<T>,
+        session: &Arc<RwLock<GatewaySession<T>>>,
+        state: &mut ControlState,
+        msg: ControlMessage,
+    ) -> Result<Option<AckMessage>, Rejection> {
+        match msg.msg_type.as_str() {
+            control_type::AUTH => {
+                if state.authenticated {
+                    return Err(reject(
+                        "auth_already_completed",
+                        "auth message already handled",
+                    ));
+                }
+                let auth = msg
+                    .auth
+                    .ok_or_else(|| reject("auth_payload_missing", "auth payload is required"))?;
+                let (token_key, token) = self
+                    .authenticate(&auth.token)
+                    .await
+                    .map_err(|e| reject("auth_failed", e))?;
+
+                state.authenticated = true;
+                let mut s = session.write().await;
+                s.token = Some(token);
+                s.token_key = token_key;
+                s.authenticated = true;
+                Ok(Some(ack("auth_ok", &s)))
+            }
+
+            control_type::REGISTER => {
+                if !state.authenticated {
+                    return Err(reject("not_authenticated", "authentication is required"));
+                }
+                if state.worker_id.is_some() {
+                    return Err(reject(
+                        "register_already_completed",
+                        "register message already handled",
+                    ));
+                }
+                let register = msg.register.ok_or_else(|| {
+                    reject("register_payload_missing", "register payload is required")
+                })?;
+
+                let session_id = session.read().await.id;
+                let result = self
+                    .registry
+                    .register_worker(
+                        session_id,
+                        &register.namespace,
+                        &register.node_name,
+                        &register.group,
+                        &register.models,
+                        &register.backend_type,
+                    )
+                    .await
+                    .map_err(|e| reject("register_failed", e))?;
+
+                if let Err(e) = manager.claim_namespace(session, &result.namespace).await {
+                    let _ = self.registry.cleanup_worker(result.worker_id).await;
+                    return Err(reject("register_failed", e));
+                }
+                manager
+                    .bind_channel(session, ChannelBindParams {
+                        worker_id:    result.worker_id,
+                        channel_id:   result.channel_id,
+                        namespace:    result.namespace.clone(),
+                        group:        result.group.clone(),
+                        models:       result.models.clone(),
+                        backend_type: result.backend_type.clone(),
+                        status:       result.status,
+                    })
+                    .await;
+                state.worker_id = Some(result.worker_id);
+
+                tracing::info!(
+                    "worker registered: id={} channel={} namespace={} models={:?}",
+                    result.worker_id,
+                    result.channel_id,
+                    result.namespace,
+                    result.models
+                );
+                Ok(Some(ack("register_ok", &*session.read().await)))
+            }
+
+            control_type::HEARTBEAT => {
+                let worker_id = registered(state, "heartbeat")?;
+                let heartbeat = msg.heartbeat.ok_or_else(|| {
+                    reject("heartbeat_payload_missing", "heartbeat payload is required")
+                })?;
+                self.registry
+                    .update_heartbeat(
+                        worker_id,
+                        heartbeat.status,
+                        &heartbeat.node_name,
+                        &heartbeat.current_models,
+                    )
+                    .await
+                    .map_err(|e| reject("heartbeat_failed", e))?;
+                // Workers repeat their models on every heartbeat; only take
+                // the session's write lock when the list actually changed.
+                let changed = !heartbeat.current_models.is_empty()
+                    && session
+                        .read()
+                        .await
+                        .worker_info
+                        .as_ref()
+                        .is_some_and(|info| info.models != heartbeat.current_models);
+                if changed {
+                    manager
+                        .update_models(session, &heartbeat.current_models)
+                        .await;
+                }
+                Ok(Some(ack("heartbeat_ok", &*session.read().await)))
+            }
+
+            control_type::MODELS_SYNC => {
+                let worker_id = registered(state, "models_sync")?;
+                let sync = msg.models_sync.ok_or_else(|| {
+                    reject("models_payload_missing", "models_sync payload is required")
+                })?;
+                self.registry
+                    .sync_models(worker_id, &sync.group, &sync.models, &sync.backend_type)
+                    .await
+                    .map_err(|e| reject("models_sync_failed", e))?;
+
+                manager.update_models(session, &sync.models).await;
+                let mut s = session.write().await;
+                if let Some(info) = s.worker_info.as_mut() {
+                    if !sync.group.is_empty() {
+                        info.group = sync.group;
+                    }
+                    if !sync.backend_type.is_empty() {
+                        info.backend_type = sync.backend_type;
+                    }
+                }
+                Ok(Some(ack("models_sync_ok", &s)))
+            }
+
+            // Workers acknowledge cancel_request with cancel_ok / cancel_noop.
+            control_type::ACK => {
+                if let Some(ack) = msg.ack {
+                    tracing::debug!(
+                        "tokiame ack {}: {}",
+                        msg.request_id.as_deref().unwrap_or_default(),
+                        ack.message
+                    );
+                }
+                Ok(None)
+            }
+
+            control_type::ERROR => {
+                if let Some(err) = msg.error {
+                    tracing::warn!("tokiame reported error: {}", err);
+                }
+                Ok(None)
+            }
+
+            other => {
+                if !state.authenticated {
+                    return Err(reject("not_authenticated", "authentication is required"));
+                }
+                Err(reject(
+                    "unsupported_message_type",
+                    format!("unsupported message type: {}", other),
+                ))
+            }
+        }
+    }
+}
+
+fn registered(state: &ControlState, action: &str) -> Result<i32, Rejection> {
+    if !state.authenticated {
+        return Err(reject("not_authenticated", "authentication is required"));
+    }
+    state.worker_id.ok_or_else(|| {
+        reject(
+            "not_registered",
+            format!("register is required before {}", action),
+        )
+    })
+}
+
+fn ack<T: TunnelSession>(message: &str, session: &GatewaySession<T>) -> AckMessage {
+    let info = session.worker_info.as_ref();
+    AckMessage {
+        message:    message.to_string(),
+        namespace:  info.map_or(String::new(), |i| i.namespace.clone()),
+        worker_id:  info.map_or(0, |i| i.worker_id),
+        channel_id: info.map_or(0, |i| i.channel_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::{ModelTarget, WorkerClient, WorkerConfig};
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicI32, Ordering},
+    };
+
+    struct StaticAuth;
+
+    impl Authenticator for StaticAuth {
+        async fn authenticate_token_key(
+            &self,
+            token_key: &str,
+        ) -> Result<(String, Token), TunnelError> {
+            match token_key {
+                "worker-token" => Ok((token_key.to_string(), Token { user_id: 7 })),
+                _ => Err(TunnelError::auth_failed("invalid token")),
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct CountingRegistry {
+        registered: AtomicI32,
+        heartbeats: AtomicI32,
+        cleaned_up: AtomicI32,
+    }
+
+    impl WorkerRegistry for CountingRegistry {
+        async fn register_worker(
+            &self,
+            _session_id: u64,
+            namespace: &str,
+            _node_name: &str,
+            group: &str,
+            models: &[String],
+            backend_type: &str,
+        ) -> Result<RegisterResult, TunnelError> {
+            let worker_id = self.registered.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(RegisterResult {
+                worker_id,
+                channel_id: 100 + worker_id,
+                namespace: namespace.to_string(),
+                group: group.to_string(),
+                models: models.to_vec(),
+                backend_type: backend_type.to_string(),
+                status: 1,
+            })
+        }
+
+        async fn update_heartbeat(
+            &self,
+            _worker_id: i32,
+            _status: i32,
+            _node_name: &str,
+            _current_models: &[String],
+        ) -> Result<(), TunnelError> {
+            self.heartbeats.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn sync_models(
+            &self,
+            _worker_id: i32,
+            _group: &str,
+            _models: &[String],
+            _backend_type: &str,
+        ) -> Result<(), TunnelError> {
+            Ok(())
+        }
+
+        async fn cleanup_worker(&self, _worker_id: i32) -> Result<(), TunnelError> {
+            self.cleaned_up.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn worker(token: &str) -> WorkerClient {
+        let mut config = WorkerConfig {
+            gateway_url: "http://127.0.0.1:1/connect".to_string(),
+            token: token.to_string(),
+            namespace: "test-ns".to_string(),
+            heartbeat_interval_seconds: 1,
+            model_targets: HashMap::from([("gpt-4".to_string(), ModelTarget {
+                url: "http://127.0.0.1:1".to_string(),
+                ..Default::default()
+            })]),
+            ..Default::default()
+        };
+        config.validate().unwrap();
+        WorkerClient::new(config)
+    }
+
+    fn smux_pair() -> (tokilake_smux::Session, tokilake_smux::Session) {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        (
+            tokilake_smux::Session::server(a, Default::default()),
+            tokilake_smux::Session::client(b, Default::default()),
+        )
+    }
+
+    #[test]
+    fn test_extract_connect_token() {
+        assert_eq!(
+            extract_connect_token(Some("Bearer sk-abc"), None, None).unwrap(),
+            "abc"
+        );
+        assert_eq!(
+            extract_connect_token(Some(""), Some("sk-xyz"), Some("other")).unwrap(),
+            "xyz"
+        );
+        assert!(extract_connect_token(Some("Bearer "), None, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_gateway_registers_and_cleans_up_worker() {
+        let gateway = Gateway::new(StaticAuth, CountingRegistry::default());
+        let manager = Arc::new(SessionManager::new());
+        let (gateway_side, worker_side) = smux_pair();
+
+        let served = tokio::spawn({
+            let gateway = gateway.clone();
+            let manager = manager.clone();
+            async move {
+                let info = ConnectInfo {
+                    token:       Some(Token { user_id: 7 }),
+                    token_key:   "worker-token".to_string(),
+                    remote_addr: "127.0.0.1:1".to_string(),
+                    transport:   transport::WEBSOCKET.to_string(),
+                };
+                gateway.serve(&manager, gateway_side, info).await
+            }
+        });
+        let client = worker("worker-token");
+        let worker_task = tokio::spawn({
+            let client = client.clone();
+            async move { client.serve(worker_side, transport::WEBSOCKET).await }
+        });
+
+        // Wait for register + models_sync + one heartbeat.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while gateway.registry().heartbeats.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let session = manager.get_by_namespace("test-ns").unwrap();
+        {
+            let s = session.read().await;
+            assert!(s.is_alive());
+            assert_eq!(s.worker_info.as_ref().unwrap().channel_id, 101);
+            assert_eq!(s.worker_info.as_ref().unwrap().models, vec!["gpt-4"]);
+        }
+        assert!(manager.get_by_channel_id(101).is_some());
+        assert_eq!(manager.get_by_model("gpt-4").len(), 1);
+
+        client.shutdown();
+        worker_task.await.unwrap().unwrap();
+        served.await.unwrap().unwrap();
+        assert_eq!(gateway.registry().cleaned_up.load(Ordering::SeqCst), 1);
+        assert!(manager.get_by_namespace("test-ns").is_none());
+        assert!(manager.get_by_channel_id(101).is_none());
+        assert!(manager.get_by_model("gpt-4").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_gateway_requires_auth_message_without_token() {
+        let gateway = Gateway::new(StaticAuth, CountingRegistry::default());
+        let manager = SessionManager::new();
+        let (gateway_side, worker_side) = smux_pair();
+
+        let client = worker("wrong-token");
+        let (served, worker_result) = tokio::join!(
+            gateway.serve(&manager, gateway_side, ConnectInfo {
+                transport: transport::QUIC.to_string(),
+                ..Default::default()
+            }),
+            client.serve(worker_side, transport::QUIC),
+        );
+        assert!(matches!(served, Err(TunnelError::AuthFailed { .. })));
+        assert!(matches!(worker_result, Err(TunnelError::AuthFailed { .. })));
+        assert_eq!(gateway.registry().registered.load(Ordering::SeqCst), 0);
+        assert_eq!(manager.session_count(), 0);
+    }
+}
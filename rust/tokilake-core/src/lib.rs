@@ -9,14 +9,22 @@
 //! - [`session`]: Gateway session management
 //! - [`gateway`]: Core gateway logic
 //! - [`codec`]: NDJSON message codecs
+//! - [`model_list`]: The OpenAI model list both binaries serve
+//! - [`ratelimit`]: Sliding-window RPM and TPM limits
+//! - [`usage`]: Token usage reported in OpenAI responses
+//! - [`worker`]: Tokiame worker client (the tunnel's far end)
 
 pub mod codec;
 pub mod error;
 pub mod gateway;
+pub mod model_list;
 pub mod protocol;
+pub mod ratelimit;
 pub mod roundtrip;
 pub mod service;
 pub mod session;
 pub mod tunnel;
+pub mod usage;
+pub mod worker;
 
 pub use anyhow::{Error as AnyError, Result as AnyResult};
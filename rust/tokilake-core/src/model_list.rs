@@ -0,0 +1,78 @@
+//! The body of OpenAI's `GET /v1/models` and `GET /v1/models/{id}`.
+//!
+//! Both binaries answer these from what they can route to: a [`ModelList`]
+//! is built from `(model, owner)` pairs, where the owner is the channel or
+//! worker namespace serving the model.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// One model, as `GET /v1/models/{id}` returns it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ModelObject {
+    pub id:       String,
+    pub object:   &'static str,
+    /// When the model was added; Tokilake does not track it, so always 0.
+    pub created:  i64,
+    pub owned_by: String,
+}
+
+/// Every model a caller may use, sorted by id.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ModelList {
+    pub object: &'static str,
+    pub data:   Vec<ModelObject>,
+}
+
+impl ModelList {
+    /// The list of `(model, owner)` pairs; a model served by several owners
+    /// is listed once, owned by the first of them.
+    pub fn new(models: impl IntoIterator<Item = (String, String)>) -> Self {
+        let mut owners = BTreeMap::new();
+        for (model, owner) in models {
+            owners.entry(model).or_insert(owner);
+        }
+        Self {
+            object: "list",
+            data:   owners
+                .into_iter()
+                .map(|(id, owned_by)| ModelObject {
+                    id,
+                    object: "model",
+                    created: 0,
+                    owned_by,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ModelObject> {
+        self.data
+            .binary_search_by(|model| model.id.as_str().cmp(id))
+            .ok()
+            .map(|index| &self.data[index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_owner_wins_and_list_is_sorted() {
+        let list = ModelList::new([
+            ("llama".to_string(), "gpu-a".to_string()),
+            ("gpt-4o".to_string(), "openai".to_string()),
+            ("llama".to_string(), "gpu-b".to_string()),
+        ]);
+        let ids: Vec<_> = list.data.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, ["gpt-4o", "llama"]);
+        assert_eq!(list.get("llama").unwrap().owned_by, "gpu-a");
+        assert!(list.get("mistral").is_none());
+
+        let json = serde_json::to_value(&list).unwrap();
+        assert_eq!(json["object"], "list");
+        assert_eq!(json["data"][0]["object"], "model");
+        assert_eq!(json["data"][0]["owned_by"], "openai");
+    }
+}
@@ -63,8 +63,66 @@ pub mod control_type {
     pub const ERROR: &str = "error";
 }
 
+pub mod transport {
+    pub const WEBSOCKET: &str = "websocket";
+    pub const QUIC: &str = "quic";
+}
+
 pub mod route_kind {
     pub const CHAT_COMPLETIONS: &str = "chat_completions";
+    pub const COMPLETIONS: &str = "completions";
+    pub const RESPONSES: &str = "responses";
+    pub const EMBEDDINGS: &str = "embeddings";
+    pub const RERANK: &str = "rerank";
+    pub const AUDIO_SPEECH: &str = "audio_speech";
+    pub const AUDIO_TRANSCRIPTION: &str = "audio_transcription";
+    pub const AUDIO_TRANSLATION: &str = "audio_translation";
+    pub const IMAGES_GENERATIONS: &str = "images_generations";
+    pub const IMAGES_EDITS: &str = "images_edits";
+    pub const IMAGES_VARIATIONS: &str = "images_variations";
+    pub const VIDEOS_CREATE: &str = "videos_create";
+    pub const VIDEOS_GET: &str = "videos_get";
+    pub const VIDEOS_CONTENT: &str = "videos_content";
+
+    pub const ALL: &[&str] = &[
+        CHAT_COMPLETIONS,
+        COMPLETIONS,
+        RESPONSES,
+        EMBEDDINGS,
+        RERANK,
+        AUDIO_SPEECH,
+        AUDIO_TRANSCRIPTION,
+        AUDIO_TRANSLATION,
+        IMAGES_GENERATIONS,
+        IMAGES_EDITS,
+        IMAGES_VARIATIONS,
+        VIDEOS_CREATE,
+        VIDEOS_GET,
+        VIDEOS_CONTENT,
+    ];
+
+    /// HTTP method and OpenAI-compatible path of a route kind. Video task
+    /// routes use `{id}` for the task id.
+    pub fn http_route(kind: &str) -> Option<(&'static str, &'static str)> {
+        let route = match kind {
+            CHAT_COMPLETIONS => ("POST", "/v1/chat/completions"),
+            COMPLETIONS => ("POST", "/v1/completions"),
+            RESPONSES => ("POST", "/v1/responses"),
+            EMBEDDINGS => ("POST", "/v1/embeddings"),
+            RERANK => ("POST", "/v1/rerank"),
+            AUDIO_SPEECH => ("POST", "/v1/audio/speech"),
+            AUDIO_TRANSCRIPTION => ("POST", "/v1/audio/transcriptions"),
+            AUDIO_TRANSLATION => ("POST", "/v1/audio/translations"),
+            IMAGES_GENERATIONS => ("POST", "/v1/images/generations"),
+            IMAGES_EDITS => ("POST", "/v1/images/edits"),
+            IMAGES_VARIATIONS => ("POST", "/v1/images/variations"),
+            VIDEOS_CREATE => ("POST", "/v1/videos"),
+            VIDEOS_GET => ("GET", "/v1/videos/{id}"),
+            VIDEOS_CONTENT => ("GET", "/v1/videos/{id}/content"),
+            _ => return None,
+        };
+        Some(route)
+    }
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -109,6 +167,22 @@ impl ControlMessage {
         }
     }
 
+    pub fn cancel_request(
+        request_id: impl Into<String>,
+        target_request_id: impl Into<String>,
+        reason: impl Into<String>,
+    ) -> Self {
+        Self {
+            msg_type: control_type::CANCEL_REQUEST.to_string(),
+            request_id: Some(request_id.into()),
+            cancel_request: Some(CancelRequestMessage {
+                target_request_id: target_request_id.into(),
+                reason:            reason.into(),
+            }),
+            ..Default::default()
+        }
+    }
+
     pub fn error_msg(request_id: impl Into<String>, error: ErrorMessage) -> Self {
         Self {
             msg_type: control_type::ERROR.to_string(),
@@ -0,0 +1,486 @@
+//! Requests-per-minute and tokens-per-minute limits over sliding windows.
+//!
+//! A [`RateLimiter`] keeps, in memory, the requests and tokens of the last
+//! minute for every client token and every model, and admits a request only
+//! if it fits in both. Tokens are counted when known: callers [`admit`]
+//! a request with an estimate of its size and may later
+//! [`correct`](RateLimiter::correct) it with the real usage.
+//!
+//! [`admit`]: RateLimiter::admit
+
+use http::{HeaderMap, HeaderValue};
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// How far back a window looks.
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+/// Rough token count of `bytes` of text, for requests whose size nobody has
+/// reported yet.
+pub fn estimate_tokens(bytes: usize) -> i64 {
+    bytes.div_ceil(4) as i64
+}
+
+/// Rough token count of a request body: the text in the string values of a
+/// JSON body. Other bodies, such as multipart audio uploads, count nothing;
+/// their size says little about what they cost.
+pub fn estimate_request_tokens(body: &[u8]) -> i64 {
+    fn text_len(value: &serde_json::Value) -> usize {
+        match value {
+            serde_json::Value::String(s) => s.len(),
+            serde_json::Value::Array(values) => values.iter().map(text_len).sum(),
+            serde_json::Value::Object(fields) => fields.values().map(text_len).sum(),
+            _ => 0,
+        }
+    }
+    serde_json::from_slice(body).map_or(0, |value| estimate_tokens(text_len(&value)))
+}
+
+/// Limits of one scope; `None` is no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Requests per minute.
+    pub rpm: Option<u64>,
+    /// Tokens per minute.
+    pub tpm: Option<u64>,
+}
+
+impl Limits {
+    pub fn is_unlimited(&self) -> bool {
+        self.rpm.is_none() && self.tpm.is_none()
+    }
+}
+
+/// What the limiter enforces.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    /// Limits of every client token, across models.
+    pub per_token: Limits,
+    /// Limits of every model, across tokens, unless `models` names it.
+    pub per_model: Limits,
+    /// Limits of single models.
+    pub models:    HashMap<String, Limits>,
+}
+
+impl RateLimits {
+    fn for_model(&self, model: &str) -> Limits {
+        self.models.get(model).copied().unwrap_or(self.per_model)
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.per_token.is_unlimited()
+            && self.per_model.is_unlimited()
+            && self.models.values().all(Limits::is_unlimited)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Scope {
+    Token(u64),
+    Model(String),
+}
+
+/// The last minute of one scope.
+#[derive(Debug, Default)]
+struct Window {
+    requests: VecDeque<Instant>,
+    tokens:   VecDeque<(Instant, i64)>,
+    /// Sum of `tokens`.
+    used:     i64,
+}
+
+impl Window {
+    fn prune(&mut self, now: Instant) {
+        while self
+            .requests
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= WINDOW)
+        {
+            self.requests.pop_front();
+        }
+        while let Some((at, tokens)) = self.tokens.front()
+            && now.duration_since(*at) >= WINDOW
+        {
+            self.used -= tokens;
+            self.tokens.pop_front();
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.requests.is_empty() && self.tokens.is_empty()
+    }
+
+    fn add_tokens(&mut self, now: Instant, tokens: i64) {
+        if tokens > 0 {
+            self.tokens.push_back((now, tokens));
+            self.used += tokens;
+        }
+    }
+
+    /// Take `tokens` back from the entries still in the window, newest
+    /// first. What has already expired can't be taken back: a negative
+    /// entry would outlive the ones it offsets and leave the window below
+    /// zero.
+    fn remove_tokens(&mut self, mut tokens: i64) {
+        for (_, used) in self.tokens.iter_mut().rev() {
+            if tokens <= 0 {
+                break;
+            }
+            let taken = tokens.min(*used);
+            *used -= taken;
+            self.used -= taken;
+            tokens -= taken;
+        }
+    }
+
+    /// When a window entry made at `at` stops counting.
+    fn expiry(at: Instant, now: Instant) -> Duration {
+        (at + WINDOW).saturating_duration_since(now)
+    }
+
+    /// How long until one more request fits under `rpm`.
+    fn request_wait(&self, rpm: u64, now: Instant) -> Duration {
+        let excess = (self.requests.len() as u64 + 1).saturating_sub(rpm);
+        match excess {
+            0 => Duration::ZERO,
+            n => self
+                .requests
+                .get(n as usize - 1)
+                .map_or(WINDOW, |at| Self::expiry(*at, now)),
+        }
+    }
+
+    /// How long until `tokens` more fit under `tpm`.
+    fn token_wait(&self, tokens: i64, tpm: u64, now: Instant) -> Duration {
+        let mut excess = self.used + tokens - tpm as i64;
+        if excess <= 0 || self.used <= 0 {
+            // A request larger than the whole limit still gets through an
+            // empty window, or it could never be served.
+            return Duration::ZERO;
+        }
+        for (at, used) in &self.tokens {
+            excess -= used;
+            if excess <= 0 {
+                return Self::expiry(*at, now);
+            }
+        }
+        WINDOW
+    }
+
+    fn status(&self, limits: Limits, now: Instant) -> RateLimitStatus {
+        RateLimitStatus {
+            limit_requests:     limits.rpm,
+            remaining_requests: limits
+                .rpm
+                .map(|rpm| rpm.saturating_sub(self.requests.len() as u64)),
+            reset_requests:     self
+                .requests
+                .back()
+                .map_or(Duration::ZERO, |at| Self::expiry(*at, now)),
+            limit_tokens:       limits.tpm,
+            remaining_tokens:   limits.tpm.map(|tpm| (tpm as i64 - self.used).max(0) as u64),
+            reset_tokens:       self
+                .tokens
+                .back()
+                .map_or(Duration::ZERO, |(at, _)| Self::expiry(*at, now)),
+        }
+    }
+}
+
+/// Where a scope stands, as reported in `x-ratelimit-*` headers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    pub limit_requests:     Option<u64>,
+    pub remaining_requests: Option<u64>,
+    /// Until the window holds no request any more.
+    pub reset_requests:     Duration,
+    pub limit_tokens:       Option<u64>,
+    pub remaining_tokens:   Option<u64>,
+    /// Until the window holds no tokens any more.
+    pub reset_tokens:       Duration,
+}
+
+impl RateLimitStatus {
+    /// Add OpenAI's `x-ratelimit-*` headers for the limits that are set.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        let mut set = |name: &'static str, value: String| {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        };
+        if let (Some(limit), Some(remaining)) = (self.limit_requests, self.remaining_requests) {
+            set("x-ratelimit-limit-requests", limit.to_string());
+            set("x-ratelimit-remaining-requests", remaining.to_string());
+            set(
+                "x-ratelimit-reset-requests",
+                format_reset(self.reset_requests),
+            );
+        }
+        if let (Some(limit), Some(remaining)) = (self.limit_tokens, self.remaining_tokens) {
+            set("x-ratelimit-limit-tokens", limit.to_string());
+            set("x-ratelimit-remaining-tokens", remaining.to_string());
+            set("x-ratelimit-reset-tokens", format_reset(self.reset_tokens));
+        }
+    }
+}
+
+/// A request turned away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimited {
+    /// What was exceeded, for the error message.
+    pub reason:      String,
+    /// How long until the request would fit.
+    pub retry_after: Duration,
+    pub status:      RateLimitStatus,
+}
+
+impl RateLimited {
+    /// `Retry-After` and `x-ratelimit-*` headers of the 429 answer.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        self.status.apply(headers);
+        let seconds = self.retry_after.as_millis().div_ceil(1000).max(1);
+        headers.insert(http::header::RETRY_AFTER, HeaderValue::from(seconds as u64));
+    }
+}
+
+/// A duration the way OpenAI writes it: `20ms`, `6s`, `1m30s`.
+pub fn format_reset(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    if millis < 1000 {
+        return format!("{}ms", millis);
+    }
+    let seconds = millis.div_ceil(1000);
+    match (seconds / 60, seconds % 60) {
+        (0, seconds) => format!("{}s", seconds),
+        (minutes, seconds) => format!("{}m{}s", minutes, seconds),
+    }
+}
+
+/// Sliding-window limiter shared by every request of a gateway.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    limits:  RateLimits,
+    windows: Mutex<HashMap<Scope, Window>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            windows: Mutex::default(),
+        }
+    }
+
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    fn scopes(&self, token_id: u64, model: &str) -> [(Scope, Limits); 2] {
+        [
+            (Scope::Token(token_id), self.limits.per_token),
+            (
+                Scope::Model(model.to_string()),
+                self.limits.for_model(model),
+            ),
+        ]
+    }
+
+    /// Count a request of `token_id` for `model`, about `tokens` large, if it
+    /// fits in every limit. The status returned is that of the token's own
+    /// limits, or the model's when the token has none.
+    pub fn admit(
+        &self,
+        token_id: u64,
+        model: &str,
+        tokens: i64,
+    ) -> Result<RateLimitStatus, Box<RateLimited>> {
+        let now = Instant::now();
+        let scopes = self.scopes(token_id, model);
+        let mut windows = self.windows.lock();
+
+        for (scope, limits) in &scopes {
+            if limits.is_unlimited() {
+                continue;
+            }
+            let window = windows.entry(scope.clone()).or_default();
+            window.prune(now);
+            let request_wait = limits
+                .rpm
+                .map_or(Duration::ZERO, |rpm| window.request_wait(rpm, now));
+            let token_wait = limits
+                .tpm
+                .map_or(Duration::ZERO, |tpm| window.token_wait(tokens, tpm, now));
+            if request_wait.is_zero() && token_wait.is_zero() {
+                continue;
+            }
+            let what = match scope {
+                Scope::Token(_) => "this API key".to_string(),
+                Scope::Model(model) => format!("model {}", model),
+            };
+            let reason = if request_wait >= token_wait {
+                format!(
+                    "Rate limit reached for {} on requests per minute: limit {}",
+                    what,
+                    limits.rpm.unwrap_or_default()
+                )
+            } else {
+                format!(
+                    "Rate limit reached for {} on tokens per minute: limit {}, used {}, requested \
+                     {}",
+                    what,
+                    limits.tpm.unwrap_or_default(),
+                    window.used,
+                    tokens
+                )
+            };
+            return Err(Box::new(RateLimited {
+                reason,
+                retry_after: request_wait.max(token_wait),
+                status: window.status(*limits, now),
+            }));
+        }
+
+        let mut status = None;
+        for (scope, limits) in &scopes {
+            if limits.is_unlimited() {
+                continue;
+            }
+            let window = windows.entry(scope.clone()).or_default();
+            window.requests.push_back(now);
+            window.add_tokens(now, tokens);
+            status.get_or_insert_with(|| window.status(*limits, now));
+        }
+        if windows.len() > 1024 {
+            windows.retain(|_, window| {
+                window.prune(now);
+                !window.is_empty()
+            });
+        }
+        Ok(status.unwrap_or_default())
+    }
+
+    /// Add `tokens` (possibly negative) to what an admitted request of
+    /// `token_id` for `model` counts, once its real size is known. A window
+    /// never counts fewer than zero tokens.
+    pub fn correct(&self, token_id: u64, model: &str, tokens: i64) {
+        if tokens == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut windows = self.windows.lock();
+        for (scope, limits) in self.scopes(token_id, model) {
+            if limits.tpm.is_none() {
+                continue;
+            }
+            let window = windows.entry(scope).or_default();
+            window.prune(now);
+            if tokens > 0 {
+                window.add_tokens(now, tokens);
+            } else {
+                window.remove_tokens(-tokens);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_token: Limits, per_model: Limits) -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            per_token,
+            per_model,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_requests_per_minute_per_token() {
+        let limiter = limiter(
+            Limits {
+                rpm: Some(2),
+                tpm: None,
+            },
+            Limits::default(),
+        );
+        let status = limiter.admit(1, "m", 10).unwrap();
+        assert_eq!(status.remaining_requests, Some(1));
+        limiter.admit(1, "m", 10).unwrap();
+        let limited = limiter.admit(1, "other", 10).unwrap_err();
+        assert!(
+            limited.reason.contains("requests per minute"),
+            "{}",
+            limited.reason
+        );
+        assert!(limited.retry_after > Duration::from_secs(59));
+        assert_eq!(limited.status.remaining_requests, Some(0));
+
+        // Another token has a window of its own.
+        assert!(limiter.admit(2, "m", 10).is_ok());
+
+        let mut headers = HeaderMap::new();
+        limited.apply(&mut headers);
+        assert_eq!(headers["retry-after"], "60");
+        assert_eq!(headers["x-ratelimit-limit-requests"], "2");
+        assert_eq!(headers["x-ratelimit-remaining-requests"], "0");
+        assert!(headers.get("x-ratelimit-limit-tokens").is_none());
+    }
+
+    #[test]
+    fn test_tokens_per_minute_per_model_with_corrections() {
+        let mut limits = RateLimits {
+            per_model: Limits {
+                rpm: None,
+                tpm: Some(100),
+            },
+            ..Default::default()
+        };
+        limits.models.insert("big".into(), Limits::default());
+        let limiter = RateLimiter::new(limits);
+
+        limiter.admit(1, "m", 60).unwrap();
+        let limited = limiter.admit(2, "m", 60).unwrap_err();
+        assert!(limited.reason.contains("model m"), "{}", limited.reason);
+        assert_eq!(limited.status.remaining_tokens, Some(40));
+
+        // The first request turned out smaller than estimated.
+        limiter.correct(1, "m", -30);
+        assert_eq!(
+            limiter.admit(2, "m", 60).unwrap().remaining_tokens,
+            Some(10)
+        );
+
+        // A model with limits of its own; an oversized first request.
+        assert!(limiter.admit(1, "big", 1000).is_ok());
+        assert!(limiter.admit(1, "fresh", 1000).is_ok());
+        assert!(limiter.admit(1, "fresh", 1).is_err());
+
+        // Taking back more than is counted frees no extra budget.
+        limiter.admit(1, "n", 10).unwrap();
+        limiter.correct(1, "n", -50);
+        assert_eq!(
+            limiter.admit(2, "n", 100).unwrap().remaining_tokens,
+            Some(0)
+        );
+        assert!(limiter.admit(3, "n", 1).is_err());
+    }
+
+    #[test]
+    fn test_request_tokens_count_json_text_only() {
+        let chat = br#"{"model":"gpt-4o","messages":[{"role":"user","content":"hello there"}]}"#;
+        assert_eq!(estimate_request_tokens(chat), estimate_tokens(6 + 4 + 11));
+        let upload = b"--b\r\nContent-Disposition: form-data; name=\"file\"\r\n\r\nRIFF....";
+        assert_eq!(estimate_request_tokens(upload), 0);
+    }
+
+    #[test]
+    fn test_format_reset() {
+        assert_eq!(format_reset(Duration::from_millis(20)), "20ms");
+        assert_eq!(format_reset(Duration::from_millis(5200)), "6s");
+        assert_eq!(format_reset(Duration::from_secs(90)), "1m30s");
+    }
+}
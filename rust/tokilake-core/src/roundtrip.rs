@@ -2,7 +2,7 @@
 //!
 //! This module handles forwarding API requests through the tunnel to workers
 //! and streaming responses back to the client. It supports:
-//! - Request routing by channel ID or namespace
+//! - Request routing by channel ID, namespace or model
 //! - Streaming response bodies
 //! - Request cancellation
 //! - Error propagation
@@ -11,11 +11,12 @@ use crate::{
     error::{ErrorMessage, TunnelError},
     protocol::{TunnelRequest, TunnelResponse},
     service::Service,
-    session::{InFlightRequest, SessionManager},
+    session::{GatewaySession, InFlightRequest, LoadBalance, LoadBalancer, SessionManager},
     tunnel::{TunnelSession, TunnelStream},
 };
 use std::{collections::HashMap, sync::Arc, time::Instant};
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{RwLock, mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 /// Error returned when a tunnel stream request fails.
@@ -94,15 +95,36 @@ pub struct TunnelRoundtripResponse {
 ///
 /// Manages the lifecycle of forwarded requests including stream setup,
 /// response pumping, and cancellation.
-#[derive(Clone)]
+/// When several replicas serve a namespace or model, requests are spread
+/// across them with round-robin unless another [`LoadBalance`] policy is set.
 pub struct Roundtrip<T: TunnelSession> {
     session_manager: Arc<SessionManager<T>>,
+    balancer:        Arc<LoadBalancer>,
+}
+
+// Not derived: that would require `T: Clone`.
+impl<T: TunnelSession> Clone for Roundtrip<T> {
+    fn clone(&self) -> Self {
+        Self {
+            session_manager: self.session_manager.clone(),
+            balancer:        self.balancer.clone(),
+        }
+    }
 }
 
 impl<T: TunnelSession> Roundtrip<T> {
     /// Create a new roundtrip handler.
     pub fn new(session_manager: Arc<SessionManager<T>>) -> Self {
-        Self { session_manager }
+        Self {
+            session_manager,
+            balancer: Arc::new(LoadBalancer::default()),
+        }
+    }
+
+    /// Use `policy` to choose between replicas.
+    pub fn with_load_balance(mut self, policy: LoadBalance) -> Self {
+        self.balancer = Arc::new(LoadBalancer::new(policy));
+        self
     }
 }
 
@@ -111,18 +133,31 @@ pub enum RoundtripRequest {
         channel_id: i32,
         request:    TunnelRequest,
     },
+    /// One of the live replicas that claimed `namespace`.
     ByNamespace {
         namespace: Arc<str>,
         request:   TunnelRequest,
     },
+    /// Any live session advertising `model`.
+    ByModel {
+        model:   Arc<str>,
+        request: TunnelRequest,
+    },
+    /// One of the live replicas of `namespace` advertising `model`.
+    ByNamespaceModel {
+        namespace: Arc<str>,
+        model:     Arc<str>,
+        request:   TunnelRequest,
+    },
 }
 
-impl<T: TunnelSession> Service<RoundtripRequest> for Roundtrip<T> {
-    type Response = TunnelRoundtripResponse;
-    type Error = TunnelError;
-
-    async fn call(&self, req: RoundtripRequest) -> Result<Self::Response, Self::Error> {
-        let (session, mut request) = match req {
+impl<T: TunnelSession> Roundtrip<T> {
+    /// Pick the session a request should be forwarded to.
+    async fn resolve(
+        &self,
+        req: RoundtripRequest,
+    ) -> Result<(Arc<RwLock<GatewaySession<T>>>, TunnelRequest), TunnelError> {
+        match req {
             RoundtripRequest::ByChannel {
                 channel_id,
                 request,
@@ -142,33 +177,82 @@ impl<T: TunnelSession> Service<RoundtripRequest> for Roundtrip<T> {
                         channel_id
                     )));
                 }
-                (session, request)
+                Ok((session, request))
             }
             RoundtripRequest::ByNamespace { namespace, request } => {
+                let replicas = self.session_manager.get_all_by_namespace(&namespace);
                 let session = self
-                    .session_manager
-                    .get_by_namespace(&namespace)
+                    .balancer
+                    .pick(&self.session_manager, replicas)
+                    .await
                     .ok_or_else(|| {
                         TunnelError::protocol(format!(
                             "tokiame session is offline for namespace {}",
                             namespace
                         ))
                     })?;
-                if !session.read().await.is_alive() {
-                    return Err(TunnelError::protocol(format!(
-                        "tokiame session is offline for namespace {}",
-                        namespace
-                    )));
-                }
-                (session, request)
+                Ok((session, request))
             }
-        };
+            RoundtripRequest::ByModel { model, request } => {
+                let candidates = self.session_manager.get_by_model(&model);
+                let session = self
+                    .balancer
+                    .pick(&self.session_manager, candidates)
+                    .await
+                    .ok_or_else(|| {
+                        TunnelError::protocol(format!("no tokiame session serves model {}", model))
+                    })?;
+                Ok((session, request))
+            }
+            RoundtripRequest::ByNamespaceModel {
+                namespace,
+                model,
+                request,
+            } => {
+                let candidates = self
+                    .session_manager
+                    .get_by_namespace_and_model(&namespace, &model);
+                let session = self
+                    .balancer
+                    .pick(&self.session_manager, candidates)
+                    .await
+                    .ok_or_else(|| {
+                        TunnelError::protocol(format!(
+                            "no tokiame session of namespace {} serves model {}",
+                            namespace, model
+                        ))
+                    })?;
+                Ok((session, request))
+            }
+        }
+    }
+}
 
-        let session_guard = session.read().await;
-        let (namespace, channel_id) = if let Some(ref info) = session_guard.worker_info {
-            (info.namespace.clone(), info.channel_id)
-        } else {
-            return Err(TunnelError::protocol("session is not fully registered"));
+impl<T: TunnelSession> Service<RoundtripRequest> for Roundtrip<T> {
+    type Response = TunnelRoundtripResponse;
+    type Error = TunnelError;
+
+    async fn call(&self, req: RoundtripRequest) -> Result<Self::Response, Self::Error> {
+        let (session, mut request) = self.resolve(req).await?;
+
+        // Copy out what the request needs and release the guard: a heartbeat
+        // updating the session's models must not wait for this request's
+        // first frame.
+        let (session_id, namespace, channel_id, tunnel_session) = {
+            let session_guard = session.read().await;
+            let Some(ref info) = session_guard.worker_info else {
+                return Err(TunnelError::protocol("session is not fully registered"));
+            };
+            let tunnel_session = session_guard
+                .tunnel_session
+                .clone()
+                .ok_or(TunnelError::StreamClosed)?;
+            (
+                session_guard.id,
+                info.namespace.clone(),
+                info.channel_id,
+                tunnel_session,
+            )
         };
 
         // Ensure request has an ID
@@ -177,10 +261,6 @@ impl<T: TunnelSession> Service<RoundtripRequest> for Roundtrip<T> {
         }
 
         // Open a stream for this request.
-        let tunnel_session = session_guard
-            .tunnel_session
-            .clone()
-            .ok_or(TunnelError::StreamClosed)?;
         let mut stream = {
             let mut session_lock = tunnel_session.lock().await;
             session_lock.open_stream().await?
@@ -189,56 +269,73 @@ impl<T: TunnelSession> Service<RoundtripRequest> for Roundtrip<T> {
         // Track the in-flight request
         let (cancel_tx, cancel_rx) = oneshot::channel();
         let request_id: Arc<str> = request.request_id.as_str().into();
+        let cancelled = CancellationToken::new();
 
         self.session_manager.track_request(InFlightRequest {
             request_id: request_id.clone(),
-            session_id: session_guard.id,
+            session_id,
             namespace: namespace.as_str().into(),
             channel_id,
             created_at: Instant::now(),
+            cancel: cancelled.clone(),
         });
 
-        // Write the request
-        let request_json = serde_json::to_vec(&request)?;
-        stream.write(&request_json).await?;
-        stream.flush().await?;
-
-        // Read the first frame for headers
-        let mut buf = vec![0u8; 8192];
+        // Write the request and wait for the first frame. The request stops
+        // counting as in flight if either fails.
         let mut response_buffer = Vec::new();
-        let first_response = loop {
-            let n = stream.read(&mut buf).await?;
-            if n == 0 {
-                return Err(TunnelError::protocol(
-                    "stream closed before receiving response",
-                ));
-            }
+        let first_response = match async {
+            // Write the request as one NDJSON line
+            let mut request_json = serde_json::to_vec(&request)?;
+            request_json.push(b'\n');
+            stream.write(&request_json).await?;
+            stream.flush().await?;
+
+            // Read the first frame for headers
+            let mut buf = vec![0u8; 8192];
+            let first_response = loop {
+                let n = stream.read(&mut buf).await?;
+                if n == 0 {
+                    return Err(TunnelError::protocol(
+                        "stream closed before receiving response",
+                    ));
+                }
 
-            response_buffer.extend_from_slice(&buf[..n]);
+                response_buffer.extend_from_slice(&buf[..n]);
 
-            // Limit max frame size to prevent OOM (16 MB)
-            if response_buffer.len() > 16 * 1024 * 1024 {
-                return Err(TunnelError::protocol(
-                    "response frame too large (potential backpressure/OOM protection)",
-                ));
-            }
+                // Limit max frame size to prevent OOM (16 MB)
+                if response_buffer.len() > 16 * 1024 * 1024 {
+                    return Err(TunnelError::protocol(
+                        "response frame too large (potential backpressure/OOM protection)",
+                    ));
+                }
 
-            if let Some(newline_pos) = response_buffer.iter().position(|&b| b == b'\n') {
-                let line = response_buffer[..newline_pos].to_vec();
-                response_buffer.drain(..=newline_pos);
+                if let Some(newline_pos) = response_buffer.iter().position(|&b| b == b'\n') {
+                    let line = response_buffer[..newline_pos].to_vec();
+                    response_buffer.drain(..=newline_pos);
 
-                if line.is_empty() {
-                    continue;
-                }
+                    if line.is_empty() {
+                        continue;
+                    }
 
-                match serde_json::from_slice::<TunnelResponse>(&line) {
-                    Ok(resp) => break resp,
-                    Err(e) => return Err(TunnelError::Serialization(e)),
+                    match serde_json::from_slice::<TunnelResponse>(&line) {
+                        Ok(resp) => break resp,
+                        Err(e) => return Err(TunnelError::Serialization(e)),
+                    }
                 }
+            };
+            Ok(first_response)
+        }
+        .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                self.session_manager.remove_request(&request_id);
+                return Err(e);
             }
         };
 
         if let Some(err) = &first_response.error {
+            self.session_manager.remove_request(&request_id);
             return Err(TunnelError::protocol(err.message.clone()));
         }
 
@@ -263,16 +360,20 @@ impl<T: TunnelSession> Service<RoundtripRequest> for Roundtrip<T> {
                     stream,
                     body_tx,
                     cancel_rx,
+                    cancelled,
                     request_id.clone(),
                     response_buffer,
                 )
                 .await;
 
-                session_manager.remove_request(&request_id);
-
-                if let Err(e) = result {
-                    tracing::error!("tunnel response pump error: {}", e);
+                match result {
+                    Ok(Some(reason)) => {
+                        session_manager.cancel_request(&request_id, &reason).await;
+                    }
+                    Ok(None) => {}
+                    Err(e) => tracing::error!("tunnel response pump error: {}", e),
                 }
+                session_manager.remove_request(&request_id);
             });
         } else {
             self.session_manager.remove_request(&request_id);
@@ -288,13 +389,12 @@ impl<T: TunnelSession> Service<RoundtripRequest> for Roundtrip<T> {
 }
 
 impl<T: TunnelSession> Roundtrip<T> {
-    /// Cancel an in-flight request.
-    pub fn cancel_request(&self, request_id: &str, _reason: &str) -> Result<(), TunnelError> {
-        if let Some(_entry) = self.session_manager.get_request(request_id) {
-            // In a real implementation, send cancel through the control stream
-            self.session_manager.remove_request(request_id);
-        }
-        Ok(())
+    /// Cancel an in-flight request: close its data stream and ask the worker
+    /// to stop over the control stream. Returns whether the worker was asked.
+    pub async fn cancel_request(&self, request_id: &str, reason: &str) -> bool {
+        self.session_manager
+            .cancel_request(request_id, reason)
+            .await
     }
 }
 
@@ -310,21 +410,26 @@ fn build_request_id(namespace: &str) -> String {
 }
 
 /// Pump response frames from the tunnel stream to the response channel.
+///
+/// Returns the reason to cancel the request on the worker if the consumer
+/// went away before the response finished.
 async fn pump_response<S: TunnelStream>(
     mut stream: S,
     body_tx: mpsc::Sender<Result<Vec<u8>, TunnelStreamError>>,
     cancel_rx: oneshot::Receiver<String>,
+    cancelled: CancellationToken,
     request_id: Arc<str>,
     mut response_buffer: Vec<u8>,
-) -> Result<(), TunnelError> {
+) -> Result<Option<String>, TunnelError> {
     let mut buf = vec![0u8; 8192];
 
-    tokio::select! {
-        _ = async {
+    let reason = tokio::select! {
+        // Resolves to whether the response was delivered in full.
+        finished = async {
             loop {
                 let n = stream.read(&mut buf).await?;
                 if n == 0 {
-                    break;
+                    return Ok(true);
                 }
 
                 response_buffer.extend_from_slice(&buf[..n]);
@@ -343,40 +448,43 @@ async fn pump_response<S: TunnelStream>(
                         continue;
                     }
 
-                    match serde_json::from_slice::<TunnelResponse>(&line) {
-                        Ok(response) => {
-                            if let Some(err) = &response.error {
-                                let _ = body_tx.send(Err(TunnelStreamError::from_error_message(err))).await;
-                                return Ok(());
-                            }
-
-                            if !response.body_chunk.is_empty()
-                                && body_tx.send(Ok(response.body_chunk.0)).await.is_err()
-                            {
-                                return Ok(());
-                            }
-
-                            if response.eof {
-                                return Ok(());
-                            }
-                        }
-                        Err(e) => {
-                            return Err(TunnelError::Serialization(e));
-                        }
+                    let response = serde_json::from_slice::<TunnelResponse>(&line)
+                        .map_err(TunnelError::Serialization)?;
+                    if let Some(err) = &response.error {
+                        let _ = body_tx.send(Err(TunnelStreamError::from_error_message(err))).await;
+                        return Ok(true);
+                    }
+
+                    if !response.body_chunk.is_empty()
+                        && body_tx.send(Ok(response.body_chunk.0)).await.is_err()
+                    {
+                        return Ok(false);
+                    }
+
+                    if response.eof {
+                        return Ok(true);
                     }
                 }
             }
-            Ok::<(), TunnelError>(())
-        } => {}
+        } => match finished {
+            Ok(true) => return Ok(None),
+            Ok(false) => Some("client_disconnected".to_string()),
+            Err(e) => return Err(e),
+        },
 
-        reason = cancel_rx => {
-            let reason = reason.unwrap_or_else(|_| "client_disconnected".to_string());
-            tracing::info!("request {} cancelled: {}", request_id, reason);
-            let _ = stream.close().await;
-        }
-    }
+        reason = cancel_rx => Some(reason.unwrap_or_else(|_| "client_disconnected".to_string())),
+
+        // Cancelled through the session manager, which already told the worker.
+        _ = cancelled.cancelled() => None,
+    };
 
-    Ok(())
+    tracing::info!(
+        "request {} cancelled: {}",
+        request_id,
+        reason.as_deref().unwrap_or("cancel_request")
+    );
+    let _ = stream.close().await;
+    Ok(reason)
 }
 
 #[cfg(test)]
@@ -1,13 +1,19 @@
-use crate::{error::TunnelError, protocol::Token, tunnel::TunnelSession};
+use crate::{
+    error::TunnelError,
+    protocol::{ControlMessage, Token},
+    tunnel::TunnelSession,
+};
 use dashmap::DashMap;
 use std::{
+    str::FromStr,
     sync::{
         Arc,
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
     },
-    time::Instant,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Debug, Clone)]
 pub struct WorkerInfo {
@@ -30,8 +36,8 @@ pub struct GatewaySession<T: TunnelSession> {
     pub worker_info:    Option<WorkerInfo>,
     pub transport:      String,
     pub authenticated:  bool,
-    // Control stream write half (for sending messages to worker)
-    pub control_tx:     Option<tokio::sync::mpsc::Sender<Vec<u8>>>,
+    // Outbound control messages, written by the session's control loop
+    pub control_tx:     Option<tokio::sync::mpsc::Sender<ControlMessage>>,
     // Tunnel session for opening data streams
     pub tunnel_session: Option<Arc<tokio::sync::Mutex<T>>>,
 }
@@ -77,12 +83,22 @@ impl<T: TunnelSession> GatewaySession<T> {
     }
 }
 
+/// A session together with its id, so it can be found without locking.
+type SessionEntry<T> = (u64, Arc<RwLock<GatewaySession<T>>>);
+
 /// Thread-safe session manager.
 pub struct SessionManager<T: TunnelSession> {
-    next_id:       AtomicU64,
-    by_namespace:  DashMap<Arc<str>, Arc<RwLock<GatewaySession<T>>>>,
-    by_channel_id: DashMap<i32, Arc<RwLock<GatewaySession<T>>>>,
-    requests:      DashMap<Arc<str>, InFlightRequest>,
+    next_id:           AtomicU64,
+    // Namespace -> replica sessions, in claim order
+    by_namespace:      DashMap<Arc<str>, Vec<SessionEntry<T>>>,
+    by_channel_id:     DashMap<i32, Arc<RwLock<GatewaySession<T>>>>,
+    // Model name -> sessions serving it, in bind order
+    by_model:          DashMap<Arc<str>, Vec<SessionEntry<T>>>,
+    // Session id -> models it is indexed under (reverse of `by_model`)
+    models_by_session: DashMap<u64, Vec<Arc<str>>>,
+    requests:          DashMap<Arc<str>, InFlightRequest>,
+    // Session id -> number of tracked requests
+    in_flight:         DashMap<u64, usize>,
 }
 
 #[derive(Debug, Clone)]
@@ -92,6 +108,9 @@ pub struct InFlightRequest {
     pub namespace:  Arc<str>,
     pub channel_id: i32,
     pub created_at: Instant,
+    /// Fired by [`SessionManager::cancel_request`] so whoever owns the data
+    /// stream closes it.
+    pub cancel:     CancellationToken,
 }
 
 /// Parameters for binding a channel to a session.
@@ -108,10 +127,13 @@ pub struct ChannelBindParams {
 impl<T: TunnelSession> SessionManager<T> {
     pub fn new() -> Self {
         Self {
-            next_id:       AtomicU64::new(1),
-            by_namespace:  DashMap::new(),
-            by_channel_id: DashMap::new(),
-            requests:      DashMap::new(),
+            next_id:           AtomicU64::new(1),
+            by_namespace:      DashMap::new(),
+            by_channel_id:     DashMap::new(),
+            by_model:          DashMap::new(),
+            models_by_session: DashMap::new(),
+            requests:          DashMap::new(),
+            in_flight:         DashMap::new(),
         }
     }
 
@@ -132,21 +154,17 @@ impl<T: TunnelSession> SessionManager<T> {
         )))
     }
 
+    /// Add `session` to the replicas serving `namespace`. Claiming twice is a
+    /// no-op.
     pub async fn claim_namespace(
         &self,
         session: &Arc<RwLock<GatewaySession<T>>>,
         namespace: &str,
     ) -> Result<(), TunnelError> {
-        use dashmap::mapref::entry::Entry;
-        match self.by_namespace.entry(namespace.into()) {
-            Entry::Occupied(e) => {
-                if !Arc::ptr_eq(e.get(), session) {
-                    return Err(TunnelError::protocol("namespace already connected"));
-                }
-            }
-            Entry::Vacant(e) => {
-                e.insert(session.clone());
-            }
+        let session_id = session.read().await.id;
+        let mut replicas = self.by_namespace.entry(namespace.into()).or_default();
+        if !replicas.iter().any(|(id, _)| *id == session_id) {
+            replicas.push((session_id, session.clone()));
         }
         Ok(())
     }
@@ -157,12 +175,14 @@ impl<T: TunnelSession> SessionManager<T> {
         params: ChannelBindParams,
     ) {
         let mut s = session.write().await;
-        if let Some(ref mut info) = s.worker_info {
-            if info.channel_id != 0 && info.channel_id != params.channel_id {
-                self.by_channel_id.remove(&info.channel_id);
-            }
+        if let Some(ref info) = s.worker_info
+            && info.channel_id != 0
+            && info.channel_id != params.channel_id
+        {
+            self.by_channel_id.remove(&info.channel_id);
         }
 
+        let params_models = params.models.clone();
         let new_info = WorkerInfo {
             worker_id:    params.worker_id,
             channel_id:   params.channel_id,
@@ -176,29 +196,141 @@ impl<T: TunnelSession> SessionManager<T> {
         s.worker_info = Some(new_info);
         self.by_channel_id
             .insert(params.channel_id, session.clone());
+        self.index_models(s.id, session, &params_models);
+    }
+
+    /// Replace the models a registered session serves, e.g. after a heartbeat
+    /// or `models_sync`.
+    pub async fn update_models(&self, session: &Arc<RwLock<GatewaySession<T>>>, models: &[String]) {
+        let mut s = session.write().await;
+        let Some(info) = s.worker_info.as_mut() else {
+            return;
+        };
+        info.models = models.to_vec();
+        let id = s.id;
+        self.index_models(id, session, models);
+    }
+
+    fn index_models(
+        &self,
+        session_id: u64,
+        session: &Arc<RwLock<GatewaySession<T>>>,
+        models: &[String],
+    ) {
+        self.unindex_models(session_id);
+        let mut indexed: Vec<Arc<str>> = Vec::with_capacity(models.len());
+        for model in models {
+            if model.is_empty() || indexed.iter().any(|m| **m == **model) {
+                continue;
+            }
+            let model: Arc<str> = model.as_str().into();
+            self.by_model
+                .entry(model.clone())
+                .or_default()
+                .push((session_id, session.clone()));
+            indexed.push(model);
+        }
+        self.models_by_session.insert(session_id, indexed);
+    }
+
+    fn unindex_models(&self, session_id: u64) {
+        let Some((_, models)) = self.models_by_session.remove(&session_id) else {
+            return;
+        };
+        for model in models {
+            self.by_model.remove_if_mut(&model, |_, sessions| {
+                sessions.retain(|(id, _)| *id != session_id);
+                sessions.is_empty()
+            });
+        }
     }
 
     pub async fn release(&self, session: &GatewaySession<T>) {
+        self.unindex_models(session.id);
         if let Some(ref info) = session.worker_info {
-            if !info.namespace.is_empty() {
-                if let Some(entry) = self.by_namespace.get(info.namespace.as_str()) {
-                    if entry.read().await.id == session.id {
-                        self.by_namespace.remove(info.namespace.as_str());
-                    }
-                }
-            }
-            if info.channel_id != 0 {
-                if let Some(entry) = self.by_channel_id.get(&info.channel_id) {
-                    if entry.read().await.id == session.id {
-                        self.by_channel_id.remove(&info.channel_id);
-                    }
-                }
+            // Other replicas keep the namespace online.
+            self.by_namespace
+                .remove_if_mut(info.namespace.as_str(), |_, replicas| {
+                    replicas.retain(|(id, _)| *id != session.id);
+                    replicas.is_empty()
+                });
+            if info.channel_id != 0
+                && let Some(entry) = self.get_by_channel_id(info.channel_id)
+                && entry.read().await.id == session.id
+            {
+                self.by_channel_id.remove(&info.channel_id);
             }
         }
     }
 
+    /// The first replica serving `namespace`.
     pub fn get_by_namespace(&self, namespace: &str) -> Option<Arc<RwLock<GatewaySession<T>>>> {
-        self.by_namespace.get(namespace).map(|r| r.value().clone())
+        self.by_namespace
+            .get(namespace)
+            .and_then(|r| r.value().first().map(|(_, session)| session.clone()))
+    }
+
+    /// All replicas serving `namespace`, in the order they claimed it.
+    pub fn get_all_by_namespace(&self, namespace: &str) -> Vec<Arc<RwLock<GatewaySession<T>>>> {
+        self.by_namespace.get(namespace).map_or_else(Vec::new, |r| {
+            r.value()
+                .iter()
+                .map(|(_, session)| session.clone())
+                .collect()
+        })
+    }
+
+    /// Sessions advertising `model`, in the order they were bound.
+    pub fn get_by_model(&self, model: &str) -> Vec<Arc<RwLock<GatewaySession<T>>>> {
+        self.by_model.get(model).map_or_else(Vec::new, |r| {
+            r.value()
+                .iter()
+                .map(|(_, session)| session.clone())
+                .collect()
+        })
+    }
+
+    /// Replicas of `namespace` advertising `model`, in the order they claimed
+    /// the namespace.
+    pub fn get_by_namespace_and_model(
+        &self,
+        namespace: &str,
+        model: &str,
+    ) -> Vec<Arc<RwLock<GatewaySession<T>>>> {
+        let Some(serving) = self.by_model.get(model) else {
+            return Vec::new();
+        };
+        self.by_namespace.get(namespace).map_or_else(Vec::new, |r| {
+            r.value()
+                .iter()
+                .filter(|(id, _)| serving.iter().any(|(serving_id, _)| serving_id == id))
+                .map(|(_, session)| session.clone())
+                .collect()
+        })
+    }
+
+    /// What every live session advertises, in no particular order.
+    pub async fn workers(&self) -> Vec<WorkerInfo> {
+        let sessions: Vec<_> = self
+            .by_namespace
+            .iter()
+            .flat_map(|r| {
+                r.value()
+                    .iter()
+                    .map(|(_, session)| session.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let mut workers = Vec::with_capacity(sessions.len());
+        for session in sessions {
+            let session = session.read().await;
+            if session.is_alive()
+                && let Some(info) = &session.worker_info
+            {
+                workers.push(info.clone());
+            }
+        }
+        workers
     }
 
     pub fn get_by_channel_id(&self, channel_id: i32) -> Option<Arc<RwLock<GatewaySession<T>>>> {
@@ -208,11 +340,65 @@ impl<T: TunnelSession> SessionManager<T> {
     }
 
     pub fn track_request(&self, request: InFlightRequest) {
-        self.requests.insert(request.request_id.clone(), request);
+        *self.in_flight.entry(request.session_id).or_default() += 1;
+        if let Some(previous) = self.requests.insert(request.request_id.clone(), request) {
+            self.finish_request(previous.session_id);
+        }
     }
 
     pub fn remove_request(&self, request_id: &str) {
-        self.requests.remove(request_id);
+        if let Some((_, request)) = self.requests.remove(request_id) {
+            self.finish_request(request.session_id);
+        }
+    }
+
+    fn finish_request(&self, session_id: u64) {
+        self.in_flight.remove_if_mut(&session_id, |_, count| {
+            *count = count.saturating_sub(1);
+            *count == 0
+        });
+    }
+
+    /// Stop tracking `request_id`, signal its data stream owner and ask the
+    /// worker running it to abort over the control stream. The worker answers
+    /// with a `cancel_ok` or `cancel_noop` ack.
+    ///
+    /// Returns whether a cancel message was queued for the worker.
+    pub async fn cancel_request(&self, request_id: &str, reason: &str) -> bool {
+        let Some((_, request)) = self.requests.remove(request_id) else {
+            return false;
+        };
+        self.finish_request(request.session_id);
+        request.cancel.cancel();
+
+        let session = self.by_namespace.get(&*request.namespace).and_then(|r| {
+            r.value()
+                .iter()
+                .find(|(id, _)| *id == request.session_id)
+                .map(|(_, session)| session.clone())
+        });
+        let control_tx = match session {
+            Some(session) => session.read().await.control_tx.clone(),
+            None => None,
+        };
+        let Some(control_tx) = control_tx else {
+            return false;
+        };
+
+        tracing::info!("cancelling request {}: {}", request_id, reason);
+        control_tx
+            .send(ControlMessage::cancel_request(
+                build_cancel_request_id(&request.namespace),
+                request_id,
+                reason,
+            ))
+            .await
+            .is_ok()
+    }
+
+    /// Number of tracked requests currently running on a session.
+    pub fn in_flight(&self, session_id: u64) -> usize {
+        self.in_flight.get(&session_id).map_or(0, |r| *r.value())
     }
 
     pub fn get_request(&self, request_id: &str) -> Option<InFlightRequest> {
@@ -220,16 +406,105 @@ impl<T: TunnelSession> SessionManager<T> {
     }
 
     pub fn session_count(&self) -> usize {
-        self.by_namespace.len()
+        self.by_namespace.iter().map(|r| r.value().len()).sum()
     }
 }
 
+/// Request id for a `cancel_request` control message.
+fn build_cancel_request_id(namespace: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    format!("{}:cancel:{}", namespace, nanos)
+}
+
 impl<T: TunnelSession> Default for SessionManager<T> {
     fn default() -> Self {
         Self::new()
     }
 }
 
+/// How a request picks one of several sessions serving the same target.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoadBalance {
+    /// Take live sessions in turn.
+    #[default]
+    RoundRobin,
+    /// Take the live session with the fewest tracked requests.
+    LeastInFlight,
+}
+
+impl FromStr for LoadBalance {
+    type Err = TunnelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+            "round_robin" | "rr" => Ok(Self::RoundRobin),
+            "least_in_flight" | "least" => Ok(Self::LeastInFlight),
+            other => Err(TunnelError::protocol(format!(
+                "unknown load balance policy: {other}"
+            ))),
+        }
+    }
+}
+
+/// Picks one live session out of a candidate list according to a
+/// [`LoadBalance`] policy.
+#[derive(Debug, Default)]
+pub struct LoadBalancer {
+    policy: LoadBalance,
+    cursor: AtomicUsize,
+}
+
+impl LoadBalancer {
+    pub fn new(policy: LoadBalance) -> Self {
+        Self {
+            policy,
+            cursor: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn policy(&self) -> LoadBalance {
+        self.policy
+    }
+
+    /// Pick a live session from `candidates`, or `None` if none is alive.
+    ///
+    /// Least-in-flight breaks ties by rotating the starting point, so idle
+    /// replicas still share the load.
+    pub async fn pick<T: TunnelSession>(
+        &self,
+        manager: &SessionManager<T>,
+        candidates: Vec<Arc<RwLock<GatewaySession<T>>>>,
+    ) -> Option<Arc<RwLock<GatewaySession<T>>>> {
+        let mut alive = Vec::with_capacity(candidates.len());
+        for session in candidates {
+            let id = {
+                let s = session.read().await;
+                if !s.is_alive() {
+                    continue;
+                }
+                s.id
+            };
+            alive.push((id, session));
+        }
+        if alive.is_empty() {
+            return None;
+        }
+
+        let len = alive.len();
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed) % len;
+        let index = match self.policy {
+            LoadBalance::RoundRobin => start,
+            LoadBalance::LeastInFlight => (0..len)
+                .map(|offset| (start + offset) % len)
+                .min_by_key(|&i| manager.in_flight(alive[i].0))
+                .unwrap_or(start),
+        };
+        Some(alive.swap_remove(index).1)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -331,10 +606,225 @@ mod tests {
             namespace:  "test".into(),
             channel_id: 42,
             created_at: Instant::now(),
+            cancel:     CancellationToken::new(),
         };
         manager.track_request(req);
         assert!(manager.get_request("req-123").is_some());
         manager.remove_request("req-123");
         assert!(manager.get_request("req-123").is_none());
     }
+
+    #[tokio::test]
+    async fn test_model_index() {
+        struct DummySession;
+        impl crate::tunnel::TunnelSession for DummySession {
+            type Stream = crate::tunnel::memory::MemoryStream;
+            async fn accept_stream(&mut self) -> Result<Option<Self::Stream>, TunnelError> {
+                Ok(None)
+            }
+            async fn open_stream(&mut self) -> Result<Self::Stream, TunnelError> {
+                Err(TunnelError::StreamClosed)
+            }
+            async fn close(&self) -> Result<(), TunnelError> {
+                Ok(())
+            }
+            fn is_alive(&self) -> bool {
+                true
+            }
+        }
+
+        let manager = SessionManager::<DummySession>::new();
+        let bind = |namespace: &str, channel_id: i32, models: &[&str]| ChannelBindParams {
+            worker_id: channel_id,
+            channel_id,
+            namespace: namespace.to_string(),
+            group: String::new(),
+            models: models.iter().map(|m| m.to_string()).collect(),
+            backend_type: String::new(),
+            status: 1,
+        };
+        let a = manager.new_session(None, String::new(), String::new(), String::new());
+        let b = manager.new_session(None, String::new(), String::new(), String::new());
+        manager.bind_channel(&a, bind("a", 1, &["m1", "m2"])).await;
+        manager.bind_channel(&b, bind("b", 2, &["m2"])).await;
+        assert_eq!(manager.get_by_model("m1").len(), 1);
+        assert_eq!(manager.get_by_model("m2").len(), 2);
+
+        manager
+            .update_models(&a, &["m3".to_string(), "m3".to_string()])
+            .await;
+        assert!(manager.get_by_model("m1").is_empty());
+        assert_eq!(manager.get_by_model("m2").len(), 1);
+        assert_eq!(manager.get_by_model("m3").len(), 1);
+        assert_eq!(a.read().await.worker_info.as_ref().unwrap().models, [
+            "m3", "m3"
+        ]);
+
+        manager.release(&*b.read().await).await;
+        assert!(manager.get_by_model("m2").is_empty());
+        assert_eq!(manager.get_by_model("m3").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_namespace_replicas_and_load_balancing() {
+        struct DummySession;
+        impl crate::tunnel::TunnelSession for DummySession {
+            type Stream = crate::tunnel::memory::MemoryStream;
+            async fn accept_stream(&mut self) -> Result<Option<Self::Stream>, TunnelError> {
+                Ok(None)
+            }
+            async fn open_stream(&mut self) -> Result<Self::Stream, TunnelError> {
+                Err(TunnelError::StreamClosed)
+            }
+            async fn close(&self) -> Result<(), TunnelError> {
+                Ok(())
+            }
+            fn is_alive(&self) -> bool {
+                true
+            }
+        }
+
+        let manager = SessionManager::<DummySession>::new();
+        let mut replicas = Vec::new();
+        for channel_id in 1..=2 {
+            let session = manager.new_session(None, String::new(), String::new(), String::new());
+            manager.claim_namespace(&session, "ns").await.unwrap();
+            manager.claim_namespace(&session, "ns").await.unwrap();
+            manager
+                .bind_channel(&session, ChannelBindParams {
+                    worker_id: channel_id,
+                    channel_id,
+                    namespace: "ns".to_string(),
+                    group: String::new(),
+                    models: vec!["m".to_string()],
+                    backend_type: String::new(),
+                    status: 1,
+                })
+                .await;
+            session.write().await.tunnel_session =
+                Some(Arc::new(tokio::sync::Mutex::new(DummySession)));
+            replicas.push(session);
+        }
+        assert_eq!(manager.session_count(), 2);
+        let workers = manager.workers().await;
+        assert_eq!(workers.len(), 2);
+        assert!(
+            workers
+                .iter()
+                .all(|w| w.namespace == "ns" && w.models == ["m"])
+        );
+        let (a, b) = (replicas[0].read().await.id, replicas[1].read().await.id);
+        assert_eq!(manager.get_by_namespace_and_model("ns", "m").len(), 2);
+        assert!(manager.get_by_namespace_and_model("ns", "x").is_empty());
+        assert!(manager.get_by_namespace_and_model("other", "m").is_empty());
+
+        let round_robin = LoadBalancer::new(LoadBalance::RoundRobin);
+        let mut picked = Vec::new();
+        for _ in 0..4 {
+            let candidates = manager.get_all_by_namespace("ns");
+            let session = round_robin.pick(&manager, candidates).await.unwrap();
+            picked.push(session.read().await.id);
+        }
+        assert_eq!(picked, [a, b, a, b]);
+
+        manager.track_request(InFlightRequest {
+            request_id: "busy".into(),
+            session_id: a,
+            namespace:  "ns".into(),
+            channel_id: 1,
+            created_at: Instant::now(),
+            cancel:     CancellationToken::new(),
+        });
+        assert_eq!(manager.in_flight(a), 1);
+        let least = LoadBalancer::new(LoadBalance::LeastInFlight);
+        for _ in 0..2 {
+            let session = least
+                .pick(&manager, manager.get_by_model("m"))
+                .await
+                .unwrap();
+            assert_eq!(session.read().await.id, b);
+        }
+        manager.remove_request("busy");
+        assert_eq!(manager.in_flight(a), 0);
+
+        manager
+            .update_models(&replicas[0], &["x".to_string()])
+            .await;
+        let serving = manager.get_by_namespace_and_model("ns", "m");
+        assert_eq!(serving.len(), 1);
+        assert_eq!(serving[0].read().await.id, b);
+
+        manager.release(&*replicas[0].read().await).await;
+        assert_eq!(manager.session_count(), 1);
+        let left = manager.get_by_namespace("ns").unwrap();
+        assert_eq!(left.read().await.id, b);
+        manager.release(&*replicas[1].read().await).await;
+        assert!(manager.get_by_namespace("ns").is_none());
+    }
+
+    #[test]
+    fn test_load_balance_from_str() {
+        assert_eq!(
+            "round-robin".parse::<LoadBalance>().unwrap(),
+            LoadBalance::RoundRobin
+        );
+        assert_eq!(
+            "least_in_flight".parse::<LoadBalance>().unwrap(),
+            LoadBalance::LeastInFlight
+        );
+        assert!("random".parse::<LoadBalance>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_request_goes_over_control_channel() {
+        struct DummySession;
+        impl crate::tunnel::TunnelSession for DummySession {
+            type Stream = crate::tunnel::memory::MemoryStream;
+            async fn accept_stream(&mut self) -> Result<Option<Self::Stream>, TunnelError> {
+                Ok(None)
+            }
+            async fn open_stream(&mut self) -> Result<Self::Stream, TunnelError> {
+                Err(TunnelError::StreamClosed)
+            }
+            async fn close(&self) -> Result<(), TunnelError> {
+                Ok(())
+            }
+            fn is_alive(&self) -> bool {
+                true
+            }
+        }
+
+        let manager = SessionManager::<DummySession>::new();
+        let session = manager.new_session(None, String::new(), String::new(), String::new());
+        manager.claim_namespace(&session, "ns").await.unwrap();
+        let (control_tx, mut control_rx) = tokio::sync::mpsc::channel(1);
+        session.write().await.control_tx = Some(control_tx);
+
+        let cancel = CancellationToken::new();
+        manager.track_request(InFlightRequest {
+            request_id: "ns:relay:1".into(),
+            session_id: session.read().await.id,
+            namespace:  "ns".into(),
+            channel_id: 0,
+            created_at: Instant::now(),
+            cancel:     cancel.clone(),
+        });
+        assert!(
+            manager
+                .cancel_request("ns:relay:1", "client_disconnected")
+                .await
+        );
+        assert!(cancel.is_cancelled());
+        assert!(manager.get_request("ns:relay:1").is_none());
+
+        let msg = control_rx.recv().await.unwrap();
+        assert_eq!(msg.msg_type, crate::protocol::control_type::CANCEL_REQUEST);
+        assert!(msg.request_id.unwrap().starts_with("ns:cancel:"));
+        let cancel_msg = msg.cancel_request.unwrap();
+        assert_eq!(cancel_msg.target_request_id, "ns:relay:1");
+        assert_eq!(cancel_msg.reason, "client_disconnected");
+
+        // Already gone: nothing to send.
+        assert!(!manager.cancel_request("ns:relay:1", "again").await);
+    }
 }
@@ -0,0 +1,103 @@
+//! Byte-stream adapter over a pair of mpsc channels.
+//!
+//! WebSocket carries smux frames as binary messages. `ChannelIo` turns the
+//! message channels pumped to and from the socket into `AsyncRead + AsyncWrite`
+//! so a `tokilake_smux::Session` can run on top of it.
+
+use bytes::Bytes;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc,
+};
+use tokio_util::sync::PollSender;
+
+/// Channel-backed byte stream. Dropping the sender side of `rx` is EOF.
+pub struct ChannelIo {
+    rx:     mpsc::Receiver<Bytes>,
+    tx:     PollSender<Bytes>,
+    buffer: Bytes,
+}
+
+impl ChannelIo {
+    pub fn new(rx: mpsc::Receiver<Bytes>, tx: mpsc::Sender<Bytes>) -> Self {
+        Self {
+            rx,
+            tx: PollSender::new(tx),
+            buffer: Bytes::new(),
+        }
+    }
+}
+
+impl AsyncRead for ChannelIo {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            if !self.buffer.is_empty() {
+                let n = std::cmp::min(buf.remaining(), self.buffer.len());
+                buf.put_slice(&self.buffer.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+
+            match std::task::ready!(self.rx.poll_recv(cx)) {
+                Some(data) => self.buffer = data,
+                // Channel closed - return 0 bytes to indicate EOF
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for ChannelIo {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let closed = || std::io::Error::new(std::io::ErrorKind::BrokenPipe, "channel closed");
+        if std::task::ready!(self.tx.poll_reserve(cx)).is_err() {
+            return Poll::Ready(Err(closed()));
+        }
+        self.tx
+            .send_item(Bytes::copy_from_slice(buf))
+            .map_err(|_| closed())?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.tx.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_channel_io_roundtrip() {
+        let (in_tx, in_rx) = mpsc::channel(4);
+        let (out_tx, mut out_rx) = mpsc::channel(4);
+        let mut io = ChannelIo::new(in_rx, out_tx);
+
+        io.write_all(b"ping").await.unwrap();
+        assert_eq!(out_rx.recv().await.unwrap(), Bytes::from_static(b"ping"));
+
+        in_tx.send(Bytes::from_static(b"pong")).await.unwrap();
+        drop(in_tx);
+        let mut received = Vec::new();
+        io.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"pong");
+    }
+}
@@ -1,6 +1,7 @@
 use crate::error::TunnelError;
 use std::future::Future;
 
+pub mod channel;
 pub mod quic;
 pub mod smux;
 
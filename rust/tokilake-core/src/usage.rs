@@ -0,0 +1,211 @@
+//! Token usage reported in OpenAI responses.
+//!
+//! A [`UsageTap`] is fed a copy of a response body as it passes and tells
+//! the usage it carried: a JSON body is parsed once it has ended; in an SSE
+//! stream, the last `data:` event carrying `usage` wins.
+
+use serde_json::Value;
+
+/// Larger JSON bodies are passed through without looking for usage.
+const MAX_JSON_BODY: usize = 8 << 20;
+
+/// Longer SSE lines are skipped.
+pub const MAX_SSE_LINE: usize = 1 << 20;
+
+/// Tokens used by one request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub prompt_tokens:     i64,
+    pub completion_tokens: i64,
+    pub total_tokens:      i64,
+}
+
+impl Usage {
+    /// The usage reported in an OpenAI response or stream event: `usage` at
+    /// the top, or inside `response` for Responses API events. Both the chat
+    /// (`prompt_tokens`) and the Responses (`input_tokens`) names are read.
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let usage = value.get("usage").filter(|u| u.is_object()).or_else(|| {
+            value
+                .get("response")?
+                .get("usage")
+                .filter(|u| u.is_object())
+        })?;
+        let count = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| usage.get(*name)?.as_i64())
+                .unwrap_or(0)
+        };
+        let prompt_tokens = count(&["prompt_tokens", "input_tokens"]);
+        let completion_tokens = count(&["completion_tokens", "output_tokens"]);
+        let total_tokens = match count(&["total_tokens"]) {
+            0 => prompt_tokens + completion_tokens,
+            total => total,
+        };
+        Some(Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens,
+        })
+    }
+}
+
+/// Looks for usage in a copy of the response body.
+#[derive(Debug)]
+pub enum UsageTap {
+    Json {
+        buf:      Vec<u8>,
+        overflow: bool,
+    },
+    Sse {
+        line:   Vec<u8>,
+        usage:  Option<Usage>,
+        /// `data:` events seen, bar `[DONE]`.
+        events: i64,
+    },
+}
+
+impl UsageTap {
+    /// A tap for a body of `content_type`; `None` when it cannot carry usage.
+    pub fn for_content_type(content_type: &str) -> Option<Self> {
+        let content_type = content_type.to_ascii_lowercase();
+        if content_type.starts_with("text/event-stream") {
+            Some(Self::Sse {
+                line:   Vec::new(),
+                usage:  None,
+                events: 0,
+            })
+        } else if content_type.starts_with("application/json") {
+            Some(Self::Json {
+                buf:      Vec::new(),
+                overflow: false,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        match self {
+            Self::Json { buf, overflow } => {
+                if *overflow || buf.len() + data.len() > MAX_JSON_BODY {
+                    *overflow = true;
+                    buf.clear();
+                } else {
+                    buf.extend_from_slice(data);
+                }
+            }
+            Self::Sse {
+                line,
+                usage,
+                events,
+            } => {
+                for chunk in data.split_inclusive(|b| *b == b'\n') {
+                    if line.len() + chunk.len() <= MAX_SSE_LINE {
+                        line.extend_from_slice(chunk);
+                    } else {
+                        // Too long to be a usage event; drop it to its end.
+                        line.clear();
+                    }
+                    if chunk.ends_with(b"\n") {
+                        if let Some(data) = line.strip_prefix(b"data:")
+                            && data.trim_ascii() != b"[DONE]"
+                        {
+                            *events += 1;
+                        }
+                        if let Some(found) = sse_usage(line) {
+                            *usage = Some(found);
+                        }
+                        line.clear();
+                    }
+                }
+            }
+        }
+    }
+
+    /// The usage reported so far.
+    pub fn finish(&self) -> Option<Usage> {
+        match self {
+            Self::Json {
+                buf,
+                overflow: false,
+            } => Usage::from_json(&serde_json::from_slice(buf).ok()?),
+            Self::Json { .. } => None,
+            Self::Sse { line, usage, .. } => sse_usage(line).or(*usage),
+        }
+    }
+
+    /// Completion tokens guessed from the events so far.
+    pub fn estimated_completion(&self) -> i64 {
+        match self {
+            Self::Sse { events, .. } => *events,
+            Self::Json { .. } => 0,
+        }
+    }
+
+    pub fn is_sse(&self) -> bool {
+        matches!(self, Self::Sse { .. })
+    }
+
+    /// Whether the body seen so far ends between two SSE lines.
+    pub fn between_events(&self) -> bool {
+        matches!(self, Self::Sse { line, .. } if line.is_empty())
+    }
+}
+
+/// The usage in one SSE line, if it is a `data:` event carrying some.
+pub fn sse_usage(line: &[u8]) -> Option<Usage> {
+    let data = line.strip_prefix(b"data:")?;
+    // Cheap check first: most events are content deltas.
+    if !data.windows(7).any(|w| w == b"\"usage\"") {
+        return None;
+    }
+    Usage::from_json(&serde_json::from_slice(data.trim_ascii()).ok()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_from_json_and_split_sse() {
+        let chat = serde_json::json!({
+            "usage": {"prompt_tokens": 9, "completion_tokens": 3, "total_tokens": 12}
+        });
+        assert_eq!(
+            Usage::from_json(&chat),
+            Some(Usage {
+                prompt_tokens:     9,
+                completion_tokens: 3,
+                total_tokens:      12,
+            })
+        );
+        let responses = serde_json::json!({
+            "type": "response.completed",
+            "response": {"usage": {"input_tokens": 5, "output_tokens": 2}}
+        });
+        assert_eq!(Usage::from_json(&responses).unwrap().total_tokens, 7);
+        assert_eq!(Usage::from_json(&serde_json::json!({"usage": null})), None);
+
+        let mut tap = UsageTap::for_content_type("text/event-stream; charset=utf-8").unwrap();
+        let stream = [
+            r#"data: {"choices":[{"delta":{"content":"hi"}}],"usage":null}"#,
+            r#"data: {"choices":[],"usage":{"prompt_tokens":4,"completion_tokens":1,"total_tokens":5}}"#,
+            "data: [DONE]",
+        ]
+        .map(|event| format!("{}\n\n", event))
+        .concat();
+        for chunk in stream.as_bytes().chunks(7) {
+            tap.feed(chunk);
+        }
+        assert_eq!(tap.finish().unwrap().total_tokens, 5);
+
+        let mut tap = UsageTap::for_content_type("application/json").unwrap();
+        tap.feed(br#"{"usage":{"prompt_tokens":1,"#);
+        assert_eq!(tap.finish(), None);
+        tap.feed(br#""completion_tokens":1}}"#);
+        assert_eq!(tap.finish().unwrap().total_tokens, 2);
+        assert!(UsageTap::for_content_type("audio/mpeg").is_none());
+    }
+}
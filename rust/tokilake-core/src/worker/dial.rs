@@ -0,0 +1,350 @@
+//! Gateway dialing: WebSocket + smux and QUIC.
+
+use super::{TransportMode, WorkerConfig};
+use crate::{error::TunnelError, tunnel::channel::ChannelIo};
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use rustls::{
+    DigitallySignedStruct, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, ServerName, UnixTime},
+};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_tungstenite::{
+    Connector,
+    tungstenite::{Message, client::IntoClientRequest, http::HeaderValue},
+};
+
+const ALPN: &[u8] = b"tokilake.v1";
+const QUIC_DIAL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A dialed gateway connection. The extra fields keep the transport alive
+/// for as long as the session is served.
+pub(super) enum Tunnel {
+    WebSocket {
+        session: tokilake_smux::Session,
+        _pumps:  PumpGuard,
+    },
+    Quic {
+        session:   crate::tunnel::quic::QuicSession,
+        _endpoint: quinn::Endpoint,
+    },
+}
+
+/// Aborts the WebSocket pump tasks on drop, which EOFs the smux session.
+pub(super) struct PumpGuard([JoinHandle<()>; 2]);
+
+impl Drop for PumpGuard {
+    fn drop(&mut self) {
+        for handle in &self.0 {
+            handle.abort();
+        }
+    }
+}
+
+pub(super) async fn dial(config: &WorkerConfig) -> Result<Tunnel, TunnelError> {
+    match config.transport_mode {
+        TransportMode::WebSocket => dial_websocket(config).await,
+        TransportMode::Quic => dial_quic(config).await,
+        TransportMode::Auto if !config.should_attempt_quic() => dial_websocket(config).await,
+        TransportMode::Auto => match dial_quic(config).await {
+            Ok(tunnel) => Ok(tunnel),
+            Err(e) => {
+                tracing::warn!("QUIC dial failed, falling back to WebSocket: {}", e);
+                dial_websocket(config).await
+            }
+        },
+    }
+}
+
+async fn dial_websocket(config: &WorkerConfig) -> Result<Tunnel, TunnelError> {
+    let url = normalize_websocket_url(&config.gateway_url)?;
+    tracing::info!("dialing gateway over WebSocket: {}", url);
+
+    let mut request = url
+        .as_str()
+        .into_client_request()
+        .map_err(|e| TunnelError::protocol(format!("invalid gateway url: {}", e)))?;
+    let headers = request.headers_mut();
+    headers.insert(
+        "authorization",
+        HeaderValue::from_str(&format!("Bearer {}", config.token))
+            .map_err(|_| TunnelError::auth_failed("token is not a valid header value"))?,
+    );
+    headers.insert(
+        "sec-websocket-protocol",
+        HeaderValue::from_static("tokilake.v1"),
+    );
+
+    let connector = Connector::Rustls(Arc::new(tls_client_config(
+        config.insecure_skip_verify,
+        None,
+    )?));
+    let (ws, _) =
+        tokio_tungstenite::connect_async_tls_with_config(request, None, true, Some(connector))
+            .await
+            .map_err(|e| {
+                TunnelError::Transport(std::io::Error::other(format!(
+                    "dial websocket gateway failed: {}",
+                    e
+                )))
+            })?;
+
+    let (mut sink, mut stream) = ws.split();
+    let (in_tx, in_rx) = mpsc::channel::<Bytes>(64);
+    let (out_tx, mut out_rx) = mpsc::channel::<Bytes>(64);
+
+    let writer = tokio::spawn(async move {
+        while let Some(data) = out_rx.recv().await {
+            if sink.send(Message::Binary(data)).await.is_err() {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    });
+    let reader = tokio::spawn(async move {
+        while let Some(msg) = stream.next().await {
+            match msg {
+                Ok(Message::Binary(data)) => {
+                    if in_tx.send(data).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(Message::Close(_)) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+    });
+
+    let smux_config = tokilake_smux::Config {
+        keep_alive_disabled: true,
+        ..Default::default()
+    };
+    let session = tokilake_smux::Session::client(ChannelIo::new(in_rx, out_tx), smux_config);
+    Ok(Tunnel::WebSocket {
+        session,
+        _pumps: PumpGuard([writer, reader]),
+    })
+}
+
+async fn dial_quic(config: &WorkerConfig) -> Result<Tunnel, TunnelError> {
+    let endpoint_addr = config.resolve_quic_endpoint()?;
+    let (host, _) = split_host_port(&endpoint_addr)?;
+    tracing::info!("dialing gateway over QUIC: {}", endpoint_addr);
+
+    let addr: SocketAddr = tokio::net::lookup_host(&endpoint_addr)
+        .await?
+        .next()
+        .ok_or_else(|| TunnelError::protocol(format!("cannot resolve {}", endpoint_addr)))?;
+
+    let crypto = tls_client_config(config.insecure_skip_verify, Some(ALPN))?;
+    let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(crypto)
+        .map_err(|e| TunnelError::Other(e.into()))?;
+    let mut client_config = quinn::ClientConfig::new(Arc::new(crypto));
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(config.heartbeat_interval()));
+    client_config.transport_config(Arc::new(transport));
+
+    let bind: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let endpoint = quinn::Endpoint::client(bind)?;
+    let connecting = endpoint
+        .connect_with(client_config, addr, &host)
+        .map_err(|e| TunnelError::protocol(format!("QUIC connect failed: {}", e)))?;
+    let conn = tokio::time::timeout(QUIC_DIAL_TIMEOUT, connecting)
+        .await
+        .map_err(|_| TunnelError::Timeout)?
+        .map_err(|e| TunnelError::Transport(std::io::Error::other(e)))?;
+
+    Ok(Tunnel::Quic {
+        session:   crate::tunnel::quic::QuicSession::new(conn),
+        _endpoint: endpoint,
+    })
+}
+
+/// Rewrite `http(s)://` gateway URLs to `ws(s)://`.
+pub fn normalize_websocket_url(raw: &str) -> Result<String, TunnelError> {
+    let raw = raw.trim();
+    let (scheme, rest) = raw
+        .split_once("://")
+        .ok_or_else(|| TunnelError::protocol(format!("invalid gateway url: {}", raw)))?;
+    let scheme = match scheme.to_ascii_lowercase().as_str() {
+        "ws" | "http" => "ws",
+        "wss" | "https" => "wss",
+        other => {
+            return Err(TunnelError::protocol(format!(
+                "unsupported gateway url scheme: {}",
+                other
+            )));
+        }
+    };
+    Ok(format!("{}://{}", scheme, rest))
+}
+
+pub(super) fn is_secure_url(raw: &str) -> bool {
+    raw.parse::<http::Uri>()
+        .ok()
+        .and_then(|uri| uri.scheme_str().map(str::to_ascii_lowercase))
+        .is_some_and(|scheme| scheme == "wss" || scheme == "https")
+}
+
+pub(super) fn resolve_quic_endpoint(
+    quic_endpoint: &str,
+    gateway_url: &str,
+) -> Result<String, TunnelError> {
+    let quic_endpoint = quic_endpoint.trim();
+    if !quic_endpoint.is_empty() {
+        if quic_endpoint.contains("://")
+            && let Ok(uri) = quic_endpoint.parse::<http::Uri>()
+            && let (Some(host), Some(port)) = (uri.host(), uri.port_u16())
+        {
+            return Ok(join_host_port(host, port));
+        }
+        return Ok(quic_endpoint.to_string());
+    }
+
+    if !is_secure_url(gateway_url) {
+        return Err(TunnelError::protocol(
+            "QUIC requires a secure TOKIAME_GATEWAY_URL or TOKIAME_QUIC_ENDPOINT",
+        ));
+    }
+    let uri: http::Uri = gateway_url
+        .trim()
+        .parse()
+        .map_err(|e| TunnelError::protocol(format!("parse TOKIAME_GATEWAY_URL: {}", e)))?;
+    let host = uri
+        .host()
+        .filter(|h| !h.is_empty())
+        .ok_or_else(|| TunnelError::protocol("TOKIAME_GATEWAY_URL host is required"))?;
+    Ok(join_host_port(host, uri.port_u16().unwrap_or(443)))
+}
+
+fn join_host_port(host: &str, port: u16) -> String {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn split_host_port(endpoint: &str) -> Result<(String, u16), TunnelError> {
+    let invalid = || TunnelError::protocol(format!("invalid QUIC endpoint {:?}", endpoint));
+    let (host, port) = endpoint.rsplit_once(':').ok_or_else(invalid)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host.to_string(), port.parse().map_err(|_| invalid())?))
+}
+
+fn tls_client_config(
+    insecure_skip_verify: bool,
+    alpn: Option<&[u8]>,
+) -> Result<rustls::ClientConfig, TunnelError> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| TunnelError::Other(e.into()))?;
+    let mut config = if insecure_skip_verify {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SkipServerVerification(provider)))
+            .with_no_client_auth()
+    } else {
+        let roots = rustls::RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    if let Some(alpn) = alpn {
+        config.alpn_protocols = vec![alpn.to_vec()];
+    }
+    Ok(config)
+}
+
+/// Accepts any server certificate (`insecure_skip_verify`), still checking
+/// handshake signatures.
+#[derive(Debug)]
+struct SkipServerVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_websocket_url() {
+        assert_eq!(
+            normalize_websocket_url("https://gw.example.com/connect").unwrap(),
+            "wss://gw.example.com/connect"
+        );
+        assert_eq!(
+            normalize_websocket_url("http://127.0.0.1:19982/connect").unwrap(),
+            "ws://127.0.0.1:19982/connect"
+        );
+        assert!(normalize_websocket_url("ftp://gw.example.com").is_err());
+    }
+
+    #[test]
+    fn test_resolve_quic_endpoint() {
+        assert_eq!(
+            resolve_quic_endpoint("", "wss://gw.example.com/connect").unwrap(),
+            "gw.example.com:443"
+        );
+        assert_eq!(
+            resolve_quic_endpoint("quic://10.0.0.1:8443", "").unwrap(),
+            "10.0.0.1:8443"
+        );
+        assert!(resolve_quic_endpoint("", "ws://gw.example.com/connect").is_err());
+    }
+}
@@ -0,0 +1,399 @@
+//! Data plane: forward tunnelled requests to the local HTTP backend.
+
+use super::{ModelTarget, WorkerInner};
+use crate::{
+    codec::TunnelCodec,
+    error::{ErrorMessage, TunnelError},
+    protocol::{BodyChunk, TunnelRequest, TunnelResponse},
+};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper_util::{
+    client::legacy::{Client, connect::HttpConnector},
+    rt::TokioExecutor,
+};
+use std::{collections::HashMap, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::oneshot,
+};
+
+/// Maximum body bytes carried by one response frame.
+pub const TUNNEL_CHUNK_SIZE: usize = 32 * 1024;
+
+/// Request headers passed from the gateway to the local backend.
+const ALLOWED_TUNNEL_REQUEST_HEADERS: &[&str] = &["accept", "content-type"];
+
+/// Local backends are plain HTTP (`http://host:port`).
+pub(super) type LocalClient = Client<HttpConnector, Full<Bytes>>;
+
+pub(super) fn local_client() -> LocalClient {
+    Client::builder(TokioExecutor::new()).build_http()
+}
+
+enum ForwardError {
+    /// Reported to the gateway as an error frame.
+    Upstream(ErrorMessage),
+    /// The tunnel stream itself failed; nothing more can be sent.
+    Tunnel(TunnelError),
+}
+
+impl From<TunnelError> for ForwardError {
+    fn from(e: TunnelError) -> Self {
+        Self::Tunnel(e)
+    }
+}
+
+fn upstream(code: &str, message: impl std::fmt::Display) -> ForwardError {
+    ForwardError::Upstream(ErrorMessage::new(code, message.to_string()))
+}
+
+/// Serve one data stream: read the request, forward it, stream the answer.
+pub(super) async fn handle_data_stream<S>(inner: Arc<WorkerInner>, stream: S)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let mut codec = TunnelCodec::new(reader, writer);
+    let request = match codec.read_request().await {
+        Ok(Some(request)) => request,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::warn!("failed to read tunnel request: {}", e);
+            return;
+        }
+    };
+    tracing::info!(
+        "tunnel request {}: {} {} model={} stream={}",
+        request.request_id,
+        request.method,
+        request.path,
+        request.model,
+        request.is_stream
+    );
+
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    inner.inflight.insert(request.request_id.clone(), cancel_tx);
+    let result = forward(&inner, &request, &mut codec, cancel_rx).await;
+    inner.inflight.remove(&request.request_id);
+
+    match result {
+        Ok(()) => {}
+        Err(ForwardError::Upstream(error)) => {
+            tracing::warn!("tunnel request {} failed: {}", request.request_id, error);
+            let _ = codec
+                .write_response(&TunnelResponse {
+                    request_id:  request.request_id.clone(),
+                    status_code: 0,
+                    headers:     HashMap::new(),
+                    body_chunk:  BodyChunk::default(),
+                    eof:         false,
+                    error:       Some(error),
+                })
+                .await;
+        }
+        Err(ForwardError::Tunnel(e)) => {
+            tracing::warn!("tunnel request {} aborted: {}", request.request_id, e);
+        }
+    }
+    let _ = codec.shutdown().await;
+}
+
+async fn forward<R, W>(
+    inner: &WorkerInner,
+    request: &TunnelRequest,
+    codec: &mut TunnelCodec<R, W>,
+    mut cancel_rx: oneshot::Receiver<()>,
+) -> Result<(), ForwardError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let model = request.model.trim();
+    let target = inner.config.model_targets.get(model).ok_or_else(|| {
+        upstream(
+            "target_not_found",
+            format!("no local target configured for model {}", model),
+        )
+    })?;
+
+    let uri = build_local_target_url(&target.url, &request.path)
+        .map_err(|e| upstream("invalid_target_url", e))?;
+    let mut headers = merge_request_headers(&request.headers, target);
+    if let Some((name, value)) = select_api_key(inner, model, target) {
+        headers.insert(name, value);
+    }
+    let upstream_model = if target.mapped_name.is_empty() {
+        model
+    } else {
+        target.mapped_name.as_str()
+    };
+    let body = rewrite_request_model(&request.body, &headers, model, upstream_model)
+        .map_err(|e| upstream("rewrite_request_failed", e))?;
+
+    let mut builder = http::Request::builder()
+        .method(request.method.as_str())
+        .uri(uri);
+    for (name, value) in &headers {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+    let local_request = builder
+        .body(Full::new(Bytes::from(body)))
+        .map_err(|e| upstream("local_request_failed", e))?;
+
+    let response = tokio::select! {
+        response = inner.http.request(local_request) => {
+            response.map_err(|e| upstream("local_request_failed", e))?
+        }
+        _ = &mut cancel_rx => return Err(upstream("request_cancelled", "request cancelled by gateway")),
+    };
+
+    codec
+        .write_response(&TunnelResponse {
+            request_id:  request.request_id.clone(),
+            status_code: response.status().as_u16(),
+            headers:     flatten_headers(response.headers()),
+            body_chunk:  BodyChunk::default(),
+            eof:         false,
+            error:       None,
+        })
+        .await?;
+
+    let mut body = response.into_body();
+    loop {
+        let frame = tokio::select! {
+            frame = body.frame() => frame,
+            _ = &mut cancel_rx => return Err(upstream("request_cancelled", "request cancelled by gateway")),
+        };
+        match frame {
+            Some(Ok(frame)) => {
+                let Ok(data) = frame.into_data() else {
+                    continue;
+                };
+                for chunk in data.chunks(TUNNEL_CHUNK_SIZE) {
+                    codec
+                        .write_response(&TunnelResponse {
+                            request_id:  request.request_id.clone(),
+                            status_code: 0,
+                            headers:     HashMap::new(),
+                            body_chunk:  BodyChunk(chunk.to_vec()),
+                            eof:         false,
+                            error:       None,
+                        })
+                        .await?;
+                }
+            }
+            Some(Err(e)) => return Err(upstream("read_local_response_failed", e)),
+            None => break,
+        }
+    }
+
+    codec
+        .write_response(&TunnelResponse {
+            request_id:  request.request_id.clone(),
+            status_code: 0,
+            headers:     HashMap::new(),
+            body_chunk:  BodyChunk::default(),
+            eof:         true,
+            error:       None,
+        })
+        .await?;
+    Ok(())
+}
+
+fn build_local_target_url(base: &str, request_path: &str) -> Result<http::Uri, String> {
+    let base: http::Uri = base
+        .trim()
+        .parse()
+        .map_err(|e| format!("parse base target {:?}: {}", base, e))?;
+    let (Some(scheme), Some(authority)) = (base.scheme_str(), base.authority()) else {
+        return Err(format!(
+            "base target must include scheme and host: {}",
+            base
+        ));
+    };
+
+    let (path, query) = match request_path.trim().split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (request_path.trim(), None),
+    };
+    let mut url = format!(
+        "{}://{}{}",
+        scheme,
+        authority,
+        merge_target_path(base.path(), path)
+    );
+    if let Some(query) = query {
+        url.push('?');
+        url.push_str(query);
+    }
+    url.parse()
+        .map_err(|e| format!("invalid target url {}: {}", url, e))
+}
+
+/// Join a target base path and a request path, collapsing the overlap so
+/// `/v1` + `/v1/chat/completions` yields `/v1/chat/completions`.
+pub fn merge_target_path(base_path: &str, request_path: &str) -> String {
+    let segments = |path: &str| -> Vec<String> {
+        path.split('/')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect()
+    };
+    let base = segments(base_path);
+    let request = segments(request_path);
+
+    let max_overlap = std::cmp::min(base.len(), request.len());
+    let overlap = (1..=max_overlap)
+        .rev()
+        .find(|&size| base[base.len() - size..] == request[..size])
+        .unwrap_or(0);
+
+    let merged: Vec<&str> = base
+        .iter()
+        .chain(request[overlap..].iter())
+        .map(String::as_str)
+        .collect();
+    format!("/{}", merged.join("/"))
+}
+
+fn merge_request_headers(
+    tunnel_headers: &HashMap<String, String>,
+    target: &ModelTarget,
+) -> HashMap<String, String> {
+    let mut merged = HashMap::new();
+    for (key, value) in tunnel_headers {
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim();
+        if ALLOWED_TUNNEL_REQUEST_HEADERS.contains(&key.as_str()) && !value.is_empty() {
+            merged.insert(key, value.to_string());
+        }
+    }
+    for (key, value) in &target.headers {
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim();
+        if !key.is_empty() && key != "host" && !value.is_empty() {
+            merged.insert(key, value.to_string());
+        }
+    }
+    merged
+}
+
+/// Next API key for `model` (round-robin) as a `(header, value)` pair.
+fn select_api_key(
+    inner: &WorkerInner,
+    model: &str,
+    target: &ModelTarget,
+) -> Option<(String, String)> {
+    let key = match target.api_keys.len() {
+        0 => return None,
+        1 => &target.api_keys[0],
+        len => {
+            let mut cursor = inner.key_cursor.entry(model.to_string()).or_insert(0);
+            let key = &target.api_keys[*cursor % len];
+            *cursor = (*cursor + 1) % len;
+            key
+        }
+    };
+
+    let header = match target.api_key_header.as_str() {
+        "" => "authorization".to_string(),
+        header => header.to_ascii_lowercase(),
+    };
+    let prefix = match target.api_key_prefix.as_str() {
+        "" if header == "authorization" => "Bearer ",
+        prefix => prefix,
+    };
+    Some((header, format!("{}{}", prefix, key.trim())))
+}
+
+/// Replace the `model` field of a JSON body with the backend's model name.
+/// Non-JSON bodies are passed through unchanged.
+fn rewrite_request_model(
+    body: &[u8],
+    headers: &HashMap<String, String>,
+    model: &str,
+    upstream_model: &str,
+) -> Result<Vec<u8>, String> {
+    let is_json = headers
+        .get("content-type")
+        .is_some_and(|ct| ct.to_ascii_lowercase().starts_with("application/json"));
+    if upstream_model == model || !is_json {
+        return Ok(body.to_vec());
+    }
+    if body.is_empty() {
+        return Ok(serde_json::json!({ "model": upstream_model })
+            .to_string()
+            .into_bytes());
+    }
+
+    let mut payload: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(body).map_err(|e| format!("rewrite json model: {}", e))?;
+    payload.insert("model".to_string(), upstream_model.into());
+    serde_json::to_vec(&payload).map_err(|e| e.to_string())
+}
+
+fn flatten_headers(headers: &http::HeaderMap) -> HashMap<String, String> {
+    let mut flattened: HashMap<String, String> = HashMap::new();
+    for (name, value) in headers {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        flattened
+            .entry(name.as_str().to_string())
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    flattened
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_target_path() {
+        assert_eq!(
+            merge_target_path("/v1", "/v1/chat/completions"),
+            "/v1/chat/completions"
+        );
+        assert_eq!(
+            merge_target_path("/api/v1", "/v1/embeddings"),
+            "/api/v1/embeddings"
+        );
+        assert_eq!(merge_target_path("", "/v1/models"), "/v1/models");
+        assert_eq!(merge_target_path("/v1/", ""), "/v1");
+        assert_eq!(merge_target_path("", ""), "/");
+    }
+
+    #[test]
+    fn test_build_local_target_url() {
+        let uri = build_local_target_url("http://127.0.0.1:8000/v1", "/v1/videos/abc?variant=hd")
+            .unwrap();
+        assert_eq!(
+            uri.to_string(),
+            "http://127.0.0.1:8000/v1/videos/abc?variant=hd"
+        );
+        assert!(build_local_target_url("/v1", "/v1/models").is_err());
+    }
+
+    #[test]
+    fn test_rewrite_request_model() {
+        let headers = HashMap::from([(
+            "content-type".to_string(),
+            "application/json; charset=utf-8".to_string(),
+        )]);
+        let body = rewrite_request_model(br#"{"model":"gpt-4","n":1}"#, &headers, "gpt-4", "qwen")
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["model"], "qwen");
+        assert_eq!(body["n"], 1);
+
+        let untouched = rewrite_request_model(b"raw", &HashMap::new(), "gpt-4", "qwen").unwrap();
+        assert_eq!(untouched, b"raw");
+    }
+}
@@ -0,0 +1,854 @@
+//! Tokiame worker client.
+//!
+//! Dials the gateway over WebSocket + smux or QUIC, registers the locally
+//! served models on the control stream and answers tunnelled requests by
+//! forwarding them to an OpenAI-compatible HTTP backend. Wire behaviour
+//! matches the Go `tokiame` client so either can talk to either gateway.
+
+mod dial;
+mod forward;
+
+use crate::{
+    codec::ControlCodec,
+    error::TunnelError,
+    protocol::{
+        AckMessage, ControlMessage, HeartbeatMessage, ModelsSyncMessage, RegisterMessage,
+        control_type, transport,
+    },
+    tunnel::TunnelSession,
+};
+use dashmap::DashMap;
+pub use dial::normalize_websocket_url;
+pub use forward::merge_target_path;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::oneshot,
+};
+use tokio_util::sync::CancellationToken;
+
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+pub const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+pub const CONTROL_ACK_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How the worker reaches the gateway.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportMode {
+    /// Try QUIC when the gateway is reachable over TLS, fall back to WebSocket.
+    #[default]
+    Auto,
+    #[serde(rename = "websocket")]
+    WebSocket,
+    Quic,
+}
+
+impl std::str::FromStr for TransportMode {
+    type Err = TunnelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "auto" => Ok(Self::Auto),
+            "websocket" => Ok(Self::WebSocket),
+            "quic" => Ok(Self::Quic),
+            _ => Err(TunnelError::protocol(
+                "TOKIAME_TRANSPORT_MODE must be one of auto, quic, websocket",
+            )),
+        }
+    }
+}
+
+/// Local backend serving one advertised model.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelTarget {
+    pub url:            String,
+    /// Model name sent to the backend; defaults to the advertised name.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub mapped_name:    String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub backend_type:   String,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub headers:        HashMap<String, String>,
+    /// Rotated round-robin per request.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub api_keys:       Vec<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub api_key_header: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub api_key_prefix: String,
+}
+
+/// Worker configuration, JSON-compatible with `config.tokiame.example.json`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorkerConfig {
+    pub gateway_url: String,
+    pub quic_endpoint: String,
+    pub transport_mode: TransportMode,
+    pub token: String,
+    pub namespace: String,
+    pub node_name: String,
+    pub group: String,
+    pub backend_type: String,
+    pub model_targets: HashMap<String, ModelTarget>,
+    pub heartbeat_interval_seconds: u64,
+    pub reconnect_delay_seconds: u64,
+    pub insecure_skip_verify: bool,
+}
+
+impl WorkerConfig {
+    /// Load from `TOKIAME_CONFIG` (if set) and apply `TOKIAME_*` overrides.
+    pub fn from_env() -> Result<Self, TunnelError> {
+        let mut config = match env_string("TOKIAME_CONFIG") {
+            Some(path) => {
+                let data = std::fs::read(&path)?;
+                serde_json::from_slice(&data)?
+            }
+            None => Self::default(),
+        };
+
+        let overrides = [
+            (&mut config.gateway_url, "TOKIAME_GATEWAY_URL"),
+            (&mut config.quic_endpoint, "TOKIAME_QUIC_ENDPOINT"),
+            (&mut config.token, "TOKIAME_TOKEN"),
+            (&mut config.namespace, "TOKIAME_NAMESPACE"),
+            (&mut config.node_name, "TOKIAME_NODE_NAME"),
+            (&mut config.group, "TOKIAME_GROUP"),
+            (&mut config.backend_type, "TOKIAME_BACKEND_TYPE"),
+        ];
+        for (field, name) in overrides {
+            if let Some(value) = env_string(name) {
+                *field = value;
+            }
+        }
+        if let Some(mode) = env_string("TOKIAME_TRANSPORT_MODE") {
+            config.transport_mode = mode.parse()?;
+        }
+        if let Some(secs) = env_positive("TOKIAME_HEARTBEAT_INTERVAL_SECONDS") {
+            config.heartbeat_interval_seconds = secs;
+        }
+        if let Some(secs) = env_positive("TOKIAME_RECONNECT_DELAY_SECONDS") {
+            config.reconnect_delay_seconds = secs;
+        }
+        if let Some(raw) = env_string("TOKIAME_MODEL_TARGETS") {
+            config.model_targets = serde_json::from_str(&raw)?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Trim fields, drop unusable targets and check required settings.
+    pub fn validate(&mut self) -> Result<(), TunnelError> {
+        for field in [
+            &mut self.gateway_url,
+            &mut self.quic_endpoint,
+            &mut self.token,
+            &mut self.namespace,
+            &mut self.node_name,
+            &mut self.group,
+            &mut self.backend_type,
+        ] {
+            *field = field.trim().to_string();
+        }
+        if self.gateway_url.is_empty() {
+            return Err(TunnelError::protocol("TOKIAME_GATEWAY_URL is required"));
+        }
+        if self.token.is_empty() {
+            return Err(TunnelError::protocol("TOKIAME_TOKEN is required"));
+        }
+        if self.namespace.is_empty() {
+            return Err(TunnelError::protocol("TOKIAME_NAMESPACE is required"));
+        }
+
+        self.model_targets = std::mem::take(&mut self.model_targets)
+            .into_iter()
+            .filter_map(|(name, mut target)| {
+                let name = name.trim().to_string();
+                target.url = target.url.trim().to_string();
+                target.mapped_name = target.mapped_name.trim().to_string();
+                target.backend_type = target.backend_type.trim().to_string();
+                target.api_key_header = target.api_key_header.trim().to_string();
+                target.api_keys.retain(|key| !key.trim().is_empty());
+                (!name.is_empty() && !target.url.is_empty()).then_some((name, target))
+            })
+            .collect();
+        if self.model_targets.is_empty() {
+            return Err(TunnelError::protocol(
+                "TOKIAME_MODEL_TARGETS must contain at least one model mapping",
+            ));
+        }
+
+        if self.transport_mode == TransportMode::Quic {
+            self.resolve_quic_endpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Advertised model names, sorted.
+    pub fn model_names(&self) -> Vec<String> {
+        let mut models: Vec<String> = self.model_targets.keys().cloned().collect();
+        models.sort();
+        models
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        match self.heartbeat_interval_seconds {
+            0 => DEFAULT_HEARTBEAT_INTERVAL,
+            secs => Duration::from_secs(secs),
+        }
+    }
+
+    pub fn reconnect_delay(&self) -> Duration {
+        match self.reconnect_delay_seconds {
+            0 => DEFAULT_RECONNECT_DELAY,
+            secs => Duration::from_secs(secs),
+        }
+    }
+
+    /// Backend type reported to the gateway; `mixed` when targets disagree.
+    pub fn control_plane_backend_type(&self) -> String {
+        let backend_types: BTreeSet<String> = self
+            .model_targets
+            .values()
+            .map(|target| effective_backend_type(&target.backend_type, &self.backend_type))
+            .collect();
+        let mut iter = backend_types.into_iter();
+        match (iter.next(), iter.next()) {
+            (None, _) => normalize_backend_type(&self.backend_type),
+            (Some(backend_type), None) => backend_type,
+            _ => "mixed".to_string(),
+        }
+    }
+
+    /// Whether `Auto` mode should try QUIC before WebSocket.
+    pub fn should_attempt_quic(&self) -> bool {
+        !self.quic_endpoint.is_empty() || dial::is_secure_url(&self.gateway_url)
+    }
+
+    /// `host:port` for the QUIC dial: explicit endpoint, else the TLS gateway host.
+    pub fn resolve_quic_endpoint(&self) -> Result<String, TunnelError> {
+        dial::resolve_quic_endpoint(&self.quic_endpoint, &self.gateway_url)
+    }
+}
+
+fn env_string(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn env_positive(name: &str) -> Option<u64> {
+    env_string(name)?.parse().ok().filter(|&v| v > 0)
+}
+
+fn effective_backend_type(target: &str, default: &str) -> String {
+    let backend_type = if target.trim().is_empty() {
+        default
+    } else {
+        target
+    };
+    normalize_backend_type(backend_type)
+}
+
+fn normalize_backend_type(backend_type: &str) -> String {
+    match backend_type.trim().to_ascii_lowercase().as_str() {
+        "" => "openai".to_string(),
+        "vllm" | "vllm-omni" | "vllm_omni" => "vllm_omni".to_string(),
+        other => other.to_string(),
+    }
+}
+
+struct WorkerInner {
+    config:     WorkerConfig,
+    http:       forward::LocalClient,
+    /// Cancel handles for requests currently forwarded to the local backend.
+    inflight:   DashMap<String, oneshot::Sender<()>>,
+    key_cursor: DashMap<String, usize>,
+    shutdown:   CancellationToken,
+}
+
+/// Tokiame worker. Cheap to clone; all clones share one connection loop.
+#[derive(Clone)]
+pub struct WorkerClient {
+    inner: Arc<WorkerInner>,
+}
+
+impl WorkerClient {
+    pub fn new(config: WorkerConfig) -> Self {
+        Self {
+            inner: Arc::new(WorkerInner {
+                config,
+                http: forward::local_client(),
+                inflight: DashMap::new(),
+                key_cursor: DashMap::new(),
+                shutdown: CancellationToken::new(),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &WorkerConfig {
+        &self.inner.config
+    }
+
+    /// Stop `run` / `serve` and drop the gateway connection.
+    pub fn shutdown(&self) {
+        self.inner.shutdown.cancel();
+    }
+
+    /// Abort a request being forwarded locally. Returns false if unknown.
+    pub fn cancel_request(&self, request_id: &str) -> bool {
+        match self.inner.inflight.remove(request_id) {
+            Some((_, cancel)) => {
+                let _ = cancel.send(());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Connect and serve until `shutdown`, reconnecting with exponential
+    /// backoff. The delay resets once a session has registered.
+    pub async fn run(&self) {
+        let config = &self.inner.config;
+        tracing::info!(
+            "tokiame worker starting: gateway={} transport={:?} namespace={} models={:?}",
+            config.gateway_url,
+            config.transport_mode,
+            config.namespace,
+            config.model_names()
+        );
+
+        let base_delay = config.reconnect_delay();
+        let mut delay = base_delay;
+        while !self.inner.shutdown.is_cancelled() {
+            let registered = match self.run_once().await {
+                Ok(registered) => registered,
+                Err(e) => {
+                    tracing::warn!("gateway connection failed: {}", e);
+                    false
+                }
+            };
+            if self.inner.shutdown.is_cancelled() {
+                break;
+            }
+
+            if registered {
+                delay = base_delay;
+            }
+            tracing::info!("reconnecting in {:?}", delay);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.inner.shutdown.cancelled() => break,
+            }
+            delay = std::cmp::min(delay * 2, MAX_RECONNECT_DELAY);
+        }
+        tracing::info!("tokiame worker stopped");
+    }
+
+    /// One dial + serve cycle. Returns whether the session got registered.
+    async fn run_once(&self) -> Result<bool, TunnelError> {
+        let tunnel = dial::dial(&self.inner.config).await?;
+        let mut registered = false;
+        let result = match tunnel {
+            dial::Tunnel::WebSocket { session, _pumps } => {
+                self.serve_inner(session, transport::WEBSOCKET, &mut registered)
+                    .await
+            }
+            dial::Tunnel::Quic { session, _endpoint } => {
+                self.serve_inner(session, transport::QUIC, &mut registered)
+                    .await
+            }
+        };
+        match result {
+            Ok(()) => Ok(registered),
+            Err(e) if registered => {
+                tracing::warn!("gateway session ended: {}", e);
+                Ok(true)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Drive an already established tunnel session until it closes or
+    /// `shutdown` is called. `transport` is one of [`transport`]'s names;
+    /// only QUIC sessions send an explicit `auth` message.
+    pub async fn serve<T>(&self, session: T, transport: &str) -> Result<(), TunnelError>
+    where
+        T: TunnelSession,
+        T::Stream: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.serve_inner(session, transport, &mut false).await
+    }
+
+    async fn serve_inner<T>(
+        &self,
+        mut session: T,
+        transport: &str,
+        registered: &mut bool,
+    ) -> Result<(), TunnelError>
+    where
+        T: TunnelSession,
+        T::Stream: AsyncRead + AsyncWrite + Unpin,
+    {
+        let control = session.open_stream().await?;
+        let (reader, writer) = tokio::io::split(control);
+        let mut codec = ControlCodec::new(reader, writer);
+
+        let result = self
+            .serve_control(&mut session, &mut codec, transport, registered)
+            .await;
+        let _ = session.close().await;
+        result
+    }
+
+    async fn serve_control<T, R, W>(
+        &self,
+        session: &mut T,
+        codec: &mut ControlCodec<R, W>,
+        transport: &str,
+        registered: &mut bool,
+    ) -> Result<(), TunnelError>
+    where
+        T: TunnelSession,
+        T::Stream: AsyncRead + AsyncWrite + Unpin,
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let config = &self.inner.config;
+        let models = config.model_names();
+        let backend_type = config.control_plane_backend_type();
+
+        if transport == transport::QUIC {
+            let request_id = self.next_request_id("auth");
+            let mut msg = ControlMessage::auth(&config.token);
+            msg.request_id = Some(request_id.clone());
+            self.request(codec, msg, &request_id).await?;
+        }
+
+        let request_id = self.next_request_id("register");
+        let msg = ControlMessage {
+            msg_type: control_type::REGISTER.to_string(),
+            request_id: Some(request_id.clone()),
+            register: Some(RegisterMessage {
+                namespace:     config.namespace.clone(),
+                node_name:     config.node_name.clone(),
+                group:         config.group.clone(),
+                models:        models.clone(),
+                hardware_info: self.hardware_info(),
+                backend_type:  backend_type.clone(),
+            }),
+            ..Default::default()
+        };
+        let ack = self.request(codec, msg, &request_id).await?;
+        *registered = true;
+
+        let request_id = self.next_request_id("models");
+        let msg = ControlMessage {
+            msg_type: control_type::MODELS_SYNC.to_string(),
+            request_id: Some(request_id.clone()),
+            models_sync: Some(ModelsSyncMessage {
+                group: config.group.clone(),
+                models: models.clone(),
+                hardware_info: self.hardware_info(),
+                backend_type,
+            }),
+            ..Default::default()
+        };
+        self.request(codec, msg, &request_id).await?;
+
+        tracing::info!(
+            "worker connected: transport={} namespace={} worker_id={} channel_id={} models={:?}",
+            transport,
+            config.namespace,
+            ack.worker_id,
+            ack.channel_id,
+            models
+        );
+
+        enum Event<S> {
+            Stream(Option<S>),
+            Control(Option<Box<ControlMessage>>),
+            Heartbeat,
+            Shutdown,
+        }
+
+        let interval = config.heartbeat_interval();
+        let mut heartbeat =
+            tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        loop {
+            let event = tokio::select! {
+                _ = self.inner.shutdown.cancelled() => Event::Shutdown,
+                stream = session.accept_stream() => Event::Stream(stream?),
+                msg = codec.read_message() => Event::Control(msg?.map(Box::new)),
+                _ = heartbeat.tick() => Event::Heartbeat,
+            };
+
+            match event {
+                Event::Shutdown => return Ok(()),
+                Event::Stream(Some(stream)) => {
+                    tokio::spawn(forward::handle_data_stream(self.inner.clone(), stream));
+                }
+                Event::Stream(None) => return Err(TunnelError::StreamClosed),
+                Event::Control(None) => return Err(TunnelError::StreamClosed),
+                Event::Control(Some(msg)) => self.handle_control_message(codec, *msg).await?,
+                Event::Heartbeat => {
+                    let msg = ControlMessage {
+                        msg_type: control_type::HEARTBEAT.to_string(),
+                        request_id: Some(self.next_request_id("heartbeat")),
+                        heartbeat: Some(HeartbeatMessage {
+                            status:         0,
+                            node_name:      config.node_name.clone(),
+                            hardware_info:  self.hardware_info(),
+                            current_models: config.model_names(),
+                        }),
+                        ..Default::default()
+                    };
+                    codec.write_message(&msg).await?;
+                }
+            }
+        }
+    }
+
+    async fn handle_control_message<R, W>(
+        &self,
+        codec: &mut ControlCodec<R, W>,
+        msg: ControlMessage,
+    ) -> Result<(), TunnelError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        match msg.msg_type.as_str() {
+            control_type::CANCEL_REQUEST => {
+                let Some(cancel) = msg.cancel_request else {
+                    return Ok(());
+                };
+                let cancelled = self.cancel_request(&cancel.target_request_id);
+                tracing::info!(
+                    "cancel_request for {} ({}): {}",
+                    cancel.target_request_id,
+                    cancel.reason,
+                    if cancelled { "cancelled" } else { "not found" }
+                );
+                let ack = AckMessage {
+                    message:    if cancelled {
+                        "cancel_ok"
+                    } else {
+                        "cancel_noop"
+                    }
+                    .to_string(),
+                    namespace:  self.inner.config.namespace.clone(),
+                    worker_id:  0,
+                    channel_id: 0,
+                };
+                codec
+                    .write_message(&ControlMessage::ack(
+                        msg.request_id.unwrap_or_default(),
+                        ack,
+                    ))
+                    .await
+            }
+            control_type::ERROR => {
+                let error = msg.error.map(|e| e.to_string()).unwrap_or_default();
+                Err(TunnelError::protocol(format!("gateway error: {}", error)))
+            }
+            other => {
+                tracing::debug!("ignoring control message: type={}", other);
+                Ok(())
+            }
+        }
+    }
+
+    /// Send `msg` and wait for the ack (or error) carrying `request_id`.
+    async fn request<R, W>(
+        &self,
+        codec: &mut ControlCodec<R, W>,
+        msg: ControlMessage,
+        request_id: &str,
+    ) -> Result<AckMessage, TunnelError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let action = msg.msg_type.clone();
+        codec.write_message(&msg).await?;
+
+        let wait = async {
+            loop {
+                let reply = codec
+                    .read_message()
+                    .await?
+                    .ok_or(TunnelError::StreamClosed)?;
+                if reply.request_id.as_deref() != Some(request_id) {
+                    tracing::debug!(
+                        "skipping {} while waiting for {} ack",
+                        reply.msg_type,
+                        action
+                    );
+                    continue;
+                }
+                match reply.msg_type.as_str() {
+                    control_type::ACK => {
+                        return Ok(reply.ack.unwrap_or(AckMessage {
+                            message:    String::new(),
+                            namespace:  String::new(),
+                            worker_id:  0,
+                            channel_id: 0,
+                        }));
+                    }
+                    control_type::ERROR => {
+                        let error = reply.error.map(|e| e.to_string()).unwrap_or_default();
+                        return Err(TunnelError::auth_failed(format!(
+                            "{} rejected: {}",
+                            action, error
+                        )));
+                    }
+                    _ => continue,
+                }
+            }
+        };
+        tokio::time::timeout(CONTROL_ACK_TIMEOUT, wait)
+            .await
+            .map_err(|_| TunnelError::Timeout)?
+    }
+
+    fn next_request_id(&self, prefix: &str) -> String {
+        format!(
+            "{}:{}:{}",
+            self.inner.config.namespace,
+            prefix,
+            uuid::Uuid::new_v4()
+        )
+    }
+
+    fn hardware_info(&self) -> HashMap<String, serde_json::Value> {
+        let config = &self.inner.config;
+        let summaries: serde_json::Map<String, serde_json::Value> = config
+            .model_targets
+            .iter()
+            .map(|(name, target)| {
+                let summary = serde_json::json!({
+                    "url": target.url,
+                    "mapped_name": target.mapped_name,
+                    "backend_type": effective_backend_type(&target.backend_type, &config.backend_type),
+                    "has_api_keys": !target.api_keys.is_empty(),
+                    "header_count": target.headers.len(),
+                });
+                (name.clone(), summary)
+            })
+            .collect();
+
+        HashMap::from([
+            (
+                "hostname".to_string(),
+                std::env::var("HOSTNAME").unwrap_or_default().into(),
+            ),
+            ("os".to_string(), std::env::consts::OS.into()),
+            ("arch".to_string(), std::env::consts::ARCH.into()),
+            (
+                "num_cpu".to_string(),
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+                    .into(),
+            ),
+            (
+                "default_backend_type".to_string(),
+                normalize_backend_type(&config.backend_type).into(),
+            ),
+            (
+                "control_plane_backend".to_string(),
+                config.control_plane_backend_type().into(),
+            ),
+            ("model_target_summaries".to_string(), summaries.into()),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codec::TunnelCodec,
+        protocol::{CancelRequestMessage, TunnelRequest},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn test_config(url: String) -> WorkerConfig {
+        let mut config = WorkerConfig {
+            gateway_url: "http://127.0.0.1:1/connect".to_string(),
+            token: "worker-token".to_string(),
+            namespace: "test-ns".to_string(),
+            model_targets: HashMap::from([("gpt-4".to_string(), ModelTarget {
+                url,
+                mapped_name: "local-model".to_string(),
+                ..Default::default()
+            })]),
+            ..Default::default()
+        };
+        config.validate().unwrap();
+        config
+    }
+
+    /// One-shot HTTP backend that echoes the request body back as JSON.
+    async fn spawn_backend() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            let body = loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|l| {
+                            l.to_ascii_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|v| v.trim().parse::<usize>().unwrap())
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        assert!(head.starts_with("POST /v1/chat/completions"));
+                        break body.to_string();
+                    }
+                }
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: \
+                 {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+        format!("http://{}/v1", addr)
+    }
+
+    #[test]
+    fn test_config_backend_type_and_validation() {
+        let mut config = test_config("http://localhost:8000".to_string());
+        assert_eq!(config.model_names(), vec!["gpt-4"]);
+        assert_eq!(config.control_plane_backend_type(), "openai");
+        config
+            .model_targets
+            .insert("flux".to_string(), ModelTarget {
+                url: "http://localhost:9000".to_string(),
+                backend_type: "vllm".to_string(),
+                ..Default::default()
+            });
+        assert_eq!(config.control_plane_backend_type(), "mixed");
+
+        config.model_targets.clear();
+        assert!(config.validate().is_err());
+        assert_eq!(
+            serde_json::from_str::<TransportMode>("\"websocket\"").unwrap(),
+            TransportMode::WebSocket
+        );
+    }
+
+    #[tokio::test]
+    async fn test_worker_serves_smux_session() {
+        let client = WorkerClient::new(test_config(spawn_backend().await));
+        let (gateway_io, worker_io) = tokio::io::duplex(64 * 1024);
+        let mut gateway = tokilake_smux::Session::server(gateway_io, Default::default());
+        let worker_session = tokilake_smux::Session::client(worker_io, Default::default());
+        let worker = tokio::spawn({
+            let client = client.clone();
+            async move { client.serve(worker_session, transport::WEBSOCKET).await }
+        });
+
+        // Control plane: register, then models_sync.
+        let (r, w) = tokio::io::split(gateway.accept().await.unwrap());
+        let mut control = ControlCodec::new(r, w);
+        for expected in [control_type::REGISTER, control_type::MODELS_SYNC] {
+            let msg = control.read_message().await.unwrap().unwrap();
+            assert_eq!(msg.msg_type, expected);
+            let request_id = msg.request_id.unwrap();
+            assert!(request_id.starts_with("test-ns:"));
+            let ack = AckMessage {
+                message:    "ok".to_string(),
+                namespace:  "test-ns".to_string(),
+                worker_id:  1,
+                channel_id: 1,
+            };
+            control
+                .write_message(&ControlMessage::ack(request_id, ack))
+                .await
+                .unwrap();
+        }
+
+        // Data plane: the model is rewritten to the mapped name and the
+        // backend's answer is streamed back ending with an eof frame.
+        let (r, w) = tokio::io::split(gateway.open().await.unwrap());
+        let mut data = TunnelCodec::new(r, w);
+        data.write_request(&TunnelRequest {
+            request_id: "req-1".to_string(),
+            route_kind: "chat_completions".to_string(),
+            method:     "POST".to_string(),
+            path:       "/v1/chat/completions".to_string(),
+            model:      "gpt-4".to_string(),
+            headers:    HashMap::from([
+                ("content-type".to_string(), "application/json".to_string()),
+                ("x-secret".to_string(), "dropped".to_string()),
+            ]),
+            is_stream:  false,
+            body:       br#"{"model":"gpt-4"}"#.to_vec(),
+        })
+        .await
+        .unwrap();
+
+        let head = data.read_response().await.unwrap().unwrap();
+        assert_eq!(head.status_code, 200);
+        assert_eq!(
+            head.headers.get("content-type").unwrap(),
+            "application/json"
+        );
+        let mut body = Vec::new();
+        loop {
+            let frame = data.read_response().await.unwrap().unwrap();
+            assert!(frame.error.is_none());
+            body.extend_from_slice(&frame.body_chunk);
+            if frame.eof {
+                break;
+            }
+        }
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["model"], "local-model");
+
+        // Cancelling an unknown request is acknowledged as a no-op.
+        control
+            .write_message(&ControlMessage {
+                msg_type: control_type::CANCEL_REQUEST.to_string(),
+                request_id: Some("cancel-1".to_string()),
+                cancel_request: Some(CancelRequestMessage {
+                    target_request_id: "missing".to_string(),
+                    reason:            "client_disconnected".to_string(),
+                }),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let ack = control.read_message().await.unwrap().unwrap();
+        assert_eq!(ack.request_id.as_deref(), Some("cancel-1"));
+        assert_eq!(ack.ack.unwrap().message, "cancel_noop");
+
+        client.shutdown();
+        worker.await.unwrap().unwrap();
+    }
+}
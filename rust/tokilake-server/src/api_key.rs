@@ -0,0 +1,165 @@
+//! Client API-key authentication for the `/v1` relay routes.
+//!
+//! Clients send `Authorization: Bearer <key>`. The key is checked with the
+//! gateway's [`Authenticator`] and the resulting [`Token`] is stored in the
+//! request extensions, where the relay handler picks up the user id, along
+//! with the [`ClientKey`] its rate limits are kept under.
+
+use crate::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use tokilake_core::{
+    error::TunnelError,
+    gateway::{Authenticator, WorkerRegistry},
+    protocol::Token,
+};
+use tracing::warn;
+
+/// Identifies the key a relay request was made with. A user may hold several
+/// keys; each has rate limits of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientKey(pub u64);
+
+impl ClientKey {
+    fn of(key: &str) -> Self {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        Self(hasher.finish())
+    }
+}
+
+/// Reject requests without a valid client key; otherwise attach its [`Token`]
+/// and [`ClientKey`].
+pub async fn require_api_key<A: Authenticator, R: WorkerRegistry>(
+    State(state): State<AppState<A, R>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = bearer_key(request.headers()) else {
+        return openai_error(
+            StatusCode::UNAUTHORIZED,
+            "missing API key, send it as 'Authorization: Bearer <key>'",
+            "invalid_request_error",
+            "missing_api_key",
+        );
+    };
+
+    match state.gateway.authenticate(&key).await {
+        Ok((key, token)) => {
+            request.extensions_mut().insert::<Token>(token);
+            request.extensions_mut().insert(ClientKey::of(&key));
+            next.run(request).await
+        }
+        Err(TunnelError::AuthFailed { .. }) => openai_error(
+            StatusCode::UNAUTHORIZED,
+            "invalid API key",
+            "invalid_request_error",
+            "invalid_api_key",
+        ),
+        Err(e) => {
+            warn!("API key check failed: {}", e);
+            openai_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to verify API key",
+                "server_error",
+                "auth_unavailable",
+            )
+        }
+    }
+}
+
+/// The key of an `Authorization: Bearer` header, if any.
+fn bearer_key(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?.trim();
+    let key = match value.get(..7) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("bearer ") => value[7..].trim(),
+        _ => return None,
+    };
+    (!key.is_empty()).then(|| key.to_string())
+}
+
+/// An error response in the OpenAI API shape.
+pub fn openai_error(status: StatusCode, message: &str, error_type: &str, code: &str) -> Response {
+    let body = serde_json::json!({
+        "error": {
+            "message": message,
+            "type": error_type,
+            "param": null,
+            "code": code,
+        }
+    });
+    (status, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{MemoryAuthenticator, MemoryWorkerRegistry};
+    use axum::{body::Body, routing::get, Extension, Router};
+    use tokilake_core::{gateway::Gateway, session::LoadBalance};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        let auth = MemoryAuthenticator::new().with_token("sk-client", 7);
+        let state = AppState::new(
+            Gateway::new(auth, MemoryWorkerRegistry::new()),
+            LoadBalance::default(),
+        );
+        Router::new()
+            .route(
+                "/v1/ping",
+                get(|Extension(token): Extension<Token>| async move { token.user_id.to_string() }),
+            )
+            .route_layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                require_api_key,
+            ))
+            .with_state(state)
+    }
+
+    async fn call(authorization: Option<&str>) -> (StatusCode, serde_json::Value, Vec<u8>) {
+        let mut request = Request::get("/v1/ping");
+        if let Some(value) = authorization {
+            request = request.header(AUTHORIZATION, value);
+        }
+        let response = app()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json = serde_json::from_slice(&bytes).unwrap_or_default();
+        (status, json, bytes.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_valid_key_attaches_token() {
+        let (status, _, body) = call(Some("Bearer sk-client")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"7");
+    }
+
+    #[tokio::test]
+    async fn test_missing_or_invalid_key_is_openai_401() {
+        let (status, json, _) = call(None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(json["error"]["code"], "missing_api_key");
+        assert_eq!(json["error"]["type"], "invalid_request_error");
+
+        let (status, json, _) = call(Some("Bearer sk-wrong")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(json["error"]["code"], "invalid_api_key");
+
+        // Bare tokens are only accepted on the worker connect path.
+        let (status, _, _) = call(Some("sk-client")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,10 +1,20 @@
+mod api_key;
+mod memory;
+mod sqlite;
+
+use crate::{
+    api_key::ClientKey,
+    memory::{MemoryAuthenticator, MemoryWorkerRegistry},
+    sqlite::SqliteStore,
+};
 use axum::{
+    body::Bytes,
     extract::{
         ws::{Message, WebSocket},
-        Query, State, WebSocketUpgrade,
+        DefaultBodyLimit, Path, Query, State, WebSocketUpgrade,
     },
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
@@ -12,189 +22,87 @@ use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, sync::Arc, time::Duration};
 use tokilake_core::{
-    error::{ErrorMessage, TunnelError},
+    gateway::{extract_connect_token, Authenticator, ConnectInfo, Gateway, WorkerRegistry},
+    model_list::ModelList,
     protocol::*,
-    session::{ChannelBindParams, GatewaySession, InFlightRequest, SessionManager},
-    tunnel::{quic::QuicSession, TunnelSession},
+    ratelimit::{estimate_request_tokens, Limits, RateLimiter, RateLimits},
+    session::{InFlightRequest, LoadBalance, LoadBalancer, SessionManager},
+    tunnel::{channel::ChannelIo, quic::QuicSession, TunnelSession},
+    usage::UsageTap,
 };
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::mpsc;
 use tracing::{info, warn};
 
-#[derive(Clone)]
-struct AppState {
-    token:                String,
+struct AppState<A, R> {
+    gateway:              Gateway<A, R>,
     session_manager:      Arc<SessionManager<tokilake_smux::Session>>,
     quic_session_manager: Arc<SessionManager<QuicSession>>,
-    registry:             Arc<MemoryWorkerRegistry>,
-}
-
-struct MemoryWorkerRegistry {
-    workers: parking_lot::RwLock<HashMap<i32, WorkerEntry>>,
+    balancer:             Arc<LoadBalancer>,
+    /// Requests and tokens per minute of each client and model.
+    limiter:              Arc<RateLimiter>,
 }
 
-struct WorkerEntry {
-    #[allow(dead_code)]
-    worker_id: i32,
-    #[allow(dead_code)]
-    namespace: String,
-    models:    Vec<String>,
-    #[allow(dead_code)]
-    group:     String,
-    #[allow(dead_code)]
-    status:    String,
-}
-
-impl MemoryWorkerRegistry {
-    fn new() -> Self {
+impl<A, R> AppState<A, R> {
+    fn new(gateway: Gateway<A, R>, policy: LoadBalance) -> Self {
         Self {
-            workers: parking_lot::RwLock::new(HashMap::new()),
-        }
-    }
-
-    fn register_worker(
-        &self,
-        namespace: &str,
-        models: &[String],
-        group: &str,
-        backend_type: &str,
-    ) -> Result<RegisterResult, TunnelError> {
-        if namespace.trim().is_empty() {
-            return Err(TunnelError::protocol("namespace is required"));
-        }
-
-        let mut workers = self.workers.write();
-        let worker_id = (workers.len() as i32) + 1;
-        workers.insert(worker_id, WorkerEntry {
-            worker_id,
-            namespace: namespace.to_string(),
-            models: models.to_vec(),
-            group: group.to_string(),
-            status: backend_type.to_string(),
-        });
-
-        info!(
-            "worker registered: id={} namespace={} models={:?}",
-            worker_id, namespace, models
-        );
-
-        Ok(RegisterResult {
-            worker_id,
-            channel_id: worker_id,
-            namespace: namespace.to_string(),
-            group: group.to_string(),
-            models: models.to_vec(),
-            backend_type: backend_type.to_string(),
-            status: 1,
-        })
-    }
-
-    fn update_heartbeat(
-        &self,
-        worker_id: i32,
-        current_models: &[String],
-    ) -> Result<(), TunnelError> {
-        let mut workers = self.workers.write();
-        if let Some(entry) = workers.get_mut(&worker_id) {
-            if !current_models.is_empty() {
-                entry.models = current_models.to_vec();
-            }
+            gateway,
+            session_manager: Arc::new(SessionManager::new()),
+            quic_session_manager: Arc::new(SessionManager::new()),
+            balancer: Arc::new(LoadBalancer::new(policy)),
+            limiter: Arc::default(),
         }
-        Ok(())
-    }
-
-    fn cleanup_worker(&self, worker_id: i32) -> Result<(), TunnelError> {
-        let mut workers = self.workers.write();
-        workers.remove(&worker_id);
-        info!("worker removed: id={}", worker_id);
-        Ok(())
     }
 }
 
-/// WebSocket stream wrapper for smux compatibility.
-struct WebSocketStream {
-    rx:     mpsc::Receiver<Vec<u8>>,
-    tx:     mpsc::Sender<Vec<u8>>,
-    buffer: Vec<u8>,
-}
-
-impl WebSocketStream {
-    fn new(rx: mpsc::Receiver<Vec<u8>>, tx: mpsc::Sender<Vec<u8>>) -> Self {
+impl<A, R> Clone for AppState<A, R> {
+    fn clone(&self) -> Self {
         Self {
-            rx,
-            tx,
-            buffer: Vec::new(),
+            gateway:              self.gateway.clone(),
+            session_manager:      self.session_manager.clone(),
+            quic_session_manager: self.quic_session_manager.clone(),
+            balancer:             self.balancer.clone(),
+            limiter:              self.limiter.clone(),
         }
     }
 }
 
-impl tokio::io::AsyncRead for WebSocketStream {
-    fn poll_read(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        if !self.buffer.is_empty() {
-            let n = std::cmp::min(buf.remaining(), self.buffer.len());
-            buf.put_slice(&self.buffer[..n]);
-            self.buffer.drain(..n);
-            return std::task::Poll::Ready(Ok(()));
-        }
-
-        match self.rx.poll_recv(cx) {
-            std::task::Poll::Ready(Some(data)) => {
-                if data.is_empty() {
-                    // Empty data means EOF
-                    return std::task::Poll::Ready(Ok(()));
-                }
-                let n = std::cmp::min(buf.remaining(), data.len());
-                buf.put_slice(&data[..n]);
-                if n < data.len() {
-                    self.buffer.extend_from_slice(&data[n..]);
-                }
-                std::task::Poll::Ready(Ok(()))
-            }
-            std::task::Poll::Ready(None) => {
-                // Channel closed - return 0 bytes to indicate EOF
-                std::task::Poll::Ready(Ok(()))
-            }
-            std::task::Poll::Pending => std::task::Poll::Pending,
-        }
-    }
+/// Trim, drop empty and duplicate model names, and sort.
+fn normalize_models(models: &[String]) -> Vec<String> {
+    let mut normalized: Vec<String> = models
+        .iter()
+        .map(|m| m.trim())
+        .filter(|m| !m.is_empty())
+        .map(str::to_string)
+        .collect();
+    normalized.sort();
+    normalized.dedup();
+    normalized
 }
 
-impl tokio::io::AsyncWrite for WebSocketStream {
-    fn poll_write(
-        self: std::pin::Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
-        buf: &[u8],
-    ) -> std::task::Poll<std::io::Result<usize>> {
-        match self.tx.try_send(buf.to_vec()) {
-            Ok(()) => std::task::Poll::Ready(Ok(buf.len())),
-            Err(mpsc::error::TrySendError::Full(_)) => {
-                // Channel is full, we need to wait
-                // For simplicity, we'll just drop the data in this case
-                // In a real implementation, you'd want to properly handle backpressure
-                std::task::Poll::Ready(Ok(buf.len()))
-            }
-            Err(_) => std::task::Poll::Ready(Err(std::io::Error::new(
-                std::io::ErrorKind::BrokenPipe,
-                "channel closed",
-            ))),
-        }
-    }
-
-    fn poll_flush(
-        self: std::pin::Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        std::task::Poll::Ready(Ok(()))
-    }
+fn flag_value(name: &str) -> Option<String> {
+    std::env::args()
+        .position(|a| a == name)
+        .and_then(|i| std::env::args().nth(i + 1))
+}
 
-    fn poll_shutdown(
-        self: std::pin::Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        std::task::Poll::Ready(Ok(()))
+/// `-rpm N` / `-tpm N` limit each client key, `-model-rpm N` / `-model-tpm N`
+/// each model across keys, per minute.
+fn rate_limits() -> RateLimits {
+    let limit = |name: &str| {
+        flag_value(name)
+            .and_then(|v| v.parse().ok())
+            .filter(|limit| *limit > 0)
+    };
+    RateLimits {
+        per_token: Limits {
+            rpm: limit("-rpm"),
+            tpm: limit("-tpm"),
+        },
+        per_model: Limits {
+            rpm: limit("-model-rpm"),
+            tpm: limit("-model-tpm"),
+        },
+        ..Default::default()
     }
 }
 
@@ -215,32 +123,55 @@ fn generate_self_signed_cert() -> (
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    let addr = std::env::args()
-        .position(|a| a == "-addr")
-        .and_then(|i| std::env::args().nth(i + 1))
-        .unwrap_or_else(|| ":18080".to_string());
-
-    let token = std::env::args()
-        .position(|a| a == "-token")
-        .and_then(|i| std::env::args().nth(i + 1))
-        .unwrap_or_else(|| "sk-test-token".to_string());
-
-    let session_manager = Arc::new(SessionManager::<tokilake_smux::Session>::new());
-    let quic_session_manager = Arc::new(SessionManager::<QuicSession>::new());
-    let registry = Arc::new(MemoryWorkerRegistry::new());
-
-    let state = AppState {
-        token,
-        session_manager,
-        quic_session_manager,
-        registry,
+    let addr = flag_value("-addr").unwrap_or_else(|| ":18080".to_string());
+    let token = flag_value("-token");
+    // `-lb round_robin|least_in_flight` spreads requests across replicas.
+    let policy = match flag_value("-lb").map(|lb| lb.parse::<LoadBalance>()) {
+        Some(Ok(policy)) => policy,
+        Some(Err(e)) => panic!("{}", e),
+        None => LoadBalance::default(),
     };
+    let limits = rate_limits();
+
+    // `-db` switches tokens and worker nodes to SQLite; `-token` then seeds
+    // the tokens table instead of being the only accepted token.
+    match flag_value("-db") {
+        Some(path) => {
+            let store = SqliteStore::open(&path).unwrap();
+            if let Some(token) = &token {
+                store.insert_token(token, 1).unwrap();
+            }
+            info!("using SQLite store at {}", path);
+            serve(&addr, Gateway::new(store.clone(), store), policy, limits).await;
+        }
+        None => {
+            let token = token.unwrap_or_else(|| "sk-test-token".to_string());
+            let auth = MemoryAuthenticator::new().with_token(&token, 1);
+            serve(
+                &addr,
+                Gateway::new(auth, MemoryWorkerRegistry::new()),
+                policy,
+                limits,
+            )
+            .await;
+        }
+    }
+}
+
+async fn serve<A: Authenticator, R: WorkerRegistry>(
+    addr: &str,
+    gateway: Gateway<A, R>,
+    policy: LoadBalance,
+    limits: RateLimits,
+) {
+    let mut state = AppState::new(gateway, policy);
+    state.limiter = Arc::new(RateLimiter::new(limits));
 
     let app = Router::new()
-        .route("/connect", get(ws_handler))
-        .route("/api/tokilake/connect", get(ws_handler))
-        .route("/health", get(health_handler))
-        .route("/v1/chat/completions", post(chat_completions_handler))
+        .route("/connect", get(ws_handler::<A, R>))
+        .route("/api/tokilake/connect", get(ws_handler::<A, R>))
+        .route("/health", get(health_handler::<A, R>))
+        .merge(relay_routes(state.clone()))
         .with_state(state.clone());
 
     let bind_addr = addr.trim_start_matches(':');
@@ -266,13 +197,81 @@ async fn main() {
     .unwrap();
 }
 
+/// One route per [`route_kind`], each relaying to a worker, and the model
+/// list. Clients must send an API key.
+fn relay_routes<A: Authenticator, R: WorkerRegistry>(
+    state: AppState<A, R>,
+) -> Router<AppState<A, R>> {
+    let mut router = Router::new();
+    for &kind in route_kind::ALL {
+        let Some((method, path)) = route_kind::http_route(kind) else {
+            continue;
+        };
+        let handler =
+            move |State(state): State<AppState<A, R>>,
+                  Query(query): Query<RelayQuery>,
+                  parts: axum::http::request::Parts,
+                  body: Bytes| { relay_handler(kind, state, query, parts, body) };
+        let method_router = match method {
+            "GET" => get(handler),
+            _ => post(handler),
+        };
+        router = router.route(path, method_router);
+    }
+    router
+        .route("/v1/models", get(list_models::<A, R>))
+        .route("/v1/models/{*id}", get(get_model::<A, R>))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state,
+            api_key::require_api_key::<A, R>,
+        ))
+        .layer(DefaultBodyLimit::max(MAX_RELAY_BODY_SIZE))
+}
+
+/// Every model a connected worker serves, owned by its namespace. Keys carry
+/// no model permissions here, so every client sees every model.
+async fn model_list<A, R>(state: &AppState<A, R>) -> ModelList {
+    let mut workers = state.session_manager.workers().await;
+    workers.extend(state.quic_session_manager.workers().await);
+    ModelList::new(workers.into_iter().flat_map(|worker| {
+        let namespace = worker.namespace;
+        worker
+            .models
+            .into_iter()
+            .map(move |model| (model, namespace.clone()))
+    }))
+}
+
+async fn list_models<A: Authenticator, R: WorkerRegistry>(
+    State(state): State<AppState<A, R>>,
+) -> Json<ModelList> {
+    Json(model_list(&state).await)
+}
+
+async fn get_model<A: Authenticator, R: WorkerRegistry>(
+    State(state): State<AppState<A, R>>,
+    Path(id): Path<String>,
+) -> Response {
+    match model_list(&state).await.get(&id) {
+        Some(model) => Json(model).into_response(),
+        None => api_key::openai_error(
+            StatusCode::NOT_FOUND,
+            &format!("The model '{}' does not exist", id),
+            "invalid_request_error",
+            "model_not_found",
+        ),
+    }
+}
+
 #[derive(Serialize)]
 struct HealthResponse {
     status:   String,
     sessions: usize,
 }
 
-async fn health_handler(State(state): State<AppState>) -> Json<HealthResponse> {
+async fn health_handler<A: Authenticator, R: WorkerRegistry>(
+    State(state): State<AppState<A, R>>,
+) -> Json<HealthResponse> {
     Json(HealthResponse {
         status:   "ok".to_string(),
         sessions: state.session_manager.session_count()
@@ -286,15 +285,26 @@ struct ConnectQuery {
     access_token: Option<String>,
 }
 
-async fn ws_handler(
+async fn ws_handler<A: Authenticator, R: WorkerRegistry>(
     ws: WebSocketUpgrade,
-    State(state): State<AppState>,
+    State(state): State<AppState<A, R>>,
     Query(query): Query<ConnectQuery>,
     axum::extract::ConnectInfo(addr): axum::extract::ConnectInfo<std::net::SocketAddr>,
     headers: axum::http::HeaderMap,
 ) -> impl IntoResponse {
-    let token_key = match extract_token_from_request(&state.token, &query, &headers) {
-        Ok(t) => t,
+    let authorization = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    let authenticated = match extract_connect_token(
+        authorization,
+        query.access_token.as_deref(),
+        query.token.as_deref(),
+    ) {
+        Ok(raw) => state.gateway.authenticate(&raw).await,
+        Err(e) => Err(e),
+    };
+    let (token_key, token) = match authenticated {
+        Ok(pair) => pair,
         Err(e) => {
             return (
                 StatusCode::UNAUTHORIZED,
@@ -304,483 +314,189 @@ async fn ws_handler(
         }
     };
 
+    let info = ConnectInfo {
+        token: Some(token),
+        token_key,
+        remote_addr: addr.to_string(),
+        transport: transport::WEBSOCKET.to_string(),
+    };
     ws.protocols(["tokilake.v1"])
-        .on_upgrade(move |socket| handle_ws_connection(socket, state, token_key, addr.to_string()))
+        .on_upgrade(move |socket| handle_ws_connection(socket, state, info))
 }
 
-fn extract_token_from_request(
-    expected: &str,
-    query: &ConnectQuery,
-    headers: &axum::http::HeaderMap,
-) -> Result<String, TunnelError> {
-    // Try Authorization header first
-    if let Some(auth) = headers.get("authorization") {
-        if let Ok(auth_str) = auth.to_str() {
-            let auth_str = auth_str.trim();
-            let token = if auth_str.to_lowercase().starts_with("bearer ") {
-                auth_str[7..].trim()
-            } else {
-                auth_str
-            };
-            if !token.is_empty() {
-                let token = token.strip_prefix("sk-").unwrap_or(token);
-                let expected = expected.strip_prefix("sk-").unwrap_or(expected);
-                if token == expected {
-                    return Ok(token.to_string());
-                }
-            }
-        }
-    }
-
-    // Try query parameters
-    let token = query
-        .token
-        .as_deref()
-        .or(query.access_token.as_deref())
-        .unwrap_or("");
-
-    let token = token.trim();
-    let token = token.strip_prefix("sk-").unwrap_or(token);
-    let expected = expected.strip_prefix("sk-").unwrap_or(expected);
-
-    if token.is_empty() || token != expected {
-        return Err(TunnelError::auth_failed("invalid token"));
-    }
-
-    Ok(token.to_string())
-}
-
-async fn handle_ws_connection(
+async fn handle_ws_connection<A: Authenticator, R: WorkerRegistry>(
     socket: WebSocket,
-    state: AppState,
-    token_key: String,
-    remote_addr: String,
+    state: AppState<A, R>,
+    info: ConnectInfo,
 ) {
-    let session = state.session_manager.new_session(
-        None,
-        token_key.clone(),
-        remote_addr.clone(),
-        "websocket".to_string(),
-    );
-
-    let result = serve_session(&state, &session, socket).await;
-
-    if let Err(e) = &result {
-        warn!("session error: {}", e);
-    }
-
-    // Cleanup
-    let session_guard = session.read().await;
-    let worker_id = session_guard
-        .worker_info
-        .as_ref()
-        .map_or(0, |i| i.worker_id);
-    let _ = state.registry.cleanup_worker(worker_id);
-    state.session_manager.release(&session_guard).await;
-    drop(session_guard);
-}
-
-async fn serve_session(
-    state: &AppState,
-    session: &Arc<RwLock<GatewaySession<tokilake_smux::Session>>>,
-    socket: WebSocket,
-) -> Result<(), TunnelError> {
     let (mut ws_sender, mut ws_receiver) = socket.split();
 
-    // Create channels for WebSocket I/O
-    let (ws_out_tx, mut ws_out_rx) = mpsc::channel::<Vec<u8>>(32);
-    let (ws_in_tx, ws_in_rx) = mpsc::channel::<Vec<u8>>(32);
+    // Pump binary WebSocket messages to and from the smux byte stream
+    let (ws_out_tx, mut ws_out_rx) = mpsc::channel::<Bytes>(32);
+    let (ws_in_tx, ws_in_rx) = mpsc::channel::<Bytes>(32);
 
-    // Spawn task to forward outgoing WebSocket messages
-    tokio::spawn(async move {
+    let writer = tokio::spawn(async move {
         while let Some(data) = ws_out_rx.recv().await {
-            if ws_sender.send(Message::Binary(data.into())).await.is_err() {
+            if ws_sender.send(Message::Binary(data)).await.is_err() {
                 break;
             }
         }
+        let _ = ws_sender.close().await;
     });
 
-    // Spawn task to forward incoming WebSocket messages
-    let ws_in_tx_clone = ws_in_tx.clone();
-    tokio::spawn(async move {
+    let reader = tokio::spawn(async move {
         while let Some(msg) = ws_receiver.next().await {
             match msg {
-                Ok(Message::Binary(data)) => {
-                    if ws_in_tx_clone.send(data.into()).await.is_err() {
-                        break;
-                    }
+                Ok(Message::Binary(data)) if ws_in_tx.send(data.clone()).await.is_err() => {
+                    break;
                 }
-                Ok(Message::Close(_)) => break,
-                Err(_) => break,
+                Ok(Message::Close(_)) | Err(_) => break,
                 _ => {}
             }
         }
-        // Drop the sender to signal EOF
-        drop(ws_in_tx_clone);
     });
 
-    // Create smux session over WebSocket stream
-    let ws_stream = WebSocketStream::new(ws_in_rx, ws_out_tx.clone());
     let smux_config = tokilake_smux::Config {
         version: 1,
         keep_alive_disabled: true,
         ..Default::default()
     };
+    let smux_session =
+        tokilake_smux::Session::server(ChannelIo::new(ws_in_rx, ws_out_tx), smux_config);
+
+    // Errors are logged by the gateway
+    let _ = state
+        .gateway
+        .serve(&state.session_manager, smux_session, info)
+        .await;
+
+    reader.abort();
+    // The writer drains and closes once the smux session drops its stream
+    let _ = writer.await;
+}
 
-    let smux_session = tokilake_smux::Session::server(ws_stream, smux_config);
-    let smux_session = Arc::new(tokio::sync::Mutex::new(smux_session));
+#[derive(Deserialize)]
+struct RelayQuery {
+    namespace: Option<String>,
+    /// Selects a worker for requests without a body, e.g. video task lookups.
+    model:     Option<String>,
+}
 
-    // Store control channel and smux session in session
-    {
-        let mut s = session.write().await;
-        s.control_tx = Some(ws_out_tx.clone());
-        s.tunnel_session = Some(smux_session.clone());
-        // Mark as authenticated since WebSocket auth is done at the HTTP level
-        s.authenticated = true;
-    }
+/// Largest request body accepted on the relay routes; audio and image uploads
+/// easily exceed axum's 2 MB default.
+const MAX_RELAY_BODY_SIZE: usize = 64 * 1024 * 1024;
 
-    // Accept control stream (first stream)
-    let control_stream = {
-        let mut smux = smux_session.lock().await;
-        match smux.accept().await {
-            Some(stream) => stream,
-            None => {
-                return Err(TunnelError::StreamClosed);
-            }
-        }
+/// Model name and stream flag read from a relay request body. The body itself
+/// is forwarded unchanged.
+struct RelayBody {
+    model:     String,
+    is_stream: bool,
+}
+
+/// Read `model` and `stream` from a JSON body, or the `model` field from a
+/// `multipart/form-data` body. Other content types carry neither.
+async fn inspect_body(content_type: &str, body: &Bytes) -> Result<RelayBody, String> {
+    let mut relay_body = RelayBody {
+        model:     String::new(),
+        is_stream: false,
     };
+    if body.is_empty() {
+        return Ok(relay_body);
+    }
 
-    let mut control_stream = control_stream;
-
-    // WebSocket connections are already authenticated via the Authorization header
-    let mut authenticated = true;
-    let mut worker_registered = false;
-    let mut worker_id = 0;
-
-    // Main control message loop - read directly from stream
-    let mut control_buf = Vec::new();
-    loop {
-        // Read data from stream
-        let mut read_buf = vec![0u8; 4096];
-        let n = match control_stream.read(&mut read_buf).await {
-            Ok(0) => {
-                info!("control stream closed");
-                break;
-            }
-            Ok(n) => n,
-            Err(e) => {
-                warn!("control stream error: {}", e);
+    if let Ok(boundary) = multer::parse_boundary(content_type) {
+        let data = body.clone();
+        let mut multipart = multer::Multipart::new(
+            futures_util::stream::once(async move { Ok::<_, std::io::Error>(data) }),
+            boundary,
+        );
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|e| format!("invalid multipart body: {}", e))?
+        {
+            if field.name() == Some("model") {
+                relay_body.model = field
+                    .text()
+                    .await
+                    .map_err(|e| format!("invalid multipart body: {}", e))?
+                    .trim()
+                    .to_string();
                 break;
             }
-        };
-        control_buf.extend_from_slice(&read_buf[..n]);
-
-        // Parse complete messages from buffer
-        while let Some(pos) = control_buf.iter().position(|&b| b == b'\n') {
-            let line = control_buf[..pos].to_vec();
-            control_buf.drain(..=pos);
-
-            let trimmed = String::from_utf8_lossy(&line);
-            let trimmed = trimmed.trim();
-            if trimmed.is_empty() {
-                continue;
-            }
-
-            let msg = match serde_json::from_str::<ControlMessage>(trimmed) {
-                Ok(m) => {
-                    info!("received control message: type={}", m.msg_type);
-                    m
-                }
-                Err(e) => {
-                    warn!("control message parse error: {}", e);
-                    continue;
-                }
-            };
-
-            let response = handle_control_message(
-                ControlMessageContext {
-                    token: &state.token,
-                    registry: &state.registry,
-                    session_manager: &state.session_manager,
-                    session,
-                    authenticated: &mut authenticated,
-                    worker_registered: &mut worker_registered,
-                    worker_id: &mut worker_id,
-                },
-                &msg,
-            )
-            .await;
-
-            if let Some(resp) = response {
-                let resp_data = serde_json::to_vec(&resp).unwrap();
-                let mut resp_with_newline = resp_data;
-                resp_with_newline.push(b'\n');
-                if control_stream.write_all(&resp_with_newline).await.is_err() {
-                    break;
-                }
-            }
         }
+        return Ok(relay_body);
     }
 
-    Ok(())
-}
-
-struct ControlMessageContext<'a, T: TunnelSession> {
-    pub token:             &'a str,
-    pub registry:          &'a MemoryWorkerRegistry,
-    pub session_manager:   &'a SessionManager<T>,
-    pub session:           &'a Arc<RwLock<GatewaySession<T>>>,
-    pub authenticated:     &'a mut bool,
-    pub worker_registered: &'a mut bool,
-    pub worker_id:         &'a mut i32,
+    let is_json = content_type.is_empty()
+        || content_type
+            .to_ascii_lowercase()
+            .starts_with("application/json");
+    if is_json {
+        let value: serde_json::Value =
+            serde_json::from_slice(body).map_err(|e| format!("invalid JSON body: {}", e))?;
+        relay_body.model = value
+            .get("model")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string();
+        relay_body.is_stream = value
+            .get("stream")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+    }
+    Ok(relay_body)
 }
 
-async fn handle_control_message<T: TunnelSession>(
-    ctx: ControlMessageContext<'_, T>,
-    msg: &ControlMessage,
-) -> Option<ControlMessage> {
-    let request_id = msg.request_id.clone().unwrap_or_default();
-
-    match msg.msg_type.as_str() {
-        control_type::AUTH => {
-            if *ctx.authenticated {
-                return Some(ControlMessage::error_msg(
-                    request_id,
-                    ErrorMessage::new("auth_already_completed", "auth message already handled"),
-                ));
-            }
-
-            let auth = match &msg.auth {
-                Some(a) => a,
-                None => {
-                    return Some(ControlMessage::error_msg(
-                        request_id,
-                        ErrorMessage::new("auth_payload_missing", "auth payload is required"),
-                    ));
-                }
-            };
-
-            let auth_token = auth.token.trim();
-            let auth_token = auth_token.strip_prefix("sk-").unwrap_or(auth_token);
-            let expected = ctx.token.strip_prefix("sk-").unwrap_or(ctx.token);
-
-            if auth_token != expected {
-                return Some(ControlMessage::error_msg(
-                    request_id,
-                    ErrorMessage::new("auth_failed", "invalid token"),
-                ));
-            }
-
-            *ctx.authenticated = true;
-            ctx.session.write().await.authenticated = true;
-
-            Some(ControlMessage::ack(request_id, AckMessage {
-                message:    "auth_ok".to_string(),
-                namespace:  String::new(),
-                worker_id:  0,
-                channel_id: 0,
-            }))
-        }
-
-        control_type::REGISTER => {
-            if !*ctx.authenticated {
-                return Some(ControlMessage::error_msg(
-                    request_id,
-                    ErrorMessage::new("not_authenticated", "authentication is required"),
-                ));
-            }
-
-            if *ctx.worker_registered {
-                return Some(ControlMessage::error_msg(
-                    request_id,
-                    ErrorMessage::new(
-                        "register_already_completed",
-                        "register message already handled",
-                    ),
-                ));
-            }
-
-            let register = match &msg.register {
-                Some(r) => r,
-                None => {
-                    return Some(ControlMessage::error_msg(
-                        request_id,
-                        ErrorMessage::new(
-                            "register_payload_missing",
-                            "register payload is required",
-                        ),
-                    ));
-                }
-            };
-
-            match ctx.registry.register_worker(
-                &register.namespace,
-                &register.models,
-                &register.group,
-                &register.backend_type,
-            ) {
-                Ok(result) => {
-                    *ctx.worker_registered = true;
-                    *ctx.worker_id = result.worker_id;
-
-                    ctx.session_manager
-                        .bind_channel(ctx.session, ChannelBindParams {
-                            worker_id:    result.worker_id,
-                            channel_id:   result.channel_id,
-                            group:        result.group.clone(),
-                            models:       result.models.clone(),
-                            backend_type: result.backend_type.clone(),
-                            status:       result.status,
-                            namespace:    result.namespace.clone(),
-                        })
-                        .await;
-
-                    let _ = ctx
-                        .session_manager
-                        .claim_namespace(ctx.session, &result.namespace)
-                        .await;
-
-                    info!(
-                        "worker registered: id={} namespace={}",
-                        result.worker_id, result.namespace
-                    );
-
-                    Some(ControlMessage::ack(request_id, AckMessage {
-                        message:    "register_ok".to_string(),
-                        namespace:  result.namespace,
-                        worker_id:  result.worker_id,
-                        channel_id: result.channel_id,
-                    }))
-                }
-                Err(e) => Some(ControlMessage::error_msg(
-                    request_id,
-                    ErrorMessage::new("register_failed", e.to_string()),
-                )),
-            }
-        }
-
-        control_type::HEARTBEAT => {
-            if !*ctx.authenticated {
-                return Some(ControlMessage::error_msg(
-                    request_id,
-                    ErrorMessage::new("not_authenticated", "authentication is required"),
-                ));
-            }
-
-            if !*ctx.worker_registered {
-                return Some(ControlMessage::error_msg(
-                    request_id,
-                    ErrorMessage::new("not_registered", "register is required before heartbeat"),
-                ));
-            }
-
-            let heartbeat = match &msg.heartbeat {
-                Some(h) => h,
-                None => {
-                    return Some(ControlMessage::error_msg(
-                        request_id,
-                        ErrorMessage::new(
-                            "heartbeat_payload_missing",
-                            "heartbeat payload is required",
-                        ),
-                    ));
-                }
-            };
-
-            let _ = ctx
-                .registry
-                .update_heartbeat(*ctx.worker_id, &heartbeat.current_models);
-
-            let s = ctx.session.read().await;
-            Some(ControlMessage::ack(request_id, AckMessage {
-                message:    "heartbeat_ok".to_string(),
-                namespace:  s
-                    .worker_info
-                    .as_ref()
-                    .map_or(String::new(), |i| i.namespace.clone()),
-                worker_id:  s.worker_info.as_ref().map_or(0, |i| i.worker_id),
-                channel_id: s.worker_info.as_ref().map_or(0, |i| i.channel_id),
-            }))
-        }
-
-        control_type::MODELS_SYNC => {
-            if !*ctx.authenticated {
-                return Some(ControlMessage::error_msg(
-                    request_id,
-                    ErrorMessage::new("not_authenticated", "authentication is required"),
-                ));
-            }
-
-            if !*ctx.worker_registered {
-                return Some(ControlMessage::error_msg(
-                    request_id,
-                    ErrorMessage::new("not_registered", "register is required before models_sync"),
-                ));
-            }
-
-            let _sync = match &msg.models_sync {
-                Some(s) => s,
-                None => {
-                    return Some(ControlMessage::error_msg(
-                        request_id,
-                        ErrorMessage::new(
-                            "models_payload_missing",
-                            "models_sync payload is required",
-                        ),
-                    ));
-                }
-            };
-
-            let s = ctx.session.read().await;
-            Some(ControlMessage::ack(request_id, AckMessage {
-                message:    "models_sync_ok".to_string(),
-                namespace:  s
-                    .worker_info
-                    .as_ref()
-                    .map_or(String::new(), |i| i.namespace.clone()),
-                worker_id:  s.worker_info.as_ref().map_or(0, |i| i.worker_id),
-                channel_id: s.worker_info.as_ref().map_or(0, |i| i.channel_id),
-            }))
-        }
-
-        control_type::ACK => None,
+/// Relay an OpenAI-compatible request of route `kind` to a worker.
+async fn relay_handler<A: Authenticator, R: WorkerRegistry>(
+    kind: &'static str,
+    state: AppState<A, R>,
+    query: RelayQuery,
+    parts: axum::http::request::Parts,
+    body: Bytes,
+) -> axum::response::Response {
+    // Attached by `api_key::require_api_key`
+    let user_id = parts.extensions.get::<Token>().map_or(0, |t| t.user_id);
+    let client_key = parts.extensions.get::<ClientKey>().map_or(0, |k| k.0);
+    let header = |name: axum::http::header::HeaderName| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let content_type = header(axum::http::header::CONTENT_TYPE);
+    let accept = header(axum::http::header::ACCEPT);
 
-        control_type::ERROR => {
-            if let Some(err) = &msg.error {
-                warn!("tokiame error: code={} message={}", err.code, err.message);
-            }
-            None
+    let RelayBody { model, is_stream } = match inspect_body(&content_type, &body).await {
+        Ok(relay_body) => relay_body,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": e})),
+            )
+                .into_response();
         }
+    };
+    let model = match query.model {
+        Some(fallback) if model.is_empty() => fallback.trim().to_string(),
+        _ => model,
+    };
 
-        _ => {
-            if !*ctx.authenticated {
-                return Some(ControlMessage::error_msg(
-                    request_id,
-                    ErrorMessage::new("not_authenticated", "authentication is required"),
-                ));
-            }
-            Some(ControlMessage::error_msg(
-                request_id,
-                ErrorMessage::new(
-                    "unsupported_message_type",
-                    format!("unsupported message type: {}", msg.msg_type),
-                ),
-            ))
+    // `?namespace=` pins a worker; otherwise any worker serving the model
+    let target = match &query.namespace {
+        Some(namespace) => format!("namespace '{}'", namespace),
+        None if model.is_empty() => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": "model is required"})),
+            )
+                .into_response();
         }
-    }
-}
-
-#[derive(Deserialize)]
-struct ChatQuery {
-    namespace: Option<String>,
-}
-
-async fn chat_completions_handler(
-    State(state): State<AppState>,
-    Query(query): Query<ChatQuery>,
-    Json(body): Json<serde_json::Value>,
-) -> impl IntoResponse {
-    let namespace = query.namespace.unwrap_or_else(|| "test-worker".to_string());
+        None => format!("model '{}'", model),
+    };
 
     // Try SMUX session first, then QUIC
     enum ResolvedSession {
@@ -798,43 +514,71 @@ async fn chat_completions_handler(
         },
     }
 
-    let resolved = if let Some(session) = state.session_manager.get_by_namespace(&namespace) {
-        let g = session.read().await;
-        match &g.tunnel_session {
-            Some(s) => Some(ResolvedSession::Smux {
-                tunnel:     s.clone(),
-                session_id: g.id,
-                channel_id: g.worker_info.as_ref().map_or(0, |i| i.channel_id),
-                mgr:        state.session_manager.clone(),
-            }),
-            None => None,
-        }
-    } else if let Some(session) = state.quic_session_manager.get_by_namespace(&namespace) {
-        let g = session.read().await;
-        match &g.tunnel_session {
-            Some(s) => Some(ResolvedSession::Quic {
-                tunnel:     s.clone(),
-                session_id: g.id,
-                channel_id: g.worker_info.as_ref().map_or(0, |i| i.channel_id),
-                mgr:        state.quic_session_manager.clone(),
-            }),
-            None => None,
-        }
+    let namespace = query.namespace.as_deref();
+    let resolved = if let Some(picked) =
+        pick_session(&state.session_manager, &state.balancer, namespace, &model).await
+    {
+        Some((picked.namespace, ResolvedSession::Smux {
+            tunnel:     picked.tunnel,
+            session_id: picked.session_id,
+            channel_id: picked.channel_id,
+            mgr:        state.session_manager.clone(),
+        }))
     } else {
-        None
+        pick_session(
+            &state.quic_session_manager,
+            &state.balancer,
+            namespace,
+            &model,
+        )
+        .await
+        .map(|picked| {
+            (picked.namespace, ResolvedSession::Quic {
+                tunnel:     picked.tunnel,
+                session_id: picked.session_id,
+                channel_id: picked.channel_id,
+                mgr:        state.quic_session_manager.clone(),
+            })
+        })
     };
 
-    let resolved = match resolved {
+    let (namespace, resolved) = match resolved {
         Some(r) => r,
         None => {
             return (
                 StatusCode::BAD_GATEWAY,
-                Json(serde_json::json!({"error": format!("namespace '{}' is offline", namespace)})),
+                Json(serde_json::json!({"error": format!("{} is offline", target)})),
             )
                 .into_response();
         }
     };
 
+    // Admitted by prompt size once a worker is found, and corrected by
+    // `TokenCount` with the usage the response reports.
+    let prompt_tokens = estimate_request_tokens(&body);
+    if let Err(limited) = state.limiter.admit(client_key, &model, prompt_tokens) {
+        info!(
+            "relay {} user={} limited: {}",
+            kind, user_id, limited.reason
+        );
+        let mut response = api_key::openai_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            &limited.reason,
+            "requests",
+            "rate_limit_exceeded",
+        );
+        limited.apply(response.headers_mut());
+        return response;
+    }
+
+    let tokens = TokenCount {
+        limiter: state.limiter.clone(),
+        client_key,
+        model: model.clone(),
+        admitted: prompt_tokens,
+        tap: None,
+    };
+
     // Extract common fields and open data stream based on transport type
     let (session_id, channel_id) = match &resolved {
         ResolvedSession::Smux {
@@ -849,32 +593,33 @@ async fn chat_completions_handler(
         } => (*session_id, *channel_id),
     };
 
-    let model = body
-        .get("model")
-        .and_then(|v| v.as_str())
-        .unwrap_or("gpt-3.5-turbo")
-        .to_string();
-
-    let is_stream = body
-        .get("stream")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false);
-
     let request_id = format!("{}:relay:{}", namespace, uuid::Uuid::new_v4());
+    info!(
+        "relay {} user={} model={} request_id={}",
+        kind, user_id, model, request_id
+    );
+
+    let mut headers = HashMap::new();
+    if !content_type.is_empty() {
+        headers.insert("Content-Type".to_string(), content_type);
+    } else if !body.is_empty() {
+        headers.insert("Content-Type".to_string(), "application/json".to_string());
+    }
+    if !accept.is_empty() {
+        headers.insert("Accept".to_string(), accept);
+    } else if is_stream {
+        headers.insert("Accept".to_string(), "text/event-stream".to_string());
+    }
 
     let tunnel_req = TunnelRequest {
         request_id: request_id.clone(),
-        route_kind: route_kind::CHAT_COMPLETIONS.to_string(),
-        method: "POST".to_string(),
-        path: "/v1/chat/completions".to_string(),
+        route_kind: kind.to_string(),
+        method: parts.method.to_string(),
+        path: parts.uri.path().to_string(),
         model,
-        headers: {
-            let mut h = HashMap::new();
-            h.insert("Content-Type".to_string(), "application/json".to_string());
-            h
-        },
+        headers,
         is_stream,
-        body: serde_json::to_vec(&body).unwrap_or_default(),
+        body: body.to_vec(),
     };
 
     // Track the request — use the appropriate manager
@@ -886,6 +631,7 @@ async fn chat_completions_handler(
                 namespace: namespace.as_str().into(),
                 channel_id,
                 created_at: std::time::Instant::now(),
+                cancel: Default::default(),
             });
         }
         ResolvedSession::Quic { mgr, .. } => {
@@ -895,6 +641,7 @@ async fn chat_completions_handler(
                 namespace: namespace.as_str().into(),
                 channel_id,
                 created_at: std::time::Instant::now(),
+                cancel: Default::default(),
             });
         }
     }
@@ -907,12 +654,14 @@ async fn chat_completions_handler(
     // Open a data stream and send the request, then read the response
     match resolved {
         ResolvedSession::Smux { tunnel, mgr, .. } => {
+            // Cancels the request on the worker if the client goes away.
+            let pending = PendingRequest::new(mgr, &request_id);
             let mut data_stream = {
                 let mut smux = tunnel.lock().await;
                 match smux.open().await {
                     Some(stream) => stream,
                     None => {
-                        mgr.remove_request(&request_id);
+                        pending.finish();
                         return (
                             StatusCode::BAD_GATEWAY,
                             Json(serde_json::json!({"error": "failed to open data stream"})),
@@ -923,7 +672,7 @@ async fn chat_completions_handler(
             };
 
             if let Err(e) = data_stream.write_all(&req_with_newline).await {
-                mgr.remove_request(&request_id);
+                pending.finish();
                 return (
                     StatusCode::BAD_GATEWAY,
                     Json(serde_json::json!({"error": format!("failed to send request: {}", e)})),
@@ -931,9 +680,10 @@ async fn chat_completions_handler(
                     .into_response();
             }
 
-            relay_response(data_stream, tokio::io::sink(), &request_id, &*mgr).await
+            relay_response(data_stream, tokio::io::sink(), pending, tokens, is_stream).await
         }
         ResolvedSession::Quic { tunnel, mgr, .. } => {
+            let pending = PendingRequest::new(mgr, &request_id);
             let (send, recv) = {
                 let conn = {
                     let q = tunnel.lock().await;
@@ -943,7 +693,7 @@ async fn chat_completions_handler(
                 match conn.open_bi().await {
                     Ok(pair) => pair,
                     Err(e) => {
-                        mgr.remove_request(&request_id);
+                        pending.finish();
                         return (
                             StatusCode::BAD_GATEWAY,
                             Json(serde_json::json!({"error": format!("failed to open QUIC stream: {}", e)})),
@@ -956,7 +706,7 @@ async fn chat_completions_handler(
             let mut send = send;
 
             if let Err(e) = send.write_all(&req_with_newline).await {
-                mgr.remove_request(&request_id);
+                pending.finish();
                 return (
                     StatusCode::BAD_GATEWAY,
                     Json(
@@ -966,32 +716,76 @@ async fn chat_completions_handler(
                     .into_response();
             }
 
-            relay_response(recv, send, &request_id, &*mgr).await
+            relay_response(recv, send, pending, tokens, is_stream).await
+        }
+    }
+}
+
+/// A live worker session chosen for a request.
+struct PickedSession<T> {
+    tunnel:     Arc<tokio::sync::Mutex<T>>,
+    session_id: u64,
+    channel_id: i32,
+    namespace:  String,
+}
+
+/// Let `balancer` choose among the live sessions advertising `model`, within
+/// `namespace` if given. Without a model, any replica of the namespace will do.
+async fn pick_session<T: TunnelSession>(
+    manager: &SessionManager<T>,
+    balancer: &LoadBalancer,
+    namespace: Option<&str>,
+    model: &str,
+) -> Option<PickedSession<T>> {
+    let candidates = match namespace {
+        // Replicas of a namespace may serve different models.
+        Some(namespace) if !model.is_empty() => {
+            manager.get_by_namespace_and_model(namespace, model)
         }
+        Some(namespace) => manager.get_all_by_namespace(namespace),
+        None => manager.get_by_model(model),
+    };
+    let session = balancer.pick(manager, candidates).await?;
+    let g = session.read().await;
+    match (&g.tunnel_session, &g.worker_info) {
+        (Some(tunnel), Some(info)) => Some(PickedSession {
+            tunnel:     tunnel.clone(),
+            session_id: g.id,
+            channel_id: info.channel_id,
+            namespace:  info.namespace.clone(),
+        }),
+        _ => None,
     }
 }
 
+/// Read timeout for each tunnel response frame.
+const RESPONSE_FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// Common response relay logic — reads the tunnel response from a reader and
 /// builds the HTTP response. Works for both SMUX streams and QUIC streams.
+///
+/// Streaming requests are relayed chunk by chunk as they arrive; everything
+/// else is buffered until EOF. Either way the body is shown to `tokens`.
 async fn relay_response<R, W, T>(
     reader: R,
     writer: W,
-    request_id: &str,
-    session_manager: &SessionManager<T>,
+    pending: PendingRequest<T>,
+    mut tokens: TokenCount,
+    is_stream: bool,
 ) -> axum::response::Response
 where
-    R: tokio::io::AsyncRead + Unpin,
-    W: tokio::io::AsyncWrite + Unpin,
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+    W: tokio::io::AsyncWrite + Unpin + Send + 'static,
     T: TunnelSession,
 {
     let mut response_codec = tokilake_core::codec::TunnelCodec::new(reader, writer);
 
     // Read first response frame
     let first_frame =
-        match tokio::time::timeout(Duration::from_secs(30), response_codec.read_response()).await {
+        match tokio::time::timeout(RESPONSE_FRAME_TIMEOUT, response_codec.read_response()).await {
             Ok(Ok(Some(resp))) => resp,
             Ok(Ok(None)) => {
-                session_manager.remove_request(request_id);
+                pending.finish();
                 return (
                     StatusCode::BAD_GATEWAY,
                     Json(serde_json::json!({"error": "stream closed before response"})),
@@ -999,7 +793,7 @@ where
                     .into_response();
             }
             Ok(Err(e)) => {
-                session_manager.remove_request(request_id);
+                pending.cancel("read_error");
                 return (
                     StatusCode::BAD_GATEWAY,
                     Json(serde_json::json!({"error": format!("failed to read response: {}", e)})),
@@ -1007,7 +801,7 @@ where
                     .into_response();
             }
             Err(_) => {
-                session_manager.remove_request(request_id);
+                pending.cancel("timeout");
                 return (
                     StatusCode::BAD_GATEWAY,
                     Json(serde_json::json!({"error": "request timeout"})),
@@ -1018,7 +812,7 @@ where
 
     // Check for errors
     if let Some(err) = &first_frame.error {
-        session_manager.remove_request(request_id);
+        pending.finish();
         return (
             StatusCode::BAD_GATEWAY,
             Json(serde_json::json!({"error": err.message})),
@@ -1032,16 +826,47 @@ where
         StatusCode::from_u16(first_frame.status_code).unwrap_or(StatusCode::OK)
     };
 
+    let mut headers = axum::http::HeaderMap::new();
+    for (k, v) in &first_frame.headers {
+        if let (Ok(name), Ok(val)) = (
+            axum::http::HeaderName::from_bytes(k.as_bytes()),
+            axum::http::HeaderValue::from_str(v),
+        ) {
+            headers.insert(name, val);
+        }
+    }
+
+    if is_stream && !first_frame.eof {
+        // The body is re-framed by hyper as chunked.
+        headers.remove(axum::http::header::CONTENT_LENGTH);
+        headers.remove(axum::http::header::TRANSFER_ENCODING);
+        headers
+            .entry(axum::http::header::CONTENT_TYPE)
+            .or_insert(axum::http::HeaderValue::from_static("text/event-stream"));
+        headers.insert(
+            axum::http::header::CACHE_CONTROL,
+            axum::http::HeaderValue::from_static("no-cache"),
+        );
+
+        tokens.tap = headers
+            .get(axum::http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(UsageTap::for_content_type);
+        let body = stream_response_body(response_codec, first_frame.body_chunk.0, pending, tokens);
+        let mut response = (headers, body).into_response();
+        *response.status_mut() = status_code;
+        return response;
+    }
+
     // Collect body chunks until EOF
     let mut body = first_frame.body_chunk.0;
     if !first_frame.eof {
         loop {
-            match tokio::time::timeout(Duration::from_secs(30), response_codec.read_response())
-                .await
+            match tokio::time::timeout(RESPONSE_FRAME_TIMEOUT, response_codec.read_response()).await
             {
                 Ok(Ok(Some(frame))) => {
                     if let Some(err) = &frame.error {
-                        session_manager.remove_request(request_id);
+                        pending.finish();
                         return (
                             StatusCode::BAD_GATEWAY,
                             Json(serde_json::json!({"error": err.message})),
@@ -1055,7 +880,7 @@ where
                 }
                 Ok(Ok(None)) => break,
                 Ok(Err(e)) => {
-                    session_manager.remove_request(request_id);
+                    pending.cancel("read_error");
                     return (
                         StatusCode::BAD_GATEWAY,
                         Json(serde_json::json!({"error": format!("read response: {}", e)})),
@@ -1063,7 +888,7 @@ where
                         .into_response();
                 }
                 Err(_) => {
-                    session_manager.remove_request(request_id);
+                    pending.cancel("timeout");
                     return (
                         StatusCode::BAD_GATEWAY,
                         Json(serde_json::json!({"error": "request timeout"})),
@@ -1074,29 +899,167 @@ where
         }
     }
 
-    session_manager.remove_request(request_id);
-
-    // Build response
-    let mut headers = axum::http::HeaderMap::new();
-    for (k, v) in &first_frame.headers {
-        if let (Ok(name), Ok(val)) = (
-            axum::http::HeaderName::from_bytes(k.as_bytes()),
-            axum::http::HeaderValue::from_str(v),
-        ) {
-            headers.insert(name, val);
-        }
-    }
+    pending.finish();
+    tokens.tap = headers
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(UsageTap::for_content_type);
+    tokens.feed(&body);
 
     let mut response = (headers, body).into_response();
     *response.status_mut() = status_code;
     response
 }
 
+/// Tracked in-flight request. Unless [`PendingRequest::finish`]ed, dropping it
+/// cancels the request on the worker — which is what happens when the client
+/// disconnects and axum drops the handler or the streaming body.
+struct PendingRequest<T: TunnelSession> {
+    session_manager: Arc<SessionManager<T>>,
+    request_id:      String,
+    cancel_reason:   Option<&'static str>,
+}
+
+impl<T: TunnelSession> PendingRequest<T> {
+    fn new(session_manager: Arc<SessionManager<T>>, request_id: &str) -> Self {
+        Self {
+            session_manager,
+            request_id: request_id.to_string(),
+            cancel_reason: Some("client_disconnected"),
+        }
+    }
+
+    /// The worker is done with the request; only stop tracking it.
+    fn finish(mut self) {
+        self.cancel_reason = None;
+    }
+
+    /// Give up on the request and tell the worker why.
+    fn cancel(mut self, reason: &'static str) {
+        self.cancel_reason = Some(reason);
+    }
+}
+
+impl<T: TunnelSession> Drop for PendingRequest<T> {
+    fn drop(&mut self) {
+        let Some(reason) = self.cancel_reason else {
+            self.session_manager.remove_request(&self.request_id);
+            return;
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                let session_manager = self.session_manager.clone();
+                let request_id = std::mem::take(&mut self.request_id);
+                handle.spawn(async move {
+                    session_manager.cancel_request(&request_id, reason).await;
+                });
+            }
+            Err(_) => self.session_manager.remove_request(&self.request_id),
+        }
+    }
+}
+
+/// Settles the tokens a request was admitted with once its response is done
+/// with: to the usage the response reported, else to the prompt plus a token
+/// per streamed event.
+struct TokenCount {
+    limiter:    Arc<RateLimiter>,
+    client_key: u64,
+    model:      String,
+    /// Tokens the request was admitted with.
+    admitted:   i64,
+    /// `None` until the response turns out to be one that can carry usage.
+    tap:        Option<UsageTap>,
+}
+
+impl TokenCount {
+    fn feed(&mut self, data: &[u8]) {
+        if let Some(tap) = &mut self.tap {
+            tap.feed(data);
+        }
+    }
+}
+
+impl Drop for TokenCount {
+    fn drop(&mut self) {
+        let Some(tap) = &self.tap else {
+            return;
+        };
+        let used = tap.finish().map_or_else(
+            || self.admitted + tap.estimated_completion(),
+            |usage| usage.total_tokens,
+        );
+        self.limiter
+            .correct(self.client_key, &self.model, used - self.admitted);
+    }
+}
+
+/// Turn the remaining tunnel frames into a response body, one HTTP chunk per
+/// `body_chunk`. Errors after the headers are sent abort the response.
+fn stream_response_body<R, W, T>(
+    codec: tokilake_core::codec::TunnelCodec<R, W>,
+    first_chunk: Vec<u8>,
+    pending: PendingRequest<T>,
+    mut tokens: TokenCount,
+) -> axum::body::Body
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+    W: tokio::io::AsyncWrite + Unpin + Send + 'static,
+    T: TunnelSession,
+{
+    tokens.feed(&first_chunk);
+    let first = (!first_chunk.is_empty()).then(|| Ok(Bytes::from(first_chunk)));
+    let rest = futures_util::stream::unfold(Some((codec, pending, tokens)), |state| async move {
+        let (mut codec, pending, mut tokens) = state?;
+        loop {
+            let frame =
+                match tokio::time::timeout(RESPONSE_FRAME_TIMEOUT, codec.read_response()).await {
+                    Ok(Ok(Some(frame))) => frame,
+                    Ok(Ok(None)) => {
+                        pending.finish();
+                        return None;
+                    }
+                    Ok(Err(e)) => {
+                        pending.cancel("read_error");
+                        return Some((Err(std::io::Error::other(e)), None));
+                    }
+                    Err(_) => {
+                        pending.cancel("timeout");
+                        return Some((Err(std::io::Error::other("request timeout")), None));
+                    }
+                };
+            if let Some(err) = frame.error {
+                warn!("stream {} failed: {}", pending.request_id, err);
+                pending.finish();
+                return Some((Err(std::io::Error::other(err.message)), None));
+            }
+            if frame.body_chunk.is_empty() {
+                if frame.eof {
+                    pending.finish();
+                    return None;
+                }
+                continue;
+            }
+            tokens.feed(&frame.body_chunk);
+            let chunk = Ok(Bytes::from(frame.body_chunk.0));
+            if frame.eof {
+                pending.finish();
+                return Some((chunk, None));
+            }
+            return Some((chunk, Some((codec, pending, tokens))));
+        }
+    });
+    axum::body::Body::from_stream(futures_util::stream::iter(first).chain(rest))
+}
+
 // --------------------------------------------------------------------------
 // QUIC listener
 // --------------------------------------------------------------------------
 
-async fn run_quic_listener(bind_addr: &str, state: AppState) -> Result<(), anyhow::Error> {
+async fn run_quic_listener<A: Authenticator, R: WorkerRegistry>(
+    bind_addr: &str,
+    state: AppState<A, R>,
+) -> Result<(), anyhow::Error> {
     let (cert_der, key_der) = generate_self_signed_cert();
 
     let server_crypto = rustls::ServerConfig::builder()
@@ -1110,128 +1073,447 @@ async fn run_quic_listener(bind_addr: &str, state: AppState) -> Result<(), anyho
     let endpoint = quinn::Endpoint::server(server_config, bind_addr.parse()?)?;
     info!("QUIC listener started on {}", bind_addr);
 
-    while let Some(incoming) = endpoint.accept().await {
-        let state = state.clone();
+    state
+        .gateway
+        .serve_quic(endpoint, state.quic_session_manager.clone())
+        .await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokilake_core::{codec::TunnelCodec, session::ChannelBindParams};
+
+    fn frame(body: &str, eof: bool) -> TunnelResponse {
+        TunnelResponse {
+            request_id: "r1".to_string(),
+            status_code: 0,
+            headers: HashMap::new(),
+            body_chunk: BodyChunk(body.as_bytes().to_vec()),
+            eof,
+            error: None,
+        }
+    }
+
+    fn track(manager: &SessionManager<QuicSession>) {
+        manager.track_request(InFlightRequest {
+            request_id: "r1".into(),
+            session_id: 1,
+            namespace:  "ns".into(),
+            channel_id: 1,
+            created_at: std::time::Instant::now(),
+            cancel:     Default::default(),
+        });
+    }
+
+    fn tokens() -> TokenCount {
+        TokenCount {
+            limiter:    Arc::new(RateLimiter::default()),
+            client_key: 1,
+            model:      "m".to_string(),
+            admitted:   0,
+            tap:        None,
+        }
+    }
+
+    /// Connect a replica of `namespace` serving `model` that answers every
+    /// request with the JSON `body`.
+    async fn connect_worker(
+        manager: &SessionManager<tokilake_smux::Session>,
+        namespace: &str,
+        channel_id: i32,
+        model: &str,
+        body: &'static str,
+    ) {
+        let session = manager.new_session(None, String::new(), String::new(), String::new());
+        manager.claim_namespace(&session, namespace).await.unwrap();
+        manager
+            .bind_channel(&session, ChannelBindParams {
+                worker_id: channel_id,
+                channel_id,
+                namespace: namespace.to_string(),
+                group: String::new(),
+                models: vec![model.to_string()],
+                backend_type: String::new(),
+                status: 1,
+            })
+            .await;
+        let (gateway_io, worker_io) = tokio::io::duplex(64 * 1024);
+        let tunnel = tokilake_smux::Session::server(gateway_io, Default::default());
+        session.write().await.tunnel_session = Some(Arc::new(tokio::sync::Mutex::new(tunnel)));
+        let mut worker = tokilake_smux::Session::client(worker_io, Default::default());
         tokio::spawn(async move {
-            match incoming.await {
-                Ok(conn) => {
-                    let remote = conn.remote_address();
-                    info!("QUIC connection from {}", remote);
-                    handle_quic_connection(conn, state, remote.to_string()).await;
-                }
-                Err(e) => {
-                    warn!("QUIC accept error: {}", e);
-                }
+            while let Some(stream) = worker.accept().await {
+                let (r, w) = tokio::io::split(stream);
+                let mut codec = TunnelCodec::new(r, w);
+                let Ok(Some(request)) = codec.read_request().await else {
+                    continue;
+                };
+                let mut response = frame(body, true);
+                response.request_id = request.request_id;
+                response.status_code = 200;
+                response.headers =
+                    HashMap::from([("Content-Type".to_string(), "application/json".to_string())]);
+                codec.write_response(&response).await.unwrap();
             }
         });
     }
 
-    Ok(())
-}
+    #[tokio::test]
+    async fn test_relay_streams_chunks_as_they_arrive() {
+        let manager = Arc::new(SessionManager::<QuicSession>::new());
+        track(&manager);
+        let (gateway_side, worker_side) = tokio::io::duplex(64 * 1024);
+        let (release_tx, release_rx) = tokio::sync::oneshot::channel::<()>();
 
-async fn handle_quic_connection(conn: quinn::Connection, state: AppState, remote_addr: String) {
-    let quic_session = QuicSession::new(conn.clone());
-    let quic_session = Arc::new(tokio::sync::Mutex::new(quic_session));
+        tokio::spawn(async move {
+            let (r, w) = tokio::io::split(worker_side);
+            let mut codec = TunnelCodec::new(r, w);
+            let mut header = frame("", false);
+            header.status_code = 200;
+            header.headers = HashMap::from([
+                ("Content-Type".to_string(), "text/event-stream".to_string()),
+                ("Content-Length".to_string(), "999".to_string()),
+            ]);
+            codec.write_response(&header).await.unwrap();
+            codec
+                .write_response(&frame("data: 1\n\n", false))
+                .await
+                .unwrap();
+            // Hold the rest back until the client has seen the first chunk.
+            release_rx.await.unwrap();
+            codec
+                .write_response(&frame("data: [DONE]\n\n", false))
+                .await
+                .unwrap();
+            codec.write_response(&frame("", true)).await.unwrap();
+        });
 
-    let session = state.quic_session_manager.new_session(
-        None,
-        String::new(), // QUIC doesn't pass token via HTTP headers
-        remote_addr.clone(),
-        "quic".to_string(),
-    );
+        let (r, _w) = tokio::io::split(gateway_side);
+        let response = relay_response(
+            r,
+            tokio::io::sink(),
+            PendingRequest::new(manager.clone(), "r1"),
+            tokens(),
+            true,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[axum::http::header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+        assert!(response
+            .headers()
+            .get(axum::http::header::CONTENT_LENGTH)
+            .is_none());
+
+        let mut body = response.into_body().into_data_stream();
+        assert_eq!(body.next().await.unwrap().unwrap(), "data: 1\n\n");
+        assert!(manager.get_request("r1").is_some());
+        release_tx.send(()).unwrap();
+        assert_eq!(body.next().await.unwrap().unwrap(), "data: [DONE]\n\n");
+        assert!(body.next().await.is_none());
+        assert!(manager.get_request("r1").is_none());
+    }
 
-    // Store the QUIC session
-    {
-        let mut s = session.write().await;
-        s.tunnel_session = Some(quic_session.clone());
+    #[tokio::test]
+    async fn test_relay_buffers_non_streaming_response() {
+        let manager = Arc::new(SessionManager::<QuicSession>::new());
+        track(&manager);
+        let (gateway_side, worker_side) = tokio::io::duplex(64 * 1024);
+
+        tokio::spawn(async move {
+            let (r, w) = tokio::io::split(worker_side);
+            let mut codec = TunnelCodec::new(r, w);
+            let mut header = frame("", false);
+            header.status_code = 201;
+            codec.write_response(&header).await.unwrap();
+            codec
+                .write_response(&frame("{\"a\":", false))
+                .await
+                .unwrap();
+            codec.write_response(&frame("1}", false)).await.unwrap();
+            codec.write_response(&frame("", true)).await.unwrap();
+        });
+
+        let (r, _w) = tokio::io::split(gateway_side);
+        let response = relay_response(
+            r,
+            tokio::io::sink(),
+            PendingRequest::new(manager.clone(), "r1"),
+            tokens(),
+            false,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(manager.get_request("r1").is_none());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "{\"a\":1}");
     }
 
-    // Accept the first bidirectional stream as the control stream
-    let (mut send, mut recv) = match conn.accept_bi().await {
-        Ok(pair) => pair,
-        Err(e) => {
-            warn!("QUIC: failed to accept control stream: {}", e);
-            let session_guard = session.read().await;
-            state.quic_session_manager.release(&session_guard).await;
-            return;
+    #[tokio::test]
+    async fn test_client_disconnect_cancels_request_on_worker() {
+        let manager = Arc::new(SessionManager::<QuicSession>::new());
+        let session = manager.new_session(None, String::new(), String::new(), String::new());
+        manager.claim_namespace(&session, "ns").await.unwrap();
+        let (control_tx, mut control_rx) = mpsc::channel(1);
+        session.write().await.control_tx = Some(control_tx);
+        track(&manager);
+
+        let (gateway_side, worker_side) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let (r, w) = tokio::io::split(worker_side);
+            let mut codec = TunnelCodec::new(r, w);
+            codec.write_response(&frame("", false)).await.unwrap();
+            codec
+                .write_response(&frame("data: 1\n\n", false))
+                .await
+                .unwrap();
+            // Keep generating until cancelled.
+            std::future::pending::<()>().await;
+        });
+
+        let (r, _w) = tokio::io::split(gateway_side);
+        let response = relay_response(
+            r,
+            tokio::io::sink(),
+            PendingRequest::new(manager.clone(), "r1"),
+            tokens(),
+            true,
+        )
+        .await;
+        let mut body = response.into_body().into_data_stream();
+        assert_eq!(body.next().await.unwrap().unwrap(), "data: 1\n\n");
+        drop(body);
+
+        let msg = control_rx.recv().await.unwrap();
+        assert_eq!(msg.msg_type, control_type::CANCEL_REQUEST);
+        let cancel = msg.cancel_request.unwrap();
+        assert_eq!(cancel.target_request_id, "r1");
+        assert_eq!(cancel.reason, "client_disconnected");
+        assert!(manager.get_request("r1").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_namespace_requests_go_to_a_replica_serving_the_model() {
+        let manager = SessionManager::<tokilake_smux::Session>::new();
+        connect_worker(&manager, "ns", 1, "llama", "{}").await;
+        connect_worker(&manager, "ns", 2, "qwen", "{}").await;
+
+        let balancer = LoadBalancer::default();
+        for _ in 0..4 {
+            let picked = pick_session(&manager, &balancer, Some("ns"), "qwen").await;
+            assert_eq!(picked.unwrap().channel_id, 2);
         }
-    };
+        assert!(pick_session(&manager, &balancer, Some("ns"), "mistral")
+            .await
+            .is_none());
+        // Without a model, any replica of the namespace will do.
+        assert!(pick_session(&manager, &balancer, Some("ns"), "")
+            .await
+            .is_some());
+    }
 
-    let mut authenticated = false;
-    let mut worker_registered = false;
-    let mut worker_id = 0i32;
-
-    // Control message loop
-    let mut control_buf = Vec::new();
-    loop {
-        let mut read_buf = vec![0u8; 4096];
-        let n = match recv.read(&mut read_buf).await {
-            Ok(Some(n)) => n,
-            Ok(None) => {
-                info!("QUIC control stream closed");
-                break;
-            }
-            Err(e) => {
-                warn!("QUIC control stream error: {}", e);
-                break;
-            }
+    #[tokio::test]
+    async fn test_inspect_json_and_multipart_bodies() {
+        let json = Bytes::from_static(br#"{"model":"m1","stream":true}"#);
+        let relay_body = inspect_body("application/json", &json).await.unwrap();
+        assert_eq!(relay_body.model, "m1");
+        assert!(relay_body.is_stream);
+        assert!(inspect_body("", &Bytes::from_static(b"{")).await.is_err());
+
+        let multipart = Bytes::from_static(
+            b"--XyZ\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"a.wav\"\r\n\
+Content-Type: audio/wav\r\n\r\n\
+RIFF\x00\x01\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"model\"\r\n\r\n\
+whisper-1\r\n\
+--XyZ--\r\n",
+        );
+        let relay_body = inspect_body("multipart/form-data; boundary=XyZ", &multipart)
+            .await
+            .unwrap();
+        assert_eq!(relay_body.model, "whisper-1");
+        assert!(!relay_body.is_stream);
+
+        let speech = inspect_body("application/octet-stream", &Bytes::from_static(b"\x00"))
+            .await
+            .unwrap();
+        assert!(speech.model.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_relay_routes_are_rate_limited_per_key() {
+        use tower::ServiceExt;
+
+        // Two keys of the same user.
+        let auth = MemoryAuthenticator::new()
+            .with_token("sk-a", 1)
+            .with_token("sk-b", 1);
+        let mut state = AppState::new(
+            Gateway::new(auth, MemoryWorkerRegistry::new()),
+            LoadBalance::default(),
+        );
+        state.limiter = Arc::new(RateLimiter::new(RateLimits {
+            per_token: Limits {
+                rpm: Some(2),
+                tpm: Some(100),
+            },
+            ..Default::default()
+        }));
+        let app = relay_routes(state.clone()).with_state(state.clone());
+        let chat = |key: &str| {
+            axum::http::Request::post("/v1/chat/completions")
+                .header(axum::http::header::AUTHORIZATION, format!("Bearer {}", key))
+                .header(axum::http::header::CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(r#"{"model":"m"}"#))
+                .unwrap()
         };
-        control_buf.extend_from_slice(&read_buf[..n]);
 
-        while let Some(pos) = control_buf.iter().position(|&b| b == b'\n') {
-            let line = control_buf[..pos].to_vec();
-            control_buf.drain(..=pos);
+        // No worker serves the model, so nothing counts.
+        for _ in 0..3 {
+            let response = app.clone().oneshot(chat("sk-a")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        }
 
-            let trimmed = String::from_utf8_lossy(&line);
-            let trimmed = trimmed.trim();
-            if trimmed.is_empty() {
-                continue;
-            }
+        // The reported usage, not the tiny prompt, fills the token window.
+        let usage = r#"{"usage":{"prompt_tokens":20,"completion_tokens":130,"total_tokens":150}}"#;
+        connect_worker(&state.session_manager, "ns", 1, "m", usage).await;
+        let response = app.clone().oneshot(chat("sk-a")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let response = app.clone().oneshot(chat("sk-a")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["x-ratelimit-remaining-requests"], "1");
+        assert_eq!(response.headers()["x-ratelimit-remaining-tokens"], "0");
+        assert!(response
+            .headers()
+            .contains_key(axum::http::header::RETRY_AFTER));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"]["code"], "rate_limit_exceeded");
+
+        let response = app.oneshot(chat("sk-b")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
 
-            let msg = match serde_json::from_str::<ControlMessage>(trimmed) {
-                Ok(m) => {
-                    info!("QUIC control message: type={}", m.msg_type);
-                    m
-                }
-                Err(e) => {
-                    warn!("QUIC control message parse error: {}", e);
-                    continue;
-                }
-            };
+    #[tokio::test]
+    async fn test_uploads_do_not_count_as_tokens() {
+        use tower::ServiceExt;
 
-            let response = handle_control_message(
-                ControlMessageContext {
-                    token:             &state.token,
-                    registry:          &state.registry,
-                    session_manager:   &state.quic_session_manager,
-                    session:           &session,
-                    authenticated:     &mut authenticated,
-                    worker_registered: &mut worker_registered,
-                    worker_id:         &mut worker_id,
-                },
-                &msg,
+        let auth = MemoryAuthenticator::new().with_token("sk-a", 1);
+        let mut state = AppState::new(
+            Gateway::new(auth, MemoryWorkerRegistry::new()),
+            LoadBalance::default(),
+        );
+        state.limiter = Arc::new(RateLimiter::new(RateLimits {
+            per_token: Limits {
+                rpm: None,
+                tpm: Some(100),
+            },
+            ..Default::default()
+        }));
+        connect_worker(&state.session_manager, "ns", 1, "whisper", r#"{"text":""}"#).await;
+        let app = relay_routes(state.clone()).with_state(state);
+        let audio = vec![0u8; 64 * 1024];
+        let mut body = b"--b\r\nContent-Disposition: form-data; name=\"model\"\r\n\r\nwhisper\r\n\
+            --b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.wav\"\r\n\r\n"
+            .to_vec();
+        body.extend_from_slice(&audio);
+        body.extend_from_slice(b"\r\n--b--\r\n");
+        let request = axum::http::Request::post("/v1/audio/transcriptions")
+            .header(axum::http::header::AUTHORIZATION, "Bearer sk-a")
+            .header(
+                axum::http::header::CONTENT_TYPE,
+                "multipart/form-data; boundary=b",
             )
-            .await;
+            .body(axum::body::Body::from(body))
+            .unwrap();
 
-            if let Some(resp) = response {
-                let resp_data = serde_json::to_vec(&resp).unwrap();
-                let mut resp_with_newline = resp_data;
-                resp_with_newline.push(b'\n');
-                if send.write_all(&resp_with_newline).await.is_err() {
-                    break;
-                }
+        // Not limited, though 64 KiB of text would be.
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_model_list_needs_a_key() {
+        use tower::ServiceExt;
+
+        let auth = MemoryAuthenticator::new().with_token("sk-a", 1);
+        let state = AppState::new(
+            Gateway::new(auth, MemoryWorkerRegistry::new()),
+            LoadBalance::default(),
+        );
+        let app = relay_routes(state.clone()).with_state(state);
+        let get = |path: &str, key: Option<&str>| {
+            let mut request = axum::http::Request::get(path);
+            if let Some(key) = key {
+                request =
+                    request.header(axum::http::header::AUTHORIZATION, format!("Bearer {}", key));
             }
-        }
+            request.body(axum::body::Body::empty()).unwrap()
+        };
+        let json = |response: Response| async move {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let response = app.clone().oneshot(get("/v1/models", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .clone()
+            .oneshot(get("/v1/models", Some("sk-a")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let list = json(response).await;
+        assert_eq!(list, serde_json::json!({"object": "list", "data": []}));
+
+        // No worker is connected.
+        let response = app
+            .oneshot(get("/v1/models/org/llama", Some("sk-a")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let error = json(response).await;
+        assert_eq!(error["error"]["code"], "model_not_found");
+        assert!(error["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("org/llama"));
     }
 
-    // Cleanup
-    let session_guard = session.read().await;
-    let wid = session_guard
-        .worker_info
-        .as_ref()
-        .map_or(0, |i| i.worker_id);
-    let _ = state.registry.cleanup_worker(wid);
-    state.quic_session_manager.release(&session_guard).await;
-    drop(session_guard);
-    info!("QUIC worker disconnected: addr={}", remote_addr);
+    #[test]
+    fn test_every_route_kind_has_a_route() {
+        for &kind in route_kind::ALL {
+            let (method, path) = route_kind::http_route(kind).unwrap();
+            assert!(path.starts_with("/v1/"), "{}", kind);
+            assert_eq!(
+                method == "GET",
+                matches!(kind, route_kind::VIDEOS_GET | route_kind::VIDEOS_CONTENT)
+            );
+        }
+        assert_eq!(
+            route_kind::http_route(route_kind::AUDIO_TRANSCRIPTION),
+            Some(("POST", "/v1/audio/transcriptions"))
+        );
+        assert!(route_kind::http_route("unknown").is_none());
+        // Building the router panics on overlapping or malformed paths.
+        let gateway = Gateway::new(MemoryAuthenticator::new(), MemoryWorkerRegistry::new());
+        let _ = relay_routes(AppState::new(gateway, LoadBalance::default()));
+    }
 }
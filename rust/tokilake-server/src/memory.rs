@@ -0,0 +1,248 @@
+//! In-memory `Authenticator` and `WorkerRegistry`.
+//!
+//! Nothing survives a restart; use [`crate::sqlite::SqliteStore`] when tokens
+//! and worker IDs must be stable.
+
+use crate::normalize_models;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicI32, Ordering},
+};
+use tokilake_core::{
+    error::TunnelError,
+    gateway::{Authenticator, WorkerRegistry},
+    protocol::{RegisterResult, Token},
+};
+use tracing::info;
+
+/// Static token table: token key (without `sk-`) → user id.
+#[derive(Default)]
+pub struct MemoryAuthenticator {
+    tokens: HashMap<String, i64>,
+}
+
+impl MemoryAuthenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_token(mut self, token: &str, user_id: i64) -> Self {
+        let token = token.trim();
+        let key = token.strip_prefix("sk-").unwrap_or(token);
+        self.tokens.insert(key.to_string(), user_id);
+        self
+    }
+}
+
+impl Authenticator for MemoryAuthenticator {
+    async fn authenticate_token_key(
+        &self,
+        token_key: &str,
+    ) -> Result<(String, Token), TunnelError> {
+        match self.tokens.get(token_key) {
+            Some(&user_id) => Ok((token_key.to_string(), Token { user_id })),
+            None => Err(TunnelError::auth_failed("invalid token")),
+        }
+    }
+}
+
+struct WorkerEntry {
+    namespace:    String,
+    node_name:    String,
+    group:        String,
+    models:       Vec<String>,
+    backend_type: String,
+    status:       i32,
+}
+
+/// Worker registry keyed by worker id. IDs are never reused.
+pub struct MemoryWorkerRegistry {
+    next_id: AtomicI32,
+    workers: parking_lot::RwLock<HashMap<i32, WorkerEntry>>,
+}
+
+impl MemoryWorkerRegistry {
+    pub fn new() -> Self {
+        Self {
+            next_id: AtomicI32::new(1),
+            workers: parking_lot::RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl Default for MemoryWorkerRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WorkerRegistry for MemoryWorkerRegistry {
+    async fn register_worker(
+        &self,
+        _session_id: u64,
+        namespace: &str,
+        node_name: &str,
+        group: &str,
+        models: &[String],
+        backend_type: &str,
+    ) -> Result<RegisterResult, TunnelError> {
+        let namespace = namespace.trim();
+        if namespace.is_empty() {
+            return Err(TunnelError::protocol("namespace is required"));
+        }
+        let models = normalize_models(models);
+        if models.is_empty() {
+            return Err(TunnelError::protocol("at least one model is required"));
+        }
+
+        let worker_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = WorkerEntry {
+            namespace: namespace.to_string(),
+            node_name: node_name.trim().to_string(),
+            group: group.trim().to_string(),
+            models,
+            backend_type: backend_type.trim().to_string(),
+            status: 1,
+        };
+        let result = RegisterResult {
+            worker_id,
+            channel_id: worker_id,
+            namespace: entry.namespace.clone(),
+            group: entry.group.clone(),
+            models: entry.models.clone(),
+            backend_type: entry.backend_type.clone(),
+            status: entry.status,
+        };
+        self.workers.write().insert(worker_id, entry);
+
+        info!(
+            "worker registered: id={} namespace={} models={:?}",
+            worker_id, result.namespace, result.models
+        );
+        Ok(result)
+    }
+
+    async fn update_heartbeat(
+        &self,
+        worker_id: i32,
+        status: i32,
+        node_name: &str,
+        current_models: &[String],
+    ) -> Result<(), TunnelError> {
+        let mut workers = self.workers.write();
+        let entry = workers
+            .get_mut(&worker_id)
+            .ok_or_else(|| TunnelError::protocol("worker not registered"))?;
+        entry.status = if status == 0 { 1 } else { status };
+        if !node_name.trim().is_empty() {
+            entry.node_name = node_name.trim().to_string();
+        }
+        if !current_models.is_empty() {
+            entry.models = normalize_models(current_models);
+        }
+        Ok(())
+    }
+
+    async fn sync_models(
+        &self,
+        worker_id: i32,
+        group: &str,
+        models: &[String],
+        backend_type: &str,
+    ) -> Result<(), TunnelError> {
+        let models = normalize_models(models);
+        if models.is_empty() {
+            return Err(TunnelError::protocol("at least one model is required"));
+        }
+        let mut workers = self.workers.write();
+        let entry = workers
+            .get_mut(&worker_id)
+            .ok_or_else(|| TunnelError::protocol("worker not registered"))?;
+        entry.models = models;
+        if !group.trim().is_empty() {
+            entry.group = group.trim().to_string();
+        }
+        if !backend_type.trim().is_empty() {
+            entry.backend_type = backend_type.trim().to_string();
+        }
+        Ok(())
+    }
+
+    async fn cleanup_worker(&self, worker_id: i32) -> Result<(), TunnelError> {
+        if let Some(entry) = self.workers.write().remove(&worker_id) {
+            info!(
+                "worker removed: id={} namespace={} node={}",
+                worker_id, entry.namespace, entry.node_name
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn models(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_memory_authenticator() {
+        let auth = MemoryAuthenticator::new().with_token("sk-secret", 7);
+        let (key, token) = auth.authenticate_token_key("secret").await.unwrap();
+        assert_eq!(key, "secret");
+        assert_eq!(token.user_id, 7);
+        assert!(auth.authenticate_token_key("other").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_worker_ids_are_not_reused_after_cleanup() {
+        let registry = MemoryWorkerRegistry::new();
+        let a = registry
+            .register_worker(1, "a", "", "", &models(&["m"]), "")
+            .await
+            .unwrap();
+        let b = registry
+            .register_worker(2, "b", "", "", &models(&["m"]), "")
+            .await
+            .unwrap();
+        registry.cleanup_worker(a.worker_id).await.unwrap();
+        let c = registry
+            .register_worker(3, "c", "", "", &models(&["m"]), "")
+            .await
+            .unwrap();
+        assert_ne!(c.worker_id, b.worker_id);
+        assert_ne!(c.channel_id, b.channel_id);
+        assert!(registry.workers.read().contains_key(&b.worker_id));
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_and_sync_update_entry() {
+        let registry = MemoryWorkerRegistry::new();
+        let result = registry
+            .register_worker(1, "ns", "node", "default", &models(&["b", "a"]), "openai")
+            .await
+            .unwrap();
+        assert_eq!(result.models, models(&["a", "b"]));
+
+        registry
+            .update_heartbeat(result.worker_id, 2, "node-2", &models(&["c"]))
+            .await
+            .unwrap();
+        registry
+            .sync_models(result.worker_id, "vip", &models(&["d", "d"]), "")
+            .await
+            .unwrap();
+        {
+            let workers = registry.workers.read();
+            let entry = &workers[&result.worker_id];
+            assert_eq!(entry.status, 2);
+            assert_eq!(entry.node_name, "node-2");
+            assert_eq!(entry.group, "vip");
+            assert_eq!(entry.models, models(&["d"]));
+            assert_eq!(entry.backend_type, "openai");
+        }
+        assert!(registry.update_heartbeat(99, 1, "", &[]).await.is_err());
+    }
+}
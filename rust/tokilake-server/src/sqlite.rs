@@ -0,0 +1,458 @@
+//! SQLite-backed `Authenticator` and `WorkerRegistry`.
+//!
+//! Worker nodes are keyed by namespace and node name, so each replica of a
+//! namespace gets its own worker and channel id and keeps it when it
+//! reconnects. An empty node name falls back to the namespace. A replica whose
+//! node name is held by another connected worker is registered as `name#2`,
+//! `name#3`, ... instead, so no two live sessions share a row. Disconnected
+//! workers are marked offline rather than deleted.
+
+use crate::normalize_models;
+use rusqlite::OptionalExtension;
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokilake_core::{
+    error::TunnelError,
+    gateway::{Authenticator, WorkerRegistry},
+    protocol::{RegisterResult, Token},
+};
+use tracing::info;
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS tokens (
+    key        TEXT PRIMARY KEY,
+    user_id    INTEGER NOT NULL,
+    created_at INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS tokilake_worker_nodes (
+    id             INTEGER PRIMARY KEY AUTOINCREMENT,
+    namespace      TEXT NOT NULL,
+    node_name      TEXT NOT NULL DEFAULT '',
+    "group"        TEXT NOT NULL DEFAULT '',
+    models         TEXT NOT NULL DEFAULT '[]',
+    backend_type   TEXT NOT NULL DEFAULT '',
+    status         INTEGER NOT NULL DEFAULT 3,
+    last_heartbeat INTEGER NOT NULL DEFAULT 0,
+    created_at     INTEGER NOT NULL DEFAULT 0,
+    updated_at     INTEGER NOT NULL DEFAULT 0,
+    UNIQUE (namespace, node_name)
+);
+"#;
+
+const STATUS_ONLINE: i32 = 1;
+const STATUS_OFFLINE: i32 = 3;
+
+/// Shared SQLite connection. Clone it to use the same database as both
+/// authenticator and registry.
+#[derive(Clone)]
+pub struct SqliteStore {
+    conn: Arc<parking_lot::Mutex<rusqlite::Connection>>,
+    /// Session holding each registered worker row.
+    live: Arc<parking_lot::Mutex<HashMap<i32, u64>>>,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database at `path` and create missing tables.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_connection(rusqlite::Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    fn open_in_memory() -> anyhow::Result<Self> {
+        Self::from_connection(rusqlite::Connection::open_in_memory()?)
+    }
+
+    fn from_connection(conn: rusqlite::Connection) -> anyhow::Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Arc::new(parking_lot::Mutex::new(conn)),
+            live: Arc::default(),
+        })
+    }
+
+    /// Add a worker token (an `sk-` prefix is stripped) unless it exists.
+    pub fn insert_token(&self, token: &str, user_id: i64) -> anyhow::Result<()> {
+        let token = token.trim();
+        let key = token.strip_prefix("sk-").unwrap_or(token);
+        self.conn.lock().execute(
+            "INSERT OR IGNORE INTO tokens (key, user_id, created_at) VALUES (?1, ?2, ?3)",
+            rusqlite::params![key, user_id, unix_now()],
+        )?;
+        Ok(())
+    }
+
+    /// Run a blocking statement off the async runtime.
+    async fn call<T, F>(&self, f: F) -> Result<T, TunnelError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut rusqlite::Connection) -> Result<T, TunnelError> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock()))
+            .await
+            .map_err(|e| TunnelError::Other(e.into()))?
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn db_err(e: rusqlite::Error) -> TunnelError {
+    TunnelError::Other(e.into())
+}
+
+fn encode_models(models: &[String]) -> String {
+    serde_json::to_string(models).unwrap_or_else(|_| "[]".to_string())
+}
+
+/// `node_name`, or the first of `node_name#2`, `node_name#3`, ... whose row
+/// no other live session holds.
+fn free_node_name(
+    conn: &rusqlite::Connection,
+    live: &HashMap<i32, u64>,
+    session_id: u64,
+    namespace: &str,
+    node_name: &str,
+) -> Result<String, TunnelError> {
+    let mut candidate = node_name.to_string();
+    for n in 2.. {
+        let id: Option<i32> = conn
+            .query_row(
+                "SELECT id FROM tokilake_worker_nodes WHERE namespace = ?1 AND node_name = ?2",
+                [namespace, candidate.as_str()],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(db_err)?;
+        match id.and_then(|id| live.get(&id)) {
+            Some(&holder) if holder != session_id => candidate = format!("{}#{}", node_name, n),
+            _ => break,
+        }
+    }
+    Ok(candidate)
+}
+
+impl Authenticator for SqliteStore {
+    async fn authenticate_token_key(
+        &self,
+        token_key: &str,
+    ) -> Result<(String, Token), TunnelError> {
+        let key = token_key.to_string();
+        let user_id = self
+            .call(move |conn| {
+                conn.query_row("SELECT user_id FROM tokens WHERE key = ?1", [&key], |row| {
+                    row.get::<_, i64>(0)
+                })
+                .map_err(|e| match e {
+                    rusqlite::Error::QueryReturnedNoRows => {
+                        TunnelError::auth_failed("invalid token")
+                    }
+                    e => db_err(e),
+                })
+            })
+            .await?;
+        Ok((token_key.to_string(), Token { user_id }))
+    }
+}
+
+impl WorkerRegistry for SqliteStore {
+    async fn register_worker(
+        &self,
+        session_id: u64,
+        namespace: &str,
+        node_name: &str,
+        group: &str,
+        models: &[String],
+        backend_type: &str,
+    ) -> Result<RegisterResult, TunnelError> {
+        let namespace = namespace.trim().to_string();
+        if namespace.is_empty() {
+            return Err(TunnelError::protocol("namespace is required"));
+        }
+        let models = normalize_models(models);
+        if models.is_empty() {
+            return Err(TunnelError::protocol("at least one model is required"));
+        }
+        let node_name = match node_name.trim() {
+            "" => namespace.clone(),
+            name => name.to_string(),
+        };
+        let group = group.trim().to_string();
+        let backend_type = backend_type.trim().to_string();
+
+        let (worker_id, node_name) = {
+            let namespace = namespace.clone();
+            let models = encode_models(&models);
+            let group = group.clone();
+            let backend_type = backend_type.clone();
+            let live = self.live.clone();
+            self.call(move |conn| {
+                let mut live = live.lock();
+                let node_name = free_node_name(conn, &live, session_id, &namespace, &node_name)?;
+                let now = unix_now();
+                let worker_id = conn
+                    .query_row(
+                        r#"INSERT INTO tokilake_worker_nodes
+                            (namespace, node_name, "group", models, backend_type, status,
+                             last_heartbeat, created_at, updated_at)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7, ?7)
+                        ON CONFLICT(namespace, node_name) DO UPDATE SET
+                            "group" = excluded."group",
+                            models = excluded.models,
+                            backend_type = excluded.backend_type,
+                            status = excluded.status,
+                            last_heartbeat = excluded.last_heartbeat,
+                            updated_at = excluded.updated_at
+                        RETURNING id"#,
+                        rusqlite::params![
+                            namespace,
+                            node_name,
+                            group,
+                            models,
+                            backend_type,
+                            STATUS_ONLINE,
+                            now
+                        ],
+                        |row| row.get::<_, i32>(0),
+                    )
+                    .map_err(db_err)?;
+                live.insert(worker_id, session_id);
+                Ok((worker_id, node_name))
+            })
+            .await?
+        };
+
+        info!(
+            "worker registered: id={} namespace={} node={} models={:?}",
+            worker_id, namespace, node_name, models
+        );
+        Ok(RegisterResult {
+            worker_id,
+            channel_id: worker_id,
+            namespace,
+            group,
+            models,
+            backend_type,
+            status: STATUS_ONLINE,
+        })
+    }
+
+    async fn update_heartbeat(
+        &self,
+        worker_id: i32,
+        status: i32,
+        // The node name is part of the worker's key, so it is fixed at
+        // registration.
+        _node_name: &str,
+        current_models: &[String],
+    ) -> Result<(), TunnelError> {
+        let status = match status {
+            2 | STATUS_OFFLINE => status,
+            _ => STATUS_ONLINE,
+        };
+        let models = normalize_models(current_models);
+        let updated = self
+            .call(move |conn| {
+                let models = (!models.is_empty()).then(|| encode_models(&models));
+                conn.execute(
+                    "UPDATE tokilake_worker_nodes SET
+                        status = ?2,
+                        models = COALESCE(?3, models),
+                        last_heartbeat = ?4,
+                        updated_at = ?4
+                    WHERE id = ?1",
+                    rusqlite::params![worker_id, status, models, unix_now()],
+                )
+                .map_err(db_err)
+            })
+            .await?;
+        if updated == 0 {
+            return Err(TunnelError::protocol("worker not registered"));
+        }
+        Ok(())
+    }
+
+    async fn sync_models(
+        &self,
+        worker_id: i32,
+        group: &str,
+        models: &[String],
+        backend_type: &str,
+    ) -> Result<(), TunnelError> {
+        let models = normalize_models(models);
+        if models.is_empty() {
+            return Err(TunnelError::protocol("at least one model is required"));
+        }
+        let group = group.trim().to_string();
+        let backend_type = backend_type.trim().to_string();
+        let updated = self
+            .call(move |conn| {
+                conn.execute(
+                    r#"UPDATE tokilake_worker_nodes SET
+                        models = ?2,
+                        "group" = CASE WHEN ?3 = '' THEN "group" ELSE ?3 END,
+                        backend_type = CASE WHEN ?4 = '' THEN backend_type ELSE ?4 END,
+                        last_heartbeat = ?5,
+                        updated_at = ?5
+                    WHERE id = ?1"#,
+                    rusqlite::params![
+                        worker_id,
+                        encode_models(&models),
+                        group,
+                        backend_type,
+                        unix_now()
+                    ],
+                )
+                .map_err(db_err)
+            })
+            .await?;
+        if updated == 0 {
+            return Err(TunnelError::protocol("worker not registered"));
+        }
+        Ok(())
+    }
+
+    async fn cleanup_worker(&self, worker_id: i32) -> Result<(), TunnelError> {
+        let live = self.live.clone();
+        self.call(move |conn| {
+            live.lock().remove(&worker_id);
+            conn.execute(
+                "UPDATE tokilake_worker_nodes
+                    SET status = ?2, last_heartbeat = ?3, updated_at = ?3
+                WHERE id = ?1",
+                rusqlite::params![worker_id, STATUS_OFFLINE, unix_now()],
+            )
+            .map_err(db_err)
+        })
+        .await?;
+        info!("worker offline: id={}", worker_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn models(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn worker_row(store: &SqliteStore, id: i32) -> (i32, String, String) {
+        store
+            .conn
+            .lock()
+            .query_row(
+                "SELECT status, models, node_name FROM tokilake_worker_nodes WHERE id = ?1",
+                [id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_token_auth() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        store.insert_token("sk-secret", 42).unwrap();
+        let (_, token) = store.authenticate_token_key("secret").await.unwrap();
+        assert_eq!(token.user_id, 42);
+        assert!(matches!(
+            store.authenticate_token_key("nope").await,
+            Err(TunnelError::AuthFailed { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_worker_lifecycle_keeps_ids_per_namespace() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let a = store
+            .register_worker(1, "ns-a", "", "default", &models(&["m1"]), "openai")
+            .await
+            .unwrap();
+        let b = store
+            .register_worker(2, "ns-b", "", "default", &models(&["m1"]), "openai")
+            .await
+            .unwrap();
+        assert_ne!(a.worker_id, b.worker_id);
+        assert_eq!(worker_row(&store, a.worker_id).2, "ns-a");
+
+        store
+            .update_heartbeat(a.worker_id, 0, "gpu-1", &models(&["m2"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            worker_row(&store, a.worker_id),
+            (STATUS_ONLINE, r#"["m2"]"#.to_string(), "ns-a".to_string())
+        );
+
+        store.cleanup_worker(a.worker_id).await.unwrap();
+        assert_eq!(worker_row(&store, a.worker_id).0, STATUS_OFFLINE);
+
+        let again = store
+            .register_worker(3, "ns-a", "", "default", &models(&["m3"]), "")
+            .await
+            .unwrap();
+        assert_eq!(again.worker_id, a.worker_id);
+        assert_eq!(worker_row(&store, a.worker_id).0, STATUS_ONLINE);
+        assert!(store
+            .sync_models(999, "", &models(&["x"]), "")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_replicas_get_their_own_ids() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let a = store
+            .register_worker(1, "ns", "gpu-1", "", &models(&["m"]), "")
+            .await
+            .unwrap();
+        let b = store
+            .register_worker(2, "ns", "gpu-2", "", &models(&["m"]), "")
+            .await
+            .unwrap();
+        assert_ne!(a.channel_id, b.channel_id);
+
+        store.cleanup_worker(a.worker_id).await.unwrap();
+        assert_eq!(worker_row(&store, a.worker_id).0, STATUS_OFFLINE);
+        assert_eq!(worker_row(&store, b.worker_id).0, STATUS_ONLINE);
+
+        let again = store
+            .register_worker(3, "ns", "gpu-1", "", &models(&["m"]), "")
+            .await
+            .unwrap();
+        assert_eq!(again.worker_id, a.worker_id);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_unnamed_replicas_do_not_share_a_row() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let a = store
+            .register_worker(1, "ns", "", "", &models(&["m"]), "")
+            .await
+            .unwrap();
+        let b = store
+            .register_worker(2, "ns", "", "", &models(&["m"]), "")
+            .await
+            .unwrap();
+        assert_ne!(a.channel_id, b.channel_id);
+        assert_eq!(worker_row(&store, b.worker_id).2, "ns#2");
+
+        store.cleanup_worker(a.worker_id).await.unwrap();
+        assert_eq!(worker_row(&store, b.worker_id).0, STATUS_ONLINE);
+
+        // Freed names are handed out again before new ones.
+        let c = store
+            .register_worker(3, "ns", "", "", &models(&["m"]), "")
+            .await
+            .unwrap();
+        assert_eq!(c.worker_id, a.worker_id);
+    }
+}
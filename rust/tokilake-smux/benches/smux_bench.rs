@@ -101,8 +101,10 @@ fn bench_conn_smux_v1(c: &mut Criterion) {
 
     group.bench_function("smux_v1", |b| {
         b.to_async(&rt).iter_custom(|iters| async move {
-            let mut config = Config::default();
-            config.version = 1;
+            let config = Config {
+                version: 1,
+                ..Default::default()
+            };
             let (mut stream0, mut stream1) = get_smux_stream_pair(config).await;
 
             let iters_usize = iters as usize;
@@ -144,8 +146,10 @@ fn bench_conn_smux_v2(c: &mut Criterion) {
 
     group.bench_function("smux_v2", |b| {
         b.to_async(&rt).iter_custom(|iters| async move {
-            let mut config = Config::default();
-            config.version = 2;
+            let config = Config {
+                version: 2,
+                ..Default::default()
+            };
             let (mut stream0, mut stream1) = get_smux_stream_pair(config).await;
 
             let iters_usize = iters as usize;
@@ -21,6 +21,7 @@ use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     sync::{Mutex, Notify, mpsc},
 };
+use tokio_util::sync::CancellationToken;
 
 /// Default accept backlog.
 const DEFAULT_ACCEPT_BACKLOG: usize = 1024;
@@ -91,6 +92,8 @@ pub(crate) struct Shared {
     pub(crate) streams:           Mutex<HashMap<u32, StreamEntry>>,
     /// Session closed flag.
     pub(crate) is_closed:         std::sync::atomic::AtomicBool,
+    /// Cancelled on close so both loops drop their transport half.
+    pub(crate) die:               CancellationToken,
     /// Token bucket for session-level flow control (V2).
     pub(crate) bucket:            std::sync::atomic::AtomicI32,
     pub(crate) bucket_notify:     Notify,
@@ -159,6 +162,7 @@ impl Session {
         let shared = Arc::new(Shared {
             streams:           Mutex::new(HashMap::new()),
             is_closed:         std::sync::atomic::AtomicBool::new(false),
+            die:               CancellationToken::new(),
             bucket:            std::sync::atomic::AtomicI32::new(config.max_receive_buffer as i32),
             bucket_notify:     Notify::new(),
             last_receive_time: std::sync::atomic::AtomicU64::new(now_ms),
@@ -252,13 +256,19 @@ impl Session {
             .load(std::sync::atomic::Ordering::Acquire)
     }
 
-    /// Close the session.
+    /// Close the session. The transport is dropped once both loops exit.
     pub fn close(&self) {
-        self.shared
-            .is_closed
+        self.shared.shutdown();
+    }
+}
+
+impl Shared {
+    pub(crate) fn shutdown(&self) {
+        self.is_closed
             .store(true, std::sync::atomic::Ordering::Release);
+        self.die.cancel();
         // Wake up loops
-        self.shared.bucket_notify.notify_one();
+        self.bucket_notify.notify_one();
     }
 }
 
@@ -292,7 +302,11 @@ async fn recv_loop<R: AsyncRead + Unpin>(
         }
 
         // Read header
-        if let Err(e) = reader.read_exact(&mut hdr).await {
+        let read = tokio::select! {
+            read = reader.read_exact(&mut hdr) => read,
+            _ = shared.die.cancelled() => break,
+        };
+        if let Err(e) = read {
             tracing::debug!("recv: header read error: {e}");
             break;
         }
@@ -325,7 +339,11 @@ async fn recv_loop<R: AsyncRead + Unpin>(
         // Read payload
         let payload = if header.has_payload() {
             let mut buf = vec![0u8; header.payload_len()];
-            if let Err(e) = reader.read_exact(&mut buf).await {
+            let read = tokio::select! {
+                read = reader.read_exact(&mut buf) => read,
+                _ = shared.die.cancelled() => break,
+            };
+            if let Err(e) = read {
                 tracing::debug!("recv: payload read error: {e}");
                 break;
             }
@@ -382,10 +400,10 @@ async fn recv_loop<R: AsyncRead + Unpin>(
                     payload.len()
                 );
                 let mut streams = shared.streams.lock().await;
-                if let Some(entry) = streams.get(&header.stream_id) {
-                    if entry.data_tx.send(payload).await.is_err() {
-                        streams.remove(&header.stream_id);
-                    }
+                if let Some(entry) = streams.get(&header.stream_id)
+                    && entry.data_tx.send(payload).await.is_err()
+                {
+                    streams.remove(&header.stream_id);
                 }
             }
             CMD_UPD => {
@@ -428,9 +446,7 @@ async fn recv_loop<R: AsyncRead + Unpin>(
     tracing::debug!("recv: loop ended");
     let mut streams = shared.streams.lock().await;
     streams.clear();
-    shared
-        .is_closed
-        .store(true, std::sync::atomic::Ordering::Release);
+    shared.shutdown();
 }
 
 /// Write loop: drains write requests to the remote with priority shaping.
@@ -446,12 +462,9 @@ async fn write_loop<W: AsyncWrite + Unpin>(
     // Tick immediately so we don't delay first keepalive unnecessarily, but we can skip the first.
     interval.tick().await;
 
+    // Frames already queued are written before `die` is observed (biased
+    // select), so a final message sent right before `close()` still goes out.
     loop {
-        if shared.is_closed.load(std::sync::atomic::Ordering::Acquire) {
-            tracing::debug!("write: session closed, exiting");
-            break;
-        }
-
         let msg_opt;
 
         if !config.keep_alive_disabled {
@@ -459,6 +472,7 @@ async fn write_loop<W: AsyncWrite + Unpin>(
                 biased;
                 msg = ctrl_rx.recv() => msg_opt = msg,
                 msg = data_rx.recv() => msg_opt = msg,
+                _ = shared.die.cancelled() => break,
                 _ = interval.tick() => {
                     let now_ms = std::time::SystemTime::now()
                         .duration_since(std::time::UNIX_EPOCH)
@@ -471,7 +485,10 @@ async fn write_loop<W: AsyncWrite + Unpin>(
                         break;
                     }
                     tracing::debug!("write: NOP");
-                    if let Err(_) = write_frame(&mut writer, &Frame::nop(config.version)).await {
+                    if write_frame(&mut writer, &Frame::nop(config.version))
+                        .await
+                        .is_err()
+                    {
                         break;
                     }
                     // Continue to next loop without processing msg_opt
@@ -483,6 +500,7 @@ async fn write_loop<W: AsyncWrite + Unpin>(
                 biased;
                 msg = ctrl_rx.recv() => msg_opt = msg,
                 msg = data_rx.recv() => msg_opt = msg,
+                _ = shared.die.cancelled() => break,
             }
         }
 
@@ -512,9 +530,7 @@ async fn write_loop<W: AsyncWrite + Unpin>(
                 };
                 if let Err(e) = write_frame(&mut writer, &frame).await {
                     tracing::debug!("write: error: {e}");
-                    shared
-                        .is_closed
-                        .store(true, std::sync::atomic::Ordering::Release);
+                    shared.shutdown();
                     break;
                 }
             }
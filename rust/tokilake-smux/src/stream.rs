@@ -8,11 +8,12 @@ use crate::session::{Config, Shared, StreamShared, WriteRequest};
 use bytes::{Buf, Bytes};
 use std::sync::Arc;
 use tokio::sync::mpsc;
+use tokio_util::sync::{PollSender, ReusableBoxFuture};
 
 /// A multiplexed stream within a session.
 ///
-/// Implements `AsyncRead` for reading data delivered by the session's recv loop.
-/// Writes are sent as PSH frames through the session's write loop.
+/// Implements `AsyncRead` for reading data delivered by the session's recv loop
+/// and `AsyncWrite` for sending PSH frames through the session's write loop.
 pub struct Stream {
     /// Stream identifier.
     id:             u32,
@@ -22,6 +23,10 @@ pub struct Stream {
     ctrl_tx:        mpsc::Sender<WriteRequest>,
     /// Sender for data requests (low priority).
     data_tx:        mpsc::Sender<WriteRequest>,
+    /// Pollable clone of `data_tx` backing the `AsyncWrite` impl.
+    poll_data_tx:   PollSender<WriteRequest>,
+    /// Pending window-update wait of the `AsyncWrite` impl (V2).
+    window_wait:    Option<ReusableBoxFuture<'static, ()>>,
     /// Session shared state (for global bucket).
     session_shared: Arc<Shared>,
     /// Stream shared state (for window updates).
@@ -58,7 +63,9 @@ impl Stream {
             id,
             data_rx,
             ctrl_tx,
+            poll_data_tx: PollSender::new(data_tx.clone()),
             data_tx,
+            window_wait: None,
             session_shared,
             stream_shared,
             config,
@@ -265,7 +272,7 @@ impl Stream {
             };
             self.incr = 0;
 
-            if let Err(_) = self.ctrl_tx.try_send(req) {
+            if self.ctrl_tx.try_send(req).is_err() {
                 // If the channel is full, spawn a task to ensure the window update is delivered
                 let tx = self.ctrl_tx.clone();
                 let stream_id = self.id;
@@ -285,6 +292,26 @@ impl Stream {
     }
 }
 
+/// Dropping a stream without closing it sends FIN, so the peer stops writing
+/// to a stream nobody reads any more.
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if self.fin_sent {
+            return;
+        }
+        self.fin_sent = true;
+        let fin = WriteRequest::Fin { stream_id: self.id };
+        if let Err(tokio::sync::mpsc::error::TrySendError::Full(fin)) = self.ctrl_tx.try_send(fin)
+            && let Ok(handle) = tokio::runtime::Handle::try_current()
+        {
+            let tx = self.ctrl_tx.clone();
+            handle.spawn(async move {
+                let _ = tx.send(fin).await;
+            });
+        }
+    }
+}
+
 /// Implement `AsyncRead` so `Stream` can be used with tokio I/O utilities.
 impl tokio::io::AsyncRead for Stream {
     fn poll_read(
@@ -324,3 +351,135 @@ impl tokio::io::AsyncRead for Stream {
         }
     }
 }
+
+/// Implement `AsyncWrite` so `Stream` can be split and driven by tokio codecs.
+///
+/// Mirrors [`Stream::write`]: each call sends at most one PSH frame and, in V2,
+/// waits for the peer window to open before doing so.
+impl tokio::io::AsyncWrite for Stream {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        if this.fin_sent {
+            return std::task::Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "stream write closed",
+            )));
+        }
+
+        if buf.is_empty() {
+            return std::task::Poll::Ready(Ok(0));
+        }
+
+        let mut limit = crate::frame::MAX_PAYLOAD_SIZE;
+        if this.config.version == 2 {
+            loop {
+                if this
+                    .session_shared
+                    .is_closed
+                    .load(std::sync::atomic::Ordering::Acquire)
+                {
+                    this.window_wait = None;
+                    return std::task::Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "session closed",
+                    )));
+                }
+
+                let peer_consumed = this
+                    .stream_shared
+                    .peer_consumed
+                    .load(std::sync::atomic::Ordering::Acquire);
+                let peer_window = this
+                    .stream_shared
+                    .peer_window
+                    .load(std::sync::atomic::Ordering::Acquire);
+
+                let inflight = this.num_written.wrapping_sub(peer_consumed) as i32;
+                if inflight < 0 {
+                    this.window_wait = None;
+                    return std::task::Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "peer consumed more than sent",
+                    )));
+                }
+
+                let win = (peer_window as i32) - inflight;
+                if win > 0 {
+                    this.window_wait = None;
+                    limit = std::cmp::min(limit, win as usize);
+                    break;
+                }
+
+                // Wait for a window update (or for the session to go away).
+                let wait = this.window_wait.get_or_insert_with(|| {
+                    let stream_shared = this.stream_shared.clone();
+                    let data_tx = this.data_tx.clone();
+                    ReusableBoxFuture::new(async move {
+                        tokio::select! {
+                            _ = stream_shared.window_notify.notified() => {}
+                            _ = data_tx.closed() => {}
+                        }
+                    })
+                });
+                std::task::ready!(wait.poll(cx));
+                this.window_wait = None;
+            }
+        }
+
+        if std::task::ready!(this.poll_data_tx.poll_reserve(cx)).is_err() {
+            return std::task::Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "session closed",
+            )));
+        }
+
+        let to_write = std::cmp::min(buf.len(), limit);
+        let request = WriteRequest::Data {
+            stream_id: this.id,
+            data:      Bytes::copy_from_slice(&buf[..to_write]),
+        };
+        if this.poll_data_tx.send_item(request).is_err() {
+            return std::task::Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "session closed",
+            )));
+        }
+
+        this.num_written = this.num_written.wrapping_add(to_write as u32);
+        std::task::Poll::Ready(Ok(to_write))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        // Frames are flushed by the session's write loop.
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        if !self.fin_sent {
+            self.fin_sent = true;
+            let stream_id = self.id;
+            if self
+                .ctrl_tx
+                .try_send(WriteRequest::Fin { stream_id })
+                .is_err()
+            {
+                // Same fallback as window updates: deliver the FIN asynchronously.
+                let tx = self.ctrl_tx.clone();
+                tokio::spawn(async move {
+                    let _ = tx.send(WriteRequest::Fin { stream_id }).await;
+                });
+            }
+        }
+        std::task::Poll::Ready(Ok(()))
+    }
+}
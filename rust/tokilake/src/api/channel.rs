@@ -0,0 +1,399 @@
+//! `/api/channel` — upstream channels.
+//!
+//! `api_key` is write-only: responses only carry a masked form of it. Each
+//! channel comes with the state of its circuit breaker, once it has carried
+//! traffic. `model_mapping` is an object from the model names clients use to
+//! the ones the upstream knows; the channel serves its keys as well. Only
+//! tokens of the channel's `group` are routed to it.
+
+use super::{ApiError, ApiResult, AppState, ListQuery, Page, mask_secret};
+use crate::{
+    gateway::breaker::{Breakers, ChannelHealth},
+    model::{Channel, DEFAULT_GROUP},
+};
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+/// A channel as the management API shows it.
+#[derive(Debug, Serialize)]
+pub(super) struct ChannelView {
+    id:            u64,
+    name:          String,
+    provider:      String,
+    models:        String,
+    base_url:      Option<String>,
+    api_key:       Option<String>,
+    status:        i32,
+    weight:        i32,
+    /// Why the gateway disabled the channel.
+    status_reason: Option<String>,
+    model_mapping: BTreeMap<String, String>,
+    group:         String,
+    breaker:       Option<ChannelHealth>,
+}
+
+impl ChannelView {
+    fn new(channel: Channel, breakers: &Breakers) -> Self {
+        Self {
+            breaker:       breakers.health(channel.id),
+            model_mapping: channel.upstream_models().into_iter().collect(),
+            id:            channel.id,
+            name:          channel.name,
+            provider:      channel.provider,
+            models:        channel.models,
+            base_url:      channel.base_url,
+            api_key:       channel.api_key.as_deref().map(mask_secret),
+            status:        channel.status,
+            weight:        channel.weight,
+            status_reason: channel.status_reason,
+            group:         channel.group,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct CreateChannel {
+    name:          String,
+    #[serde(default = "default_provider")]
+    provider:      String,
+    models:        String,
+    base_url:      Option<String>,
+    api_key:       Option<String>,
+    #[serde(default = "default_status")]
+    status:        i32,
+    #[serde(default = "default_weight")]
+    weight:        i32,
+    #[serde(default)]
+    model_mapping: HashMap<String, String>,
+    #[serde(default = "default_group")]
+    group:         String,
+}
+
+/// Fields left out are kept; an empty `base_url`, `api_key` or
+/// `model_mapping` clears it.
+#[derive(Debug, Default, Deserialize)]
+pub(super) struct UpdateChannel {
+    name:          Option<String>,
+    provider:      Option<String>,
+    models:        Option<String>,
+    base_url:      Option<String>,
+    api_key:       Option<String>,
+    status:        Option<i32>,
+    weight:        Option<i32>,
+    model_mapping: Option<HashMap<String, String>>,
+    group:         Option<String>,
+}
+
+fn default_provider() -> String {
+    "openai".to_string()
+}
+
+fn default_status() -> i32 {
+    Channel::STATUS_ENABLED
+}
+
+fn default_weight() -> i32 {
+    1
+}
+
+fn default_group() -> String {
+    DEFAULT_GROUP.to_string()
+}
+
+/// Operators switch channels between enabled and disabled; auto-disabling
+/// is left to the gateway.
+fn check_status(status: i32) -> ApiResult<()> {
+    match status {
+        Channel::STATUS_ENABLED | Channel::STATUS_DISABLED => Ok(()),
+        _ => Err(ApiError::bad_request(format!(
+            "status must be {} (enabled) or {} (disabled)",
+            Channel::STATUS_ENABLED,
+            Channel::STATUS_DISABLED
+        ))),
+    }
+}
+
+fn required(field: &str, value: &str) -> ApiResult<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(ApiError::bad_request(format!(
+            "{} must not be empty",
+            field
+        )));
+    }
+    Ok(value.to_string())
+}
+
+/// `None` for an empty string, so it can clear an optional column.
+fn optional(value: Option<String>) -> Option<String> {
+    value
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// The mapping as stored, `None` if it renames nothing.
+fn model_mapping(mapping: HashMap<String, String>) -> ApiResult<Option<String>> {
+    let mut stored = BTreeMap::new();
+    for (model, upstream) in mapping {
+        let model = required("model_mapping key", &model)?;
+        let upstream = required(&format!("model_mapping[{}]", model), &upstream)?;
+        stored.insert(model, upstream);
+    }
+    if stored.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(
+        serde_json::to_string(&stored).expect("a map of strings is JSON"),
+    ))
+}
+
+fn matches(channel: &Channel, query: &ListQuery, keyword: Option<&str>) -> bool {
+    query.status.is_none_or(|status| channel.status == status)
+        && keyword.is_none_or(|keyword| {
+            [&channel.name, &channel.provider, &channel.models]
+                .iter()
+                .any(|field| field.to_lowercase().contains(keyword))
+        })
+}
+
+async fn find(state: &AppState, id: u64) -> ApiResult<Channel> {
+    let mut db = state.db.clone();
+    Channel::filter(Channel::fields().id().eq(id))
+        .first()
+        .exec(&mut db)
+        .await?
+        .ok_or_else(|| ApiError::not_found("channel", id))
+}
+
+pub(super) async fn list(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ListQuery>,
+) -> ApiResult<Json<Page<ChannelView>>> {
+    let mut db = state.db.clone();
+    let keyword = query.keyword();
+    let mut channels: Vec<Channel> = Channel::all()
+        .exec(&mut db)
+        .await?
+        .into_iter()
+        .filter(|c| matches(c, &query, keyword.as_deref()))
+        .collect();
+    channels.sort_by_key(|c| c.id);
+    let views = channels
+        .into_iter()
+        .map(|c| ChannelView::new(c, &state.breakers))
+        .collect();
+    Ok(Json(Page::of(views, &query)))
+}
+
+pub(super) async fn get(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> ApiResult<Json<ChannelView>> {
+    Ok(Json(ChannelView::new(
+        find(&state, id).await?,
+        &state.breakers,
+    )))
+}
+
+pub(super) async fn create(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<CreateChannel>,
+) -> ApiResult<(StatusCode, Json<ChannelView>)> {
+    check_status(req.status)?;
+    let mut db = state.db.clone();
+    let channel = Channel::create()
+        .name(required("name", &req.name)?)
+        .provider(required("provider", &req.provider)?)
+        .models(required("models", &req.models)?)
+        .base_url(optional(req.base_url))
+        .api_key(optional(req.api_key))
+        .status(req.status)
+        .weight(req.weight)
+        .model_mapping(model_mapping(req.model_mapping)?)
+        .group(required("group", &req.group)?)
+        .exec(&mut db)
+        .await?;
+    state.relay.reload().await?;
+    tracing::info!("channel {} ({}) created", channel.id, channel.name);
+    Ok((
+        StatusCode::CREATED,
+        Json(ChannelView::new(channel, &state.breakers)),
+    ))
+}
+
+pub(super) async fn update(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Json(req): Json<UpdateChannel>,
+) -> ApiResult<Json<ChannelView>> {
+    let mut channel = find(&state, id).await?;
+    let mut db = state.db.clone();
+    let mut update = channel.update();
+    if let Some(name) = req.name {
+        update.set_name(required("name", &name)?);
+    }
+    if let Some(provider) = req.provider {
+        update.set_provider(required("provider", &provider)?);
+    }
+    if let Some(models) = req.models {
+        update.set_models(required("models", &models)?);
+    }
+    if req.base_url.is_some() {
+        update.set_base_url(optional(req.base_url));
+    }
+    if req.api_key.is_some() {
+        update.set_api_key(optional(req.api_key));
+    }
+    if let Some(status) = req.status {
+        check_status(status)?;
+        // The operator's decision replaces whatever the gateway decided.
+        update.set_status(status);
+        update.set_status_reason(None);
+    }
+    if let Some(weight) = req.weight {
+        update.set_weight(weight);
+    }
+    if let Some(mapping) = req.model_mapping {
+        update.set_model_mapping(model_mapping(mapping)?);
+    }
+    if let Some(group) = req.group {
+        update.set_group(required("group", &group)?);
+    }
+    update.exec(&mut db).await?;
+    if req.status.is_some() {
+        state.breakers.reset(channel.id);
+    }
+    state.relay.reload().await?;
+    tracing::info!("channel {} ({}) updated", channel.id, channel.name);
+    Ok(Json(ChannelView::new(channel, &state.breakers)))
+}
+
+pub(super) async fn delete(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> ApiResult<StatusCode> {
+    let channel = find(&state, id).await?;
+    let mut db = state.db.clone();
+    channel.delete().exec(&mut db).await?;
+    state.breakers.reset(id);
+    state.relay.reload().await?;
+    tracing::info!("channel {} deleted", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::tests::{app, send};
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_channel_lifecycle() {
+        let (app, state) = app().await;
+        for (name, models) in [
+            ("openai-main", "gpt-4o,gpt-4o-mini"),
+            ("openai-backup", "gpt-4o"),
+            ("whisper", "whisper-1"),
+        ] {
+            let (status, body) = send(
+                &app,
+                "POST",
+                "/api/channel",
+                Some(json!({
+                    "name": name,
+                    "models": models,
+                    "base_url": "https://api.openai.com",
+                    "api_key": "sk-upstream-0123456789",
+                })),
+            )
+            .await;
+            assert_eq!(status, StatusCode::CREATED);
+            assert_eq!(body["api_key"], "sk-u****6789");
+            assert_eq!(body["status"], 1);
+        }
+
+        state.breakers.record(
+            2,
+            std::time::Duration::from_millis(20),
+            Err("HTTP 503".into()),
+        );
+        let (_, body) = send(&app, "GET", "/api/channel/2", None).await;
+        assert_eq!(body["breaker"]["state"], "closed");
+        assert_eq!(body["breaker"]["failures"], 1);
+        assert_eq!(body["breaker"]["last_error"], "HTTP 503");
+
+        let (status, body) =
+            send(&app, "GET", "/api/channel?keyword=GPT-4O&page_size=1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 2);
+        assert_eq!(body["data"][0]["name"], "openai-main");
+
+        let (status, body) = send(
+            &app,
+            "PUT",
+            "/api/channel/2",
+            Some(json!({"status": 2, "api_key": ""})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], 2);
+        assert!(body["api_key"].is_null());
+        // Changing the status starts the breaker afresh.
+        assert!(body["breaker"].is_null());
+        assert_eq!(body["base_url"], "https://api.openai.com");
+
+        let (_, body) = send(&app, "GET", "/api/channel?status=1", None).await;
+        assert_eq!(body["total"], 2);
+
+        let (status, _) = send(&app, "PUT", "/api/channel/2", Some(json!({"status": 7}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send(
+            &app,
+            "POST",
+            "/api/channel",
+            Some(json!({"name": "x", "models": " "})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = send(
+            &app,
+            "PUT",
+            "/api/channel/3",
+            Some(json!({"model_mapping": {"whisper": "whisper-1"}})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["model_mapping"]["whisper"], "whisper-1");
+        let (status, _) = send(
+            &app,
+            "PUT",
+            "/api/channel/3",
+            Some(json!({"model_mapping": {"whisper": " "}})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (_, body) = send(
+            &app,
+            "PUT",
+            "/api/channel/3",
+            Some(json!({"model_mapping": {}})),
+        )
+        .await;
+        assert_eq!(body["model_mapping"], json!({}));
+
+        let (status, _) = send(&app, "DELETE", "/api/channel/2", None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, body) = send(&app, "GET", "/api/channel/2", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "not_found");
+    }
+}
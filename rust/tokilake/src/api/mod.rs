@@ -1,27 +1,78 @@
+//! HTTP API: health, the management API and the OpenAI-compatible relay.
+//!
+//! The management API (`/api/channel`, `/api/token`, `/api/price`) is for
+//! operators and scripts. Every request needs `Authorization: Bearer <admin
+//! key>`; without a configured admin key the management API refuses all
+//! requests. Every change reloads the gateway stack before it is answered, so
+//! the next relay request already sees it.
+
+mod channel;
+mod price;
+mod token;
+
+use crate::{
+    gateway::{auth::TokenCache, breaker::Breakers, quota::Quotas},
+    relay::Relay,
+};
 use axum::{
     Json, Router,
-    extract::State,
-    response::IntoResponse,
-    routing::{get, post},
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{any, get},
 };
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
 /// Shared application state available to all handlers.
 #[derive(Clone)]
 pub struct AppState {
     pub start_time: std::time::Instant,
+    /// Database holding channels and tokens.
+    pub db:         toasty::Db,
+    /// The gateway's token lookups, invalidated when a token changes.
+    pub tokens:     TokenCache,
+    /// The gateway's circuit breakers, shown with the channels.
+    pub breakers:   Breakers,
+    /// The gateway's token balances, invalidated when a quota is changed.
+    pub quotas:     Quotas,
+    /// Credential of the management API; `None` turns it off.
+    pub admin_key:  Option<String>,
+    /// The gateway service behind `/v1/*`.
+    pub relay:      Arc<Relay>,
 }
 
 pub fn router(state: AppState) -> Router {
+    let state = Arc::new(state);
+    let management = Router::new()
+        .route("/api/channel", get(channel::list).post(channel::create))
+        .route(
+            "/api/channel/{id}",
+            get(channel::get)
+                .put(channel::update)
+                .delete(channel::delete),
+        )
+        .route("/api/token", get(token::list).post(token::create))
+        .route(
+            "/api/token/{id}",
+            get(token::get).put(token::update).delete(token::delete),
+        )
+        .route("/api/price", get(price::list).post(price::create))
+        .route(
+            "/api/price/{id}",
+            get(price::get).put(price::update).delete(price::delete),
+        )
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin));
+
     Router::new()
         // Health / status
         .route("/health", get(health))
         // Management API
-        .route("/api/channel", get(list_channels))
-        .route("/api/token", get(list_tokens))
+        .merge(management)
         // OpenAI-compatible relay (gateway service pipeline)
-        .route("/v1/chat/completions", post(chat_completions))
-        .with_state(Arc::new(state))
+        .route("/v1/{*path}", any(relay))
+        .with_state(state)
 }
 
 async fn health(State(state): State<Arc<AppState>>) -> impl IntoResponse {
@@ -33,64 +84,264 @@ async fn health(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     }))
 }
 
-async fn list_channels() -> &'static str {
-    // TODO: fetch from Toasty
-    "[]"
-}
-
-async fn list_tokens() -> &'static str {
-    // TODO: fetch from Toasty
-    "[]"
-}
-
-async fn chat_completions(Json(body): Json<serde_json::Value>) -> impl IntoResponse {
-    // TODO: Wire through the gateway service stack.
-    // For now, return a stub OpenAI-compatible response.
-    let model = body
-        .get("model")
-        .and_then(|v| v.as_str())
-        .unwrap_or("unknown");
-
-    let is_stream = body
-        .get("stream")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false);
-
-    if is_stream {
-        // SSE stub
-        let sse_body = format!(
-            "data: {{\"id\":\"chatcmpl-stub\",\"object\":\"chat.completion.chunk\",\"model\":\"\
-             {model}\",\"choices\":[{{\"index\":0,\"delta\":{{\"role\":\"assistant\",\"content\":\\
-             "Hello from Tokilake!\"}},\"finish_reason\":null}}]}}\n\ndata: \
-             {{\"id\":\"chatcmpl-stub\",\"object\":\"chat.completion.chunk\",\"model\":\"{model}\"\
-             ,\"choices\":[{{\"index\":0,\"delta\":{{}},\"finish_reason\":\"stop\"}}]}}\n\ndata: \
-             [DONE]\n\n"
-        );
-        axum::response::Response::builder()
-            .status(200)
-            .header("content-type", "text/event-stream")
-            .body(axum::body::Body::from(sse_body))
-            .unwrap()
-            .into_response()
-    } else {
-        Json(serde_json::json!({
-            "id": "chatcmpl-stub",
-            "object": "chat.completion",
-            "model": model,
-            "choices": [{
-                "index": 0,
-                "message": {
-                    "role": "assistant",
-                    "content": "Hello from Tokilake!"
-                },
-                "finish_reason": "stop"
-            }],
-            "usage": {
-                "prompt_tokens": 10,
-                "completion_tokens": 5,
-                "total_tokens": 15
+async fn relay(State(state): State<Arc<AppState>>, req: Request) -> Response {
+    state.relay.call(req).await
+}
+
+/// Let a request through only if it carries the admin key.
+async fn require_admin(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let Some(admin_key) = state.admin_key.as_deref() else {
+        return ApiError::new(
+            StatusCode::FORBIDDEN,
+            "management API is disabled: no admin key configured",
+        )
+        .into_response();
+    };
+    let presented = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .unwrap_or_default();
+    if !constant_time_eq(presented.as_bytes(), admin_key.as_bytes()) {
+        return ApiError::new(StatusCode::UNAUTHORIZED, "invalid admin key").into_response();
+    }
+    next.run(req).await
+}
+
+/// Compare secrets without leaking the length of the common prefix.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// ---------- Shared pieces of the management handlers ----------
+
+/// An error reply of the management API, in the same shape as the gateway's.
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    status:  StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    fn not_found(what: &str, id: u64) -> Self {
+        Self::new(StatusCode::NOT_FOUND, format!("{} {} not found", what, id))
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        tracing::error!("management API error: {:#}", e);
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+    }
+}
+
+impl From<toasty::Error> for ApiError {
+    fn from(e: toasty::Error) -> Self {
+        anyhow::Error::from(e).into()
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let code = match self.status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => "invalid_admin_key",
+            StatusCode::NOT_FOUND => "not_found",
+            StatusCode::INTERNAL_SERVER_ERROR => "internal_error",
+            _ => "invalid_request",
+        };
+        let error_type = match self.status {
+            StatusCode::INTERNAL_SERVER_ERROR => "server_error",
+            _ => "invalid_request_error",
+        };
+        let body = serde_json::json!({
+            "error": {
+                "message": self.message,
+                "type": error_type,
+                "param": null,
+                "code": code,
             }
-        }))
-        .into_response()
+        });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+type ApiResult<T> = Result<T, ApiError>;
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+/// Query string of the list endpoints: `?page=1&page_size=20&keyword=&status=`.
+#[derive(Debug, Default, Deserialize)]
+struct ListQuery {
+    /// 1-based page number.
+    page:      Option<usize>,
+    page_size: Option<usize>,
+    /// Case-insensitive substring searched in names (and channel models).
+    keyword:   Option<String>,
+    status:    Option<i32>,
+}
+
+impl ListQuery {
+    fn keyword(&self) -> Option<String> {
+        self.keyword
+            .as_deref()
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .map(str::to_lowercase)
+    }
+}
+
+/// One page of a list endpoint.
+#[derive(Debug, Serialize)]
+struct Page<T> {
+    data:      Vec<T>,
+    total:     usize,
+    page:      usize,
+    page_size: usize,
+}
+
+impl<T> Page<T> {
+    /// Cut the page `query` asks for out of all matching `items`.
+    fn of(items: Vec<T>, query: &ListQuery) -> Self {
+        let page = query.page.unwrap_or(1).max(1);
+        let page_size = query
+            .page_size
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let total = items.len();
+        let data = items
+            .into_iter()
+            .skip((page - 1).saturating_mul(page_size))
+            .take(page_size)
+            .collect();
+        Self {
+            data,
+            total,
+            page,
+            page_size,
+        }
+    }
+}
+
+/// Show a secret's shape without revealing it: `sk-a****wxyz`.
+fn mask_secret(secret: &str) -> String {
+    let chars: Vec<char> = secret.chars().collect();
+    if chars.len() <= 8 {
+        return "*".repeat(chars.len());
+    }
+    let head: String = chars[..4].iter().collect();
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("{}****{}", head, tail)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::gateway::GatewayConfig;
+    use axum::body::Body;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    pub(crate) const ADMIN_KEY: &str = "admin-secret";
+
+    pub(crate) async fn app() -> (Router, AppState) {
+        let db = crate::db::init_db(crate::db::MEMORY_DATABASE_URL)
+            .await
+            .unwrap();
+        let config = GatewayConfig::new(db);
+        let state = AppState {
+            start_time: std::time::Instant::now(),
+            db:         config.db.clone(),
+            tokens:     config.tokens.clone(),
+            breakers:   config.breakers.clone(),
+            quotas:     config.quotas.clone(),
+            admin_key:  Some(ADMIN_KEY.to_string()),
+            relay:      Arc::new(Relay::new(config).await.unwrap()),
+        };
+        (router(state.clone()), state)
+    }
+
+    /// Send a management request as the admin and return status and JSON body.
+    pub(crate) async fn send(
+        app: &Router,
+        method: &str,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        send_as(app, Some(ADMIN_KEY), method, uri, body).await
+    }
+
+    async fn send_as(
+        app: &Router,
+        key: Option<&str>,
+        method: &str,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let mut request = axum::http::Request::builder().method(method).uri(uri);
+        if let Some(key) = key {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", key));
+        }
+        let request = match body {
+            Some(body) => request
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        }
+        .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+        (status, json)
+    }
+
+    #[tokio::test]
+    async fn test_management_api_requires_admin_key() {
+        let (app, mut state) = app().await;
+        let (status, body) = send_as(&app, None, "GET", "/api/channel", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["code"], "invalid_admin_key");
+        let (status, _) = send_as(&app, Some("sk-guess"), "GET", "/api/token", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, body) = send(&app, "GET", "/api/token", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 0);
+
+        // Health stays public.
+        let (status, _) = send_as(&app, None, "GET", "/health", None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        state.admin_key = None;
+        let app = router(state);
+        let (status, _) = send(&app, "GET", "/api/channel", None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_page_and_mask() {
+        let query = ListQuery {
+            page: Some(3),
+            page_size: Some(2),
+            ..Default::default()
+        };
+        let page = Page::of((1..=5).collect(), &query);
+        assert_eq!((page.data, page.total, page.page_size), (vec![5], 5, 2));
+
+        assert_eq!(mask_secret("sk-abcdefghwxyz"), "sk-a****wxyz");
+        assert_eq!(mask_secret("short"), "*****");
     }
 }
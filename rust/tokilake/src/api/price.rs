@@ -0,0 +1,179 @@
+//! `/api/price` — what each model costs in token quota.
+//!
+//! A model without a price costs one quota per token, prompt or completion.
+
+use super::{ApiError, ApiResult, AppState, ListQuery, Page};
+use crate::model::ModelPrice;
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct CreatePrice {
+    model:            String,
+    prompt_ratio:     f64,
+    completion_ratio: f64,
+}
+
+/// Fields left out are kept.
+#[derive(Debug, Default, Deserialize)]
+pub(super) struct UpdatePrice {
+    prompt_ratio:     Option<f64>,
+    completion_ratio: Option<f64>,
+}
+
+fn check_ratio(field: &str, ratio: f64) -> ApiResult<f64> {
+    if !ratio.is_finite() || ratio < 0.0 {
+        return Err(ApiError::bad_request(format!(
+            "{} must be a number of at least 0",
+            field
+        )));
+    }
+    Ok(ratio)
+}
+
+async fn find(state: &AppState, id: u64) -> ApiResult<ModelPrice> {
+    let mut db = state.db.clone();
+    ModelPrice::filter(ModelPrice::fields().id().eq(id))
+        .first()
+        .exec(&mut db)
+        .await?
+        .ok_or_else(|| ApiError::not_found("price", id))
+}
+
+pub(super) async fn list(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ListQuery>,
+) -> ApiResult<Json<Page<ModelPrice>>> {
+    let mut db = state.db.clone();
+    let keyword = query.keyword();
+    let mut prices: Vec<ModelPrice> = ModelPrice::all()
+        .exec(&mut db)
+        .await?
+        .into_iter()
+        .filter(|p| {
+            keyword
+                .as_deref()
+                .is_none_or(|keyword| p.model.to_lowercase().contains(keyword))
+        })
+        .collect();
+    prices.sort_by(|a, b| a.model.cmp(&b.model));
+    Ok(Json(Page::of(prices, &query)))
+}
+
+pub(super) async fn get(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> ApiResult<Json<ModelPrice>> {
+    Ok(Json(find(&state, id).await?))
+}
+
+pub(super) async fn create(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<CreatePrice>,
+) -> ApiResult<(StatusCode, Json<ModelPrice>)> {
+    let model = req.model.trim();
+    if model.is_empty() {
+        return Err(ApiError::bad_request("model must not be empty"));
+    }
+    let mut db = state.db.clone();
+    let existing = ModelPrice::filter(ModelPrice::fields().model().eq(model))
+        .first()
+        .exec(&mut db)
+        .await?;
+    if let Some(existing) = existing {
+        return Err(ApiError::bad_request(format!(
+            "model {} already has price {}",
+            model, existing.id
+        )));
+    }
+    let price = ModelPrice::create()
+        .model(model)
+        .prompt_ratio(check_ratio("prompt_ratio", req.prompt_ratio)?)
+        .completion_ratio(check_ratio("completion_ratio", req.completion_ratio)?)
+        .exec(&mut db)
+        .await?;
+    state.relay.reload().await?;
+    tracing::info!("price {} ({}) created", price.id, price.model);
+    Ok((StatusCode::CREATED, Json(price)))
+}
+
+pub(super) async fn update(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Json(req): Json<UpdatePrice>,
+) -> ApiResult<Json<ModelPrice>> {
+    let mut price = find(&state, id).await?;
+    let mut db = state.db.clone();
+    let mut update = price.update();
+    if let Some(ratio) = req.prompt_ratio {
+        update.set_prompt_ratio(check_ratio("prompt_ratio", ratio)?);
+    }
+    if let Some(ratio) = req.completion_ratio {
+        update.set_completion_ratio(check_ratio("completion_ratio", ratio)?);
+    }
+    update.exec(&mut db).await?;
+    state.relay.reload().await?;
+    tracing::info!("price {} ({}) updated", price.id, price.model);
+    Ok(Json(price))
+}
+
+pub(super) async fn delete(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> ApiResult<StatusCode> {
+    let price = find(&state, id).await?;
+    let mut db = state.db.clone();
+    price.delete().exec(&mut db).await?;
+    state.relay.reload().await?;
+    tracing::info!("price {} deleted", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::tests::{app, send};
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_price_lifecycle() {
+        let (app, _) = app().await;
+        let price = json!({"model": "gpt-4o", "prompt_ratio": 2.5, "completion_ratio": 10});
+        let (status, body) = send(&app, "POST", "/api/price", Some(price.clone())).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["completion_ratio"], 10.0);
+        let id = body["id"].as_u64().unwrap();
+
+        let (status, _) = send(&app, "POST", "/api/price", Some(price)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send(
+            &app,
+            "PUT",
+            &format!("/api/price/{}", id),
+            Some(json!({"prompt_ratio": -1})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = send(
+            &app,
+            "PUT",
+            &format!("/api/price/{}", id),
+            Some(json!({"prompt_ratio": 1.25})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["prompt_ratio"], 1.25);
+        assert_eq!(body["model"], "gpt-4o");
+
+        let (status, _) = send(&app, "DELETE", &format!("/api/price/{}", id), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (_, body) = send(&app, "GET", "/api/price", None).await;
+        assert_eq!(body["total"], 0);
+    }
+}
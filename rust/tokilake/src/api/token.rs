@@ -0,0 +1,437 @@
+//! `/api/token` — client tokens of the gateway.
+//!
+//! Keys are generated by the gateway. The full key is returned once, by the
+//! request that creates the token; afterwards only a masked form is shown.
+//!
+//! A token has unlimited quota unless created or updated with
+//! `"unlimited_quota": false`; it can then spend `remaining_quota`.
+//! `used_quota` is kept by the gateway.
+//!
+//! A token can be scoped: `expires_at` (Unix seconds) ends it, `models` and
+//! `allowed_ips` (comma-separated models, and addresses or CIDR networks)
+//! restrict what it reaches and from where, and `group` picks the channels
+//! it is routed to. An `expires_at` of 0 or an empty list lifts the limit.
+
+use super::{ApiError, ApiResult, AppState, ListQuery, Page, mask_secret};
+use crate::model::{DEFAULT_GROUP, Token, parse_network};
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use rand::{Rng, distr::Alphanumeric};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// `status` of a token turned off by an operator.
+const STATUS_DISABLED: i32 = 2;
+
+/// Random characters after the `sk-` prefix of a generated key.
+const KEY_LENGTH: usize = 48;
+
+/// A token as the management API shows it.
+#[derive(Debug, Serialize)]
+pub(super) struct TokenView {
+    id:              u64,
+    name:            String,
+    key:             String,
+    status:          i32,
+    remaining_quota: i64,
+    used_quota:      i64,
+    unlimited_quota: bool,
+    expires_at:      Option<i64>,
+    models:          Option<String>,
+    allowed_ips:     Option<String>,
+    group:           String,
+}
+
+impl TokenView {
+    fn masked(token: Token) -> Self {
+        Self {
+            key: mask_secret(&token.key),
+            ..Self::revealed(token)
+        }
+    }
+
+    fn revealed(token: Token) -> Self {
+        Self {
+            id:              token.id,
+            name:            token.name,
+            key:             token.key,
+            status:          token.status,
+            remaining_quota: token.remaining_quota,
+            used_quota:      token.used_quota,
+            unlimited_quota: token.unlimited_quota,
+            expires_at:      token.expires_at,
+            models:          token.models,
+            allowed_ips:     token.allowed_ips,
+            group:           token.group,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct CreateToken {
+    name:            String,
+    #[serde(default = "default_status")]
+    status:          i32,
+    #[serde(default)]
+    remaining_quota: i64,
+    #[serde(default = "default_unlimited_quota")]
+    unlimited_quota: bool,
+    expires_at:      Option<i64>,
+    models:          Option<String>,
+    allowed_ips:     Option<String>,
+    #[serde(default = "default_group")]
+    group:           String,
+}
+
+/// Fields left out are kept.
+#[derive(Debug, Default, Deserialize)]
+pub(super) struct UpdateToken {
+    name:            Option<String>,
+    status:          Option<i32>,
+    remaining_quota: Option<i64>,
+    unlimited_quota: Option<bool>,
+    expires_at:      Option<i64>,
+    models:          Option<String>,
+    allowed_ips:     Option<String>,
+    group:           Option<String>,
+}
+
+fn default_status() -> i32 {
+    Token::STATUS_ENABLED
+}
+
+fn default_unlimited_quota() -> bool {
+    true
+}
+
+fn default_group() -> String {
+    DEFAULT_GROUP.to_string()
+}
+
+fn check_status(status: i32) -> ApiResult<()> {
+    match status {
+        Token::STATUS_ENABLED | STATUS_DISABLED => Ok(()),
+        _ => Err(ApiError::bad_request(format!(
+            "status must be {} (enabled) or {} (disabled)",
+            Token::STATUS_ENABLED,
+            STATUS_DISABLED
+        ))),
+    }
+}
+
+fn check_name(name: &str) -> ApiResult<String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ApiError::bad_request("name must not be empty"));
+    }
+    Ok(name.to_string())
+}
+
+fn check_group(group: &str) -> ApiResult<String> {
+    let group = group.trim();
+    if group.is_empty() {
+        return Err(ApiError::bad_request("group must not be empty"));
+    }
+    Ok(group.to_string())
+}
+
+/// `None` for no expiry.
+fn expiry(expires_at: Option<i64>) -> Option<i64> {
+    expires_at.filter(|at| *at > 0)
+}
+
+/// A comma-separated list tidied up, `None` if it has no entries.
+fn tidy_list(value: Option<String>) -> Option<String> {
+    let entries: Vec<&str> = value
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .collect();
+    (!entries.is_empty()).then(|| entries.join(","))
+}
+
+fn check_networks(value: Option<String>) -> ApiResult<Option<String>> {
+    let networks = tidy_list(value);
+    for network in networks.iter().flat_map(|n| n.split(',')) {
+        if parse_network(network).is_none() {
+            return Err(ApiError::bad_request(format!(
+                "allowed_ips: {} is not an IP address or CIDR network",
+                network
+            )));
+        }
+    }
+    Ok(networks)
+}
+
+/// A fresh `sk-…` key.
+fn generate_key() -> String {
+    let random: String = rand::rng()
+        .sample_iter(Alphanumeric)
+        .take(KEY_LENGTH)
+        .map(char::from)
+        .collect();
+    format!("sk-{}", random)
+}
+
+async fn find(state: &AppState, id: u64) -> ApiResult<Token> {
+    let mut db = state.db.clone();
+    Token::filter(Token::fields().id().eq(id))
+        .first()
+        .exec(&mut db)
+        .await?
+        .ok_or_else(|| ApiError::not_found("token", id))
+}
+
+pub(super) async fn list(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ListQuery>,
+) -> ApiResult<Json<Page<TokenView>>> {
+    let mut db = state.db.clone();
+    let keyword = query.keyword();
+    let mut tokens: Vec<Token> = Token::all()
+        .exec(&mut db)
+        .await?
+        .into_iter()
+        .filter(|t| query.status.is_none_or(|status| t.status == status))
+        .filter(|t| {
+            keyword
+                .as_deref()
+                .is_none_or(|keyword| t.name.to_lowercase().contains(keyword))
+        })
+        .collect();
+    tokens.sort_by_key(|t| t.id);
+    let views = tokens.into_iter().map(TokenView::masked).collect();
+    Ok(Json(Page::of(views, &query)))
+}
+
+pub(super) async fn get(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> ApiResult<Json<TokenView>> {
+    Ok(Json(TokenView::masked(find(&state, id).await?)))
+}
+
+pub(super) async fn create(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<CreateToken>,
+) -> ApiResult<(StatusCode, Json<TokenView>)> {
+    check_status(req.status)?;
+    let mut db = state.db.clone();
+    let token = Token::create()
+        .name(check_name(&req.name)?)
+        .key(generate_key())
+        .status(req.status)
+        .remaining_quota(req.remaining_quota)
+        .unlimited_quota(req.unlimited_quota)
+        .expires_at(expiry(req.expires_at))
+        .models(tidy_list(req.models))
+        .allowed_ips(check_networks(req.allowed_ips)?)
+        .group(check_group(&req.group)?)
+        .exec(&mut db)
+        .await?;
+    state.tokens.invalidate(&token.key);
+    state.relay.reload().await?;
+    tracing::info!("token {} ({}) created", token.id, token.name);
+    Ok((StatusCode::CREATED, Json(TokenView::revealed(token))))
+}
+
+pub(super) async fn update(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Json(req): Json<UpdateToken>,
+) -> ApiResult<Json<TokenView>> {
+    let mut token = find(&state, id).await?;
+    let mut db = state.db.clone();
+    let mut update = token.update();
+    if let Some(name) = req.name {
+        update.set_name(check_name(&name)?);
+    }
+    if let Some(status) = req.status {
+        check_status(status)?;
+        update.set_status(status);
+    }
+    if let Some(remaining_quota) = req.remaining_quota {
+        update.set_remaining_quota(remaining_quota);
+    }
+    if let Some(unlimited_quota) = req.unlimited_quota {
+        update.set_unlimited_quota(unlimited_quota);
+    }
+    if req.expires_at.is_some() {
+        update.set_expires_at(expiry(req.expires_at));
+    }
+    if req.models.is_some() {
+        update.set_models(tidy_list(req.models));
+    }
+    if req.allowed_ips.is_some() {
+        update.set_allowed_ips(check_networks(req.allowed_ips)?);
+    }
+    if let Some(group) = req.group {
+        update.set_group(check_group(&group)?);
+    }
+    {
+        // Charges of this token wait, so none of them writes back the
+        // balance from before a top-up.
+        let _locked = state.quotas.lock(id).await;
+        update.exec(&mut db).await?;
+        state.quotas.invalidate(id);
+    }
+    // The gateway must see a disabled token at once, not after the cache TTL.
+    state.tokens.invalidate(&token.key);
+    state.relay.reload().await?;
+    tracing::info!("token {} ({}) updated", token.id, token.name);
+    Ok(Json(TokenView::masked(token)))
+}
+
+pub(super) async fn delete(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> ApiResult<StatusCode> {
+    let token = find(&state, id).await?;
+    let mut db = state.db.clone();
+    let key = token.key.clone();
+    token.delete().exec(&mut db).await?;
+    state.tokens.invalidate(&key);
+    state.quotas.invalidate(id);
+    state.relay.reload().await?;
+    tracing::info!("token {} deleted", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::tests::{app, send},
+        gateway::auth::TokenState,
+    };
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_token_key_is_generated_and_disabling_takes_effect() {
+        let (app, state) = app().await;
+        let (status, body) = send(&app, "POST", "/api/token", Some(json!({"name": "ci"}))).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let key = body["key"].as_str().unwrap().to_string();
+        assert!(key.starts_with("sk-") && key.len() == 51, "{}", key);
+        let id = body["id"].as_u64().unwrap();
+
+        let (_, body) = send(&app, "GET", &format!("/api/token/{}", id), None).await;
+        assert_ne!(body["key"], key.as_str());
+        assert!(body["key"].as_str().unwrap().contains("****"));
+
+        let resolve = || state.tokens.resolve(&state.db, &key);
+        assert!(matches!(resolve().await.unwrap(), TokenState::Active(_)));
+
+        let (status, body) = send(
+            &app,
+            "PUT",
+            &format!("/api/token/{}", id),
+            Some(json!({"status": 2})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], 2);
+        assert_eq!(resolve().await.unwrap(), TokenState::Disabled);
+
+        let (status, _) = send(&app, "DELETE", &format!("/api/token/{}", id), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(resolve().await.unwrap(), TokenState::Unknown);
+        let (_, body) = send(&app, "GET", "/api/token", None).await;
+        assert_eq!(body["total"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_token_quota_is_set_and_topped_up() {
+        let (app, state) = app().await;
+        let (status, body) = send(
+            &app,
+            "POST",
+            "/api/token",
+            Some(json!({"name": "team", "unlimited_quota": false, "remaining_quota": 500})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["remaining_quota"], 500);
+        assert_eq!(body["used_quota"], 0);
+        assert_eq!(body["unlimited_quota"], false);
+        let id = body["id"].as_u64().unwrap();
+
+        state.quotas.charge(&state.db, id, 500).await.unwrap();
+        assert_eq!(state.quotas.remaining(&state.db, id).await.unwrap(), 0);
+
+        let (status, body) = send(
+            &app,
+            "PUT",
+            &format!("/api/token/{}", id),
+            Some(json!({"remaining_quota": 1000})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["remaining_quota"], 1000);
+        assert_eq!(body["used_quota"], 500);
+        assert_eq!(state.quotas.remaining(&state.db, id).await.unwrap(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_token_scope_is_validated_and_cleared() {
+        let (app, state) = app().await;
+        let (status, body) = send(
+            &app,
+            "POST",
+            "/api/token",
+            Some(json!({"name": "contractor", "allowed_ips": "10.0.0.0/33"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            body["error"]["message"]
+                .as_str()
+                .unwrap()
+                .contains("10.0.0.0/33")
+        );
+
+        let (status, body) = send(
+            &app,
+            "POST",
+            "/api/token",
+            Some(json!({
+                "name": "contractor",
+                "expires_at": 4102444800_i64,
+                "models": " gpt-4o, ,whisper-1",
+                "allowed_ips": "10.0.0.0/8, 203.0.113.9",
+                "group": "contractors",
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["models"], "gpt-4o,whisper-1");
+        assert_eq!(body["allowed_ips"], "10.0.0.0/8,203.0.113.9");
+        assert_eq!(body["group"], "contractors");
+        let id = body["id"].as_u64().unwrap();
+        let key = body["key"].as_str().unwrap().to_string();
+        let TokenState::Active(token) = state.tokens.resolve(&state.db, &key).await.unwrap() else {
+            panic!("token is not active");
+        };
+        assert_eq!(token.scope.models, ["gpt-4o", "whisper-1"]);
+        assert_eq!(token.scope.allowed_ips.len(), 2);
+        assert_eq!(token.scope.expires_at, Some(4102444800));
+
+        let (status, body) = send(
+            &app,
+            "PUT",
+            &format!("/api/token/{}", id),
+            Some(json!({"expires_at": 0, "models": "", "allowed_ips": ""})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["expires_at"].is_null());
+        assert!(body["models"].is_null());
+        assert!(body["allowed_ips"].is_null());
+        assert_eq!(body["group"], "contractors");
+    }
+}
@@ -1,15 +1,503 @@
-use anyhow::Result;
+//! Database connection and schema migrations.
+//!
+//! A new database gets its tables from toasty's `push_schema` and every entry
+//! of [`MIGRATIONS`] is recorded as applied. An existing database gets the
+//! migrations it has not seen yet, in order, each in its own transaction and
+//! recorded in toasty's `__toasty_migrations` table. The file is copied to
+//! `<file>.pre-migration-<id>` first, so a failed upgrade can be rolled back;
+//! the copy is taken before the connection pool opens, so no change is still
+//! waiting in a connection's journal.
+//!
+//! Every change to a model needs a new migration that turns the previous
+//! tables into what `push_schema` now creates.
+
+use anyhow::{Context, Result, bail};
+use std::path::{Path, PathBuf};
 use toasty::Db;
+use toasty_core::{
+    driver::{Connection, Driver},
+    schema::db::Migration,
+};
+use tracing::info;
+
+/// Used when no database is configured.
+pub const DEFAULT_DATABASE_URL: &str = "sqlite:tokilake.db";
+
+/// A throwaway database, for tests and experiments.
+pub const MEMORY_DATABASE_URL: &str = "sqlite::memory:";
+
+/// One versioned schema change.
+#[derive(Debug, Clone)]
+pub struct SchemaMigration {
+    pub id:         u64,
+    pub name:       &'static str,
+    pub statements: &'static [&'static str],
+}
+
+/// Every schema change since the first release, oldest first. IDs never
+/// change once released.
+pub const MIGRATIONS: &[SchemaMigration] = &[
+    SchemaMigration {
+        id:         1,
+        name:       "initial_schema",
+        // The first schema is whatever `push_schema` creates.
+        statements: &[],
+    },
+    SchemaMigration {
+        id:         2,
+        name:       "channel_status_reason",
+        statements: &[r#"ALTER TABLE "channels" ADD COLUMN "status_reason" TEXT"#],
+    },
+    SchemaMigration {
+        id:         3,
+        name:       "usage_logs",
+        statements: &[
+            r#"CREATE TABLE IF NOT EXISTS "usage_logs" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "request_id" TEXT NOT NULL,
+    "token_id" INTEGER NOT NULL,
+    "channel_id" INTEGER NOT NULL,
+    "model" TEXT NOT NULL,
+    "prompt_tokens" BIGINT NOT NULL,
+    "completion_tokens" BIGINT NOT NULL,
+    "total_tokens" BIGINT NOT NULL,
+    "stream" BOOLEAN NOT NULL,
+    "created_at" BIGINT NOT NULL
+)"#,
+            r#"CREATE INDEX "index_usage_logs_by_token_id" ON "usage_logs" ("token_id")"#,
+        ],
+    },
+    SchemaMigration {
+        id:         4,
+        name:       "token_quotas",
+        statements: &[
+            r#"ALTER TABLE "tokens" ADD COLUMN "remaining_quota" BIGINT NOT NULL DEFAULT 0"#,
+            r#"ALTER TABLE "tokens" ADD COLUMN "used_quota" BIGINT NOT NULL DEFAULT 0"#,
+            // Tokens issued before quotas existed keep working as they did.
+            r#"ALTER TABLE "tokens" ADD COLUMN "unlimited_quota" BOOLEAN NOT NULL DEFAULT 1"#,
+            r#"ALTER TABLE "usage_logs" ADD COLUMN "quota" BIGINT NOT NULL DEFAULT 0"#,
+            r#"CREATE TABLE IF NOT EXISTS "model_prices" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "model" TEXT NOT NULL,
+    "prompt_ratio" REAL NOT NULL,
+    "completion_ratio" REAL NOT NULL
+)"#,
+            r#"CREATE UNIQUE INDEX "index_model_prices_by_model" ON "model_prices" ("model")"#,
+        ],
+    },
+    SchemaMigration {
+        id:         5,
+        name:       "channel_model_mapping",
+        statements: &[r#"ALTER TABLE "channels" ADD COLUMN "model_mapping" TEXT"#],
+    },
+    SchemaMigration {
+        id:         6,
+        name:       "token_scopes",
+        statements: &[
+            r#"ALTER TABLE "tokens" ADD COLUMN "expires_at" BIGINT"#,
+            r#"ALTER TABLE "tokens" ADD COLUMN "models" TEXT"#,
+            r#"ALTER TABLE "tokens" ADD COLUMN "allowed_ips" TEXT"#,
+            r#"ALTER TABLE "tokens" ADD COLUMN "group" TEXT NOT NULL DEFAULT 'default'"#,
+            r#"ALTER TABLE "channels" ADD COLUMN "group" TEXT NOT NULL DEFAULT 'default'"#,
+        ],
+    },
+];
+
+/// Turn a `-db` value into a connection URL: a bare path becomes a SQLite
+/// file URL.
+pub fn database_url(value: &str) -> String {
+    let value = value.trim();
+    if value.starts_with("sqlite:") {
+        value.to_string()
+    } else {
+        format!("sqlite:{}", value)
+    }
+}
+
+/// The file behind a SQLite URL, if it is not in memory.
+fn database_file(url: &str) -> Option<PathBuf> {
+    let path = url.strip_prefix("sqlite:")?;
+    let path = path.strip_prefix("//").unwrap_or(path);
+    (path != ":memory:" && !path.is_empty()).then(|| PathBuf::from(path))
+}
+
+pub async fn init_db(url: &str) -> Result<Db> {
+    open(url, MIGRATIONS).await
+}
+
+async fn open(url: &str, migrations: &[SchemaMigration]) -> Result<Db> {
+    let file = database_file(url);
+    if let Some(file) = &file {
+        back_up(url, file, migrations).await?;
+    }
 
-pub async fn init_db() -> Result<Db> {
     // Build a Db handle, registering all models in this crate
-    let db = toasty::Db::builder()
-        .models(toasty::models!(crate::model::Channel, crate::model::Token))
-        .connect("sqlite::memory:")
-        .await?;
+    let mut builder = toasty::Db::builder();
+    builder.models(toasty::models!(
+        crate::model::Channel,
+        crate::model::Token,
+        crate::model::UsageLog,
+        crate::model::ModelPrice
+    ));
+    if file.is_none() {
+        // Every connection to `:memory:` is a database of its own.
+        builder.max_pool_size(1);
+    }
+    let db = builder
+        .connect(url)
+        .await
+        .with_context(|| format!("failed to open database {}", url))?;
 
-    // Create tables based on registered models
-    db.push_schema().await?;
+    match file {
+        Some(file) => migrate(&db, &file, migrations).await?,
+        // Every in-memory database starts empty.
+        None => db.push_schema().await?,
+    }
 
     Ok(db)
 }
+
+async fn applied_migrations(conn: &mut dyn Connection) -> Result<Vec<u64>> {
+    Ok(conn
+        .applied_migrations()
+        .await?
+        .iter()
+        .map(|m| m.id())
+        .collect())
+}
+
+/// Copy `file` to `<file>.pre-migration-<id>` if it is an existing database
+/// that `migrations` will change. Must run while no other connection is open.
+async fn back_up(url: &str, file: &Path, migrations: &[SchemaMigration]) -> Result<()> {
+    if !file.exists() {
+        return Ok(());
+    }
+    let applied = {
+        let driver = toasty::db::Connect::new(url).await?;
+        let mut conn = driver.connect().await?;
+        applied_migrations(conn.as_mut()).await?
+    };
+    if applied.is_empty() {
+        return Ok(());
+    }
+    let Some(first) = migrations.iter().find(|m| !applied.contains(&m.id)) else {
+        return Ok(());
+    };
+
+    let backup = PathBuf::from(format!("{}.pre-migration-{}", file.display(), first.id));
+    std::fs::copy(file, &backup)
+        .with_context(|| format!("failed to back up database to {}", backup.display()))?;
+    info!("database backed up to {}", backup.display());
+    Ok(())
+}
+
+async fn migrate(db: &Db, file: &Path, migrations: &[SchemaMigration]) -> Result<()> {
+    let mut conn = db.driver().connect().await?;
+    let applied = applied_migrations(conn.as_mut()).await?;
+
+    if applied.is_empty() {
+        info!("creating database schema in {}", file.display());
+        db.push_schema().await?;
+        for migration in migrations {
+            record(conn.as_mut(), migration).await?;
+        }
+        return Ok(());
+    }
+
+    if let Some(unknown) = applied
+        .iter()
+        .find(|id| !migrations.iter().any(|m| m.id == **id))
+    {
+        bail!(
+            "database {} has migration {} which this version does not know; refusing to start an \
+             older release on it",
+            file.display(),
+            unknown
+        );
+    }
+
+    for migration in migrations.iter().filter(|m| !applied.contains(&m.id)) {
+        info!("applying migration {} ({})", migration.id, migration.name);
+        let statements = Migration::new_sql_with_breakpoints(migration.statements);
+        conn.apply_migration(migration.id, migration.name, &statements)
+            .await
+            .with_context(|| format!("migration {} ({}) failed", migration.id, migration.name))?;
+    }
+    Ok(())
+}
+
+/// Mark `migration` as applied without running it; its changes are already
+/// part of the schema `push_schema` created.
+async fn record(conn: &mut dyn Connection, migration: &SchemaMigration) -> Result<()> {
+    // A migration needs at least one statement; this one changes nothing.
+    let noop = Migration::new_sql(format!("PRAGMA user_version = {}", migration.id));
+    conn.apply_migration(migration.id, migration.name, &noop)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Channel, DEFAULT_GROUP, ModelPrice, Token, UsageLog};
+
+    fn temp_db(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "tokilake-{}-{}-{}.db",
+            name,
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn released() -> Vec<u64> {
+        MIGRATIONS.iter().map(|m| m.id).collect()
+    }
+
+    async fn applied(db: &Db) -> Vec<u64> {
+        let mut conn = db.driver().connect().await.unwrap();
+        let applied = conn.applied_migrations().await.unwrap();
+        let mut ids: Vec<u64> = applied.iter().map(|m| m.id()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn test_file_database_survives_restart() {
+        let path = temp_db("restart");
+        let url = database_url(path.to_str().unwrap());
+
+        let mut db = init_db(&url).await.unwrap();
+        Token::create()
+            .name("kept")
+            .key("sk-kept")
+            .status(Token::STATUS_ENABLED)
+            .exec(&mut db)
+            .await
+            .unwrap();
+        assert_eq!(applied(&db).await, released());
+        drop(db);
+
+        let mut db = init_db(&url).await.unwrap();
+        let token = Token::filter(Token::fields().key().eq("sk-kept"))
+            .first()
+            .exec(&mut db)
+            .await
+            .unwrap();
+        assert_eq!(token.unwrap().name, "kept");
+        assert_eq!(applied(&db).await, released());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_pending_migrations_run_once_after_a_backup() {
+        let path = temp_db("upgrade");
+        let url = database_url(path.to_str().unwrap());
+        drop(init_db(&url).await.unwrap());
+
+        let mut next = MIGRATIONS.to_vec();
+        next.push(SchemaMigration {
+            id:         100,
+            name:       "add_notes",
+            statements: &[
+                "CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT)",
+                "INSERT INTO notes (body) VALUES ('hello')",
+            ],
+        });
+        drop(open(&url, &next).await.unwrap());
+        let db = open(&url, &next).await.unwrap();
+        let mut upgraded = released();
+        upgraded.push(100);
+        assert_eq!(applied(&db).await, upgraded);
+        drop(db);
+        let backup = PathBuf::from(format!("{}.pre-migration-100", path.display()));
+        assert!(backup.exists());
+        assert_eq!(tables(&backup).len(), tables(&path).len() - 1);
+
+        // The older release refuses the upgraded database.
+        let err = init_db(&url).await.unwrap_err();
+        assert!(err.to_string().contains("migration 100"), "{}", err);
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&backup);
+    }
+
+    /// The tables of the database at `path`: their columns and indexes, in
+    /// name order. Column defaults are left out: SQLite only adds a
+    /// `NOT NULL` column with one, while `push_schema` leaves defaults to the
+    /// models.
+    fn tables(path: &Path) -> Vec<(String, Vec<String>, Vec<String>)> {
+        let conn = rusqlite::Connection::open(path).unwrap();
+        let names: Vec<String> = conn
+            .prepare(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' \
+                 ORDER BY name",
+            )
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let rows = |sql: String| -> Vec<String> {
+            let mut rows: Vec<String> = conn
+                .prepare(&sql)
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .map(Result::unwrap)
+                .collect();
+            rows.sort();
+            rows
+        };
+        names
+            .into_iter()
+            .map(|table| {
+                let columns = rows(format!(
+                    "SELECT name || ' ' || type || ' ' || \"notnull\" || ' ' || pk FROM \
+                     pragma_table_info('{}')",
+                    table
+                ));
+                let indexes = rows(format!(
+                    "SELECT l.name || ' ' || l.\"unique\" || ' ' || group_concat(i.name) FROM \
+                     pragma_index_list('{0}') l, pragma_index_info(l.name) i GROUP BY l.name",
+                    table
+                ));
+                (table, columns, indexes)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_first_release_database_upgrades_to_the_current_schema() {
+        let path = temp_db("first-release");
+        let url = database_url(path.to_str().unwrap());
+        {
+            // The tables `push_schema` created when only migration 1 existed.
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            conn.execute_batch(
+                r#"CREATE TABLE "channels" (
+                    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                    "name" TEXT NOT NULL,
+                    "provider" TEXT NOT NULL,
+                    "models" TEXT NOT NULL,
+                    "base_url" TEXT,
+                    "api_key" TEXT,
+                    "status" INTEGER NOT NULL,
+                    "weight" INTEGER NOT NULL
+                );
+                CREATE TABLE "tokens" (
+                    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                    "name" TEXT NOT NULL,
+                    "key" TEXT NOT NULL,
+                    "status" INTEGER NOT NULL
+                );
+                CREATE UNIQUE INDEX "index_tokens_by_key" ON "tokens" ("key");
+                INSERT INTO "channels" ("name", "provider", "models", "status", "weight")
+                    VALUES ('openai', 'openai', 'gpt-4o', 1, 1);
+                INSERT INTO "tokens" ("name", "key", "status") VALUES ('old', 'sk-old', 1);"#,
+            )
+            .unwrap();
+        }
+        {
+            let driver = toasty::db::Connect::new(&url).await.unwrap();
+            let mut conn = driver.connect().await.unwrap();
+            record(conn.as_mut(), &MIGRATIONS[0]).await.unwrap();
+        }
+
+        let mut db = init_db(&url).await.unwrap();
+        assert_eq!(applied(&db).await, released());
+        let backup = PathBuf::from(format!("{}.pre-migration-2", path.display()));
+        assert!(backup.exists());
+
+        // Rows from before keep the behaviour they had.
+        let token = Token::filter(Token::fields().key().eq("sk-old"))
+            .first()
+            .exec(&mut db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(token.unlimited_quota);
+        assert_eq!((token.remaining_quota, token.used_quota), (0, 0));
+        assert_eq!(token.group, DEFAULT_GROUP);
+        assert_eq!(token.expires_at, None);
+        let channel = Channel::all().first().exec(&mut db).await.unwrap().unwrap();
+        assert_eq!(channel.group, DEFAULT_GROUP);
+        assert_eq!(channel.model_mapping, None);
+
+        // Every model can be written and read.
+        Channel::create()
+            .name("mapped")
+            .provider("openai")
+            .models("gpt-4")
+            .model_mapping(r#"{"gpt-4":"gpt-4o"}"#)
+            .status(Channel::STATUS_ENABLED)
+            .weight(1)
+            .group("vip")
+            .exec(&mut db)
+            .await
+            .unwrap();
+        Token::create()
+            .name("scoped")
+            .key("sk-scoped")
+            .status(Token::STATUS_ENABLED)
+            .models("gpt-4o")
+            .allowed_ips("10.0.0.0/8")
+            .expires_at(4_102_444_800)
+            .exec(&mut db)
+            .await
+            .unwrap();
+        ModelPrice::create()
+            .model("gpt-4o")
+            .prompt_ratio(0.5)
+            .completion_ratio(1.5)
+            .exec(&mut db)
+            .await
+            .unwrap();
+        UsageLog::create()
+            .request_id("req-1")
+            .token_id(token.id)
+            .channel_id(channel.id)
+            .model("gpt-4o")
+            .prompt_tokens(1)
+            .completion_tokens(2)
+            .total_tokens(3)
+            .stream(false)
+            .quota(3)
+            .created_at(0)
+            .exec(&mut db)
+            .await
+            .unwrap();
+        assert_eq!(UsageLog::all().exec(&mut db).await.unwrap().len(), 1);
+        assert_eq!(ModelPrice::all().exec(&mut db).await.unwrap().len(), 1);
+        drop(db);
+
+        // The upgraded tables are the ones a new database gets.
+        let fresh = temp_db("fresh");
+        drop(
+            init_db(&database_url(fresh.to_str().unwrap()))
+                .await
+                .unwrap(),
+        );
+        assert_eq!(tables(&path), tables(&fresh));
+
+        for file in [&path, &backup, &fresh] {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[test]
+    fn test_database_url_and_file() {
+        assert_eq!(database_url("data/tokilake.db"), "sqlite:data/tokilake.db");
+        assert_eq!(database_url("sqlite::memory:"), MEMORY_DATABASE_URL);
+        assert_eq!(
+            database_file("sqlite:///var/lib/tokilake.db"),
+            Some(PathBuf::from("/var/lib/tokilake.db"))
+        );
+        assert_eq!(database_file(MEMORY_DATABASE_URL), None);
+    }
+}
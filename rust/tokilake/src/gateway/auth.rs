@@ -1,63 +1,221 @@
 //! Auth service — validates the Bearer token.
 //!
 //! Topmost layer of the gateway stack. Extracts `Authorization: Bearer <key>`
-//! from the incoming request, validates it (against Toasty DB in the future),
-//! and passes an `AuthedRequest` to the RouteService below.
+//! from the incoming request, looks the key up in the Toasty `Token` table
+//! (through a [`TokenCache`]), and passes an `AuthedRequest` carrying the
+//! token's identity to the ModelsService below. A token with a limited quota
+//! is turned away once its balance is used up, as is one past its expiry or
+//! used from an address outside its allowlist. The token's model allowlist
+//! and group are left to the RouteService, which reads the model.
 
-use super::AuthedRequest;
+use super::{
+    AuthedRequest, BoxError, GatewayConfig, GatewayResponse, TokenInfo, TokenScope, boxed_body,
+    error_response, quota::Quotas,
+};
+use crate::model::Token;
+use anyhow::Context;
 use bytes::Bytes;
-use http::{Request, Response, StatusCode};
-use http_body_util::Full;
-use hyper::body::Incoming;
+use http::{Request, StatusCode};
 use service_async::{
     MakeService, Service,
     layer::{FactoryLayer, layer_fn},
 };
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How long a lookup is trusted before the database is asked again, so
+/// changes made behind the gateway's back are picked up eventually.
+pub const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(60);
+
+/// What a key resolved to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenState {
+    Unknown,
+    Disabled,
+    Active(TokenInfo),
+}
+
+struct CachedToken {
+    state:      TokenState,
+    fetched_at: Instant,
+}
+
+/// In-process cache of token lookups by key. Clones share the same entries.
+#[derive(Clone)]
+pub struct TokenCache {
+    entries: Arc<RwLock<HashMap<String, CachedToken>>>,
+    ttl:     Duration,
+}
+
+impl Default for TokenCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_TOKEN_TTL)
+    }
+}
+
+impl TokenCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Arc::default(),
+            ttl,
+        }
+    }
+
+    /// Resolve `key`, from the cache if the entry is still fresh.
+    pub async fn resolve(&self, db: &toasty::Db, key: &str) -> anyhow::Result<TokenState> {
+        if let Some(entry) = self.entries.read().unwrap().get(key)
+            && entry.fetched_at.elapsed() < self.ttl
+        {
+            return Ok(entry.state.clone());
+        }
+
+        let mut db = db.clone();
+        let token = Token::filter(Token::fields().key().eq(key))
+            .first()
+            .exec(&mut db)
+            .await
+            .context("failed to look up token")?;
+        let state = match token {
+            None => TokenState::Unknown,
+            Some(token) if token.status != Token::STATUS_ENABLED => TokenState::Disabled,
+            Some(token) => TokenState::Active(TokenInfo {
+                scope:           TokenScope {
+                    expires_at:  token.expires_at,
+                    models:      token.allowed_models(),
+                    allowed_ips: token.allowed_networks(),
+                    group:       token.group.clone(),
+                },
+                id:              token.id,
+                name:            token.name,
+                unlimited_quota: token.unlimited_quota,
+            }),
+        };
+
+        // Misses are not cached: clients choose the keys they send, and every
+        // made-up key would otherwise stay in the map for good.
+        let mut entries = self.entries.write().unwrap();
+        if state == TokenState::Unknown {
+            entries.remove(key);
+        } else {
+            entries.insert(key.to_string(), CachedToken {
+                state:      state.clone(),
+                fetched_at: Instant::now(),
+            });
+        }
+        Ok(state)
+    }
+
+    /// Forget `key`; call after the token is created, changed or deleted.
+    pub fn invalidate(&self, key: &str) {
+        self.entries.write().unwrap().remove(key);
+    }
+
+    /// Forget every cached lookup.
+    pub fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+}
 
 /// Authentication service: validates Bearer tokens.
 pub struct AuthService<T> {
     pub inner: T,
+    db:        toasty::Db,
+    tokens:    TokenCache,
+    quotas:    Quotas,
 }
 
-impl<T> Service<Request<Incoming>> for AuthService<T>
+impl<T, B> Service<Request<B>> for AuthService<T>
 where
-    T: Service<AuthedRequest, Response = Response<Full<Bytes>>, Error = anyhow::Error>,
+    T: Service<AuthedRequest, Response = GatewayResponse, Error = anyhow::Error>,
+    B: hyper::body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
 {
-    type Response = Response<Full<Bytes>>;
+    type Response = GatewayResponse;
     type Error = anyhow::Error;
 
-    async fn call(&self, req: Request<Incoming>) -> Result<Self::Response, Self::Error> {
+    async fn call(&self, req: Request<B>) -> Result<Self::Response, Self::Error> {
         // Extract Bearer token
         let auth_header = req
             .headers()
             .get("authorization")
             .and_then(|v| v.to_str().ok())
             .and_then(|v| v.strip_prefix("Bearer "))
-            .map(|s| s.to_string());
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty());
 
         let Some(token_key) = auth_header else {
-            let body = serde_json::json!({
-                "error": {
-                    "message": "Missing or invalid Authorization header. Expected: Bearer <token>",
-                    "type": "invalid_request_error",
-                    "code": "invalid_api_key",
-                }
-            });
-            let resp = Response::builder()
-                .status(StatusCode::UNAUTHORIZED)
-                .header("content-type", "application/json")
-                .body(Full::new(Bytes::from(serde_json::to_vec(&body)?)))
-                .unwrap();
-            return Ok(resp);
+            return Ok(error_response(
+                StatusCode::UNAUTHORIZED,
+                "Missing or invalid Authorization header. Expected: Bearer <token>",
+                "invalid_request_error",
+                "invalid_api_key",
+            ));
         };
 
-        // TODO: Validate token_key against Toasty Token table.
-        // For now, accept any non-empty token.
-        let token_name = format!("token:{}", &token_key[..token_key.len().min(8)]);
+        let token = match self.tokens.resolve(&self.db, token_key).await? {
+            TokenState::Active(token) => token,
+            TokenState::Disabled => {
+                return Ok(error_response(
+                    StatusCode::UNAUTHORIZED,
+                    "This API key has been disabled",
+                    "invalid_request_error",
+                    "invalid_api_key",
+                ));
+            }
+            TokenState::Unknown => {
+                return Ok(error_response(
+                    StatusCode::UNAUTHORIZED,
+                    "Incorrect API key provided",
+                    "invalid_request_error",
+                    "invalid_api_key",
+                ));
+            }
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        if token.scope.expired(now) {
+            return Ok(error_response(
+                StatusCode::UNAUTHORIZED,
+                "This API key has expired",
+                "invalid_request_error",
+                "expired_api_key",
+            ));
+        }
+
+        // Set by axum's `into_make_service_with_connect_info`.
+        let client_ip = req
+            .extensions()
+            .get::<axum::extract::ConnectInfo<SocketAddr>>()
+            .map(|info| info.0.ip());
+        if !token.scope.allows_ip(client_ip) {
+            let client = client_ip.map_or("an unknown address".to_string(), |ip| ip.to_string());
+            return Ok(error_response(
+                StatusCode::FORBIDDEN,
+                &format!("This API key may not be used from {}", client),
+                "permission_error",
+                "ip_not_allowed",
+            ));
+        }
+
+        if !token.unlimited_quota && self.quotas.remaining(&self.db, token.id).await? <= 0 {
+            return Ok(error_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                "You exceeded your current quota",
+                "insufficient_quota",
+                "insufficient_quota",
+            ));
+        }
 
         let authed = AuthedRequest {
-            inner: req,
-            token_name,
+            inner: req.map(boxed_body),
+            token,
         };
 
         self.inner.call(authed).await
@@ -67,7 +225,10 @@ where
 // -- Factory / Layer ----------------------------------------------------------
 
 pub struct AuthServiceFactory<T> {
-    inner: T,
+    inner:  T,
+    db:     toasty::Db,
+    tokens: TokenCache,
+    quotas: Quotas,
 }
 
 impl<T: MakeService> MakeService for AuthServiceFactory<T> {
@@ -76,13 +237,185 @@ impl<T: MakeService> MakeService for AuthServiceFactory<T> {
 
     fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
         Ok(AuthService {
-            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            inner:  self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            db:     self.db.clone(),
+            tokens: self.tokens.clone(),
+            quotas: self.quotas.clone(),
         })
     }
 }
 
 impl<T> AuthService<T> {
-    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = AuthServiceFactory<T>> {
-        layer_fn(|_c: &C, inner| AuthServiceFactory { inner })
+    pub fn layer() -> impl FactoryLayer<GatewayConfig, T, Factory = AuthServiceFactory<T>> {
+        layer_fn(|c: &GatewayConfig, inner| AuthServiceFactory {
+            inner,
+            db: c.db.clone(),
+            tokens: c.tokens.clone(),
+            quotas: c.quotas.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway::full_body;
+    use http_body_util::BodyExt;
+
+    /// Leaf stub answering with the id and name of the authenticated token.
+    struct Whoami;
+
+    impl Service<AuthedRequest> for Whoami {
+        type Response = GatewayResponse;
+        type Error = anyhow::Error;
+
+        async fn call(&self, req: AuthedRequest) -> Result<Self::Response, Self::Error> {
+            let reply = format!("{} {}", req.token.id, req.token.name);
+            Ok(http::Response::new(full_body(reply)))
+        }
+    }
+
+    async fn service() -> AuthService<Whoami> {
+        let mut db = crate::db::init_db(crate::db::MEMORY_DATABASE_URL)
+            .await
+            .unwrap();
+        for (name, key, status, quota) in [
+            ("alice", "sk-alice", Token::STATUS_ENABLED, None),
+            ("bob", "sk-bob", 2, None),
+            ("carol", "sk-carol", Token::STATUS_ENABLED, Some(0)),
+        ] {
+            Token::create()
+                .name(name)
+                .key(key)
+                .status(status)
+                .remaining_quota(quota.unwrap_or_default())
+                .unlimited_quota(quota.is_none())
+                .exec(&mut db)
+                .await
+                .unwrap();
+        }
+        AuthService {
+            inner: Whoami,
+            db,
+            tokens: TokenCache::default(),
+            quotas: Quotas::default(),
+        }
+    }
+
+    async fn call(service: &AuthService<Whoami>, key: &str) -> (StatusCode, String) {
+        let request = Request::post("/v1/chat/completions")
+            .header("authorization", format!("Bearer {}", key))
+            .body(full_body(""))
+            .unwrap();
+        let response = service.call(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[tokio::test]
+    async fn test_only_enabled_tokens_pass() {
+        let service = service().await;
+        let (status, body) = call(&service, "sk-alice").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.ends_with(" alice"), "{}", body);
+
+        let (status, body) = call(&service, "sk-bob").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(body.contains("disabled"), "{}", body);
+
+        let (status, body) = call(&service, "sk-nobody").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(body.contains("invalid_api_key"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_quota_is_rejected_until_topped_up() {
+        let mut service = service().await;
+        let (status, body) = call(&service, "sk-carol").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(body.contains("insufficient_quota"), "{}", body);
+
+        let mut token = Token::filter(Token::fields().key().eq("sk-carol"))
+            .first()
+            .exec(&mut service.db)
+            .await
+            .unwrap()
+            .unwrap();
+        token
+            .update()
+            .remaining_quota(10)
+            .exec(&mut service.db)
+            .await
+            .unwrap();
+        service.quotas.invalidate(token.id);
+        assert_eq!(call(&service, "sk-carol").await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_expired_and_out_of_network_tokens_are_refused() {
+        let mut service = service().await;
+        for (name, expires_at, allowed_ips) in [
+            ("dave", Some(1), None),
+            ("erin", Some(i64::MAX), Some("10.0.0.0/8,192.168.1.7")),
+        ] {
+            Token::create()
+                .name(name)
+                .key(format!("sk-{}", name))
+                .status(Token::STATUS_ENABLED)
+                .expires_at(expires_at)
+                .allowed_ips(allowed_ips.map(str::to_string))
+                .exec(&mut service.db)
+                .await
+                .unwrap();
+        }
+        let (status, body) = call(&service, "sk-dave").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(body.contains("expired_api_key"), "{}", body);
+
+        // Without the connection's address the allowlist cannot be met.
+        let (status, body) = call(&service, "sk-erin").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body.contains("ip_not_allowed"), "{}", body);
+
+        for (addr, expected) in [
+            ("10.1.2.3:4000", StatusCode::OK),
+            ("[::ffff:192.168.1.7]:4000", StatusCode::OK),
+            ("192.168.1.8:4000", StatusCode::FORBIDDEN),
+        ] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let mut request = Request::post("/v1/chat/completions")
+                .header("authorization", "Bearer sk-erin")
+                .body(full_body(""))
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(axum::extract::ConnectInfo(addr));
+            let response = service.call(request).await.unwrap();
+            assert_eq!(response.status(), expected, "{}", addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_lookup_until_invalidated() {
+        let mut service = service().await;
+        assert_eq!(call(&service, "sk-alice").await.0, StatusCode::OK);
+
+        let token = Token::filter(Token::fields().key().eq("sk-alice"))
+            .first()
+            .exec(&mut service.db)
+            .await
+            .unwrap()
+            .unwrap();
+        token.delete().exec(&mut service.db).await.unwrap();
+        assert_eq!(call(&service, "sk-alice").await.0, StatusCode::OK);
+
+        service.tokens.invalidate("sk-alice");
+        assert_eq!(call(&service, "sk-alice").await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            call(&service, "sk-made-up").await.0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert!(service.tokens.entries.read().unwrap().is_empty());
     }
 }
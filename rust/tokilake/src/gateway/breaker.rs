@@ -0,0 +1,462 @@
+//! Breaker service — per-channel circuit breaker.
+//!
+//! Sits between the RetryService and the UpstreamService, so it sees every
+//! attempt on every channel. It records each attempt's outcome and latency
+//! in the shared [`Breakers`], and turns attempts on a channel whose circuit
+//! is open into an immediate 503, which the RetryService fails over from.
+//!
+//! A circuit opens after [`BreakerConfig::failure_threshold`] failures in a
+//! row (5xx, 429 or no response at all). After
+//! [`BreakerConfig::cool_down`], one probe request is let through: success
+//! closes the circuit, failure opens it again. Optionally, a channel whose
+//! circuit trips [`BreakerConfig::disable_after_trips`] times without
+//! recovering is auto-disabled in the database, with the reason recorded.
+
+use super::{GatewayConfig, GatewayRequest, GatewayResponse, error_response, retry::retryable};
+use crate::model::Channel;
+use http::StatusCode;
+use serde::Serialize;
+use service_async::{
+    MakeService, Service,
+    layer::{FactoryLayer, layer_fn},
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tracing::{error, warn};
+
+/// Weight of the newest sample in the latency average.
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    /// Failures in a row that open the circuit.
+    pub failure_threshold:   u32,
+    /// How long an open circuit skips the channel before a probe.
+    pub cool_down:           Duration,
+    /// Auto-disable the channel in the database after this many trips in a
+    /// row; `None` leaves the status alone.
+    pub disable_after_trips: Option<u32>,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold:   5,
+            cool_down:           Duration::from_secs(30),
+            disable_after_trips: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Traffic flows normally.
+    Closed,
+    /// The channel is skipped until the cool-down is over.
+    Open,
+    /// The cool-down is over; the next request is a probe.
+    HalfOpen,
+}
+
+/// What the breaker knows about one channel, as shown to admins.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelHealth {
+    pub state:                BreakerState,
+    pub requests:             u64,
+    pub failures:             u64,
+    pub error_rate:           f64,
+    pub consecutive_failures: u32,
+    /// Times the circuit opened since the channel last succeeded.
+    pub trips:                u32,
+    /// Smoothed time to the response headers.
+    pub latency_ms:           Option<f64>,
+    pub last_error:           Option<String>,
+    /// Seconds until an open circuit lets a probe through.
+    pub retry_in_secs:        Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Breaker {
+    requests:             u64,
+    failures:             u64,
+    consecutive_failures: u32,
+    trips:                u32,
+    latency_ms:           Option<f64>,
+    last_error:           Option<String>,
+    /// Set while the circuit is open: when a probe may go through.
+    open_until:           Option<Instant>,
+    /// Set while a probe is in flight.
+    probe_since:          Option<Instant>,
+}
+
+impl Breaker {
+    fn state(&self, now: Instant) -> BreakerState {
+        match self.open_until {
+            None => BreakerState::Closed,
+            Some(until) if now < until => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Whether a probe is in flight and has not been given up on yet.
+    fn probing(&self, now: Instant, cool_down: Duration) -> bool {
+        self.probe_since
+            .is_some_and(|since| now.duration_since(since) < cool_down)
+    }
+}
+
+/// Circuit breakers of all channels, by channel id. Clones share the same
+/// state, which therefore survives rebuilds of the stack.
+#[derive(Clone, Default)]
+pub struct Breakers {
+    config:   BreakerConfig,
+    channels: Arc<Mutex<HashMap<u64, Breaker>>>,
+}
+
+impl Breakers {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            channels: Arc::default(),
+        }
+    }
+
+    pub fn config(&self) -> &BreakerConfig {
+        &self.config
+    }
+
+    /// Whether a request may be sent to `channel` now. Past the cool-down of
+    /// an open circuit, this admits the single probe.
+    pub fn admit(&self, channel: u64) -> bool {
+        let now = Instant::now();
+        let mut channels = self.channels.lock().unwrap();
+        let Some(breaker) = channels.get_mut(&channel) else {
+            return true;
+        };
+        match breaker.state(now) {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen if breaker.probing(now, self.config.cool_down) => false,
+            BreakerState::HalfOpen => {
+                breaker.probe_since = Some(now);
+                true
+            }
+        }
+    }
+
+    /// Whether [`admit`](Self::admit) would let a request through, without
+    /// taking the probe.
+    pub fn available(&self, channel: u64) -> bool {
+        let now = Instant::now();
+        let channels = self.channels.lock().unwrap();
+        channels
+            .get(&channel)
+            .is_none_or(|breaker| match breaker.state(now) {
+                BreakerState::Closed => true,
+                BreakerState::Open => false,
+                BreakerState::HalfOpen => !breaker.probing(now, self.config.cool_down),
+            })
+    }
+
+    /// Record one attempt on `channel`: `Err` carries why it failed. Returns
+    /// the number of trips in a row if this failure opened the circuit.
+    pub fn record(
+        &self,
+        channel: u64,
+        elapsed: Duration,
+        result: Result<(), String>,
+    ) -> Option<u32> {
+        let now = Instant::now();
+        let mut channels = self.channels.lock().unwrap();
+        let breaker = channels.entry(channel).or_default();
+        let sample = elapsed.as_secs_f64() * 1000.0;
+        breaker.latency_ms = Some(match breaker.latency_ms {
+            Some(mean) => mean + LATENCY_SMOOTHING * (sample - mean),
+            None => sample,
+        });
+        breaker.requests += 1;
+
+        let Err(reason) = result else {
+            breaker.consecutive_failures = 0;
+            breaker.trips = 0;
+            breaker.open_until = None;
+            breaker.probe_since = None;
+            return None;
+        };
+        breaker.failures += 1;
+        breaker.consecutive_failures += 1;
+        breaker.last_error = Some(reason);
+        let probe_failed = breaker.probe_since.take().is_some();
+        let threshold_hit = breaker.open_until.is_none()
+            && breaker.consecutive_failures >= self.config.failure_threshold;
+        if !(probe_failed || threshold_hit) {
+            return None;
+        }
+        breaker.trips += 1;
+        breaker.open_until = Some(now + self.config.cool_down);
+        Some(breaker.trips)
+    }
+
+    /// Forget everything about `channel`, e.g. after an admin re-enabled it.
+    pub fn reset(&self, channel: u64) {
+        self.channels.lock().unwrap().remove(&channel);
+    }
+
+    /// The breaker of `channel`, if it has seen any traffic.
+    pub fn health(&self, channel: u64) -> Option<ChannelHealth> {
+        let now = Instant::now();
+        let channels = self.channels.lock().unwrap();
+        let breaker = channels.get(&channel)?;
+        Some(ChannelHealth {
+            state:                breaker.state(now),
+            requests:             breaker.requests,
+            failures:             breaker.failures,
+            error_rate:           match breaker.requests {
+                0 => 0.0,
+                requests => breaker.failures as f64 / requests as f64,
+            },
+            consecutive_failures: breaker.consecutive_failures,
+            trips:                breaker.trips,
+            latency_ms:           breaker.latency_ms,
+            last_error:           breaker.last_error.clone(),
+            retry_in_secs:        breaker
+                .open_until
+                .filter(|until| *until > now)
+                .map(|until| (until - now).as_secs()),
+        })
+    }
+}
+
+/// Circuit breaker service: guards and records every attempt.
+pub struct BreakerService<T> {
+    pub inner:        T,
+    breakers:         Breakers,
+    db:               toasty::Db,
+    channels_changed: Arc<Notify>,
+}
+
+impl<T> Service<GatewayRequest> for BreakerService<T>
+where
+    T: Service<GatewayRequest, Response = GatewayResponse, Error = anyhow::Error>,
+{
+    type Response = GatewayResponse;
+    type Error = anyhow::Error;
+
+    async fn call(&self, req: GatewayRequest) -> Result<Self::Response, Self::Error> {
+        let id = req.channel.id;
+        let name = req.channel.name.clone();
+        if !self.breakers.admit(id) {
+            return Ok(error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                &format!("channel {} is temporarily unavailable", name),
+                "api_error",
+                "channel_unavailable",
+            ));
+        }
+
+        let started = Instant::now();
+        let result = self.inner.call(req).await;
+        let outcome = match &result {
+            Ok(response) if retryable(response.status()) => {
+                Err(format!("HTTP {}", response.status()))
+            }
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("{:#}", e)),
+        };
+        let reason = outcome.clone().err();
+        if let Some(trips) = self.breakers.record(id, started.elapsed(), outcome) {
+            let config = self.breakers.config();
+            warn!(
+                "circuit of channel {} opened (trip {}) after {} failures in a row",
+                name, trips, config.failure_threshold
+            );
+            if config
+                .disable_after_trips
+                .is_some_and(|limit| trips >= limit)
+            {
+                let reason = format!(
+                    "circuit breaker tripped {} times in a row; last error: {}",
+                    trips,
+                    reason.unwrap_or_default()
+                );
+                if let Err(e) = self.disable(id, &reason).await {
+                    error!("failed to disable channel {}: {:#}", name, e);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<T> BreakerService<T> {
+    /// Auto-disable channel `id` in the database, unless it is already off.
+    async fn disable(&self, id: u64, reason: &str) -> anyhow::Result<()> {
+        let mut db = self.db.clone();
+        let Some(mut channel) = Channel::filter(Channel::fields().id().eq(id))
+            .first()
+            .exec(&mut db)
+            .await?
+        else {
+            return Ok(());
+        };
+        if channel.status != Channel::STATUS_ENABLED {
+            return Ok(());
+        }
+        channel
+            .update()
+            .status(Channel::STATUS_AUTO_DISABLED)
+            .status_reason(Some(reason.to_string()))
+            .exec(&mut db)
+            .await?;
+        warn!("channel {} auto-disabled: {}", channel.name, reason);
+        self.channels_changed.notify_one();
+        Ok(())
+    }
+}
+
+// -- Factory / Layer ----------------------------------------------------------
+
+pub struct BreakerServiceFactory<T> {
+    inner:            T,
+    breakers:         Breakers,
+    db:               toasty::Db,
+    channels_changed: Arc<Notify>,
+}
+
+impl<T: MakeService> MakeService for BreakerServiceFactory<T> {
+    type Service = BreakerService<T::Service>;
+    type Error = T::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(BreakerService {
+            inner:            self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            breakers:         self.breakers.clone(),
+            db:               self.db.clone(),
+            channels_changed: self.channels_changed.clone(),
+        })
+    }
+}
+
+impl<T> BreakerService<T> {
+    pub fn layer() -> impl FactoryLayer<GatewayConfig, T, Factory = BreakerServiceFactory<T>> {
+        layer_fn(|c: &GatewayConfig, inner| BreakerServiceFactory {
+            inner,
+            breakers: c.breakers.clone(),
+            db: c.db.clone(),
+            channels_changed: c.channels_changed.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway::{ChannelInfo, fixtures, full_body};
+
+    fn breakers(cool_down: Duration) -> Breakers {
+        Breakers::new(BreakerConfig {
+            failure_threshold: 2,
+            cool_down,
+            disable_after_trips: Some(2),
+        })
+    }
+
+    #[test]
+    fn test_opens_probes_and_closes() {
+        let breakers = breakers(Duration::from_secs(60));
+        let fail = || Err("HTTP 502".to_string());
+        assert!(breakers.admit(7));
+        assert_eq!(breakers.record(7, Duration::from_millis(100), fail()), None);
+        assert_eq!(
+            breakers.record(7, Duration::from_millis(300), fail()),
+            Some(1)
+        );
+        assert!(!breakers.available(7));
+        assert!(!breakers.admit(7));
+
+        let health = breakers.health(7).unwrap();
+        assert_eq!(health.state, BreakerState::Open);
+        assert_eq!((health.requests, health.failures, health.trips), (2, 2, 1));
+        assert_eq!(health.latency_ms, Some(140.0));
+        assert_eq!(health.last_error.as_deref(), Some("HTTP 502"));
+        assert!(health.retry_in_secs.is_some());
+        assert!(breakers.health(8).is_none());
+
+        // Past the cool-down exactly one probe goes through.
+        let breakers = self::breakers(Duration::ZERO);
+        breakers.record(7, Duration::ZERO, fail());
+        breakers.record(7, Duration::ZERO, fail());
+        assert_eq!(breakers.health(7).unwrap().state, BreakerState::HalfOpen);
+        assert!(breakers.admit(7));
+        assert_eq!(breakers.record(7, Duration::ZERO, fail()), Some(2));
+        assert!(breakers.admit(7));
+        assert_eq!(breakers.record(7, Duration::ZERO, Ok(())), None);
+        let health = breakers.health(7).unwrap();
+        assert_eq!(health.state, BreakerState::Closed);
+        assert_eq!((health.consecutive_failures, health.trips), (0, 0));
+    }
+
+    /// Leaf stub that always fails.
+    struct Down;
+
+    impl Service<GatewayRequest> for Down {
+        type Response = GatewayResponse;
+        type Error = anyhow::Error;
+
+        async fn call(&self, _req: GatewayRequest) -> Result<Self::Response, Self::Error> {
+            anyhow::bail!("connection refused")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_repeated_trips_disable_the_channel() {
+        let mut db = crate::db::init_db(crate::db::MEMORY_DATABASE_URL)
+            .await
+            .unwrap();
+        let channel = Channel::create()
+            .name("flaky")
+            .provider("openai")
+            .models("m")
+            .status(Channel::STATUS_ENABLED)
+            .weight(1)
+            .exec(&mut db)
+            .await
+            .unwrap();
+        let service = BreakerService {
+            inner:            Down,
+            breakers:         breakers(Duration::ZERO),
+            db:               db.clone(),
+            channels_changed: Arc::default(),
+        };
+        let info = ChannelInfo::from(channel);
+        for _ in 0..3 {
+            let request = GatewayRequest {
+                channel: info.clone(),
+                ..fixtures::request(http::Request::new(full_body("")))
+            };
+            assert!(service.call(request).await.is_err());
+        }
+
+        let channel = Channel::filter(Channel::fields().id().eq(info.id))
+            .first()
+            .exec(&mut db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(channel.status, Channel::STATUS_AUTO_DISABLED);
+        let reason = channel.status_reason.unwrap();
+        assert!(reason.contains("tripped 2 times"), "{}", reason);
+        assert!(reason.contains("connection refused"), "{}", reason);
+        // The relay is asked to rebuild the stack without the channel.
+        let Ok(()) =
+            tokio::time::timeout(Duration::from_secs(1), service.channels_changed.notified()).await
+        else {
+            panic!("no reload was requested");
+        };
+    }
+}
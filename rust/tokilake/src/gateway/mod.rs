@@ -8,42 +8,171 @@
 //! ```ignore
 //! FactoryStack::new(config)
 //!     .push(UpstreamService::layer())      // bottom: forward to upstream LLM provider
+//!     .push(UsageService::layer())         // record usage, charge quota
+//!     .push(BreakerService::layer())       // skip channels whose circuit is open
+//!     .push(RetryService::layer())         // fail over to another channel
+//!     .push(RateLimitService::layer())     // enforce RPM and TPM limits
 //!     .push(RouteService::layer())         // match model → channel
+//!     .push(ModelsService::layer())        // answer GET /v1/models
 //!     .push(AuthService::layer())          // validate Bearer token
 //! ```
 
 pub mod auth;
+pub mod breaker;
+pub mod models;
+pub mod quota;
+pub mod ratelimit;
+pub mod retry;
 pub mod route;
+pub mod tokiame;
 pub mod upstream;
+pub mod usage;
 
+use crate::model::{Channel, DEFAULT_GROUP};
+use anyhow::Context;
+use bytes::Bytes;
 use http::Request;
-use hyper::body::Incoming;
+use http_body_util::{BodyExt, Full, combinators::UnsyncBoxBody};
+use ipnet::IpNet;
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+use tokilake_core::ratelimit::RateLimiter;
+use tokio::sync::Notify;
 
 // ---------- Shared types flowing through the pipeline ----------
 
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Body type used for requests and responses inside the pipeline. Upstream
+/// responses are streamed through it without buffering.
+pub type GatewayBody = UnsyncBoxBody<Bytes, BoxError>;
+
+/// Response returned by every service in the pipeline.
+pub type GatewayResponse = http::Response<GatewayBody>;
+
+/// A complete, in-memory body (error replies and other local responses).
+pub fn full_body(data: impl Into<Bytes>) -> GatewayBody {
+    Full::new(data.into())
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+/// Box any body so it can flow through the pipeline.
+pub fn boxed_body<B>(body: B) -> GatewayBody
+where
+    B: hyper::body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    body.map_err(Into::into).boxed_unsync()
+}
+
+/// An error reply in the OpenAI API shape.
+pub fn error_response(
+    status: http::StatusCode,
+    message: &str,
+    error_type: &str,
+    code: &str,
+) -> GatewayResponse {
+    let body = serde_json::json!({
+        "error": {
+            "message": message,
+            "type": error_type,
+            "param": null,
+            "code": code,
+        }
+    });
+    http::Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(full_body(body.to_string()))
+        .expect("static response parts are valid")
+}
+
 /// Information about a resolved upstream channel.
 #[derive(Debug, Clone)]
 pub struct ChannelInfo {
-    pub name:     String,
-    pub provider: String,
-    pub base_url: Option<String>,
-    pub api_key:  Option<String>,
-    pub models:   String,
-    pub weight:   i32,
+    pub id:            u64,
+    pub name:          String,
+    pub provider:      String,
+    pub base_url:      Option<String>,
+    pub api_key:       Option<String>,
+    pub models:        String,
+    pub weight:        i32,
+    /// Names the channel's upstream uses for the models clients ask for.
+    pub model_mapping: HashMap<String, String>,
+}
+
+/// The client token a request was authenticated with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenInfo {
+    pub id:              u64,
+    pub name:            String,
+    /// Whether requests go through whatever the token's balance.
+    pub unlimited_quota: bool,
+    pub scope:           TokenScope,
+}
+
+/// When, from where and to what a token may be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenScope {
+    /// Unix time from which the token is refused.
+    pub expires_at:  Option<i64>,
+    /// Models the token may use; any if empty.
+    pub models:      Vec<String>,
+    /// Networks requests may come from; anywhere if empty.
+    pub allowed_ips: Vec<IpNet>,
+    /// The token is only routed to channels of this group.
+    pub group:       String,
+}
+
+impl Default for TokenScope {
+    fn default() -> Self {
+        Self {
+            expires_at:  None,
+            models:      Vec::new(),
+            allowed_ips: Vec::new(),
+            group:       DEFAULT_GROUP.to_string(),
+        }
+    }
+}
+
+impl TokenScope {
+    pub fn expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|at| now >= at)
+    }
+
+    pub fn allows_model(&self, model: &str) -> bool {
+        self.models.is_empty() || self.models.iter().any(|m| m == model)
+    }
+
+    /// Whether a request from `ip` may use the token; one from an unknown
+    /// address only if the token allows any.
+    pub fn allows_ip(&self, ip: Option<IpAddr>) -> bool {
+        if self.allowed_ips.is_empty() {
+            return true;
+        }
+        ip.is_some_and(|ip| {
+            let ip = ip.to_canonical();
+            self.allowed_ips.iter().any(|net| net.contains(&ip))
+        })
+    }
 }
 
 /// A request that has passed authentication.
 pub struct AuthedRequest {
-    pub inner:      Request<Incoming>,
-    pub token_name: String,
+    pub inner: Request<GatewayBody>,
+    pub token: TokenInfo,
 }
 
 /// A request that has been routed to a specific channel.
 pub struct GatewayRequest {
-    pub inner:      Request<Incoming>,
-    pub token_name: String,
+    pub inner:      Request<GatewayBody>,
+    pub token:      TokenInfo,
     pub model:      String,
     pub channel:    ChannelInfo,
+    /// Other channels serving the model, in the order to fail over to them.
+    pub fallbacks:  Vec<ChannelInfo>,
+    /// Identifies the request in logs and usage records.
+    pub request_id: String,
 }
 
 // ---------- Gateway configuration ----------
@@ -51,28 +180,153 @@ pub struct GatewayRequest {
 /// Config struct used by the `FactoryStack`.
 #[derive(Clone)]
 pub struct GatewayConfig {
-    // Future: DB handle, rate-limit settings, etc.
+    /// Database holding channels and tokens.
+    pub db:               toasty::Db,
+    /// Token lookups, shared across rebuilds of the stack.
+    pub tokens:           auth::TokenCache,
+    /// Connected tokiame workers, shared with the `/connect` endpoint.
+    pub workers:          Arc<tokiame::WorkerSessions>,
+    /// Enabled channels as of the last [`load_channels`](Self::load_channels);
+    /// the RouteService routes over this snapshot.
+    pub channels:         Arc<Vec<Channel>>,
+    /// Signalled when channels change outside the management API, e.g. when
+    /// a tokiame worker comes or goes, so the stack gets rebuilt.
+    pub channels_changed: Arc<Notify>,
+    /// How many fallback channels a failed request is retried on.
+    pub retries:          usize,
+    /// Circuit breakers of the channels, shared across rebuilds of the stack.
+    pub breakers:         breaker::Breakers,
+    /// Token balances, shared across rebuilds of the stack.
+    pub quotas:           quota::Quotas,
+    /// Model prices as of the last [`load_prices`](Self::load_prices).
+    pub prices:           quota::Prices,
+    /// Request and token rates, shared across rebuilds of the stack.
+    pub limiter:          Arc<RateLimiter>,
+    /// Model names clients may use for others, e.g. `gpt-4` for `gpt-4o`.
+    pub aliases:          Arc<HashMap<String, String>>,
+}
+
+impl GatewayConfig {
+    pub fn new(db: toasty::Db) -> Self {
+        Self {
+            db,
+            tokens: auth::TokenCache::default(),
+            workers: Default::default(),
+            channels: Default::default(),
+            channels_changed: Default::default(),
+            retries: retry::DEFAULT_RETRIES,
+            breakers: Default::default(),
+            quotas: Default::default(),
+            prices: Default::default(),
+            limiter: Default::default(),
+            aliases: Default::default(),
+        }
+    }
+
+    /// Replace the channel snapshot with the enabled channels in the database.
+    pub async fn load_channels(&mut self) -> anyhow::Result<()> {
+        let mut db = self.db.clone();
+        let channels = Channel::filter(Channel::fields().status().eq(Channel::STATUS_ENABLED))
+            .exec(&mut db)
+            .await
+            .context("failed to load channels")?;
+        self.channels = Arc::new(channels);
+        Ok(())
+    }
+
+    /// Replace the price table with the one in the database.
+    pub async fn load_prices(&mut self) -> anyhow::Result<()> {
+        self.prices = quota::Prices::load(&self.db).await?;
+        Ok(())
+    }
 }
 
+/// The factory of the composed stack: Auth → Route → RateLimit → Retry →
+/// Breaker → Usage → Upstream.
+pub type GatewayFactory = auth::AuthServiceFactory<
+    models::ModelsServiceFactory<
+        route::RouteServiceFactory<
+            ratelimit::RateLimitServiceFactory<
+                retry::RetryServiceFactory<
+                    breaker::BreakerServiceFactory<
+                        usage::UsageServiceFactory<upstream::UpstreamServiceFactory>,
+                    >,
+                >,
+            >,
+        >,
+    >,
+>;
+
+/// The composed service made by a [`GatewayFactory`].
+pub type GatewayService = auth::AuthService<
+    models::ModelsService<
+        route::RouteService<
+            ratelimit::RateLimitService<
+                retry::RetryService<
+                    breaker::BreakerService<usage::UsageService<upstream::UpstreamService>>,
+                >,
+            >,
+        >,
+    >,
+>;
+
 /// Build the complete gateway service stack.
 ///
 /// Returns a `MakeService` whose `.make()` produces the final composed Service.
-pub fn build_gateway_stack(
-    config: GatewayConfig,
-) -> impl service_async::MakeService<
-    Service = impl service_async::Service<
-        Request<Incoming>,
-        Response = http::Response<http_body_util::Full<bytes::Bytes>>,
-        Error = anyhow::Error,
-    >,
-    Error = std::convert::Infallible,
-> {
+pub fn build_gateway_stack(config: GatewayConfig) -> GatewayFactory {
     use service_async::stack::FactoryStack;
 
     let stack = FactoryStack::new(config)
         .push(upstream::UpstreamService::layer())
+        .push(usage::UsageService::layer())
+        .push(breaker::BreakerService::layer())
+        .push(retry::RetryService::layer())
+        .push(ratelimit::RateLimitService::layer())
         .push(route::RouteService::layer())
+        .push(models::ModelsService::layer())
         .push(auth::AuthService::layer());
 
     stack.into_inner()
 }
+
+/// Requests, tokens and channels for the tests of the gateway layers.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+
+    /// An OpenAI channel serving `m`.
+    pub fn channel(name: &str) -> ChannelInfo {
+        ChannelInfo {
+            id:            1,
+            name:          name.into(),
+            provider:      "openai".into(),
+            base_url:      None,
+            api_key:       None,
+            models:        "m".into(),
+            weight:        1,
+            model_mapping: HashMap::new(),
+        }
+    }
+
+    /// A token with an unlimited quota and no restrictions.
+    pub fn token(id: u64) -> TokenInfo {
+        TokenInfo {
+            id,
+            name: "test".into(),
+            unlimited_quota: true,
+            scope: TokenScope::default(),
+        }
+    }
+
+    /// `inner` from token 1, routed to the `local` channel for model `m`.
+    pub fn request(inner: Request<GatewayBody>) -> GatewayRequest {
+        GatewayRequest {
+            inner,
+            token: token(1),
+            model: "m".into(),
+            channel: channel("local"),
+            fallbacks: Vec::new(),
+            request_id: "req".into(),
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! Models service — answers `GET /v1/models` and `GET /v1/models/{id}`.
+//!
+//! Sits between the AuthService and the RouteService. Lists what the caller's
+//! token can be routed to: the models of the enabled channels of its group,
+//! the aliases of those models, and the models the tokiame workers behind
+//! those channels advertise right now. A token with a model allowlist only
+//! sees the models on it. Workers' models are owned by their namespace,
+//! every other model by the channel serving it. Every other request goes on
+//! to the RouteService.
+
+use super::{
+    AuthedRequest, GatewayConfig, GatewayResponse, TokenScope, error_response, full_body,
+    tokiame::{self, WorkerSessions},
+};
+use crate::model::Channel;
+use http::{Method, StatusCode, header};
+use serde::Serialize;
+use service_async::{
+    MakeService, Service,
+    layer::{FactoryLayer, layer_fn},
+};
+use std::{collections::HashMap, sync::Arc};
+use tokilake_core::model_list::ModelList;
+
+const MODELS_PATH: &str = "/v1/models";
+
+/// Models service: lists models, delegates everything else to inner.
+pub struct ModelsService<T> {
+    pub inner: T,
+    channels:  Arc<Vec<Channel>>,
+    workers:   Arc<WorkerSessions>,
+    aliases:   Arc<HashMap<String, String>>,
+}
+
+impl<T> Service<AuthedRequest> for ModelsService<T>
+where
+    T: Service<AuthedRequest, Response = GatewayResponse, Error = anyhow::Error>,
+{
+    type Response = GatewayResponse;
+    type Error = anyhow::Error;
+
+    async fn call(&self, req: AuthedRequest) -> Result<Self::Response, Self::Error> {
+        if req.inner.method() != Method::GET {
+            return self.inner.call(req).await;
+        }
+        let path = req.inner.uri().path();
+        let id = match path.strip_prefix(MODELS_PATH) {
+            Some("" | "/") => None,
+            Some(rest) => match rest.strip_prefix('/') {
+                Some(id) => Some(id.to_string()),
+                None => return self.inner.call(req).await,
+            },
+            None => return self.inner.call(req).await,
+        };
+
+        let models = self.models(&req.token.scope).await;
+        let Some(id) = id else {
+            return Ok(json_response(&models));
+        };
+        match models.get(&id) {
+            Some(model) => Ok(json_response(model)),
+            None => Ok(error_response(
+                StatusCode::NOT_FOUND,
+                &format!("The model '{}' does not exist", id),
+                "invalid_request_error",
+                "model_not_found",
+            )),
+        }
+    }
+}
+
+impl<T> ModelsService<T> {
+    /// The models `scope` allows, with their owners.
+    async fn models(&self, scope: &TokenScope) -> ModelList {
+        let channels: Vec<&Channel> = self
+            .channels
+            .iter()
+            .filter(|c| c.group == scope.group)
+            .collect();
+
+        // Live workers first, so their namespace owns what they serve.
+        let mut models: Vec<(String, String)> = Vec::new();
+        for worker in self.workers.workers().await {
+            let routed = channels.iter().any(|c| {
+                c.provider == tokiame::PROVIDER
+                    && c.base_url.as_deref().and_then(tokiame::namespace)
+                        == Some(worker.namespace.as_str())
+            });
+            if routed {
+                for model in worker.models {
+                    models.push((model, worker.namespace.clone()));
+                }
+            }
+        }
+        for channel in &channels {
+            let listed = channel
+                .models
+                .split(',')
+                .map(str::trim)
+                .filter(|m| !m.is_empty())
+                .map(str::to_string);
+            for model in listed.chain(channel.upstream_models().into_keys()) {
+                models.push((model, channel.name.clone()));
+            }
+        }
+
+        let owners: HashMap<&str, &str> = models
+            .iter()
+            .rev()
+            .map(|(model, owner)| (model.as_str(), owner.as_str()))
+            .collect();
+        // As in routing, an alias counts if it or its target is allowed.
+        let aliases: Vec<(String, String)> = self
+            .aliases
+            .iter()
+            .filter(|(alias, target)| scope.allows_model(alias) || scope.allows_model(target))
+            .filter_map(|(alias, target)| {
+                let owner = owners.get(target.as_str())?;
+                Some((alias.clone(), owner.to_string()))
+            })
+            .collect();
+
+        ModelList::new(
+            models
+                .into_iter()
+                .filter(|(model, _)| scope.allows_model(model))
+                .chain(aliases),
+        )
+    }
+}
+
+fn json_response(body: &impl Serialize) -> GatewayResponse {
+    let body = serde_json::to_vec(body).expect("a model list is JSON");
+    let mut response = http::Response::new(full_body(body));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+// -- Factory / Layer ----------------------------------------------------------
+
+pub struct ModelsServiceFactory<T> {
+    inner:    T,
+    channels: Arc<Vec<Channel>>,
+    workers:  Arc<WorkerSessions>,
+    aliases:  Arc<HashMap<String, String>>,
+}
+
+impl<T: MakeService> MakeService for ModelsServiceFactory<T> {
+    type Service = ModelsService<T::Service>;
+    type Error = T::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(ModelsService {
+            inner:    self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            channels: self.channels.clone(),
+            workers:  self.workers.clone(),
+            aliases:  self.aliases.clone(),
+        })
+    }
+}
+
+impl<T> ModelsService<T> {
+    pub fn layer() -> impl FactoryLayer<GatewayConfig, T, Factory = ModelsServiceFactory<T>> {
+        layer_fn(|c: &GatewayConfig, inner| ModelsServiceFactory {
+            inner,
+            channels: c.channels.clone(),
+            workers: c.workers.clone(),
+            aliases: c.aliases.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway::{TokenInfo, fixtures};
+    use http_body_util::BodyExt;
+
+    /// Leaf stub standing in for the RouteService.
+    struct Routed;
+
+    impl Service<AuthedRequest> for Routed {
+        type Response = GatewayResponse;
+        type Error = anyhow::Error;
+
+        async fn call(&self, _req: AuthedRequest) -> Result<Self::Response, Self::Error> {
+            Ok(http::Response::new(full_body("routed")))
+        }
+    }
+
+    async fn service() -> ModelsService<Routed> {
+        let mut db = crate::db::init_db(crate::db::MEMORY_DATABASE_URL)
+            .await
+            .unwrap();
+        for (name, models, group) in [
+            ("openai-main", "gpt-4o,gpt-4o-mini", "default"),
+            ("openai-backup", "gpt-4o", "default"),
+            ("reasoning", "o1", "vip"),
+        ] {
+            Channel::create()
+                .name(name)
+                .provider("openai")
+                .models(models)
+                .status(Channel::STATUS_ENABLED)
+                .weight(1)
+                .group(group)
+                .exec(&mut db)
+                .await
+                .unwrap();
+        }
+        let mut config = GatewayConfig::new(db);
+        config.load_channels().await.unwrap();
+        ModelsService {
+            inner:    Routed,
+            channels: config.channels,
+            workers:  config.workers,
+            aliases:  Arc::new(HashMap::from([("gpt-4".into(), "gpt-4o".into())])),
+        }
+    }
+
+    async fn get(
+        service: &ModelsService<Routed>,
+        scope: TokenScope,
+        path: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let inner = http::Request::get(path).body(full_body("")).unwrap();
+        let response = service
+            .call(AuthedRequest {
+                inner,
+                token: TokenInfo {
+                    scope,
+                    ..fixtures::token(1)
+                },
+            })
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_lists_models_of_the_token_group() {
+        let service = service().await;
+        let (status, body) = get(&service, TokenScope::default(), "/v1/models").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["object"], "list");
+        let listed: Vec<(&str, &str)> = body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| (m["id"].as_str().unwrap(), m["owned_by"].as_str().unwrap()))
+            .collect();
+        assert_eq!(listed, [
+            ("gpt-4", "openai-main"),
+            ("gpt-4o", "openai-main"),
+            ("gpt-4o-mini", "openai-main"),
+        ]);
+
+        let vip = TokenScope {
+            group: "vip".into(),
+            ..Default::default()
+        };
+        let (status, body) = get(&service, vip, "/v1/models/o1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["object"], "model");
+        assert_eq!(body["owned_by"], "reasoning");
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_hides_models() {
+        let service = service().await;
+        let contractor = TokenScope {
+            models: vec!["gpt-4o-mini".into()],
+            ..Default::default()
+        };
+        let (_, body) = get(&service, contractor.clone(), "/v1/models").await;
+        assert_eq!(body["data"].as_array().unwrap().len(), 1);
+        assert_eq!(body["data"][0]["id"], "gpt-4o-mini");
+
+        let (status, body) = get(&service, contractor, "/v1/models/gpt-4o").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "model_not_found");
+
+        // Other paths go on down the stack.
+        let (status, _) = get(&service, TokenScope::default(), "/v1/modelsx").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
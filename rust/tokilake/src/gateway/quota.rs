@@ -0,0 +1,247 @@
+//! Token quotas — balances and the model price table.
+//!
+//! A request costs `prompt_tokens × prompt_ratio + completion_tokens ×
+//! completion_ratio` quota, rounded up, with the ratios of the model's
+//! [`ModelPrice`]. The UsageService charges the cost to the token once the
+//! usage is known; every token's spending is added up in `used_quota`, and a
+//! token without `unlimited_quota` also has it taken from `remaining_quota`.
+//!
+//! The AuthService turns away requests of a limited token with nothing left,
+//! and the UsageService cuts a stream off once its estimated cost passes what
+//! was left when it started. Concurrent requests of one token may each spend
+//! the same balance, so a token can end up slightly below zero.
+
+use crate::model::{ModelPrice, Token};
+use anyhow::Context;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokilake_core::usage::Usage;
+
+/// The price table as of the last [`load`](Self::load), by model.
+#[derive(Debug, Clone, Default)]
+pub struct Prices(Arc<HashMap<String, ModelPrice>>);
+
+impl Prices {
+    pub async fn load(db: &toasty::Db) -> anyhow::Result<Self> {
+        let mut db = db.clone();
+        let prices = ModelPrice::all()
+            .exec(&mut db)
+            .await
+            .context("failed to load model prices")?;
+        Ok(Self(Arc::new(
+            prices.into_iter().map(|p| (p.model.clone(), p)).collect(),
+        )))
+    }
+
+    /// Prompt and completion ratio of `model`.
+    pub fn ratios(&self, model: &str) -> (f64, f64) {
+        self.0.get(model).map_or(
+            (ModelPrice::DEFAULT_RATIO, ModelPrice::DEFAULT_RATIO),
+            |p| (p.prompt_ratio, p.completion_ratio),
+        )
+    }
+
+    /// Quota charged for `usage` of `model`.
+    pub fn cost(&self, model: &str, usage: &Usage) -> i64 {
+        let (prompt_ratio, completion_ratio) = self.ratios(model);
+        let cost = usage.prompt_tokens as f64 * prompt_ratio
+            + usage.completion_tokens as f64 * completion_ratio;
+        cost.max(0.0).ceil() as i64
+    }
+}
+
+/// How many locks charges are spread over; tokens whose ids share a lock
+/// wait for each other, all others charge side by side.
+const CHARGE_LOCKS: usize = 64;
+
+/// Balances of limited tokens, shared across rebuilds of the stack. The
+/// database is the record; this only saves reading it on every request.
+#[derive(Clone)]
+pub struct Quotas {
+    balances: Arc<Mutex<HashMap<u64, i64>>>,
+    /// A token's charges and balance changes hold the lock of its id while
+    /// they read and write it, so none of them overwrites another.
+    locks:    Arc<[tokio::sync::Mutex<()>]>,
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Self {
+            balances: Arc::default(),
+            locks:    (0..CHARGE_LOCKS).map(|_| Default::default()).collect(),
+        }
+    }
+}
+
+impl Quotas {
+    /// Lock the balance of `token_id`. An operator changing a token's quota
+    /// holds this across the write and the [`invalidate`](Self::invalidate)
+    /// after it, so a charge in progress can't put back the old balance.
+    pub async fn lock(&self, token_id: u64) -> tokio::sync::MutexGuard<'_, ()> {
+        self.locks[(token_id % self.locks.len() as u64) as usize]
+            .lock()
+            .await
+    }
+
+    /// Quota left to `token_id`; an unknown token has none.
+    pub async fn remaining(&self, db: &toasty::Db, token_id: u64) -> anyhow::Result<i64> {
+        if let Some(balance) = self.balances.lock().unwrap().get(&token_id) {
+            return Ok(*balance);
+        }
+        let _locked = self.lock(token_id).await;
+        let mut db = db.clone();
+        let token = Token::filter(Token::fields().id().eq(token_id))
+            .first()
+            .exec(&mut db)
+            .await
+            .context("failed to look up token quota")?;
+        let balance = token.map_or(0, |t| t.remaining_quota);
+        self.balances.lock().unwrap().insert(token_id, balance);
+        Ok(balance)
+    }
+
+    /// Charge `quota` to `token_id`.
+    pub async fn charge(&self, db: &toasty::Db, token_id: u64, quota: i64) -> anyhow::Result<()> {
+        let _locked = self.lock(token_id).await;
+        let mut db = db.clone();
+        let Some(mut token) = Token::filter(Token::fields().id().eq(token_id))
+            .first()
+            .exec(&mut db)
+            .await
+            .context("failed to look up token quota")?
+        else {
+            // Deleted while its request was in flight.
+            self.invalidate(token_id);
+            return Ok(());
+        };
+        let remaining = if token.unlimited_quota {
+            token.remaining_quota
+        } else {
+            token.remaining_quota - quota
+        };
+        let used = token.used_quota + quota;
+        token
+            .update()
+            .remaining_quota(remaining)
+            .used_quota(used)
+            .exec(&mut db)
+            .await
+            .context("failed to charge token quota")?;
+        self.balances.lock().unwrap().insert(token_id, remaining);
+        Ok(())
+    }
+
+    /// Forget the balance of `token_id`; call after an operator changed it.
+    pub fn invalidate(&self, token_id: u64) {
+        self.balances.lock().unwrap().remove(&token_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_charges_limited_and_unlimited_tokens() {
+        let mut db = crate::db::init_db(crate::db::MEMORY_DATABASE_URL)
+            .await
+            .unwrap();
+        let limited = Token::create()
+            .name("team")
+            .key("sk-team")
+            .status(Token::STATUS_ENABLED)
+            .remaining_quota(100)
+            .unlimited_quota(false)
+            .exec(&mut db)
+            .await
+            .unwrap();
+        let unlimited = Token::create()
+            .name("ops")
+            .key("sk-ops")
+            .status(Token::STATUS_ENABLED)
+            .exec(&mut db)
+            .await
+            .unwrap();
+
+        let quotas = Quotas::default();
+        assert_eq!(quotas.remaining(&db, limited.id).await.unwrap(), 100);
+        quotas.charge(&db, limited.id, 30).await.unwrap();
+        quotas.charge(&db, limited.id, 80).await.unwrap();
+        assert_eq!(quotas.remaining(&db, limited.id).await.unwrap(), -10);
+        quotas.charge(&db, unlimited.id, 50).await.unwrap();
+
+        let tokens = Token::all().exec(&mut db).await.unwrap();
+        let stored: Vec<_> = tokens
+            .iter()
+            .map(|t| (t.name.as_str(), t.remaining_quota, t.used_quota))
+            .collect();
+        assert_eq!(stored, [("team", -10, 110), ("ops", 0, 50)]);
+        assert_eq!(quotas.remaining(&db, 99).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_top_up_during_a_charge_is_kept() {
+        let mut db = crate::db::init_db(crate::db::MEMORY_DATABASE_URL)
+            .await
+            .unwrap();
+        let mut token = Token::create()
+            .name("team")
+            .key("sk-team")
+            .status(Token::STATUS_ENABLED)
+            .remaining_quota(100)
+            .unlimited_quota(false)
+            .exec(&mut db)
+            .await
+            .unwrap();
+        let id = token.id;
+
+        let quotas = Quotas::default();
+        assert_eq!(quotas.remaining(&db, id).await.unwrap(), 100);
+        let locked = quotas.lock(id).await;
+        let charging = tokio::spawn({
+            let (quotas, db) = (quotas.clone(), db.clone());
+            async move { quotas.charge(&db, id, 30).await }
+        });
+        // An operator tops the token up while the charge waits.
+        token
+            .update()
+            .remaining_quota(1000)
+            .exec(&mut db)
+            .await
+            .unwrap();
+        quotas.invalidate(id);
+        drop(locked);
+        charging.await.unwrap().unwrap();
+
+        assert_eq!(quotas.remaining(&db, id).await.unwrap(), 970);
+        let stored = Token::filter(Token::fields().id().eq(id))
+            .first()
+            .exec(&mut db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((stored.remaining_quota, stored.used_quota), (970, 30));
+    }
+
+    #[test]
+    fn test_cost_rounds_up_with_default_ratio() {
+        let prices = Prices(Arc::new(HashMap::from([(
+            "gpt-4o".to_string(),
+            ModelPrice {
+                id:               1,
+                model:            "gpt-4o".into(),
+                prompt_ratio:     0.5,
+                completion_ratio: 1.5,
+            },
+        )])));
+        let usage = Usage {
+            prompt_tokens:     3,
+            completion_tokens: 1,
+            total_tokens:      4,
+        };
+        assert_eq!(prices.cost("gpt-4o", &usage), 3);
+        assert_eq!(prices.cost("other", &usage), 4);
+    }
+}
@@ -0,0 +1,158 @@
+//! Rate-limit service — requests and tokens per minute.
+//!
+//! Sits right below the RouteService, which has found the token and the
+//! model. Each request is admitted by the shared [`RateLimiter`] or answered
+//! with a 429 carrying `Retry-After`; either way the response gets OpenAI's
+//! `x-ratelimit-*` headers. A request counts a token per four bytes of text in
+//! its JSON body when admitted, and nothing for other bodies such as audio
+//! uploads; the UsageService corrects that once the real usage is known.
+
+use super::{GatewayConfig, GatewayRequest, GatewayResponse, error_response, full_body};
+use anyhow::Context;
+use http::StatusCode;
+use http_body_util::BodyExt;
+use service_async::{
+    MakeService, Service,
+    layer::{FactoryLayer, layer_fn},
+};
+use std::sync::Arc;
+use tokilake_core::ratelimit::{RateLimiter, estimate_request_tokens};
+use tracing::debug;
+
+/// Rate-limit service: turns away requests over their token's or model's
+/// limits.
+pub struct RateLimitService<T> {
+    pub inner: T,
+    limiter:   Arc<RateLimiter>,
+}
+
+impl<T> Service<GatewayRequest> for RateLimitService<T>
+where
+    T: Service<GatewayRequest, Response = GatewayResponse, Error = anyhow::Error>,
+{
+    type Response = GatewayResponse;
+    type Error = anyhow::Error;
+
+    async fn call(&self, mut req: GatewayRequest) -> Result<Self::Response, Self::Error> {
+        if self.limiter.limits().is_unlimited() {
+            return self.inner.call(req).await;
+        }
+        // The RouteService has the body in memory, so reading it is cheap.
+        let (parts, body) = req.inner.into_parts();
+        let body = body
+            .collect()
+            .await
+            .map_err(|e| anyhow::anyhow!(e))
+            .context("failed to read request body")?
+            .to_bytes();
+        let tokens = estimate_request_tokens(&body);
+        req.inner = http::Request::from_parts(parts, full_body(body));
+        let status = match self.limiter.admit(req.token.id, &req.model, tokens) {
+            Ok(status) => status,
+            Err(limited) => {
+                debug!(
+                    "request {} of token {} limited: {}",
+                    req.request_id, req.token.id, limited.reason
+                );
+                let mut response = error_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    &limited.reason,
+                    "requests",
+                    "rate_limit_exceeded",
+                );
+                limited.apply(response.headers_mut());
+                return Ok(response);
+            }
+        };
+        let mut response = self.inner.call(req).await?;
+        status.apply(response.headers_mut());
+        Ok(response)
+    }
+}
+
+// -- Factory / Layer ----------------------------------------------------------
+
+pub struct RateLimitServiceFactory<T> {
+    inner:   T,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<T: MakeService> MakeService for RateLimitServiceFactory<T> {
+    type Service = RateLimitService<T::Service>;
+    type Error = T::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(RateLimitService {
+            inner:   self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            limiter: self.limiter.clone(),
+        })
+    }
+}
+
+impl<T> RateLimitService<T> {
+    pub fn layer() -> impl FactoryLayer<GatewayConfig, T, Factory = RateLimitServiceFactory<T>> {
+        layer_fn(|c: &GatewayConfig, inner| RateLimitServiceFactory {
+            inner,
+            limiter: c.limiter.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway::fixtures;
+    use tokilake_core::ratelimit::{Limits, RateLimits};
+
+    /// Leaf stub answering every request.
+    struct Ok200;
+
+    impl Service<GatewayRequest> for Ok200 {
+        type Response = GatewayResponse;
+        type Error = anyhow::Error;
+
+        async fn call(&self, _req: GatewayRequest) -> Result<Self::Response, Self::Error> {
+            Ok(http::Response::new(full_body("ok")))
+        }
+    }
+
+    fn request(token_id: u64) -> GatewayRequest {
+        let inner = http::Request::post("/v1/chat/completions")
+            .body(full_body(r#"{"model":"m"}"#))
+            .unwrap();
+        GatewayRequest {
+            token: fixtures::token(token_id),
+            ..fixtures::request(inner)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_over_the_limit_is_429_with_headers() {
+        let service = RateLimitService {
+            inner:   Ok200,
+            limiter: Arc::new(RateLimiter::new(RateLimits {
+                per_token: Limits {
+                    rpm: Some(1),
+                    tpm: Some(1000),
+                },
+                ..Default::default()
+            })),
+        };
+        let response = service.call(request(1)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers["x-ratelimit-remaining-requests"], "0");
+        // The text of `{"model":"m"}` is one byte, rounded up to a token.
+        assert_eq!(headers["x-ratelimit-remaining-tokens"], "999");
+
+        let response = service.call(request(1)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(http::header::RETRY_AFTER));
+        assert_eq!(response.headers()["x-ratelimit-limit-requests"], "1");
+
+        assert_eq!(
+            service.call(request(2)).await.unwrap().status(),
+            StatusCode::OK
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! Retry service — fails over to another channel.
+//!
+//! Sits between the RouteService and the UpstreamService. When the channel
+//! the RouteService picked answers 5xx or 429, or cannot be reached at all,
+//! the request is sent again to the next of its `fallbacks`, up to
+//! [`GatewayConfig::retries`] times.
+//!
+//! The decision is made on the status line alone: a failed response is
+//! dropped before any of its body is read, and once a response is handed up
+//! the stack it is final, so no byte ever reaches the client from an attempt
+//! that is later retried. Errors in the middle of a streamed body are not
+//! retried.
+//!
+//! Every attempt is logged, and the list of them is attached to the final
+//! response as an [`Attempts`] extension.
+
+use super::{GatewayConfig, GatewayRequest, GatewayResponse, full_body};
+use anyhow::Context;
+use http::StatusCode;
+use http_body_util::BodyExt;
+use service_async::{
+    MakeService, Service,
+    layer::{FactoryLayer, layer_fn},
+};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Further channels tried after the first one fails.
+pub const DEFAULT_RETRIES: usize = 2;
+
+/// How one attempt ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Status(StatusCode),
+    Error(String),
+}
+
+/// One try of a request against one channel.
+#[derive(Debug, Clone)]
+pub struct Attempt {
+    pub channel: String,
+    pub outcome: Outcome,
+    pub elapsed: Duration,
+}
+
+/// Every attempt made for a request, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct Attempts(pub Vec<Attempt>);
+
+/// Whether another channel may do better than this answer.
+pub(super) fn retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Retry service: replays the request on fallback channels.
+pub struct RetryService<T> {
+    pub inner: T,
+    retries:   usize,
+}
+
+impl<T> Service<GatewayRequest> for RetryService<T>
+where
+    T: Service<GatewayRequest, Response = GatewayResponse, Error = anyhow::Error>,
+{
+    type Response = GatewayResponse;
+    type Error = anyhow::Error;
+
+    async fn call(&self, req: GatewayRequest) -> Result<Self::Response, Self::Error> {
+        if req.fallbacks.is_empty() || self.retries == 0 {
+            return self.inner.call(req).await;
+        }
+        let GatewayRequest {
+            inner,
+            token,
+            model,
+            channel,
+            fallbacks,
+            request_id,
+        } = req;
+
+        // Kept in memory so every attempt can send it again.
+        let (parts, body) = inner.into_parts();
+        let body = body
+            .collect()
+            .await
+            .map_err(|e| anyhow::anyhow!(e))
+            .context("failed to read request body")?
+            .to_bytes();
+
+        let channels: Vec<_> = std::iter::once(channel)
+            .chain(fallbacks)
+            .take(self.retries + 1)
+            .collect();
+        let last = channels.len() - 1;
+        let mut attempts = Vec::with_capacity(channels.len());
+        for (n, channel) in channels.into_iter().enumerate() {
+            let name = channel.name.clone();
+            let started = Instant::now();
+            let result = self
+                .inner
+                .call(GatewayRequest {
+                    inner: http::Request::from_parts(parts.clone(), full_body(body.clone())),
+                    token: token.clone(),
+                    model: model.clone(),
+                    channel,
+                    fallbacks: Vec::new(),
+                    request_id: request_id.clone(),
+                })
+                .await;
+            let outcome = match &result {
+                Ok(response) => Outcome::Status(response.status()),
+                Err(e) => Outcome::Error(format!("{:#}", e)),
+            };
+            let attempt = Attempt {
+                channel: name,
+                outcome,
+                elapsed: started.elapsed(),
+            };
+
+            let failed = match &attempt.outcome {
+                Outcome::Status(status) => retryable(*status),
+                Outcome::Error(_) => true,
+            };
+            if failed {
+                warn!(
+                    "request {} model {} attempt {} on channel {} failed after {:?}: {:?}",
+                    request_id,
+                    model,
+                    n + 1,
+                    attempt.channel,
+                    attempt.elapsed,
+                    attempt.outcome
+                );
+            } else if n > 0 {
+                info!(
+                    "model {} served by channel {} on attempt {}",
+                    model,
+                    attempt.channel,
+                    n + 1
+                );
+            }
+            attempts.push(attempt);
+
+            if !failed || n == last {
+                return result.map(|mut response| {
+                    response.extensions_mut().insert(Attempts(attempts));
+                    response
+                });
+            }
+        }
+        unreachable!("at least one channel is always tried")
+    }
+}
+
+// -- Factory / Layer ----------------------------------------------------------
+
+pub struct RetryServiceFactory<T> {
+    inner:   T,
+    retries: usize,
+}
+
+impl<T: MakeService> MakeService for RetryServiceFactory<T> {
+    type Service = RetryService<T::Service>;
+    type Error = T::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(RetryService {
+            inner:   self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            retries: self.retries,
+        })
+    }
+}
+
+impl<T> RetryService<T> {
+    pub fn layer() -> impl FactoryLayer<GatewayConfig, T, Factory = RetryServiceFactory<T>> {
+        layer_fn(|c: &GatewayConfig, inner| RetryServiceFactory {
+            inner,
+            retries: c.retries,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway::fixtures;
+    use std::sync::Mutex;
+
+    /// Leaf stub answering per channel name, and recording who was called
+    /// with which body.
+    #[derive(Default)]
+    struct Scripted {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl Service<GatewayRequest> for Scripted {
+        type Response = GatewayResponse;
+        type Error = anyhow::Error;
+
+        async fn call(&self, req: GatewayRequest) -> Result<Self::Response, Self::Error> {
+            let body = req.inner.into_body().collect().await.unwrap().to_bytes();
+            let name = req.channel.name;
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", name, String::from_utf8_lossy(&body)));
+            let status = match name.as_str() {
+                "down" => anyhow::bail!("connection refused"),
+                "busy" => StatusCode::TOO_MANY_REQUESTS,
+                "broken" => StatusCode::BAD_GATEWAY,
+                "picky" => StatusCode::BAD_REQUEST,
+                _ => StatusCode::OK,
+            };
+            let mut response = http::Response::new(full_body(name));
+            *response.status_mut() = status;
+            Ok(response)
+        }
+    }
+
+    async fn call(
+        retries: usize,
+        names: &[&str],
+    ) -> (anyhow::Result<GatewayResponse>, Vec<String>) {
+        let service = RetryService {
+            inner: Scripted::default(),
+            retries,
+        };
+        let inner = http::Request::post("/v1/chat/completions")
+            .body(full_body("{}"))
+            .unwrap();
+        let result = service
+            .call(GatewayRequest {
+                channel: fixtures::channel(names[0]),
+                fallbacks: names[1..].iter().map(|n| fixtures::channel(n)).collect(),
+                ..fixtures::request(inner)
+            })
+            .await;
+        (result, service.inner.calls.into_inner().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_fails_over_until_a_channel_answers() {
+        let (result, calls) = call(3, &["down", "busy", "broken", "ok", "spare"]).await;
+        assert_eq!(calls, ["down {}", "busy {}", "broken {}", "ok {}"]);
+        let response = result.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let Attempts(attempts) = response.extensions().get::<Attempts>().unwrap();
+        let outcomes: Vec<_> = attempts.iter().map(|a| a.outcome.clone()).collect();
+        assert_eq!(outcomes, [
+            Outcome::Error("connection refused".into()),
+            Outcome::Status(StatusCode::TOO_MANY_REQUESTS),
+            Outcome::Status(StatusCode::BAD_GATEWAY),
+            Outcome::Status(StatusCode::OK),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_retry_count_and_non_retryable_statuses() {
+        // Out of retries: the last failure is what the client gets.
+        let (result, calls) = call(1, &["busy", "broken", "ok"]).await;
+        assert_eq!(calls.len(), 2);
+        assert_eq!(result.unwrap().status(), StatusCode::BAD_GATEWAY);
+        let (result, _) = call(1, &["busy", "down", "ok"]).await;
+        assert!(result.is_err());
+
+        // A client error is the client's, whichever channel serves it.
+        let (result, calls) = call(2, &["picky", "ok"]).await;
+        assert_eq!(calls, ["picky {}"]);
+        assert_eq!(result.unwrap().status(), StatusCode::BAD_REQUEST);
+
+        let (_, calls) = call(0, &["busy", "ok"]).await;
+        assert_eq!(calls, ["busy {}"]);
+    }
+}
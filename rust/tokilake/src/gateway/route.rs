@@ -1,62 +1,367 @@
 //! Route service — resolves the requested model to a Channel.
 //!
-//! Sits in the middle of the stack. Receives a raw HTTP request from the
-//! AuthService above, extracts the `model` field from the JSON body (or path),
-//! looks up a matching Channel, and passes a `GatewayRequest` down to the
-//! UpstreamService below.
-
-use super::{AuthedRequest, ChannelInfo, GatewayRequest};
-use bytes::Bytes;
-use http::Response;
-use http_body_util::Full;
+//! Sits in the middle of the stack. Receives an authenticated request from the
+//! ModelsService above, extracts the `model` field from the JSON body (or
+//! multipart form), orders the enabled Channels serving it by a weighted draw,
+//! and passes a `GatewayRequest` for the first one — with the rest as
+//! fallbacks — down to the RateLimitService below. Channels whose circuit is
+//! open are put last.
+//!
+//! A token only reaches the channels of its group, and only the models its
+//! allowlist names, if it has one.
+//!
+//! Model names are rewritten twice. A global alias ([`GatewayConfig::aliases`])
+//! is resolved here, before the channels are looked up; a channel's own model
+//! mapping is applied by [`map_model`] once an attempt on that channel is
+//! made. Either way the `model` field of the body is changed to match.
+//!
+//! Bodies over 64 MiB are refused with a 413.
+//!
+//! Each request gets an ID, the client's `x-request-id` if it sent one, which
+//! is echoed back on the response.
+//!
+//! Channels come from the snapshot in [`GatewayConfig::channels`], taken when
+//! the stack is built; a rebuild picks up channel changes.
+
+use super::{
+    AuthedRequest, BoxError, ChannelInfo, GatewayBody, GatewayConfig, GatewayRequest,
+    GatewayResponse, breaker::Breakers, error_response, full_body,
+};
+use crate::model::Channel;
+use anyhow::Context;
+use bytes::{Bytes, BytesMut};
+use http::{HeaderValue, StatusCode, header};
+use http_body_util::BodyExt;
+use rand::{Rng, distr::Alphanumeric};
 use service_async::{
     MakeService, Service,
     layer::{FactoryLayer, layer_fn},
 };
+use std::{collections::HashMap, sync::Arc};
+
+/// Largest request body accepted; audio and image uploads easily exceed the
+/// usual 2 MB limits.
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
 
 /// Routing service: maps model → channel, then delegates to inner.
 pub struct RouteService<T> {
     pub inner: T,
+    channels:  Arc<Vec<Channel>>,
+    breakers:  Breakers,
+    aliases:   Arc<HashMap<String, String>>,
 }
 
 impl<T> Service<AuthedRequest> for RouteService<T>
 where
-    T: Service<GatewayRequest, Response = Response<Full<Bytes>>, Error = anyhow::Error>,
+    T: Service<GatewayRequest, Response = GatewayResponse, Error = anyhow::Error>,
 {
-    type Response = Response<Full<Bytes>>;
+    type Response = GatewayResponse;
     type Error = anyhow::Error;
 
     async fn call(&self, req: AuthedRequest) -> Result<Self::Response, Self::Error> {
-        // Extract model from the URI path, e.g. /v1/chat/completions → use a
-        // default model, or parse the JSON body.
-        // For now, use a simple path-based heuristic.
-        let model = "default".to_string();
-
-        // TODO: look up from Toasty DB.  For now, use a stub channel.
-        let channel = ChannelInfo {
-            name:     "stub".into(),
-            provider: "openai".into(),
-            base_url: Some("https://api.openai.com".into()),
-            api_key:  Some("sk-stub".into()),
-            models:   "gpt-4,gpt-3.5-turbo".into(),
-            weight:   1,
+        // The body has to be read to find the model; it is put back unchanged.
+        let (mut parts, body) = req.inner.into_parts();
+        let body = match read_body(body).await {
+            Ok(Some(body)) => body,
+            Ok(None) => {
+                return Ok(error_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    &format!("Request body exceeds {} bytes", MAX_BODY_SIZE),
+                    "invalid_request_error",
+                    "body_too_large",
+                ));
+            }
+            Err(e) => return Err(anyhow::anyhow!(e)).context("failed to read request body"),
+        };
+        let model = match request_model(content_type(&parts.headers), &body).await {
+            Ok(Some(model)) => model,
+            Ok(None) => {
+                return Ok(error_response(
+                    StatusCode::BAD_REQUEST,
+                    "you must provide a model parameter",
+                    "invalid_request_error",
+                    "missing_model",
+                ));
+            }
+            Err(message) => {
+                return Ok(error_response(
+                    StatusCode::BAD_REQUEST,
+                    &message,
+                    "invalid_request_error",
+                    "invalid_body",
+                ));
+            }
+        };
+        let scope = &req.token.scope;
+        let alias = self.aliases.get(&model);
+        // An alias counts if it or the model it stands for is allowed.
+        if !scope.allows_model(&model) && !alias.is_some_and(|target| scope.allows_model(target)) {
+            return Ok(error_response(
+                StatusCode::FORBIDDEN,
+                &format!("This API key may not use the model '{}'", model),
+                "permission_error",
+                "model_not_allowed",
+            ));
+        }
+        let (model, body) = match alias {
+            Some(target) => {
+                let body = with_model(&mut parts, body, target);
+                (target.clone(), body)
+            }
+            None => (model, body),
+        };
+
+        let channels: Vec<&Channel> = self
+            .channels
+            .iter()
+            .filter(|c| c.group == scope.group && c.serves(&model))
+            .collect();
+
+        let mut ordered = weighted_order(channels, &mut rand::rng());
+        // Stable, so the weighted order holds within each group.
+        ordered.sort_by_key(|c| !self.breakers.available(c.id));
+        let mut ordered = ordered.into_iter().map(|c| ChannelInfo::from(c.clone()));
+        let Some(channel) = ordered.next() else {
+            return Ok(error_response(
+                StatusCode::NOT_FOUND,
+                &format!("The model '{}' does not exist", model),
+                "invalid_request_error",
+                "model_not_found",
+            ));
         };
 
+        let request_id = request_id(&parts.headers);
         let gw_req = GatewayRequest {
-            inner: req.inner,
-            token_name: req.token_name,
+            inner: http::Request::from_parts(parts, full_body(body)),
+            token: req.token,
             model,
             channel,
+            fallbacks: ordered.collect(),
+            request_id: request_id.clone(),
+        };
+
+        let mut response = self.inner.call(gw_req).await?;
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            response.headers_mut().insert(REQUEST_ID, value);
+        }
+        Ok(response)
+    }
+}
+
+/// Rename the model of `req` to what its channel calls it, if the channel
+/// maps it. The body is rewritten to match.
+pub(super) async fn map_model(req: GatewayRequest) -> anyhow::Result<GatewayRequest> {
+    let Some(upstream) = req.channel.model_mapping.get(&req.model).cloned() else {
+        return Ok(req);
+    };
+    let (mut parts, body) = req.inner.into_parts();
+    let body = body
+        .collect()
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+        .context("failed to read request body")?
+        .to_bytes();
+    let body = with_model(&mut parts, body, &upstream);
+    Ok(GatewayRequest {
+        inner: http::Request::from_parts(parts, full_body(body)),
+        model: upstream,
+        ..req
+    })
+}
+
+fn content_type(headers: &http::HeaderMap) -> &str {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+}
+
+/// `body` naming `model` instead; its length changes with it.
+fn with_model(parts: &mut http::request::Parts, body: Bytes, model: &str) -> Bytes {
+    match rewrite_model(content_type(&parts.headers), &body, model) {
+        Some(rewritten) => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            rewritten
+        }
+        None => body,
+    }
+}
+
+/// Read `body`, or `None` once it grows past [`MAX_BODY_SIZE`].
+async fn read_body(mut body: GatewayBody) -> Result<Option<Bytes>, BoxError> {
+    let mut buf = BytesMut::new();
+    while let Some(frame) = body.frame().await {
+        let Ok(data) = frame?.into_data() else {
+            continue;
         };
+        if buf.len() + data.len() > MAX_BODY_SIZE {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&data);
+    }
+    Ok(Some(buf.freeze()))
+}
+
+/// Header carrying the request ID, both ways.
+const REQUEST_ID: &str = "x-request-id";
+
+/// The client's request ID if it sent a sensible one, else a fresh one.
+fn request_id(headers: &http::HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| {
+            let id: String = rand::rng()
+                .sample_iter(Alphanumeric)
+                .take(24)
+                .map(char::from)
+                .collect();
+            format!("req-{}", id)
+        })
+}
+
+/// The `model` named by a JSON or `multipart/form-data` body.
+async fn request_model(content_type: &str, body: &Bytes) -> Result<Option<String>, String> {
+    if let Ok(boundary) = multer::parse_boundary(content_type) {
+        let data = body.clone();
+        let mut multipart = multer::Multipart::new(
+            futures_util::stream::once(async move { Ok::<_, std::io::Error>(data) }),
+            boundary,
+        );
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|e| format!("invalid multipart body: {}", e))?
+        {
+            if field.name() == Some("model") {
+                let model = field
+                    .text()
+                    .await
+                    .map_err(|e| format!("invalid multipart body: {}", e))?;
+                return Ok(Some(model.trim().to_string()).filter(|m| !m.is_empty()));
+            }
+        }
+        return Ok(None);
+    }
+
+    if body.is_empty() {
+        return Ok(None);
+    }
+    let value: serde_json::Value =
+        serde_json::from_slice(body).map_err(|e| format!("invalid JSON body: {}", e))?;
+    Ok(value
+        .get("model")
+        .and_then(|m| m.as_str())
+        .map(|m| m.trim().to_string())
+        .filter(|m| !m.is_empty()))
+}
+
+/// A JSON or `multipart/form-data` body with its `model` set to `model`, or
+/// `None` if it names no model.
+fn rewrite_model(content_type: &str, body: &Bytes, model: &str) -> Option<Bytes> {
+    if let Ok(boundary) = multer::parse_boundary(content_type) {
+        return rewrite_multipart_model(body, &boundary, model);
+    }
+    let mut value: serde_json::Value = serde_json::from_slice(body).ok()?;
+    *value.get_mut("model")? = model.into();
+    serde_json::to_vec(&value).ok().map(Bytes::from)
+}
+
+/// Replace the content of the `model` part in place, leaving every other
+/// part, files included, as it was.
+fn rewrite_multipart_model(body: &[u8], boundary: &str, model: &str) -> Option<Bytes> {
+    let opening = format!("--{}", boundary);
+    let delimiter = format!("\r\n--{}", boundary);
+    let mut offset = find(body, opening.as_bytes())? + opening.len();
+    loop {
+        let headers_end = offset + find(&body[offset..], b"\r\n\r\n")? + 4;
+        let part_end = headers_end + find(&body[headers_end..], delimiter.as_bytes())?;
+        if is_model_part(&body[offset..headers_end]) {
+            let mut rewritten = Vec::with_capacity(body.len() + model.len());
+            rewritten.extend_from_slice(&body[..headers_end]);
+            rewritten.extend_from_slice(model.as_bytes());
+            rewritten.extend_from_slice(&body[part_end..]);
+            return Some(rewritten.into());
+        }
+        offset = part_end + delimiter.len();
+    }
+}
+
+/// Whether the headers of a multipart part name it `model`.
+fn is_model_part(headers: &[u8]) -> bool {
+    String::from_utf8_lossy(headers).lines().any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("content-disposition")
+                && value
+                    .split(';')
+                    .any(|param| matches!(param.trim(), "name=\"model\"" | "name=model"))
+        })
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
 
-        self.inner.call(gw_req).await
+/// Channels with a non-positive weight still get a share of one.
+fn channel_weight(channel: &Channel) -> u64 {
+    channel.weight.max(1) as u64
+}
+
+fn total_weight(channels: &[&Channel]) -> u64 {
+    channels.iter().map(|c| channel_weight(c)).sum()
+}
+
+/// The index of the channel whose weight interval contains `roll` (in
+/// `0..total_weight`).
+fn pick_weighted(channels: &[&Channel], mut roll: u64) -> Option<usize> {
+    for (index, channel) in channels.iter().enumerate() {
+        let weight = channel_weight(channel);
+        if roll < weight {
+            return Some(index);
+        }
+        roll -= weight;
+    }
+    None
+}
+
+/// `channels` in the order to try them, each drawn by weight from the ones
+/// left.
+fn weighted_order<'a>(mut channels: Vec<&'a Channel>, rng: &mut impl Rng) -> Vec<&'a Channel> {
+    let mut ordered = Vec::with_capacity(channels.len());
+    while !channels.is_empty() {
+        let roll = rng.random_range(0..total_weight(&channels));
+        let index = pick_weighted(&channels, roll).expect("roll is below the total weight");
+        ordered.push(channels.remove(index));
+    }
+    ordered
+}
+
+impl From<Channel> for ChannelInfo {
+    fn from(channel: Channel) -> Self {
+        Self {
+            model_mapping: channel.upstream_models(),
+            id:            channel.id,
+            name:          channel.name,
+            provider:      channel.provider,
+            base_url:      channel.base_url,
+            api_key:       channel.api_key,
+            models:        channel.models,
+            weight:        channel.weight,
+        }
     }
 }
 
 // -- Factory / Layer ----------------------------------------------------------
 
 pub struct RouteServiceFactory<T> {
-    inner: T,
+    inner:    T,
+    channels: Arc<Vec<Channel>>,
+    breakers: Breakers,
+    aliases:  Arc<HashMap<String, String>>,
 }
 
 impl<T: MakeService> MakeService for RouteServiceFactory<T> {
@@ -64,14 +369,293 @@ impl<T: MakeService> MakeService for RouteServiceFactory<T> {
     type Error = T::Error;
 
     fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        // The channels always come from the new config: that is what a
+        // rebuild is for.
         Ok(RouteService {
-            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            inner:    self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            channels: self.channels.clone(),
+            breakers: self.breakers.clone(),
+            aliases:  self.aliases.clone(),
         })
     }
 }
 
 impl<T> RouteService<T> {
-    pub fn layer<C>() -> impl FactoryLayer<C, T, Factory = RouteServiceFactory<T>> {
-        layer_fn(|_c: &C, inner| RouteServiceFactory { inner })
+    pub fn layer() -> impl FactoryLayer<GatewayConfig, T, Factory = RouteServiceFactory<T>> {
+        layer_fn(|c: &GatewayConfig, inner| RouteServiceFactory {
+            inner,
+            channels: c.channels.clone(),
+            breakers: c.breakers.clone(),
+            aliases: c.aliases.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway::{TokenInfo, TokenScope, fixtures};
+
+    /// Leaf stub answering with the name of the channel it was routed to.
+    struct Echo;
+
+    impl Service<GatewayRequest> for Echo {
+        type Response = GatewayResponse;
+        type Error = anyhow::Error;
+
+        async fn call(&self, req: GatewayRequest) -> Result<Self::Response, Self::Error> {
+            let body = req.inner.into_body().collect().await.unwrap().to_bytes();
+            let reply = format!("{} {} {}", req.channel.name, req.model, body.len());
+            Ok(http::Response::new(full_body(reply)))
+        }
+    }
+
+    async fn service() -> RouteService<Echo> {
+        let mut db = crate::db::init_db(crate::db::MEMORY_DATABASE_URL)
+            .await
+            .unwrap();
+        for (name, models, status, weight) in [
+            ("primary", "gpt-4o, gpt-4o-mini", Channel::STATUS_ENABLED, 3),
+            ("disabled", "gpt-4o,whisper-1", 2, 100),
+            ("audio", "whisper-1", Channel::STATUS_ENABLED, 1),
+        ] {
+            Channel::create()
+                .name(name)
+                .provider("openai")
+                .models(models)
+                .status(status)
+                .weight(weight)
+                .exec(&mut db)
+                .await
+                .unwrap();
+        }
+        Channel::create()
+            .name("anthropic")
+            .provider("openai")
+            .models("")
+            .model_mapping(r#"{"claude-sonnet":"claude-3-5-sonnet-20241022"}"#)
+            .status(Channel::STATUS_ENABLED)
+            .weight(1)
+            .exec(&mut db)
+            .await
+            .unwrap();
+        let mut config = GatewayConfig::new(db);
+        config.load_channels().await.unwrap();
+        RouteService {
+            inner:    Echo,
+            channels: config.channels,
+            breakers: config.breakers,
+            aliases:  Arc::new(HashMap::from([("gpt-4".into(), "gpt-4o".into())])),
+        }
+    }
+
+    async fn call(
+        service: &RouteService<Echo>,
+        content_type: &str,
+        body: &'static [u8],
+    ) -> (StatusCode, String) {
+        call_scoped(service, TokenScope::default(), content_type, body).await
+    }
+
+    async fn call_scoped(
+        service: &RouteService<Echo>,
+        scope: TokenScope,
+        content_type: &str,
+        body: &'static [u8],
+    ) -> (StatusCode, String) {
+        let inner = http::Request::post("/v1/chat/completions")
+            .header(header::CONTENT_TYPE, content_type)
+            .body(full_body(body))
+            .unwrap();
+        let response = service
+            .call(AuthedRequest {
+                inner,
+                token: TokenInfo {
+                    scope,
+                    ..fixtures::token(1)
+                },
+            })
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[tokio::test]
+    async fn test_routes_json_and_multipart_to_enabled_channels() {
+        let service = service().await;
+        let (status, body) =
+            call(&service, "application/json", br#"{"model":"gpt-4o-mini"}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "primary gpt-4o-mini 23");
+
+        let multipart = b"--XyZ\r\n\
+Content-Disposition: form-data; name=\"model\"\r\n\r\n\
+whisper-1\r\n\
+--XyZ--\r\n";
+        for _ in 0..10 {
+            let (status, body) =
+                call(&service, "multipart/form-data; boundary=XyZ", multipart).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(body.starts_with("audio whisper-1 "), "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_aliases_and_mapped_models_are_routed() {
+        let service = service().await;
+        // `{"model":"gpt-4o"}` is 18 bytes.
+        let (status, body) = call(&service, "application/json", br#"{"model":"gpt-4"}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "primary gpt-4o 18");
+
+        // The channel mapping is applied per attempt, further down.
+        let (status, body) = call(
+            &service,
+            "application/json",
+            br#"{"model":"claude-sonnet"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with("anthropic claude-sonnet "), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_map_model_rewrites_multipart_body() {
+        let body = b"--XyZ\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"model\"\r\n\r\n\
+name=\"model\"\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"model\"\r\n\r\n\
+whisper\r\n\
+--XyZ--\r\n";
+        let inner = http::Request::post("/v1/audio/transcriptions")
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=XyZ")
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(full_body(&body[..]))
+            .unwrap();
+        let req = GatewayRequest {
+            model: "whisper".into(),
+            channel: ChannelInfo {
+                models: String::new(),
+                model_mapping: HashMap::from([("whisper".into(), "whisper-1".into())]),
+                ..fixtures::channel("audio")
+            },
+            ..fixtures::request(inner)
+        };
+        let req = map_model(req).await.unwrap();
+        assert_eq!(req.model, "whisper-1");
+        assert!(!req.inner.headers().contains_key(header::CONTENT_LENGTH));
+        let content_type = content_type(req.inner.headers()).to_string();
+        let body = req.inner.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            request_model(&content_type, &body)
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("whisper-1")
+        );
+        // The file part is untouched.
+        assert!(body.windows(14).any(|w| w == b"name=\"model\"\r\n"));
+
+        assert_eq!(
+            rewrite_model("application/json", &Bytes::from_static(b"{}"), "m"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_scope_limits_models_and_channels() {
+        let service = service().await;
+        let contractor = TokenScope {
+            models: vec!["gpt-4o".into()],
+            ..Default::default()
+        };
+        let json = "application/json";
+        let (status, body) = call_scoped(
+            &service,
+            contractor.clone(),
+            json,
+            br#"{"model":"gpt-4o-mini"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body.contains("model_not_allowed"), "{}", body);
+        // Allowed through the alias's target.
+        let (status, body) = call_scoped(&service, contractor, json, br#"{"model":"gpt-4"}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with("primary gpt-4o "), "{}", body);
+
+        let other_group = TokenScope {
+            group: "vip".into(),
+            ..Default::default()
+        };
+        let (status, body) =
+            call_scoped(&service, other_group, json, br#"{"model":"gpt-4o"}"#).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.contains("model_not_found"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_model_is_openai_404() {
+        let service = service().await;
+        let (status, body) = call(&service, "application/json", br#"{"model":"gpt-5"}"#).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["error"]["code"], "model_not_found");
+
+        let (status, _) = call(&service, "application/json", b"{}").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_is_413() {
+        let service = service().await;
+        let inner = http::Request::post("/v1/audio/transcriptions")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(full_body(vec![b' '; MAX_BODY_SIZE + 1]))
+            .unwrap();
+        let response = service
+            .call(AuthedRequest {
+                inner,
+                token: fixtures::token(1),
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_pick_weighted_follows_weight_intervals() {
+        let channel = |name: &str, weight| Channel {
+            id: 0,
+            name: name.into(),
+            provider: "openai".into(),
+            models: "m".into(),
+            base_url: None,
+            api_key: None,
+            status: Channel::STATUS_ENABLED,
+            weight,
+            status_reason: None,
+            model_mapping: None,
+            group: crate::model::DEFAULT_GROUP.into(),
+        };
+        let all = [channel("a", 2), channel("b", 0), channel("c", 5)];
+        let channels = || all.iter().collect::<Vec<_>>();
+        assert_eq!(total_weight(&channels()), 8);
+        let names: Vec<&str> = (0..8)
+            .map(|roll| all[pick_weighted(&channels(), roll).unwrap()].name.as_str())
+            .collect();
+        assert_eq!(names, ["a", "a", "b", "c", "c", "c", "c", "c"]);
+        assert!(pick_weighted(&channels(), 8).is_none());
+
+        let mut rng = rand::rng();
+        let mut order = weighted_order(channels(), &mut rng);
+        assert_eq!(order.len(), 3);
+        order.sort_by_key(|c| c.name.as_str());
+        assert_eq!(order.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), [
+            "a", "b", "c"
+        ]);
     }
 }
@@ -0,0 +1,194 @@
+//! Tokiame channels — requests served by self-hosted workers.
+//!
+//! A channel with `provider = "tokiame"` is not reached over HTTP: the
+//! UpstreamService hands the request to [`Roundtrip`], which forwards it over
+//! the tunnel of a connected worker. The channel's `base_url` names the worker
+//! namespace as `tokiame://<namespace>`, and one of its replicas advertising
+//! the model is used; without one, any worker advertising the model is.
+
+use super::{BoxError, GatewayRequest, GatewayResponse, boxed_body, upstream::strip_headers};
+use anyhow::Context;
+use bytes::Bytes;
+use http::{
+    HeaderValue,
+    header::{self, HeaderName},
+};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::Frame;
+use std::collections::HashMap;
+use tokilake_core::{
+    protocol::{TunnelRequest, route_kind},
+    roundtrip::{Roundtrip, RoundtripRequest, TunnelRoundtripResponse},
+    service::Service,
+    session::SessionManager,
+};
+
+/// `Channel.provider` of channels served by tokiame workers.
+pub const PROVIDER: &str = "tokiame";
+
+const BASE_URL_SCHEME: &str = "tokiame://";
+
+/// Worker sessions connected to this gateway.
+pub type WorkerSessions = SessionManager<tokilake_smux::Session>;
+
+/// `base_url` of the channel serving `namespace`.
+pub fn base_url(namespace: &str) -> String {
+    format!("{}{}", BASE_URL_SCHEME, namespace.trim())
+}
+
+/// The namespace named by a `tokiame://<namespace>` base URL.
+pub fn namespace(base_url: &str) -> Option<&str> {
+    let namespace = base_url
+        .strip_prefix(BASE_URL_SCHEME)?
+        .trim_end_matches('/');
+    (!namespace.is_empty()).then_some(namespace)
+}
+
+/// Forward `req` to a worker and stream its response back.
+pub(super) async fn forward(
+    roundtrip: &Roundtrip<tokilake_smux::Session>,
+    req: GatewayRequest,
+) -> anyhow::Result<GatewayResponse> {
+    let channel = req.channel;
+    let (parts, body) = req.inner.into_parts();
+    let kind = route_kind_of(parts.method.as_str(), parts.uri.path()).with_context(|| {
+        format!(
+            "{} {} cannot be served by a tokiame worker",
+            parts.method,
+            parts.uri.path()
+        )
+    })?;
+    let body = body
+        .collect()
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+        .context("failed to read request body")?
+        .to_bytes();
+    let is_stream = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|v| v.get("stream")?.as_bool())
+        .unwrap_or(false);
+    let headers: HashMap<String, String> = [header::CONTENT_TYPE, header::ACCEPT]
+        .into_iter()
+        .filter_map(|name| {
+            let value = parts.headers.get(&name)?.to_str().ok()?;
+            Some((name.as_str().to_string(), value.to_string()))
+        })
+        .collect();
+
+    let request = TunnelRequest {
+        request_id: String::new(),
+        route_kind: kind.to_string(),
+        method: parts.method.to_string(),
+        path: parts.uri.path().to_string(),
+        model: req.model.clone(),
+        headers,
+        is_stream,
+        body: body.to_vec(),
+    };
+    // Replicas of a namespace may serve different models.
+    let target = match channel.base_url.as_deref().and_then(namespace) {
+        Some(namespace) => RoundtripRequest::ByNamespaceModel {
+            namespace: namespace.into(),
+            model: req.model.as_str().into(),
+            request,
+        },
+        None => RoundtripRequest::ByModel {
+            model: req.model.as_str().into(),
+            request,
+        },
+    };
+    let response = roundtrip
+        .call(target)
+        .await
+        .with_context(|| format!("tokiame channel {} failed", channel.name))?;
+
+    let TunnelRoundtripResponse {
+        status_code,
+        headers,
+        body_rx,
+        cancel_tx,
+    } = response;
+    let mut builder = http::Response::builder().status(status_code);
+    if let Some(response_headers) = builder.headers_mut() {
+        for (name, value) in &headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                response_headers.append(name, value);
+            }
+        }
+        strip_headers(response_headers);
+    }
+
+    // `cancel_tx` lives as long as the body: dropping the body before the
+    // end cancels the request on the worker.
+    let frames = futures_util::stream::unfold(
+        (body_rx, cancel_tx),
+        |(mut body_rx, cancel_tx)| async move {
+            let chunk = body_rx.recv().await?;
+            let frame = chunk
+                .map(|data| Frame::data(Bytes::from(data)))
+                .map_err(BoxError::from);
+            Some((frame, (body_rx, cancel_tx)))
+        },
+    );
+    builder
+        .body(boxed_body(StreamBody::new(frames)))
+        .context("invalid worker response")
+}
+
+/// The [`route_kind`] served at `method path`.
+fn route_kind_of(method: &str, path: &str) -> Option<&'static str> {
+    route_kind::ALL.iter().copied().find(|&kind| {
+        route_kind::http_route(kind).is_some_and(|(route_method, template)| {
+            route_method.eq_ignore_ascii_case(method) && path_matches(template, path)
+        })
+    })
+}
+
+/// Whether `path` fits `template`, where `{…}` segments match any segment.
+fn path_matches(template: &str, path: &str) -> bool {
+    let mut template = template.trim_end_matches('/').split('/');
+    let mut path = path.trim_end_matches('/').split('/');
+    loop {
+        match (template.next(), path.next()) {
+            (None, None) => return true,
+            (Some(t), Some(p)) if t.starts_with('{') && !p.is_empty() => {}
+            (Some(t), Some(p)) if t == p => {}
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_namespace_base_url() {
+        assert_eq!(base_url(" gpu-a "), "tokiame://gpu-a");
+        assert_eq!(namespace("tokiame://gpu-a/"), Some("gpu-a"));
+        assert_eq!(namespace("tokiame://"), None);
+        assert_eq!(namespace("https://api.openai.com"), None);
+    }
+
+    #[test]
+    fn test_route_kind_of_request() {
+        assert_eq!(
+            route_kind_of("POST", "/v1/chat/completions"),
+            Some(route_kind::CHAT_COMPLETIONS)
+        );
+        assert_eq!(
+            route_kind_of("GET", "/v1/videos/vid_1/content"),
+            Some(route_kind::VIDEOS_CONTENT)
+        );
+        assert_eq!(
+            route_kind_of("GET", "/v1/videos/vid_1"),
+            Some(route_kind::VIDEOS_GET)
+        );
+        assert_eq!(route_kind_of("GET", "/v1/chat/completions"), None);
+        assert_eq!(route_kind_of("POST", "/v1/unknown"), None);
+    }
+}
@@ -1,78 +1,258 @@
 //! Upstream forwarding service — the bottom of the service stack.
 //!
 //! Receives a fully routed request (with the upstream base_url and api_key already
-//! resolved by the RouteService above it) and forwards it to the LLM provider via
-//! an HTTP client, streaming the response body back.
+//! resolved by the RouteService above it), renames the model as the channel's
+//! model mapping says, and forwards it to the LLM provider via an HTTP client,
+//! streaming the response body back. Tokiame channels go
+//! through the worker tunnel instead (see [`super::tokiame`]).
 
-use super::GatewayRequest;
-use bytes::Bytes;
-use http::{Response, StatusCode};
-use http_body_util::Full;
+use super::{
+    GatewayBody, GatewayConfig, GatewayRequest, GatewayResponse, boxed_body, route, tokiame,
+};
+use anyhow::Context;
+use http::{
+    HeaderMap, HeaderValue, Uri,
+    header::{self, HeaderName},
+};
+use hyper_rustls::HttpsConnector;
+use hyper_util::{
+    client::legacy::{Client, connect::HttpConnector},
+    rt::TokioExecutor,
+};
 use service_async::{
     MakeService, Service,
     layer::{FactoryLayer, layer_fn},
 };
 use std::convert::Infallible;
+use tokilake_core::roundtrip::Roundtrip;
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com";
+
+/// Headers that describe a single connection and must not be forwarded
+/// (RFC 9110 §7.6.1), plus the client's own credentials, which are replaced
+/// by the channel key.
+const STRIPPED_HEADERS: &[HeaderName] = &[
+    header::CONNECTION,
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+    header::HOST,
+    header::AUTHORIZATION,
+];
+
+/// Non-standard hop-by-hop and credential headers.
+const STRIPPED_HEADER_NAMES: &[&str] = &["keep-alive", "proxy-connection", "x-api-key", "api-key"];
+
+type HttpClient = Client<HttpsConnector<HttpConnector>, GatewayBody>;
+
+fn http_client() -> HttpClient {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .build();
+    Client::builder(TokioExecutor::new()).build(connector)
+}
 
 /// The leaf service: forwards the request to the resolved upstream.
 #[derive(Clone)]
-pub struct UpstreamService;
+pub struct UpstreamService {
+    client: HttpClient,
+    tunnel: Roundtrip<tokilake_smux::Session>,
+}
+
+impl UpstreamService {
+    pub fn new(tunnel: Roundtrip<tokilake_smux::Session>) -> Self {
+        Self {
+            client: http_client(),
+            tunnel,
+        }
+    }
+}
 
 impl Service<GatewayRequest> for UpstreamService {
-    type Response = Response<Full<Bytes>>;
+    type Response = GatewayResponse;
     type Error = anyhow::Error;
 
     async fn call(&self, req: GatewayRequest) -> Result<Self::Response, Self::Error> {
-        let channel = &req.channel;
-        let base_url = channel
-            .base_url
-            .as_deref()
-            .unwrap_or("https://api.openai.com");
-        let _api_key = channel.api_key.as_deref().unwrap_or("");
+        let req = route::map_model(req).await?;
+        if req.channel.provider == tokiame::PROVIDER {
+            return tokiame::forward(&self.tunnel, req).await;
+        }
 
-        // Build upstream URI
+        let channel = &req.channel;
+        let base_url = channel.base_url.as_deref().unwrap_or(DEFAULT_BASE_URL);
         let path = req
             .inner
             .uri()
             .path_and_query()
             .map(|pq| pq.as_str())
             .unwrap_or("/");
-        let upstream_uri = format!("{}{}", base_url.trim_end_matches('/'), path);
-
-        // TODO: Use hyper client to forward the request body as a stream.
-        // For now, return a placeholder that proves the pipeline works end-to-end.
-        let body = serde_json::json!({
-            "status": "ok",
-            "upstream": upstream_uri,
-            "provider": &channel.provider,
-            "model": &req.model,
-        });
-
-        let response = Response::builder()
-            .status(StatusCode::OK)
-            .header("content-type", "application/json")
-            .body(Full::new(Bytes::from(serde_json::to_vec(&body)?)))
-            .unwrap();
+        let upstream_uri: Uri = format!("{}{}", base_url.trim_end_matches('/'), path)
+            .parse()
+            .with_context(|| format!("invalid upstream url for channel {}", channel.name))?;
+
+        let (mut parts, body) = req.inner.into_parts();
+        parts.uri = upstream_uri;
+        strip_headers(&mut parts.headers);
+        if let Some(api_key) = channel.api_key.as_deref().filter(|k| !k.is_empty()) {
+            let value = HeaderValue::from_str(&format!("Bearer {}", api_key))
+                .context("channel api key is not a valid header value")?;
+            parts.headers.insert(header::AUTHORIZATION, value);
+        }
+
+        let response = self
+            .client
+            .request(http::Request::from_parts(parts, body))
+            .await
+            .with_context(|| format!("upstream request to channel {} failed", channel.name))?;
 
-        Ok(response)
+        let (mut parts, body) = response.into_parts();
+        strip_headers(&mut parts.headers);
+        Ok(GatewayResponse::from_parts(parts, boxed_body(body)))
+    }
+}
+
+pub(super) fn strip_headers(headers: &mut HeaderMap) {
+    // Headers named in `Connection` are hop-by-hop as well.
+    let named: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in STRIPPED_HEADERS.iter().chain(&named) {
+        headers.remove(name);
+    }
+    for name in STRIPPED_HEADER_NAMES {
+        headers.remove(*name);
     }
 }
 
 // -- Factory / Layer ----------------------------------------------------------
 
-pub struct UpstreamServiceFactory;
+pub struct UpstreamServiceFactory {
+    tunnel: Roundtrip<tokilake_smux::Session>,
+}
 
 impl MakeService for UpstreamServiceFactory {
     type Service = UpstreamService;
     type Error = Infallible;
 
-    fn make_via_ref(&self, _old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
-        Ok(UpstreamService)
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        // Keep the old client so its connection pool survives a rebuild.
+        Ok(UpstreamService {
+            client: old.map(|o| o.client.clone()).unwrap_or_else(http_client),
+            tunnel: self.tunnel.clone(),
+        })
     }
 }
 
 impl UpstreamService {
-    pub fn layer<C>() -> impl FactoryLayer<C, (), Factory = UpstreamServiceFactory> {
-        layer_fn(|_c: &C, _inner: ()| UpstreamServiceFactory)
+    pub fn layer() -> impl FactoryLayer<GatewayConfig, (), Factory = UpstreamServiceFactory> {
+        layer_fn(|c: &GatewayConfig, _inner: ()| UpstreamServiceFactory {
+            tunnel: Roundtrip::new(c.workers.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway::{ChannelInfo, fixtures, full_body};
+    use axum::{Router, body::Body, extract::Request, routing::post};
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+    use http_body_util::BodyExt;
+    use std::sync::Arc;
+    use tokio::sync::Notify;
+
+    /// Fake provider echoing what it received. Everything after the first
+    /// chunk is held back until `release` is notified.
+    async fn spawn_provider() -> (String, Arc<Notify>) {
+        let release = Arc::new(Notify::new());
+        let held = release.clone();
+        let echo = move |request: Request| async move {
+            let auth = request.headers()[header::AUTHORIZATION]
+                .to_str()
+                .unwrap()
+                .to_string();
+            let forwarded_key = request.headers().contains_key("x-api-key");
+            let uri = request.uri().to_string();
+            let body = request.into_body().collect().await.unwrap().to_bytes();
+            let first = format!("auth={} x-api-key={}\n", auth, forwarded_key);
+            let rest = format!("uri={}\nbody={}\n", uri, String::from_utf8_lossy(&body));
+            let stream = futures_util::stream::once(async move { Ok(Bytes::from(first)) }).chain(
+                futures_util::stream::once(async move {
+                    held.notified().await;
+                    Ok::<_, std::io::Error>(Bytes::from(rest))
+                }),
+            );
+            axum::response::Response::builder()
+                .header(header::CONTENT_TYPE, "text/event-stream")
+                .header("keep-alive", "timeout=5")
+                .body(Body::from_stream(stream))
+                .unwrap()
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/v1/chat/completions", post(echo));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}/", addr), release)
+    }
+
+    fn gateway_request(base_url: String) -> GatewayRequest {
+        let inner = http::Request::post("/v1/chat/completions?trace=1")
+            .header(header::AUTHORIZATION, "Bearer client-key")
+            .header("x-api-key", "client-key")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(full_body(r#"{"model":"m"}"#))
+            .unwrap();
+        GatewayRequest {
+            channel: ChannelInfo {
+                base_url: Some(base_url),
+                api_key: Some("sk-channel".into()),
+                ..fixtures::channel("local")
+            },
+            ..fixtures::request(inner)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forwards_request_and_streams_response() {
+        let (base_url, release) = spawn_provider().await;
+        let service = UpstreamService::new(Roundtrip::new(Arc::default()));
+        let response = service.call(gateway_request(base_url)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+        assert!(!response.headers().contains_key("keep-alive"));
+
+        let mut body = response.into_body();
+        let first = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(first, "auth=Bearer sk-channel x-api-key=false\n");
+        release.notify_one();
+        let rest = body.collect().await.unwrap().to_bytes();
+        assert_eq!(
+            rest,
+            "uri=/v1/chat/completions?trace=1\nbody={\"model\":\"m\"}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_failure_is_an_error() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let service = UpstreamService::new(Roundtrip::new(Arc::default()));
+        assert!(service.call(gateway_request(base_url)).await.is_err());
     }
 }
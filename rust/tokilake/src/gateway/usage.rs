@@ -0,0 +1,714 @@
+//! Usage service — records the token usage of every answered request and
+//! charges its cost to the token's quota.
+//!
+//! Sits right above the UpstreamService, so it knows the channel that really
+//! answered. Streaming chat and completion requests get
+//! `stream_options.include_usage` turned on, so the provider (or tokiame
+//! worker) ends the stream with a usage chunk. When the client did not ask
+//! for that chunk, it is recorded but left out of the stream the client gets.
+//!
+//! The response body is not buffered for the client: it is passed through
+//! frame by frame while a copy is scanned. A JSON body is parsed once it has
+//! ended; in an SSE stream, the last `data:` event carrying `usage` wins. When
+//! the body is dropped — finished or not — the usage found, if any, is written
+//! as a [`UsageLog`] row and charged (see [`super::quota`]), and the token
+//! count the RateLimitService admitted the request with is corrected.
+//!
+//! A stream that never reports usage, because it was cut short, is charged
+//! an estimate: a token per `data:` event and a token per four bytes of
+//! request. A token with a limited quota has its stream cut off, between two
+//! events, once the estimate costs more than it had left: the client gets an
+//! `insufficient_quota` error event and `data: [DONE]`, and the upstream
+//! request is dropped.
+
+use super::{
+    BoxError, GatewayBody, GatewayConfig, GatewayRequest, GatewayResponse, boxed_body, full_body,
+    quota::{Prices, Quotas},
+};
+use crate::model::UsageLog;
+use anyhow::Context;
+use bytes::Bytes;
+use http::header;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use serde_json::Value;
+use service_async::{
+    MakeService, Service,
+    layer::{FactoryLayer, layer_fn},
+};
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll, ready},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokilake_core::{
+    ratelimit::{RateLimiter, estimate_request_tokens},
+    usage::{MAX_SSE_LINE, Usage, UsageTap},
+};
+use tracing::{debug, error};
+
+/// Paths whose streaming responses only report usage when asked to.
+const STREAM_OPTIONS_PATHS: &[&str] = &["/v1/chat/completions", "/v1/completions"];
+
+/// Ends a stream cut off for want of quota. The leading newline ends an event
+/// the last frame may have left open.
+const QUOTA_EXHAUSTED_EVENTS: &[u8] = b"\ndata: {\"error\":{\"message\":\"You exceeded your \
+current quota\",\"type\":\"insufficient_quota\",\"param\":null,\"code\":\"insufficient_quota\"}}\n\n\
+data: [DONE]\n\n";
+
+/// Turn on `stream_options.include_usage` in a streaming JSON request.
+/// Returns `None` when the body is left as it is.
+fn include_usage(path: &str, body: &[u8]) -> Option<Vec<u8>> {
+    if !STREAM_OPTIONS_PATHS.contains(&path) {
+        return None;
+    }
+    let mut value: Value = serde_json::from_slice(body).ok()?;
+    let request = value.as_object_mut()?;
+    if request.get("stream").and_then(Value::as_bool) != Some(true) {
+        return None;
+    }
+    let options = request
+        .entry("stream_options")
+        .or_insert_with(|| Value::Object(Default::default()));
+    let options = options.as_object_mut()?;
+    if options.get("include_usage").and_then(Value::as_bool) == Some(true) {
+        return None;
+    }
+    options.insert("include_usage".into(), Value::Bool(true));
+    serde_json::to_vec(&value).ok()
+}
+
+/// Whether `line` is a `data:` event carrying usage and no choices, as sent
+/// last by a stream with `include_usage` on.
+fn usage_only_event(line: &[u8]) -> bool {
+    let Some(data) = line.strip_prefix(b"data:") else {
+        return false;
+    };
+    if !data.windows(7).any(|w| w == b"\"usage\"") {
+        return false;
+    }
+    serde_json::from_slice::<Value>(data.trim_ascii()).is_ok_and(|event| {
+        let no_choices = event
+            .get("choices")
+            .and_then(Value::as_array)
+            .is_some_and(Vec::is_empty);
+        no_choices && Usage::from_json(&event).is_some()
+    })
+}
+
+/// Takes the usage-only event out of an SSE stream, for clients that did not
+/// ask for it. Lines are held back until they end, so the event can be told
+/// apart from the others.
+#[derive(Debug, Default)]
+struct StripUsage {
+    line:    Vec<u8>,
+    /// The line being received was too long to hold and is passed through.
+    passing: bool,
+    /// The last event was dropped; so is the blank line ending it.
+    dropped: bool,
+}
+
+impl StripUsage {
+    /// The part of `data` to pass on.
+    fn feed(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut kept = Vec::with_capacity(data.len());
+        for chunk in data.split_inclusive(|b| *b == b'\n') {
+            let ended = chunk.ends_with(b"\n");
+            if self.passing {
+                kept.extend_from_slice(chunk);
+                self.passing = !ended;
+                continue;
+            }
+            self.line.extend_from_slice(chunk);
+            if !ended {
+                if self.line.len() > MAX_SSE_LINE {
+                    kept.append(&mut self.line);
+                    self.passing = true;
+                }
+                continue;
+            }
+            if self.dropped && self.line.trim_ascii().is_empty() {
+                self.dropped = false;
+            } else if usage_only_event(&self.line) {
+                self.dropped = true;
+            } else {
+                self.dropped = false;
+                kept.extend_from_slice(&self.line);
+            }
+            self.line.clear();
+        }
+        kept
+    }
+
+    /// What is left of a stream that ended mid-line.
+    fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.line)
+    }
+}
+
+/// Who a usage row is written for and charged to.
+#[derive(Clone)]
+struct Recorder {
+    db:         toasty::Db,
+    prices:     Prices,
+    quotas:     Quotas,
+    limiter:    Arc<RateLimiter>,
+    /// Tokens the request was admitted with.
+    admitted:   i64,
+    request_id: String,
+    token_id:   u64,
+    channel_id: u64,
+    model:      String,
+    stream:     bool,
+}
+
+impl Recorder {
+    fn record(self, usage: Usage) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            error!("usage of request {} lost: no runtime", self.request_id);
+            return;
+        };
+        self.limiter.correct(
+            self.token_id,
+            &self.model,
+            usage.total_tokens - self.admitted,
+        );
+        runtime.spawn(async move {
+            let mut db = self.db.clone();
+            let quota = self.prices.cost(&self.model, &usage);
+            let created_at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default();
+            let result = UsageLog::create()
+                .request_id(&self.request_id)
+                .token_id(self.token_id)
+                .channel_id(self.channel_id)
+                .model(&self.model)
+                .prompt_tokens(usage.prompt_tokens)
+                .completion_tokens(usage.completion_tokens)
+                .total_tokens(usage.total_tokens)
+                .stream(self.stream)
+                .quota(quota)
+                .created_at(created_at)
+                .exec(&mut db)
+                .await;
+            match result {
+                Ok(_) => debug!(
+                    "request {} used {:?} for {} quota",
+                    self.request_id, usage, quota
+                ),
+                Err(e) => error!(
+                    "failed to record usage of request {}: {}",
+                    self.request_id, e
+                ),
+            }
+            if let Err(e) = self.quotas.charge(&self.db, self.token_id, quota).await {
+                error!(
+                    "failed to charge request {} to token {}: {:#}",
+                    self.request_id, self.token_id, e
+                );
+            }
+        });
+    }
+}
+
+/// How far a stream is from being cut off for want of quota.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cut {
+    No,
+    /// The upstream body is dropped; the error events are next.
+    Pending,
+    Done,
+}
+
+/// A response body that records the usage it carries once dropped.
+struct UsageBody {
+    inner:         GatewayBody,
+    tap:           UsageTap,
+    /// Prompt tokens guessed from the request.
+    prompt_tokens: i64,
+    /// Quota the token had left when the request started; `None` when it is
+    /// unlimited.
+    budget:        Option<i64>,
+    cut:           Cut,
+    /// Set when the gateway asked for the usage event, not the client.
+    strip:         Option<StripUsage>,
+    recorder:      Option<Recorder>,
+}
+
+impl UsageBody {
+    /// The usage reported so far, else a guess at it.
+    fn usage(&self) -> Option<Usage> {
+        self.tap.finish().or_else(|| {
+            let completion_tokens = self.tap.estimated_completion();
+            (completion_tokens > 0).then_some(Usage {
+                prompt_tokens: self.prompt_tokens,
+                completion_tokens,
+                total_tokens: self.prompt_tokens + completion_tokens,
+            })
+        })
+    }
+
+    fn over_budget(&self) -> bool {
+        let (Some(budget), Some(recorder)) = (self.budget, &self.recorder) else {
+            return false;
+        };
+        self.tap.between_events()
+            && self
+                .usage()
+                .is_some_and(|usage| recorder.prices.cost(&recorder.model, &usage) > budget)
+    }
+}
+
+impl Body for UsageBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.cut {
+            Cut::No => {}
+            Cut::Pending => {
+                self.cut = Cut::Done;
+                let events = Bytes::from_static(QUOTA_EXHAUSTED_EVENTS);
+                return Poll::Ready(Some(Ok(Frame::data(events))));
+            }
+            Cut::Done => return Poll::Ready(None),
+        }
+        loop {
+            let mut frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+            match &mut frame {
+                Some(Ok(frame)) if frame.is_data() => {
+                    let data = frame.data_ref().cloned().unwrap_or_default();
+                    self.tap.feed(&data);
+                    if let Some(strip) = &mut self.strip {
+                        let kept = strip.feed(&data);
+                        // Wait for the rest of a held line.
+                        if kept.is_empty() {
+                            continue;
+                        }
+                        *frame = Frame::data(kept.into());
+                    }
+                    if self.over_budget() {
+                        if let Some(recorder) = &self.recorder {
+                            debug!(
+                                "request {} cut off: token {} is out of quota",
+                                recorder.request_id, recorder.token_id
+                            );
+                        }
+                        // Dropping the upstream body cancels the request.
+                        self.inner = full_body(Bytes::new());
+                        self.cut = Cut::Pending;
+                    }
+                }
+                None => {
+                    let rest = self.strip.as_mut().map(StripUsage::finish);
+                    if let Some(rest) = rest.filter(|rest| !rest.is_empty()) {
+                        self.inner = full_body(Bytes::new());
+                        return Poll::Ready(Some(Ok(Frame::data(rest.into()))));
+                    }
+                }
+                _ => {}
+            }
+            return Poll::Ready(frame);
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match self.cut {
+            Cut::No => {
+                self.inner.is_end_stream()
+                    && self
+                        .strip
+                        .as_ref()
+                        .is_none_or(|strip| strip.line.is_empty())
+            }
+            Cut::Pending => false,
+            Cut::Done => true,
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.strip {
+            Some(_) => SizeHint::default(),
+            None => self.inner.size_hint(),
+        }
+    }
+}
+
+impl Drop for UsageBody {
+    fn drop(&mut self) {
+        if let Some(usage) = self.usage()
+            && let Some(recorder) = self.recorder.take()
+        {
+            recorder.record(usage);
+        }
+    }
+}
+
+/// Usage accounting service.
+pub struct UsageService<T> {
+    pub inner: T,
+    db:        toasty::Db,
+    prices:    Prices,
+    quotas:    Quotas,
+    limiter:   Arc<RateLimiter>,
+}
+
+impl<T> Service<GatewayRequest> for UsageService<T>
+where
+    T: Service<GatewayRequest, Response = GatewayResponse, Error = anyhow::Error>,
+{
+    type Response = GatewayResponse;
+    type Error = anyhow::Error;
+
+    async fn call(&self, req: GatewayRequest) -> Result<Self::Response, Self::Error> {
+        let GatewayRequest {
+            inner,
+            token,
+            model,
+            channel,
+            fallbacks,
+            request_id,
+        } = req;
+        let (parts, body) = inner.into_parts();
+        let body = body
+            .collect()
+            .await
+            .map_err(|e| anyhow::anyhow!(e))
+            .context("failed to read request body")?
+            .to_bytes();
+        let stream = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|v| v.get("stream")?.as_bool())
+            .unwrap_or(false);
+        let prompt_tokens = estimate_request_tokens(&body);
+        let budget = if token.unlimited_quota {
+            None
+        } else {
+            Some(self.quotas.remaining(&self.db, token.id).await?)
+        };
+        let mut parts = parts;
+        let injected = include_usage(parts.uri.path(), &body);
+        let strip = injected.is_some().then(StripUsage::default);
+        let body: Bytes = match injected {
+            Some(rewritten) => {
+                parts.headers.remove(header::CONTENT_LENGTH);
+                rewritten.into()
+            }
+            None => body,
+        };
+
+        let recorder = Recorder {
+            db: self.db.clone(),
+            prices: self.prices.clone(),
+            quotas: self.quotas.clone(),
+            limiter: self.limiter.clone(),
+            admitted: prompt_tokens,
+            request_id: request_id.clone(),
+            token_id: token.id,
+            channel_id: channel.id,
+            model: model.clone(),
+            stream,
+        };
+        let response = self
+            .inner
+            .call(GatewayRequest {
+                inner: http::Request::from_parts(parts, full_body(body)),
+                token,
+                model,
+                channel,
+                fallbacks,
+                request_id,
+            })
+            .await?;
+        if !response.status().is_success() {
+            return Ok(response);
+        }
+        let tap = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(UsageTap::for_content_type);
+        let Some(tap) = tap else {
+            return Ok(response);
+        };
+        let strip = strip.filter(|_| tap.is_sse());
+        let mut response = response;
+        if strip.is_some() {
+            response.headers_mut().remove(header::CONTENT_LENGTH);
+        }
+        Ok(response.map(|inner| {
+            boxed_body(UsageBody {
+                inner,
+                tap,
+                prompt_tokens,
+                budget,
+                cut: Cut::No,
+                strip,
+                recorder: Some(recorder),
+            })
+        }))
+    }
+}
+
+// -- Factory / Layer ----------------------------------------------------------
+
+pub struct UsageServiceFactory<T> {
+    inner:   T,
+    db:      toasty::Db,
+    prices:  Prices,
+    quotas:  Quotas,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<T: MakeService> MakeService for UsageServiceFactory<T> {
+    type Service = UsageService<T::Service>;
+    type Error = T::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(UsageService {
+            inner:   self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            db:      self.db.clone(),
+            prices:  self.prices.clone(),
+            quotas:  self.quotas.clone(),
+            limiter: self.limiter.clone(),
+        })
+    }
+}
+
+impl<T> UsageService<T> {
+    pub fn layer() -> impl FactoryLayer<GatewayConfig, T, Factory = UsageServiceFactory<T>> {
+        layer_fn(|c: &GatewayConfig, inner| UsageServiceFactory {
+            inner,
+            db: c.db.clone(),
+            prices: c.prices.clone(),
+            quotas: c.quotas.clone(),
+            limiter: c.limiter.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        gateway::{ChannelInfo, TokenInfo, fixtures},
+        model::Token,
+    };
+    use futures_util::StreamExt;
+    use http_body_util::StreamBody;
+    use std::time::Duration;
+
+    #[test]
+    fn test_include_usage_only_for_streaming_chat() {
+        let rewritten = include_usage(
+            "/v1/chat/completions",
+            br#"{"model":"m","stream":true,"stream_options":{"foo":1}}"#,
+        )
+        .unwrap();
+        let value: Value = serde_json::from_slice(&rewritten).unwrap();
+        assert_eq!(value["stream_options"]["include_usage"], true);
+        assert_eq!(value["stream_options"]["foo"], 1);
+
+        assert!(include_usage("/v1/chat/completions", br#"{"model":"m"}"#).is_none());
+        assert!(include_usage("/v1/embeddings", br#"{"stream":true}"#).is_none());
+        assert!(
+            include_usage(
+                "/v1/completions",
+                br#"{"stream":true,"stream_options":{"include_usage":true}}"#
+            )
+            .is_none()
+        );
+    }
+
+    /// Leaf stub streaming back `events` SSE deltas, and usage only if the
+    /// request asked for it.
+    struct Streamer {
+        events: usize,
+    }
+
+    impl Service<GatewayRequest> for Streamer {
+        type Response = GatewayResponse;
+        type Error = anyhow::Error;
+
+        async fn call(&self, req: GatewayRequest) -> Result<Self::Response, Self::Error> {
+            let body = req.inner.into_body().collect().await.unwrap().to_bytes();
+            let request: Value = serde_json::from_slice(&body).unwrap();
+            let mut events = vec![
+                "data: {\"choices\":[{\"delta\":{\"content\":\"hi\"}}]}\n\n"
+                    .to_string();
+                self.events
+            ];
+            if request["stream_options"]["include_usage"] == true {
+                events.push(
+                    "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":8,\"completion_tokens\":2,\
+                     \"total_tokens\":10}}\n\n"
+                        .to_string(),
+                );
+            }
+            events.push("data: [DONE]\n\n".to_string());
+            let frames = futures_util::stream::iter(events)
+                .map(|e| Ok::<_, BoxError>(Frame::data(Bytes::from(e))));
+            Ok(http::Response::builder()
+                .header(header::CONTENT_TYPE, "text/event-stream")
+                .body(boxed_body(StreamBody::new(frames)))
+                .unwrap())
+        }
+    }
+
+    async fn service(events: usize) -> UsageService<Streamer> {
+        let db = crate::db::init_db(crate::db::MEMORY_DATABASE_URL)
+            .await
+            .unwrap();
+        UsageService {
+            inner: Streamer { events },
+            db,
+            prices: Prices::default(),
+            quotas: Quotas::default(),
+            limiter: Default::default(),
+        }
+    }
+
+    fn request(token: TokenInfo) -> GatewayRequest {
+        let inner = http::Request::post("/v1/chat/completions")
+            .body(full_body(r#"{"model":"m","stream":true}"#))
+            .unwrap();
+        GatewayRequest {
+            token,
+            channel: ChannelInfo {
+                id: 9,
+                ..fixtures::channel("local")
+            },
+            request_id: "req-1".into(),
+            ..fixtures::request(inner)
+        }
+    }
+
+    /// The usage rows, once the first one is written.
+    async fn logged(db: &toasty::Db) -> Vec<UsageLog> {
+        let mut db = db.clone();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let logs = UsageLog::all().exec(&mut db).await.unwrap();
+                if !logs.is_empty() {
+                    return logs;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_streamed_usage_is_logged() {
+        let service = service(1).await;
+        let token = TokenInfo {
+            name: "client".into(),
+            ..fixtures::token(4)
+        };
+        let response = service.call(request(token.clone())).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        // The usage event the gateway asked for is not the client's.
+        assert_eq!(
+            body,
+            "data: {\"choices\":[{\"delta\":{\"content\":\"hi\"}}]}\n\ndata: [DONE]\n\n"
+        );
+
+        let logs = logged(&service.db).await;
+        let log = &logs[0];
+        assert_eq!(log.request_id, "req-1");
+        assert_eq!((log.token_id, log.channel_id), (4, 9));
+        assert_eq!(log.model, "m");
+        assert_eq!(
+            (log.prompt_tokens, log.completion_tokens, log.total_tokens),
+            (8, 2, 10)
+        );
+        assert!(log.stream);
+        assert_eq!(log.quota, 10);
+
+        let mut asked = request(token);
+        asked.inner = http::Request::post("/v1/chat/completions")
+            .body(full_body(
+                r#"{"model":"m","stream":true,"stream_options":{"include_usage":true}}"#,
+            ))
+            .unwrap();
+        let response = service.call(asked).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.windows(7).any(|w| w == b"\"usage\""));
+    }
+
+    #[test]
+    fn test_strip_usage_across_frames() {
+        let stream = [
+            r#"data: {"choices":[{"delta":{"content":"hi"}}],"usage":null}"#,
+            r#"data: {"choices":[],"usage":{"prompt_tokens":4,"completion_tokens":1,"total_tokens":5}}"#,
+            "data: [DONE]",
+        ]
+        .map(|event| format!("{}\r\n\r\n", event))
+        .concat();
+        let mut strip = StripUsage::default();
+        let mut kept: Vec<u8> = stream
+            .as_bytes()
+            .chunks(7)
+            .flat_map(|chunk| strip.feed(chunk))
+            .collect();
+        kept.extend(strip.finish());
+        assert_eq!(
+            String::from_utf8(kept).unwrap(),
+            "data: {\"choices\":[{\"delta\":{\"content\":\"hi\"}}],\"usage\":null}\r\n\r\ndata: \
+             [DONE]\r\n\r\n"
+        );
+
+        let mut strip = StripUsage::default();
+        assert_eq!(strip.feed(b"data: [DO"), b"");
+        assert_eq!(strip.finish(), b"data: [DO");
+    }
+
+    #[tokio::test]
+    async fn test_stream_is_cut_off_when_quota_runs_out() {
+        let mut service = service(50).await;
+        let token = Token::create()
+            .name("team")
+            .key("sk-team")
+            .status(Token::STATUS_ENABLED)
+            .remaining_quota(20)
+            .unlimited_quota(false)
+            .exec(&mut service.db)
+            .await
+            .unwrap();
+        let token = TokenInfo {
+            name: token.name,
+            unlimited_quota: false,
+            ..fixtures::token(token.id)
+        };
+        let response = service.call(request(token)).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.ends_with(QUOTA_EXHAUSTED_EVENTS));
+        // A prompt token guessed from the request, then a token per event.
+        let deltas = body.windows(7).filter(|w| w == b"\"delta\"").count();
+        assert_eq!(deltas, 20);
+
+        let logs = logged(&service.db).await;
+        assert_eq!((logs[0].prompt_tokens, logs[0].completion_tokens), (1, 20));
+        assert_eq!(logs[0].quota, 21);
+        let balance = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let token = Token::all().first().exec(&mut service.db).await.unwrap();
+                let token = token.unwrap();
+                if token.used_quota > 0 {
+                    return token.remaining_quota;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(balance, -1);
+    }
+}
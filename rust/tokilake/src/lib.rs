@@ -4,3 +4,4 @@ pub mod db;
 pub mod gateway;
 pub mod model;
 pub mod relay;
+pub mod tunnel;
@@ -1,11 +1,16 @@
 use anyhow::Result;
-use service_async::MakeService;
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 use tokilake::{
     api::{self, AppState},
-    db::init_db,
-    gateway::{self, GatewayConfig},
+    db::{self, init_db},
+    gateway::{
+        GatewayConfig,
+        breaker::{BreakerConfig, Breakers},
+    },
+    relay::Relay,
+    tunnel,
 };
+use tokilake_core::ratelimit::{Limits, RateLimiter, RateLimits};
 use tokio::net::TcpListener;
 
 #[tokio::main]
@@ -13,33 +18,171 @@ async fn main() -> Result<()> {
     // Parse port from args: -addr :PORT or --port PORT
     let port = parse_port();
 
-    // Initialize Toasty ORM
-    let _db = init_db().await?;
+    // Initialize Toasty ORM: `-db PATH` (default ./tokilake.db), migrated on
+    // startup
+    let database_url = parse_database_url();
+    let db = init_db(&database_url).await?;
+    let admin_key = parse_admin_key();
+    if admin_key.is_none() {
+        println!(
+            "No admin key configured (-admin-key or TOKILAKE_ADMIN_KEY); management API disabled"
+        );
+    }
 
     println!("Tokilake starting...");
 
     // Build the gateway service stack (monolake-style)
-    let config = GatewayConfig {};
-    let gateway_factory = gateway::build_gateway_stack(config);
-    let _gateway_svc = gateway_factory
-        .make()
-        .expect("failed to build gateway service");
+    let mut config = GatewayConfig::new(db);
+    if let Some(retries) = parse_retries() {
+        config.retries = retries;
+    }
+    if let Some(trips) = parse_breaker_disable_after() {
+        config.breakers = Breakers::new(BreakerConfig {
+            disable_after_trips: Some(trips),
+            ..Default::default()
+        });
+    }
+    let limits = parse_rate_limits();
+    if !limits.is_unlimited() {
+        config.limiter = Arc::new(RateLimiter::new(limits));
+    }
+    config.aliases = Arc::new(parse_model_aliases());
+    let worker_gateway = tunnel::worker_gateway(&config);
+    let workers = config.workers.clone();
+    let db = config.db.clone();
+    let tokens = config.tokens.clone();
+    let breakers = config.breakers.clone();
+    let quotas = config.quotas.clone();
+    let relay = Arc::new(Relay::new(config).await?);
+    relay.reload_on_changes();
+    reload_on_sighup(relay.clone())?;
 
-    // Unified server: management API + OpenAI-compatible relay
+    // Unified server: management API + OpenAI-compatible relay + worker tunnel
     let state = AppState {
         start_time: std::time::Instant::now(),
+        db,
+        tokens,
+        breakers,
+        quotas,
+        admin_key,
+        relay,
     };
-    let app = api::router(state);
+    let app = api::router(state).merge(tunnel::connect_router(worker_gateway, workers));
 
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     println!("Tokilake listening on http://{}", addr);
 
     let listener = TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
 
+fn parse_database_url() -> String {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter()
+        .position(|arg| arg == "-db" || arg == "--db")
+        .and_then(|i| args.get(i + 1))
+        .map(|value| db::database_url(value))
+        .unwrap_or_else(|| db::DEFAULT_DATABASE_URL.to_string())
+}
+
+/// Rebuild the gateway stack from the database on SIGHUP, e.g. after channels
+/// were edited directly in the database.
+#[cfg(unix)]
+fn reload_on_sighup(relay: Arc<Relay>) -> Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            println!("SIGHUP received, reloading gateway");
+            if let Err(e) = relay.reload().await {
+                eprintln!("gateway reload failed: {:#}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn reload_on_sighup(_relay: Arc<Relay>) -> Result<()> {
+    Ok(())
+}
+
+/// `-retries N`: fallback channels tried after a failed attempt.
+fn parse_retries() -> Option<usize> {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter()
+        .position(|arg| arg == "-retries" || arg == "--retries")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|value| value.parse().ok())
+}
+
+/// `-breaker-disable-after N`: auto-disable a channel after its circuit
+/// breaker tripped N times in a row.
+fn parse_breaker_disable_after() -> Option<u32> {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter()
+        .position(|arg| arg == "-breaker-disable-after" || arg == "--breaker-disable-after")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|value| value.parse().ok())
+        .filter(|trips| *trips > 0)
+}
+
+/// `-rpm N` / `-tpm N`: requests and tokens per minute of each client token;
+/// `-model-rpm N` / `-model-tpm N`: the same for each model, across tokens.
+fn parse_rate_limits() -> RateLimits {
+    let args: Vec<String> = std::env::args().collect();
+    let flag = |name: &str| {
+        args.iter()
+            .position(|arg| arg.starts_with('-') && arg.trim_start_matches('-') == name)
+            .and_then(|i| args.get(i + 1))
+            .and_then(|value| value.parse().ok())
+            .filter(|limit| *limit > 0)
+    };
+    RateLimits {
+        per_token: Limits {
+            rpm: flag("rpm"),
+            tpm: flag("tpm"),
+        },
+        per_model: Limits {
+            rpm: flag("model-rpm"),
+            tpm: flag("model-tpm"),
+        },
+        ..Default::default()
+    }
+}
+
+/// `-model-alias ALIAS=MODEL`, repeatable: let clients ask for MODEL by
+/// another name.
+fn parse_model_aliases() -> HashMap<String, String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter()
+        .zip(args.iter().skip(1))
+        .filter(|(arg, _)| *arg == "-model-alias" || *arg == "--model-alias")
+        .filter_map(|(_, value)| value.split_once('='))
+        .map(|(alias, model)| (alias.trim().to_string(), model.trim().to_string()))
+        .filter(|(alias, model)| !alias.is_empty() && !model.is_empty())
+        .collect()
+}
+
+/// Credential of the management API: `-admin-key KEY`, else the
+/// `TOKILAKE_ADMIN_KEY` environment variable.
+fn parse_admin_key() -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter()
+        .position(|arg| arg == "-admin-key" || arg == "--admin-key")
+        .and_then(|i| args.get(i + 1).cloned())
+        .or_else(|| std::env::var("TOKILAKE_ADMIN_KEY").ok())
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+}
+
 fn parse_port() -> u16 {
     let args: Vec<String> = std::env::args().collect();
     let mut i = 1;
@@ -1,24 +1,154 @@
-#[derive(Debug, toasty::Model)]
+use ipnet::IpNet;
+use std::{collections::HashMap, net::IpAddr};
+
+/// Group of tokens and channels that were not given one.
+pub const DEFAULT_GROUP: &str = "default";
+
+#[derive(Debug, Clone, toasty::Model)]
 pub struct Channel {
     #[key]
     #[auto]
-    pub id:       u64,
-    pub name:     String,
-    pub provider: String,
-    pub models:   String,
-    pub base_url: Option<String>,
-    pub api_key:  Option<String>,
-    pub status:   i32,
-    pub weight:   i32,
+    pub id:            u64,
+    pub name:          String,
+    pub provider:      String,
+    pub models:        String,
+    pub base_url:      Option<String>,
+    pub api_key:       Option<String>,
+    pub status:        i32,
+    pub weight:        i32,
+    /// Why the channel was disabled by the gateway, if it was.
+    pub status_reason: Option<String>,
+    /// JSON object from the model names clients use to the names the
+    /// upstream knows them by.
+    pub model_mapping: Option<String>,
+    /// Only tokens of the same group are routed to the channel.
+    #[default(DEFAULT_GROUP.to_string())]
+    pub group:         String,
 }
 
 #[derive(Debug, toasty::Model)]
 pub struct Token {
     #[key]
     #[auto]
-    pub id:     u64,
-    pub name:   String,
+    pub id:              u64,
+    pub name:            String,
     #[unique]
-    pub key:    String,
-    pub status: i32,
+    pub key:             String,
+    pub status:          i32,
+    /// Quota left to spend; ignored while `unlimited_quota` is set.
+    #[default(0)]
+    pub remaining_quota: i64,
+    /// Quota spent so far.
+    #[default(0)]
+    pub used_quota:      i64,
+    #[default(true)]
+    pub unlimited_quota: bool,
+    /// Unix time from which the token is refused; never if unset.
+    pub expires_at:      Option<i64>,
+    /// Comma-separated models the token may use; any if unset.
+    pub models:          Option<String>,
+    /// Comma-separated addresses and CIDR networks requests may come from;
+    /// anywhere if unset.
+    pub allowed_ips:     Option<String>,
+    /// The token only reaches channels of this group.
+    #[default(DEFAULT_GROUP.to_string())]
+    pub group:           String,
+}
+
+impl Channel {
+    /// `status` of a channel that may receive traffic.
+    pub const STATUS_ENABLED: i32 = 1;
+    /// `status` of a channel turned off by an operator.
+    pub const STATUS_DISABLED: i32 = 2;
+    /// `status` of a channel turned off by the gateway itself, e.g. a
+    /// tokiame channel whose workers all disconnected.
+    pub const STATUS_AUTO_DISABLED: i32 = 3;
+
+    /// Whether `model` appears in the comma-separated `models` list or is
+    /// renamed by the model mapping.
+    pub fn serves(&self, model: &str) -> bool {
+        self.models.split(',').any(|m| m.trim() == model)
+            || self.upstream_models().contains_key(model)
+    }
+
+    /// The model mapping; an unreadable one renames nothing.
+    pub fn upstream_models(&self) -> HashMap<String, String> {
+        self.model_mapping
+            .as_deref()
+            .and_then(|mapping| serde_json::from_str(mapping).ok())
+            .unwrap_or_default()
+    }
+}
+
+impl Token {
+    /// `status` of a token that may be used.
+    pub const STATUS_ENABLED: i32 = 1;
+
+    /// The `models` list; empty if unset.
+    pub fn allowed_models(&self) -> Vec<String> {
+        split_list(self.models.as_deref())
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// The `allowed_ips` list; entries that do not parse are left out.
+    pub fn allowed_networks(&self) -> Vec<IpNet> {
+        split_list(self.allowed_ips.as_deref())
+            .filter_map(parse_network)
+            .collect()
+    }
+}
+
+/// A CIDR network, or a single address as a network of one.
+pub fn parse_network(value: &str) -> Option<IpNet> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+}
+
+/// The non-empty entries of a comma-separated list.
+fn split_list(list: Option<&str>) -> impl Iterator<Item = &str> {
+    list.unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+}
+
+/// Token usage of one answered request.
+#[derive(Debug, toasty::Model)]
+pub struct UsageLog {
+    #[key]
+    #[auto]
+    pub id:                u64,
+    pub request_id:        String,
+    #[index]
+    pub token_id:          u64,
+    pub channel_id:        u64,
+    pub model:             String,
+    pub prompt_tokens:     i64,
+    pub completion_tokens: i64,
+    pub total_tokens:      i64,
+    pub stream:            bool,
+    /// Quota charged to the token for the request.
+    pub quota:             i64,
+    /// Unix time, in seconds.
+    pub created_at:        i64,
+}
+
+/// What a model costs: quota charged per prompt and per completion token.
+/// Models without an entry cost [`ModelPrice::DEFAULT_RATIO`] per token.
+#[derive(Debug, Clone, serde::Serialize, toasty::Model)]
+pub struct ModelPrice {
+    #[key]
+    #[auto]
+    pub id:               u64,
+    #[unique]
+    pub model:            String,
+    pub prompt_ratio:     f64,
+    pub completion_ratio: f64,
+}
+
+impl ModelPrice {
+    pub const DEFAULT_RATIO: f64 = 1.0;
 }
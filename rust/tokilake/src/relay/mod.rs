@@ -1,6 +1,240 @@
 //! Relay module — OpenAI-compatible API passthrough.
 //!
-//! This module will house the logic for forwarding `/v1/chat/completions`
-//! and other OpenAI-compatible endpoints through the gateway service stack.
+//! Every `/v1/*` request is handed to the composed gateway service
+//! (`AuthService` → `RouteService` → … → `UpstreamService`) and its response,
+//! streamed or not, is sent back unchanged.
 //!
-//! Auth is now handled by `gateway::auth::AuthService` as a composable layer.
+//! [`Relay::reload`] rebuilds the service with `MakeService::make_via_ref`
+//! over a fresh snapshot of the channels and model prices, so state carried
+//! by the old service (the upstream connection pool, the token cache) moves
+//! over to the new one. The new service is swapped in atomically; requests
+//! already in flight finish on the service they started with.
+//!
+//! Reloads happen after every change made through the management API, when
+//! [`GatewayConfig::channels_changed`] is signalled (see
+//! [`Relay::reload_on_changes`]) and on SIGHUP.
+
+use crate::gateway::{
+    GatewayConfig, GatewayFactory, GatewayService, build_gateway_stack, error_response,
+};
+use axum::{body::Body, extract::Request, response::Response};
+use http::StatusCode;
+use service_async::{MakeService, Service};
+use std::sync::{Arc, RwLock};
+use tokio::sync::Mutex;
+
+/// The live gateway service and the factory that made it.
+pub struct Relay {
+    config:  GatewayConfig,
+    factory: Mutex<GatewayFactory>,
+    service: RwLock<Arc<GatewayService>>,
+}
+
+impl Relay {
+    pub async fn new(mut config: GatewayConfig) -> anyhow::Result<Self> {
+        config.load_channels().await?;
+        config.load_prices().await?;
+        let factory = build_gateway_stack(config.clone());
+        let service = factory.make().unwrap_or_else(|never| match never {});
+        Ok(Self {
+            config,
+            factory: Mutex::new(factory),
+            service: RwLock::new(Arc::new(service)),
+        })
+    }
+
+    /// The service new requests are sent to.
+    pub fn service(&self) -> Arc<GatewayService> {
+        self.service.read().unwrap().clone()
+    }
+
+    /// Reload the channels and prices, rebuild the stack from the current one
+    /// and switch new requests over to it.
+    pub async fn reload(&self) -> anyhow::Result<()> {
+        // Held throughout, so a slow reload can never overwrite the snapshot
+        // of one that started after it.
+        let mut factory = self.factory.lock().await;
+        let mut config = self.config.clone();
+        config.load_channels().await?;
+        config.load_prices().await?;
+        let channels = config.channels.len();
+
+        let next = build_gateway_stack(config);
+        let service = next
+            .make_via_ref(Some(&self.service()))
+            .unwrap_or_else(|never| match never {});
+        *self.service.write().unwrap() = Arc::new(service);
+        *factory = next;
+        tracing::info!("gateway reloaded with {} enabled channels", channels);
+        Ok(())
+    }
+
+    /// Reload whenever [`GatewayConfig::channels_changed`] is signalled.
+    pub fn reload_on_changes(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let relay = Arc::downgrade(self);
+        let changed = self.config.channels_changed.clone();
+        tokio::spawn(async move {
+            loop {
+                changed.notified().await;
+                let Some(relay) = relay.upgrade() else {
+                    return;
+                };
+                if let Err(e) = relay.reload().await {
+                    tracing::warn!("gateway reload failed: {:#}", e);
+                }
+            }
+        })
+    }
+
+    /// Serve one relay request.
+    pub async fn call(&self, req: Request) -> Response {
+        let service = self.service();
+        let response = match service.call(req).await {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::warn!("relay request failed: {:#}", e);
+                error_response(
+                    StatusCode::BAD_GATEWAY,
+                    "upstream request failed",
+                    "api_error",
+                    "upstream_error",
+                )
+            }
+        };
+        response.map(Body::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Channel, Token};
+    use axum::{Router, routing::post};
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+    use http::header;
+    use http_body_util::BodyExt;
+
+    /// Fake provider streaming two SSE events.
+    async fn spawn_provider() -> String {
+        let sse = |_: Request| async {
+            let events = futures_util::stream::iter(["data: {\"n\":1}\n\n", "data: [DONE]\n\n"])
+                .map(|event| Ok::<_, std::io::Error>(Bytes::from(event)));
+            Response::builder()
+                .header(header::CONTENT_TYPE, "text/event-stream")
+                .body(Body::from_stream(events))
+                .unwrap()
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/v1/chat/completions", post(sse));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    async fn config() -> GatewayConfig {
+        let mut db = crate::db::init_db(crate::db::MEMORY_DATABASE_URL)
+            .await
+            .unwrap();
+        Token::create()
+            .name("client")
+            .key("sk-client")
+            .status(Token::STATUS_ENABLED)
+            .exec(&mut db)
+            .await
+            .unwrap();
+        Channel::create()
+            .name("local")
+            .provider("openai")
+            .models("gpt-4o")
+            .base_url(Some(spawn_provider().await))
+            .status(Channel::STATUS_ENABLED)
+            .weight(1)
+            .exec(&mut db)
+            .await
+            .unwrap();
+        GatewayConfig::new(db)
+    }
+
+    async fn chat(relay: &Relay, key: &str) -> (StatusCode, String) {
+        let request = Request::post("/v1/chat/completions")
+            .header(header::AUTHORIZATION, format!("Bearer {}", key))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"model":"gpt-4o","stream":true}"#))
+            .unwrap();
+        let response = relay.call(request).await;
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[tokio::test]
+    async fn test_requests_go_through_the_stack_across_reloads() {
+        let relay = Relay::new(config().await).await.unwrap();
+
+        let (status, body) = chat(&relay, "sk-client").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "data: {\"n\":1}\n\ndata: [DONE]\n\n");
+        assert_eq!(chat(&relay, "sk-other").await.0, StatusCode::UNAUTHORIZED);
+
+        // A request in flight keeps the service it started on.
+        let before = relay.service();
+        let request = Request::post("/v1/chat/completions")
+            .header(header::AUTHORIZATION, "Bearer sk-client")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"model":"gpt-4o","stream":true}"#))
+            .unwrap();
+        let in_flight = before.call(request).await.unwrap();
+
+        let mut db = relay.config.db.clone();
+        let mut channel = Channel::all().first().exec(&mut db).await.unwrap().unwrap();
+        channel
+            .update()
+            .models("gpt-4.1")
+            .exec(&mut db)
+            .await
+            .unwrap();
+        relay.reload().await.unwrap();
+        assert!(!Arc::ptr_eq(&before, &relay.service()));
+
+        let body = in_flight.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.ends_with(b"data: [DONE]\n\n"));
+        assert_eq!(chat(&relay, "sk-client").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_reloads_when_channels_change() {
+        let config = config().await;
+        let relay = Arc::new(Relay::new(config.clone()).await.unwrap());
+        let watcher = relay.reload_on_changes();
+
+        let mut db = config.db.clone();
+        let mut channel = Channel::all().first().exec(&mut db).await.unwrap().unwrap();
+        channel
+            .update()
+            .status(Channel::STATUS_DISABLED)
+            .exec(&mut db)
+            .await
+            .unwrap();
+        assert_eq!(chat(&relay, "sk-client").await.0, StatusCode::OK);
+
+        let before = relay.service();
+        config.channels_changed.notify_one();
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while Arc::ptr_eq(&before, &relay.service()) {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(chat(&relay, "sk-client").await.0, StatusCode::NOT_FOUND);
+
+        // The watcher stops with the relay.
+        drop((relay, before));
+        config.channels_changed.notify_one();
+        tokio::time::timeout(std::time::Duration::from_secs(5), watcher)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,625 @@
+//! Worker side of the gateway: tokiame workers connect here.
+//!
+//! Workers authenticate with a gateway token on `/connect` (WebSocket +
+//! smux). Each namespace they register becomes — or re-enables — a `tokiame`
+//! [`Channel`] whose `base_url` points back at the namespace, so the
+//! RouteService picks it like any other channel. The channel lists every model
+//! a live replica of the namespace serves; when the last worker of a
+//! namespace leaves, its channel is auto-disabled. Every such change signals
+//! [`GatewayConfig::channels_changed`] so the gateway stack is rebuilt.
+
+use crate::{
+    gateway::{
+        GatewayConfig,
+        auth::{TokenCache, TokenState},
+        tokiame::{self, WorkerSessions},
+    },
+    model::Channel,
+};
+use axum::{
+    Json, Router,
+    extract::{
+        Query, State, WebSocketUpgrade,
+        ws::{Message, WebSocket},
+    },
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicI32, Ordering},
+    },
+};
+use tokilake_core::{
+    error::TunnelError,
+    gateway::{Authenticator, ConnectInfo, Gateway, WorkerRegistry, extract_connect_token},
+    protocol::{RegisterResult, Token, transport},
+    tunnel::channel::ChannelIo,
+};
+use tokio::sync::{Mutex, Notify, mpsc};
+use tracing::info;
+
+/// The control plane served to workers.
+pub type WorkerGateway = Gateway<DbAuthenticator, DbWorkerRegistry>;
+
+/// Build the worker gateway over the same database, token cache and worker
+/// sessions as the gateway stack.
+pub fn worker_gateway(config: &GatewayConfig) -> WorkerGateway {
+    Gateway::new(
+        DbAuthenticator {
+            db:     config.db.clone(),
+            tokens: config.tokens.clone(),
+        },
+        DbWorkerRegistry::new(config.db.clone(), config.channels_changed.clone()),
+    )
+}
+
+/// Authenticates workers against the `Token` table.
+pub struct DbAuthenticator {
+    db:     toasty::Db,
+    tokens: TokenCache,
+}
+
+impl Authenticator for DbAuthenticator {
+    async fn authenticate_token_key(
+        &self,
+        token_key: &str,
+    ) -> Result<(String, Token), TunnelError> {
+        // Connect tokens arrive without `sk-`; keys may be stored with it.
+        for key in [token_key.to_string(), format!("sk-{}", token_key)] {
+            match self.tokens.resolve(&self.db, &key).await? {
+                TokenState::Active(token) => {
+                    return Ok((token_key.to_string(), Token {
+                        user_id: token.id as i64,
+                    }));
+                }
+                TokenState::Disabled => return Err(TunnelError::auth_failed("token is disabled")),
+                TokenState::Unknown => {}
+            }
+        }
+        Err(TunnelError::auth_failed("invalid token"))
+    }
+}
+
+/// Keeps one `tokiame` channel per worker namespace. Its `models` are the
+/// union of what the namespace's live replicas advertise.
+pub struct DbWorkerRegistry {
+    db:               toasty::Db,
+    channels_changed: Arc<Notify>,
+    next_id:          AtomicI32,
+    /// Held across database updates so replicas of one namespace never race
+    /// to create or rewrite its channel.
+    workers:          Mutex<HashMap<i32, Replica>>,
+}
+
+/// A registered worker.
+struct Replica {
+    namespace: String,
+    models:    Vec<String>,
+}
+
+impl DbWorkerRegistry {
+    pub fn new(db: toasty::Db, channels_changed: Arc<Notify>) -> Self {
+        Self {
+            db,
+            channels_changed,
+            next_id: AtomicI32::new(1),
+            workers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn find_channel(&self, namespace: &str) -> Result<Option<Channel>, TunnelError> {
+        let base_url = tokiame::base_url(namespace);
+        let mut db = self.db.clone();
+        let channels = Channel::filter(Channel::fields().provider().eq(tokiame::PROVIDER))
+            .exec(&mut db)
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(channels
+            .into_iter()
+            .find(|c| c.base_url.as_deref() == Some(base_url.as_str())))
+    }
+
+    /// Enable the channel of `namespace` with `models`, creating it if needed.
+    async fn open_channel(
+        &self,
+        namespace: &str,
+        models: &[String],
+    ) -> Result<Channel, TunnelError> {
+        let mut db = self.db.clone();
+        let channel = match self.find_channel(namespace).await? {
+            Some(mut channel) => {
+                // An operator's "disabled" wins over a reconnecting worker.
+                let status = match channel.status {
+                    Channel::STATUS_DISABLED => Channel::STATUS_DISABLED,
+                    _ => Channel::STATUS_ENABLED,
+                };
+                let reason = match status {
+                    Channel::STATUS_DISABLED => channel.status_reason.clone(),
+                    _ => None,
+                };
+                channel
+                    .update()
+                    .models(models.join(","))
+                    .status(status)
+                    .status_reason(reason)
+                    .exec(&mut db)
+                    .await
+                    .map_err(anyhow::Error::from)?;
+                channel
+            }
+            None => Channel::create()
+                .name(format!("tokiame-{}", namespace))
+                .provider(tokiame::PROVIDER)
+                .models(models.join(","))
+                .base_url(tokiame::base_url(namespace))
+                .status(Channel::STATUS_ENABLED)
+                .weight(1)
+                .exec(&mut db)
+                .await
+                .map_err(anyhow::Error::from)?,
+        };
+        self.channels_changed.notify_one();
+        Ok(channel)
+    }
+
+    /// Replace the models of `worker_id` and rewrite its channel's union.
+    async fn set_models(&self, worker_id: i32, models: &[String]) -> Result<(), TunnelError> {
+        let models = normalize_models(models);
+        let mut workers = self.workers.lock().await;
+        let namespace = match workers.get(&worker_id) {
+            Some(replica) if models.is_empty() || replica.models == models => return Ok(()),
+            Some(replica) => replica.namespace.clone(),
+            None => return Err(TunnelError::protocol("worker not registered")),
+        };
+        let before = served_models(&workers, &namespace);
+        workers
+            .entry(worker_id)
+            .and_modify(|replica| replica.models = models);
+        self.write_models(&namespace, &before, &served_models(&workers, &namespace))
+            .await
+    }
+
+    /// Store `served` as the channel's models if they differ from `before`.
+    async fn write_models(
+        &self,
+        namespace: &str,
+        before: &[String],
+        served: &[String],
+    ) -> Result<(), TunnelError> {
+        if before == served {
+            return Ok(());
+        }
+        if let Some(mut channel) = self.find_channel(namespace).await? {
+            let mut db = self.db.clone();
+            channel
+                .update()
+                .models(served.join(","))
+                .exec(&mut db)
+                .await
+                .map_err(anyhow::Error::from)?;
+            self.channels_changed.notify_one();
+        }
+        Ok(())
+    }
+}
+
+fn normalize_models(models: &[String]) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for model in models.iter().map(|m| m.trim()).filter(|m| !m.is_empty()) {
+        if !normalized.iter().any(|m| m == model) {
+            normalized.push(model.to_string());
+        }
+    }
+    normalized
+}
+
+/// Every model some replica of `namespace` serves, oldest replica first.
+fn served_models(workers: &HashMap<i32, Replica>, namespace: &str) -> Vec<String> {
+    let mut replicas: Vec<_> = workers
+        .iter()
+        .filter(|(_, replica)| replica.namespace == namespace)
+        .collect();
+    replicas.sort_by_key(|(id, _)| **id);
+    let models: Vec<String> = replicas
+        .into_iter()
+        .flat_map(|(_, replica)| replica.models.iter().cloned())
+        .collect();
+    normalize_models(&models)
+}
+
+impl WorkerRegistry for DbWorkerRegistry {
+    async fn register_worker(
+        &self,
+        _session_id: u64,
+        namespace: &str,
+        _node_name: &str,
+        group: &str,
+        models: &[String],
+        backend_type: &str,
+    ) -> Result<RegisterResult, TunnelError> {
+        let namespace = namespace.trim();
+        if namespace.is_empty() {
+            return Err(TunnelError::protocol("namespace is required"));
+        }
+        let models = normalize_models(models);
+        if models.is_empty() {
+            return Err(TunnelError::protocol("at least one model is required"));
+        }
+
+        let mut workers = self.workers.lock().await;
+        let worker_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        workers.insert(worker_id, Replica {
+            namespace: namespace.to_string(),
+            models:    models.clone(),
+        });
+        let channel = match self
+            .open_channel(namespace, &served_models(&workers, namespace))
+            .await
+        {
+            Ok(channel) => channel,
+            Err(e) => {
+                workers.remove(&worker_id);
+                return Err(e);
+            }
+        };
+
+        info!(
+            "worker registered: id={} namespace={} channel={} models={:?}",
+            worker_id, namespace, channel.id, models
+        );
+        Ok(RegisterResult {
+            worker_id,
+            channel_id: channel.id as i32,
+            namespace: namespace.to_string(),
+            group: group.trim().to_string(),
+            models,
+            backend_type: backend_type.trim().to_string(),
+            status: 1,
+        })
+    }
+
+    async fn update_heartbeat(
+        &self,
+        worker_id: i32,
+        _status: i32,
+        _node_name: &str,
+        current_models: &[String],
+    ) -> Result<(), TunnelError> {
+        self.set_models(worker_id, current_models).await
+    }
+
+    async fn sync_models(
+        &self,
+        worker_id: i32,
+        _group: &str,
+        models: &[String],
+        _backend_type: &str,
+    ) -> Result<(), TunnelError> {
+        self.set_models(worker_id, models).await
+    }
+
+    async fn cleanup_worker(&self, worker_id: i32) -> Result<(), TunnelError> {
+        let mut workers = self.workers.lock().await;
+        let Some(namespace) = workers.get(&worker_id).map(|r| r.namespace.clone()) else {
+            return Ok(());
+        };
+        let before = served_models(&workers, &namespace);
+        workers.remove(&worker_id);
+        if workers
+            .values()
+            .any(|replica| replica.namespace == namespace)
+        {
+            return self
+                .write_models(&namespace, &before, &served_models(&workers, &namespace))
+                .await;
+        }
+        if let Some(mut channel) = self.find_channel(&namespace).await?
+            && channel.status == Channel::STATUS_ENABLED
+        {
+            let mut db = self.db.clone();
+            channel
+                .update()
+                .status(Channel::STATUS_AUTO_DISABLED)
+                .status_reason(Some("no workers connected".to_string()))
+                .exec(&mut db)
+                .await
+                .map_err(anyhow::Error::from)?;
+            info!("channel {} disabled: no workers left", channel.name);
+            self.channels_changed.notify_one();
+        }
+        Ok(())
+    }
+}
+
+// -- /connect -----------------------------------------------------------------
+
+#[derive(Clone)]
+struct ConnectState {
+    gateway: WorkerGateway,
+    workers: Arc<WorkerSessions>,
+}
+
+/// Routes accepting worker connections. The server must be run with
+/// `into_make_service_with_connect_info::<SocketAddr>`.
+pub fn connect_router(gateway: WorkerGateway, workers: Arc<WorkerSessions>) -> Router {
+    Router::new()
+        .route("/connect", get(ws_handler))
+        .route("/api/tokilake/connect", get(ws_handler))
+        .with_state(ConnectState { gateway, workers })
+}
+
+#[derive(Deserialize)]
+struct ConnectQuery {
+    token:        Option<String>,
+    access_token: Option<String>,
+}
+
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<ConnectState>,
+    Query(query): Query<ConnectQuery>,
+    axum::extract::ConnectInfo(addr): axum::extract::ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let authorization = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    let authenticated = match extract_connect_token(
+        authorization,
+        query.access_token.as_deref(),
+        query.token.as_deref(),
+    ) {
+        Ok(raw) => state.gateway.authenticate(&raw).await,
+        Err(e) => Err(e),
+    };
+    let (token_key, token) = match authenticated {
+        Ok(pair) => pair,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(serde_json::json!({"error": e.to_string()})),
+            )
+                .into_response();
+        }
+    };
+
+    let info = ConnectInfo {
+        token: Some(token),
+        token_key,
+        remote_addr: addr.to_string(),
+        transport: transport::WEBSOCKET.to_string(),
+    };
+    ws.protocols(["tokilake.v1"])
+        .on_upgrade(move |socket| handle_ws_connection(socket, state, info))
+}
+
+async fn handle_ws_connection(socket: WebSocket, state: ConnectState, info: ConnectInfo) {
+    let (mut ws_sender, mut ws_receiver) = socket.split();
+
+    // Pump binary WebSocket messages to and from the smux byte stream
+    let (ws_out_tx, mut ws_out_rx) = mpsc::channel::<Bytes>(32);
+    let (ws_in_tx, ws_in_rx) = mpsc::channel::<Bytes>(32);
+
+    let writer = tokio::spawn(async move {
+        while let Some(data) = ws_out_rx.recv().await {
+            if ws_sender.send(Message::Binary(data)).await.is_err() {
+                break;
+            }
+        }
+        let _ = ws_sender.close().await;
+    });
+
+    let reader = tokio::spawn(async move {
+        while let Some(msg) = ws_receiver.next().await {
+            match msg {
+                Ok(Message::Binary(data)) if ws_in_tx.send(data.clone()).await.is_err() => {
+                    break;
+                }
+                Ok(Message::Close(_)) | Err(_) => break,
+                _ => {}
+            }
+        }
+    });
+
+    let smux_config = tokilake_smux::Config {
+        version: 1,
+        keep_alive_disabled: true,
+        ..Default::default()
+    };
+    let smux_session =
+        tokilake_smux::Session::server(ChannelIo::new(ws_in_rx, ws_out_tx), smux_config);
+
+    // Errors are logged by the gateway
+    let _ = state
+        .gateway
+        .serve(&state.workers, smux_session, info)
+        .await;
+
+    reader.abort();
+    // The writer drains and closes once the smux session drops its stream
+    let _ = writer.await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{model::Token, relay::Relay};
+    use axum::body::Body;
+    use http_body_util::BodyExt;
+    use std::time::Duration;
+    use tokilake_core::worker::{ModelTarget, WorkerClient, WorkerConfig};
+
+    /// Local model backend echoing the request body.
+    async fn spawn_backend() -> String {
+        let echo = |body: Bytes| async move { body };
+        let app = Router::new().route("/v1/chat/completions", axum::routing::post(echo));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    async fn channel(config: &GatewayConfig) -> Option<Channel> {
+        let registry = DbWorkerRegistry::new(config.db.clone(), Arc::default());
+        registry.find_channel("gpu-a").await.unwrap()
+    }
+
+    /// Chat through the relay until it answers with `expected`; the stack is
+    /// rebuilt in the background after a worker comes or goes.
+    async fn chat_until(relay: &Relay, expected: StatusCode) -> String {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let request = http::Request::post("/v1/chat/completions")
+                    .header(header::AUTHORIZATION, "Bearer sk-client")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(r#"{"model":"llama"}"#))
+                    .unwrap();
+                let response = relay.call(request).await;
+                let status = response.status();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                if status == expected {
+                    return String::from_utf8_lossy(&body).into_owned();
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_channel_serves_the_models_of_every_replica() {
+        let db = crate::db::init_db(crate::db::MEMORY_DATABASE_URL)
+            .await
+            .unwrap();
+        let registry = DbWorkerRegistry::new(db, Arc::default());
+        let models = |list: &[&str]| list.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+        let served = async || registry.find_channel("gpu-a").await.unwrap().unwrap();
+
+        let first = registry
+            .register_worker(1, "gpu-a", "", "", &models(&["llama", "qwen"]), "")
+            .await
+            .unwrap();
+        let second = registry
+            .register_worker(2, "gpu-a", "", "", &models(&["llama", "mistral"]), "")
+            .await
+            .unwrap();
+        assert_eq!(first.channel_id, second.channel_id);
+        assert_eq!(served().await.models, "llama,qwen,mistral");
+
+        registry
+            .sync_models(second.worker_id, "", &models(&["phi"]), "")
+            .await
+            .unwrap();
+        assert_eq!(served().await.models, "llama,qwen,phi");
+
+        registry.cleanup_worker(first.worker_id).await.unwrap();
+        let channel = served().await;
+        assert_eq!(channel.models, "phi");
+        assert_eq!(channel.status, Channel::STATUS_ENABLED);
+
+        registry.cleanup_worker(second.worker_id).await.unwrap();
+        assert_eq!(served().await.status, Channel::STATUS_AUTO_DISABLED);
+    }
+
+    #[tokio::test]
+    async fn test_tokiame_channel_follows_connected_worker() {
+        let mut db = crate::db::init_db(crate::db::MEMORY_DATABASE_URL)
+            .await
+            .unwrap();
+        for key in ["sk-worker", "sk-client"] {
+            Token::create()
+                .name(key)
+                .key(key)
+                .status(Token::STATUS_ENABLED)
+                .exec(&mut db)
+                .await
+                .unwrap();
+        }
+        let config = GatewayConfig::new(db);
+        let relay = Arc::new(Relay::new(config.clone()).await.unwrap());
+        relay.reload_on_changes();
+        chat_until(&relay, StatusCode::NOT_FOUND).await;
+
+        // Worker connected through an in-memory smux session.
+        let gateway = worker_gateway(&config);
+        let (token_key, token) = gateway.authenticate("worker").await.unwrap();
+        let (gateway_io, worker_io) = tokio::io::duplex(64 * 1024);
+        let served = tokio::spawn({
+            let workers = config.workers.clone();
+            let session = tokilake_smux::Session::server(gateway_io, Default::default());
+            let info = ConnectInfo {
+                token: Some(token),
+                token_key,
+                remote_addr: "test".into(),
+                transport: transport::WEBSOCKET.into(),
+            };
+            async move { gateway.serve(&workers, session, info).await }
+        });
+        let mut worker_config = WorkerConfig {
+            gateway_url: "http://127.0.0.1:1/connect".into(),
+            token: "worker".into(),
+            namespace: "gpu-a".into(),
+            model_targets: HashMap::from([("llama".to_string(), ModelTarget {
+                url: spawn_backend().await,
+                ..Default::default()
+            })]),
+            ..Default::default()
+        };
+        worker_config.validate().unwrap();
+        let worker = WorkerClient::new(worker_config);
+        tokio::spawn({
+            let worker = worker.clone();
+            let session = tokilake_smux::Session::client(worker_io, Default::default());
+            async move { worker.serve(session, transport::WEBSOCKET).await }
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while config.workers.get_all_by_namespace("gpu-a").is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let registered = channel(&config).await.unwrap();
+        assert_eq!(registered.provider, tokiame::PROVIDER);
+        assert_eq!(registered.models, "llama");
+        assert_eq!(registered.status, Channel::STATUS_ENABLED);
+
+        let body = chat_until(&relay, StatusCode::OK).await;
+        assert_eq!(body, r#"{"model":"llama"}"#);
+
+        // The worker's namespace owns the models it serves.
+        let request = http::Request::get("/v1/models/llama")
+            .header(header::AUTHORIZATION, "Bearer sk-client")
+            .body(Body::empty())
+            .unwrap();
+        let response = relay.call(request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let model: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(model["owned_by"], "gpu-a");
+
+        // The last worker leaving disables the channel.
+        worker.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), served)
+            .await
+            .unwrap()
+            .unwrap()
+            .ok();
+        assert_eq!(
+            channel(&config).await.unwrap().status,
+            Channel::STATUS_AUTO_DISABLED
+        );
+        let body = chat_until(&relay, StatusCode::NOT_FOUND).await;
+        assert!(body.contains("model_not_found"), "{}", body);
+    }
+}